@@ -0,0 +1,329 @@
+// Particle vs shape collision for the CPU solver.
+use crate::{
+	state::{planes_of, Shape},
+	sys::{eNvFlexPhaseShapeChannelMask, NvFlexParams},
+	types::{Quat, Vector3, Vector4},
+};
+
+/// A shape uploaded through [SolverBackend::set_shapes](super::SolverBackend::set_shapes),
+/// along with where it was last step so particles can be dragged along by moving shapes.
+#[derive(Debug)]
+pub struct Collider {
+	pub shape: Shape,
+	prev_pos: Vector3,
+	prev_rot: Quat,
+	/// Only particles with one of these channels in their phase collide with it
+	channels: i32,
+}
+
+impl Collider {
+	pub fn new(shape: Shape, prev_pos: Vector4, prev_rot: Quat, flags: i32) -> Self {
+		Self {
+			shape,
+			prev_pos: prev_pos.xyz(),
+			prev_rot,
+			channels: flags & eNvFlexPhaseShapeChannelMask,
+		}
+	}
+
+	/// Where the point of the shape currently at `point` was at the start of the step.
+	fn previous_point(&self, point: Vector3) -> Vector3 {
+		let local = self.shape.get_rot().conjugate().rotate(point - self.shape.get_pos().xyz());
+		self.prev_rot.rotate(local) + self.prev_pos
+	}
+}
+
+/// A collider a particle was close enough to when contacts were found, see [find_contacts].
+#[derive(Debug, Clone, Copy)]
+pub struct Candidate {
+	particle: u32,
+	collider: u32,
+}
+
+/// Finds the colliders each particle is within `collisionDistance + shapeCollisionMargin` of, in particle order.
+/// Like in FleX, only these are collided with while solving, so the margin has to cover how far particles move during it.
+pub fn find_contacts(
+	params: &NvFlexParams,
+	colliders: &[Collider],
+	predicted: &[Vector3],
+	imass: &[f32],
+	phases: &[i32],
+	candidates: &mut Vec<Candidate>,
+) {
+	let reach = params.collisionDistance + params.shapeCollisionMargin;
+	candidates.clear();
+
+	for k in 0..predicted.len() {
+		if imass[k] == 0.0 {
+			continue;
+		}
+
+		for (c, collider) in colliders.iter().enumerate() {
+			if phases[k] & collider.channels == 0 || collider.shape.signed_distance(predicted[k]).0 >= reach {
+				continue;
+			}
+
+			candidates.push(Candidate {
+				particle: k as u32,
+				collider: c as u32,
+			});
+		}
+	}
+}
+
+/// Pushes particles out of the colliders in `candidates` and out of planes until they're `collisionDistance` away from the surface,
+/// applying friction to their motion along it.
+/// `contacts` is filled with the normal each particle was pushed along, or zero if it didn't touch anything.
+pub fn collide(
+	params: &NvFlexParams,
+	colliders: &[Collider],
+	candidates: &[Candidate],
+	predicted: &mut [Vector3],
+	previous: &[Vector3],
+	imass: &[f32],
+	contacts: &mut [Vector3],
+) {
+	let planes = planes_of(params);
+	let mut candidates = candidates.iter().peekable();
+
+	for k in 0..predicted.len() {
+		contacts[k] = Vector3::ZERO;
+
+		while let Some(candidate) = candidates.next_if(|c| c.particle as usize == k) {
+			let collider = &colliders[candidate.collider as usize];
+			let (distance, normal) = collider.shape.signed_distance(predicted[k]);
+
+			// Friction is relative to how the surface itself moved
+			let surface_motion = |point| point - collider.previous_point(point);
+			push_out(params, &mut predicted[k], previous[k], surface_motion, distance, normal, &mut contacts[k]);
+		}
+
+		if imass[k] == 0.0 {
+			continue;
+		}
+
+		// Planes are `ax + by + cz + d = 0`, particles being kept on the side the normal points to.
+		for plane in planes {
+			let normal = Vector3(plane[0], plane[1], plane[2]);
+			let distance = normal.dot(predicted[k]) + plane[3];
+			push_out(params, &mut predicted[k], previous[k], |_| Vector3::ZERO, distance, normal, &mut contacts[k]);
+		}
+	}
+}
+
+/// Pushes a particle `distance` from a surface out along its `normal`, then applies friction.
+/// `surface_motion` gives how far the surface at a point moved this step, and is taken where the particle ends up.
+fn push_out(
+	params: &NvFlexParams,
+	predicted: &mut Vector3,
+	previous: Vector3,
+	surface_motion: impl Fn(Vector3) -> Vector3,
+	distance: f32,
+	normal: Vector3,
+	contact: &mut Vector3,
+) {
+	let depth = params.collisionDistance - distance;
+	if depth <= 0.0 {
+		return;
+	}
+
+	*predicted += normal * depth;
+	*contact = normal;
+
+	let motion = (*predicted - previous) - surface_motion(*predicted);
+	let tangent = motion - normal * motion.dot(normal);
+	let slide = tangent.length();
+
+	if slide < params.staticFriction * depth {
+		*predicted -= tangent;
+	} else if slide > 0.0 {
+		*predicted -= tangent * (params.dynamicFriction * depth / slide).min(1.0);
+	}
+}
+
+/// Makes particles that hit a collider this step bounce off of it.
+/// `incoming` is the velocity particles had before collisions were solved.
+pub fn restitution(params: &NvFlexParams, contacts: &[Vector3], incoming: &[Vector3], velocities: &mut [Vector3]) {
+	if params.restitution <= 0.0 {
+		return;
+	}
+
+	for k in 0..velocities.len() {
+		let normal = contacts[k];
+		let approach = incoming[k].dot(normal);
+		if normal == Vector3::ZERO || approach >= 0.0 {
+			continue;
+		}
+
+		let bounce = -approach * params.restitution;
+		let current = velocities[k].dot(normal);
+		if bounce > current {
+			velocities[k] += normal * (bounce - current);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		config::PARAMS,
+		helper::{NvFlexMakePhase, NvFlexMakePhaseWithChannels, NvFlexMakeShapeFlags, NvFlexMakeShapeFlagsWithChannels},
+		state::{Capsule, Cube, Sphere},
+		sys::eNvFlexPhaseFluid,
+	};
+
+	const EPSILON: f32 = 1e-4;
+
+	/// Lowest channel bit in phases and shape flags
+	const FIRST_CHANNEL: i32 = 1 << eNvFlexPhaseShapeChannelMask.trailing_zeros();
+
+	fn assert_near(a: Vector3, b: Vector3) {
+		assert!((a - b).length() < EPSILON, "{:?} != {:?}", a, b);
+	}
+
+	fn still(shape: Shape) -> Collider {
+		let (pos, rot) = (*shape.get_pos(), *shape.get_rot());
+		let flags = NvFlexMakeShapeFlags(shape.kind(), false);
+		Collider::new(shape, pos, rot, flags)
+	}
+
+	/// Collides a single particle moving from `previous` to `predicted`, returning where it ended up and its contact normal.
+	fn collide_one(params: &NvFlexParams, colliders: &[Collider], previous: Vector3, predicted: Vector3) -> (Vector3, Vector3) {
+		let phase = NvFlexMakePhase(0, eNvFlexPhaseFluid);
+		let (mut predicted, mut contact, mut candidates) = ([predicted], [Vector3::ZERO], vec![]);
+		find_contacts(params, colliders, &predicted, &[1.0], &[phase], &mut candidates);
+		collide(params, colliders, &candidates, &mut predicted, &[previous], &[1.0], &mut contact);
+		(predicted[0], contact[0])
+	}
+
+	#[test]
+	fn box_pushes_out_through_closest_face() {
+		let cube = still(Cube::new(Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, [1.0, 2.0, 1.0]).into());
+		let start = Vector3(0.2, 0.0, 0.9);
+
+		let (pos, contact) = collide_one(&PARAMS, &[cube], start, start);
+		assert_near(pos, Vector3(0.2, 0.0, 1.0 + PARAMS.collisionDistance));
+		assert_near(contact, Vector3(0.0, 0.0, 1.0));
+	}
+
+	#[test]
+	fn sphere_pushes_out_radially() {
+		let sphere = still(Sphere::new(Vector4(1.0, 0.0, 0.0, 0.0), Quat::IDENTITY, 1.0).into());
+		let start = Vector3(1.0, 0.6, 0.0);
+
+		let (pos, contact) = collide_one(&PARAMS, &[sphere], start, start);
+		assert_near(pos, Vector3(1.0, 1.0 + PARAMS.collisionDistance, 0.0));
+		assert_near(contact, Vector3(0.0, 1.0, 0.0));
+	}
+
+	#[test]
+	fn capsule_pushes_out_from_its_axis() {
+		let capsule = still(Capsule::new(Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, 0.5, 1.0).into());
+
+		// Along the side, the push is straight away from the axis
+		let start = Vector3(0.7, 0.0, 0.3);
+		let (pos, contact) = collide_one(&PARAMS, std::slice::from_ref(&capsule), start, start);
+		assert_near(pos, Vector3(0.7, 0.0, 0.5 + PARAMS.collisionDistance));
+		assert_near(contact, Vector3(0.0, 0.0, 1.0));
+
+		// Past the end, it's away from the end cap's center
+		let start = Vector3(1.3, 0.0, 0.0);
+		let (pos, contact) = collide_one(&PARAMS, &[capsule], start, start);
+		assert_near(pos, Vector3(1.5 + PARAMS.collisionDistance, 0.0, 0.0));
+		assert_near(contact, Vector3(1.0, 0.0, 0.0));
+	}
+
+	#[test]
+	fn leaves_particles_outside_of_collision_distance() {
+		let sphere = still(Sphere::new(Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, 1.0).into());
+		let start = Vector3(0.0, 0.0, 1.0 + PARAMS.collisionDistance + PARAMS.shapeCollisionMargin * 0.5);
+
+		let (pos, contact) = collide_one(&PARAMS, &[sphere], start, start);
+		assert_near(pos, start);
+		assert_eq!(contact, Vector3::ZERO);
+	}
+
+	#[test]
+	fn margin_catches_particles_moving_into_shapes() {
+		let sphere = [still(Sphere::new(Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, 1.0).into())];
+		let phase = NvFlexMakePhase(0, eNvFlexPhaseFluid);
+
+		// Contacts are found before solving moves the particle into the sphere
+		let collide_after_moving = |margin: f32| {
+			let params = NvFlexParams { shapeCollisionMargin: margin, ..PARAMS };
+			let mut candidates = vec![];
+			find_contacts(&params, &sphere, &[Vector3(0.0, 0.0, 1.5)], &[1.0], &[phase], &mut candidates);
+
+			let mut predicted = [Vector3(0.0, 0.0, 0.9)];
+			collide(&params, &sphere, &candidates, &mut predicted, &[Vector3(0.0, 0.0, 1.5)], &[1.0], &mut [Vector3::ZERO]);
+			predicted[0]
+		};
+
+		assert_near(collide_after_moving(0.01), Vector3(0.0, 0.0, 0.9));
+		assert_near(collide_after_moving(1.0), Vector3(0.0, 0.0, 1.0 + PARAMS.collisionDistance));
+	}
+
+	#[test]
+	fn skips_shapes_on_other_channels() {
+		let shape: Shape = Sphere::new(Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, 1.0).into();
+		let flags = NvFlexMakeShapeFlagsWithChannels(shape.kind(), false, FIRST_CHANNEL << 1);
+		let sphere = Collider::new(shape, Vector4(0.0, 0.0, 0.0, 0.0), Quat::IDENTITY, flags);
+
+		let phase = NvFlexMakePhaseWithChannels(0, eNvFlexPhaseFluid, FIRST_CHANNEL);
+		let mut candidates = vec![];
+		find_contacts(&PARAMS, &[sphere], &[Vector3(0.0, 0.0, 0.5)], &[1.0], &[phase], &mut candidates);
+		assert!(candidates.is_empty());
+	}
+
+	#[test]
+	fn friction_slows_sliding() {
+		let floor = || still(Cube::new(Vector4(0.0, 0.0, -1.0, 0.0), Quat::IDENTITY, [10.0, 10.0, 1.0]).into());
+		let (previous, predicted) = (Vector3(0.0, 0.0, 0.0), Vector3(0.1, 0.0, -0.05));
+
+		// Without friction it keeps sliding
+		let (pos, _) = collide_one(&PARAMS, &[floor()], previous, predicted);
+		assert_near(pos, Vector3(0.1, 0.0, PARAMS.collisionDistance));
+
+		// Static friction holds it in place when the slide is small compared to how far it was pushed out
+		let params = NvFlexParams { staticFriction: 10.0, ..PARAMS };
+		let (pos, _) = collide_one(&params, &[floor()], previous, predicted);
+		assert_near(pos, Vector3(0.0, 0.0, PARAMS.collisionDistance));
+
+		// Dynamic friction takes away part of it
+		let params = NvFlexParams { dynamicFriction: 0.5, ..PARAMS };
+		let (pos, _) = collide_one(&params, &[floor()], previous, predicted);
+		let depth = PARAMS.collisionDistance + 0.05;
+		assert_near(pos, Vector3(0.1 - 0.5 * depth, 0.0, PARAMS.collisionDistance));
+	}
+
+	#[test]
+	fn friction_follows_moving_shapes() {
+		// The floor moved along with the particle, so there's nothing to slow down
+		let shape: Shape = Cube::new(Vector4(0.1, 0.0, -1.0, 0.0), Quat::IDENTITY, [10.0, 10.0, 1.0]).into();
+		let flags = NvFlexMakeShapeFlags(shape.kind(), true);
+		let floor = Collider::new(shape, Vector4(0.0, 0.0, -1.0, 0.0), Quat::IDENTITY, flags);
+
+		let params = NvFlexParams { staticFriction: 10.0, ..PARAMS };
+		let (pos, _) = collide_one(&params, &[floor], Vector3(0.0, 0.0, 0.0), Vector3(0.1, 0.0, -0.05));
+		assert_near(pos, Vector3(0.1, 0.0, PARAMS.collisionDistance));
+	}
+
+	#[test]
+	fn restitution_bounces_off_contacts() {
+		let contacts = [Vector3(0.0, 0.0, 1.0), Vector3::ZERO, Vector3(0.0, 0.0, 1.0)];
+		let incoming = [Vector3(1.0, 0.0, -10.0), Vector3(0.0, 0.0, -10.0), Vector3(0.0, 0.0, 10.0)];
+		let mut velocities = [Vector3(1.0, 0.0, 0.0), Vector3(0.0, 0.0, -10.0), Vector3(0.0, 0.0, 10.0)];
+
+		// Disabled by default
+		restitution(&PARAMS, &contacts, &incoming, &mut velocities);
+		assert_near(velocities[0], Vector3(1.0, 0.0, 0.0));
+
+		let params = NvFlexParams { restitution: 0.5, ..PARAMS };
+		restitution(&params, &contacts, &incoming, &mut velocities);
+		assert_near(velocities[0], Vector3(1.0, 0.0, 5.0));
+		// No contact, or already moving away from it
+		assert_near(velocities[1], Vector3(0.0, 0.0, -10.0));
+		assert_near(velocities[2], Vector3(0.0, 0.0, 10.0));
+	}
+}
@@ -0,0 +1,252 @@
+use std::ffi::c_void;
+use std::mem::size_of;
+use std::ops::Range;
+
+use crate::{
+	config,
+	state::{InitError, Shape},
+	sys::{NvFlexCollisionGeometry, NvFlexParams},
+	types::{Quat, Vector3, Vector4},
+};
+
+use super::{Buffer, ParticleBuffers, ShapeBuffers, SolverBackend, VelocityFn};
+
+mod collision;
+use collision::Collider;
+
+mod pbf;
+use pbf::Pbf;
+
+/// Host memory backing a [Buffer].
+/// Stored as u64s so anything FleX would put in a buffer is properly aligned.
+#[derive(Debug)]
+pub(super) struct CpuBuffer {
+	pub(super) data: Vec<u64>,
+	count: usize,
+	stride: usize,
+}
+
+impl CpuBuffer {
+	pub(super) fn new(count: usize, stride: usize) -> Self {
+		Self {
+			data: vec![0; (count * stride).div_ceil(8)],
+			count,
+			stride,
+		}
+	}
+
+	pub(super) fn as_slice<T: Copy>(&self) -> &[T] {
+		assert_eq!(self.stride, size_of::<T>(), "Buffer stride doesn't match element type");
+		unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const T, self.count) }
+	}
+
+	pub(super) fn as_mut_slice<T: Copy>(&mut self) -> &mut [T] {
+		assert_eq!(self.stride, size_of::<T>(), "Buffer stride doesn't match element type");
+		unsafe { std::slice::from_raw_parts_mut(self.data.as_mut_ptr() as *mut T, self.count) }
+	}
+
+	/// Bytes of the elements in `range`, or of every element, along with how far into the buffer they start.
+	pub(super) fn as_bytes(&self, range: Option<Range<usize>>) -> (usize, &[u8]) {
+		let len = self.count * self.stride;
+		let range = range.map_or(0..len, |range| (range.start * self.stride).min(len)..(range.end * self.stride).min(len));
+
+		let bytes = unsafe { std::slice::from_raw_parts(self.data.as_ptr() as *const u8, len) };
+		(range.start, &bytes[range])
+	}
+}
+
+/// Copies `range` of `src` into `dst` like NvFlexSet* would, or all of it if there's no range.
+fn upload<T: Copy>(src: &[T], range: Option<Range<usize>>, dst: &mut Vec<T>) {
+	let Some(range) = range.filter(|range| range.end <= src.len()) else {
+		*dst = src.to_vec();
+		return;
+	};
+
+	// FleX has room for every particle, the ones never set before just haven't been filled in yet.
+	if dst.len() < src.len() {
+		dst.extend_from_slice(&src[dst.len()..]);
+	}
+
+	dst[range.clone()].copy_from_slice(&src[range]);
+}
+
+/// Simulates particles in-process, without needing a GPU or FleX at all.
+/// Uses the same position based fluids approach as FleX, so it's only practical for lower particle counts.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct CpuBackend {
+	#[derivative(Debug = "ignore")]
+	buffers: Vec<Option<CpuBuffer>>,
+
+	#[derivative(Debug = "ignore")]
+	params: NvFlexParams,
+
+	#[derivative(Debug = "ignore")]
+	pbf: Pbf,
+
+	positions: Vec<Vector4>,
+	velocities: Vec<Vector3>,
+	phases: Vec<i32>,
+	active: Vec<i32>,
+
+	colliders: Vec<Collider>,
+}
+
+impl CpuBackend {
+	pub fn new() -> Self {
+		Self {
+			buffers: vec![],
+			params: config::PARAMS,
+			pbf: Pbf::default(),
+
+			positions: vec![],
+			velocities: vec![],
+			phases: vec![],
+			active: vec![],
+
+			colliders: vec![],
+		}
+	}
+
+	fn get(&self, buffer: Buffer) -> &CpuBuffer {
+		self.buffers[buffer.0].as_ref().expect("Use of freed buffer")
+	}
+
+	fn get_mut(&mut self, buffer: Buffer) -> &mut CpuBuffer {
+		self.buffers[buffer.0].as_mut().expect("Use of freed buffer")
+	}
+
+	/// Copies `src` into the start of a buffer, like NvFlexGet* would.
+	fn download<T: Copy>(&mut self, buffer: Buffer, src: &[T]) {
+		let dst = self.get_mut(buffer).as_mut_slice::<T>();
+		let len = dst.len().min(src.len());
+		dst[..len].copy_from_slice(&src[..len]);
+	}
+}
+
+impl Default for CpuBackend {
+	fn default() -> Self {
+		Self::new()
+	}
+}
+
+impl SolverBackend for CpuBackend {
+	fn alloc(&mut self, count: usize, stride: usize) -> Option<Buffer> {
+		let buffer = Some(CpuBuffer::new(count, stride));
+
+		match self.buffers.iter().position(Option::is_none) {
+			Some(slot) => {
+				self.buffers[slot] = buffer;
+				Some(Buffer(slot))
+			}
+			None => {
+				self.buffers.push(buffer);
+				Some(Buffer(self.buffers.len() - 1))
+			}
+		}
+	}
+
+	fn free(&mut self, buffer: Buffer) {
+		self.buffers[buffer.0] = None;
+	}
+
+	fn map(&mut self, buffer: Buffer) -> *mut c_void {
+		// Nothing runs asynchronously, so there's nothing to wait on.
+		self.get_mut(buffer).data.as_mut_ptr() as *mut c_void
+	}
+
+	fn unmap(&mut self, _buffer: Buffer) {}
+
+	fn set_particles(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let src = self.buffers[buffer.0].as_ref().expect("Use of freed buffer").as_slice::<Vector4>();
+		upload(src, range, &mut self.positions);
+	}
+
+	fn get_particles(&mut self, buffer: Buffer) {
+		let positions = std::mem::take(&mut self.positions);
+		self.download(buffer, &positions);
+		self.positions = positions;
+	}
+
+	fn set_velocities(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let src = self.buffers[buffer.0].as_ref().expect("Use of freed buffer").as_slice::<Vector3>();
+		upload(src, range, &mut self.velocities);
+	}
+
+	fn get_velocities(&mut self, buffer: Buffer) {
+		let velocities = std::mem::take(&mut self.velocities);
+		self.download(buffer, &velocities);
+		self.velocities = velocities;
+	}
+
+	fn set_phases(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let src = self.buffers[buffer.0].as_ref().expect("Use of freed buffer").as_slice::<i32>();
+		upload(src, range, &mut self.phases);
+	}
+
+	fn get_phases(&mut self, buffer: Buffer) {
+		let phases = std::mem::take(&mut self.phases);
+		self.download(buffer, &phases);
+		self.phases = phases;
+	}
+
+	fn is_current(&self, _buffer: Buffer) -> bool {
+		true
+	}
+
+	fn reserve_particles(&mut self, _count: usize) -> Result<(), InitError> {
+		// Everything is sized to whatever's set on it, there's no limit to grow.
+		Ok(())
+	}
+
+	fn update_velocities(&mut self, buffers: &ParticleBuffers, count: usize, f: VelocityFn) -> bool {
+		super::update_velocities_now(self, buffers, count, f);
+		true
+	}
+
+	fn set_active(&mut self, buffer: Buffer, count: usize) {
+		let indices = self.get(buffer).as_slice::<i32>();
+		self.active = indices[..count.min(indices.len())].to_vec();
+	}
+
+	fn set_shapes(&mut self, shapes: &ShapeBuffers, count: usize) {
+		let geometry = self.get(shapes.geometry).as_slice::<NvFlexCollisionGeometry>();
+		let positions = self.get(shapes.positions).as_slice::<Vector4>();
+		let rotations = self.get(shapes.rotations).as_slice::<Quat>();
+		let previous_positions = self.get(shapes.previous_positions).as_slice::<Vector4>();
+		let previous_rotations = self.get(shapes.previous_rotations).as_slice::<Quat>();
+		let flags = self.get(shapes.flags).as_slice::<i32>();
+
+		let count = count.min(geometry.len());
+		self.colliders = (0..count)
+			.filter_map(|i| {
+				let shape = Shape::from_raw(&geometry[i], flags[i], positions[i], rotations[i])?;
+				Some(Collider::new(shape, previous_positions[i], previous_rotations[i], flags[i]))
+			})
+			.collect();
+	}
+
+	fn set_triangles(&mut self, _indices: Buffer, _normals: Buffer, _count: usize) {
+		// Triangle meshes aren't collided against yet.
+	}
+
+	fn set_params(&mut self, params: &NvFlexParams) {
+		self.params = *params;
+	}
+
+	fn update(&mut self, dt: f32, substeps: i32) {
+		let substeps = substeps.max(1);
+		let sdt = dt / substeps as f32;
+
+		// Ignore indices of particles that were never uploaded
+		let count = self.positions.len().min(self.velocities.len());
+		let active: Vec<usize> = self.active.iter()
+			.map(|&i| i as usize)
+			.filter(|&i| i < count)
+			.collect();
+
+		for _ in 0..substeps {
+			self.pbf.step(&self.params, &mut self.positions, &mut self.velocities, &self.phases, &active, &self.colliders, sdt);
+		}
+	}
+}
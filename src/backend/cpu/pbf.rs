@@ -0,0 +1,551 @@
+// Position based fluids, as described in Macklin & Müller 2013.
+// This is the same family of solver FleX uses, just a lot slower.
+use std::f32::consts::PI;
+
+use super::collision::{self, Candidate, Collider};
+use crate::{
+	helper::{fluid_rest_distance, solid_rest_distance},
+	spatial::SpatialHash,
+	sys::*,
+	types::{Vector3, Vector4},
+};
+
+/// Same as FleX's default maxNeighborsPerParticle
+const MAX_NEIGHBORS: usize = 96;
+
+/// Constraint force mixing, keeps the density solve from blowing up when a particle has few neighbors.
+/// Relative to the inverse squared interaction radius.
+const RELAXATION: f32 = 10.0;
+
+/// Artificial pressure that stops particles from clumping together at the surface.
+/// `CORR_K` is relative to the squared interaction radius.
+const CORR_K: f32 = 0.03;
+const CORR_DQ: f32 = 0.2;
+const CORR_N: i32 = 4;
+
+/// Scales `vorticityConfinement` down, values tuned for FleX inject too much energy into this solver otherwise.
+const VORTICITY_SCALE: f32 = 0.1;
+
+/// Kernels, `h` being the interaction radius.
+#[derive(Debug, Clone, Copy)]
+struct Kernel {
+	h: f32,
+	h2: f32,
+	poly6: f32,
+	spiky: f32,
+}
+
+impl Kernel {
+	fn new(h: f32) -> Self {
+		Self {
+			h,
+			h2: h * h,
+			poly6: 315.0 / (64.0 * PI * h.powi(9)),
+			spiky: -45.0 / (PI * h.powi(6)),
+		}
+	}
+
+	#[inline]
+	fn poly6(&self, r2: f32) -> f32 {
+		if r2 >= self.h2 {
+			return 0.0;
+		}
+		let d = self.h2 - r2;
+		self.poly6 * d * d * d
+	}
+
+	/// Gradient of the spiky kernel with respect to `r` (pi - pj)
+	#[inline]
+	fn spiky_grad(&self, r: Vector3) -> Vector3 {
+		let len = r.length();
+		if len >= self.h || len <= f32::EPSILON {
+			return Vector3::ZERO;
+		}
+		let d = self.h - len;
+		r * (self.spiky * d * d / len)
+	}
+
+	/// Density of particles packed in a lattice `spacing` apart, used as the rest density.
+	fn rest_density(&self, spacing: f32) -> f32 {
+		let n = (self.h / spacing).ceil() as i32;
+		let mut density = 0.0;
+		for x in -n..=n {
+			for y in -n..=n {
+				for z in -n..=n {
+					let r = Vector3(x as f32, y as f32, z as f32) * spacing;
+					density += self.poly6(r.length_sqr());
+				}
+			}
+		}
+		density
+	}
+}
+
+#[inline]
+fn is_fluid(phase: i32) -> bool {
+	phase & eNvFlexPhaseFluid != 0
+}
+
+/// Same rules as FleX, particles in the same group only interact if they're self colliding.
+#[inline]
+fn interacts(a: i32, b: i32) -> bool {
+	(a & eNvFlexPhaseGroupMask) != (b & eNvFlexPhaseGroupMask) || (a & eNvFlexPhaseSelfCollide != 0)
+}
+
+/// Scratch space for the solver, kept around between steps to avoid reallocating.
+/// Everything is indexed by position in the active list, not by particle index.
+#[derive(Debug, Default)]
+pub struct Pbf {
+	grid: SpatialHash,
+
+	/// Start of the previous step
+	previous: Vec<Vector3>,
+	predicted: Vec<Vector3>,
+	velocities: Vec<Vector3>,
+	imass: Vec<f32>,
+	phases: Vec<i32>,
+
+	neighbor_start: Vec<u32>,
+	neighbors: Vec<u32>,
+
+	lambdas: Vec<f32>,
+	deltas: Vec<Vector3>,
+	vorticity: Vec<Vector3>,
+
+	/// Velocity before constraints were solved, for restitution
+	incoming: Vec<Vector3>,
+	/// Normal of the collider each particle touched, if any
+	contacts: Vec<Vector3>,
+	/// Colliders each particle is close enough to to touch this step
+	candidates: Vec<Candidate>,
+}
+
+impl Pbf {
+	/// Steps the active particles forward by `dt`, colliding them against `colliders`.
+	#[allow(clippy::too_many_arguments)]
+	pub fn step(
+		&mut self,
+		params: &NvFlexParams,
+		positions: &mut [Vector4],
+		velocities: &mut [Vector3],
+		phases: &[i32],
+		active: &[usize],
+		colliders: &[Collider],
+		dt: f32,
+	) {
+		if dt <= 0.0 || active.is_empty() {
+			return;
+		}
+
+		self.gather(positions, velocities, phases, active);
+		self.predict(params, dt);
+		self.find_neighbors(params);
+		collision::find_contacts(params, colliders, &self.predicted, &self.imass, &self.phases, &mut self.candidates);
+
+		let kernel = Kernel::new(params.radius);
+		let rest_density = kernel.rest_density(fluid_rest_distance(params));
+
+		for _ in 0..params.numIterations.max(1) {
+			self.solve_density(params, &kernel, rest_density);
+			self.solve_contacts(params);
+			collision::collide(params, colliders, &self.candidates, &mut self.predicted, &self.previous, &self.imass, &mut self.contacts);
+		}
+
+		// Derive velocities from how far the particles moved
+		self.incoming.clone_from(&self.velocities);
+		let inv_dt = 1.0 / dt;
+		for k in 0..self.predicted.len() {
+			self.velocities[k] = (self.predicted[k] - self.previous[k]) * inv_dt;
+
+			if self.velocities[k].length() < params.sleepThreshold {
+				self.predicted[k] = self.previous[k];
+				self.velocities[k] = Vector3::ZERO;
+			}
+		}
+
+		collision::restitution(params, &self.contacts, &self.incoming, &mut self.velocities);
+
+		self.apply_vorticity(params, &kernel, rest_density, dt);
+		self.apply_viscosity(params, &kernel, rest_density, dt);
+		self.apply_cohesion(params, &kernel, rest_density, dt);
+
+		for (k, &i) in active.iter().enumerate() {
+			positions[i] = self.predicted[k].with_w(self.imass[k]);
+			velocities[i] = self.velocities[k].clamp_length(params.maxSpeed);
+		}
+	}
+
+	fn gather(&mut self, positions: &[Vector4], velocities: &[Vector3], phases: &[i32], active: &[usize]) {
+		self.previous.clear();
+		self.velocities.clear();
+		self.imass.clear();
+		self.phases.clear();
+
+		for &i in active {
+			self.previous.push(positions[i].xyz());
+			self.imass.push(positions[i].3);
+			self.velocities.push(velocities[i]);
+			self.phases.push(phases.get(i).copied().unwrap_or(0));
+		}
+
+		let n = active.len();
+		self.lambdas.resize(n, 0.0);
+		self.deltas.resize(n, Vector3::ZERO);
+		self.vorticity.resize(n, Vector3::ZERO);
+		self.contacts.clear();
+		self.contacts.resize(n, Vector3::ZERO);
+	}
+
+	/// Applies external forces and guesses where particles will end up
+	fn predict(&mut self, params: &NvFlexParams, dt: f32) {
+		let gravity = Vector3::from(params.gravity);
+		let max_dv = params.maxAcceleration * dt;
+
+		self.predicted.clear();
+		for k in 0..self.previous.len() {
+			let v = self.velocities[k];
+
+			// Infinite mass particles don't move
+			if self.imass[k] == 0.0 {
+				self.velocities[k] = Vector3::ZERO;
+				self.predicted.push(self.previous[k]);
+				continue;
+			}
+
+			let dv = (gravity - v * params.damping) * dt;
+			let v = (v + dv.clamp_length(max_dv)).clamp_length(params.maxSpeed);
+
+			self.velocities[k] = v;
+			self.predicted.push(self.previous[k] + v * dt);
+		}
+	}
+
+	fn find_neighbors(&mut self, params: &NvFlexParams) {
+		let h = params.radius;
+		self.grid.build(h, &self.predicted);
+
+		self.neighbor_start.clear();
+		self.neighbors.clear();
+
+		for k in 0..self.predicted.len() {
+			self.neighbor_start.push(self.neighbors.len() as u32);
+
+			let (neighbors, phases) = (&mut self.neighbors, &self.phases);
+			let start = neighbors.len();
+			self.grid.query(&self.predicted, self.predicted[k], h, |j| {
+				if j != k && neighbors.len() - start < MAX_NEIGHBORS && interacts(phases[k], phases[j]) {
+					neighbors.push(j as u32);
+				}
+			});
+		}
+		self.neighbor_start.push(self.neighbors.len() as u32);
+	}
+
+	#[inline]
+	fn neighbors_of(&self, k: usize) -> &[u32] {
+		&self.neighbors[self.neighbor_start[k] as usize..self.neighbor_start[k + 1] as usize]
+	}
+
+	/// Pushes fluid particles apart so they stay at rest density
+	fn solve_density(&mut self, params: &NvFlexParams, kernel: &Kernel, rest_density: f32) {
+		let n = self.predicted.len();
+		let epsilon = RELAXATION / kernel.h2;
+
+		for k in 0..n {
+			self.lambdas[k] = 0.0;
+			if !is_fluid(self.phases[k]) {
+				continue;
+			}
+
+			let pk = self.predicted[k];
+			let mut density = kernel.poly6(0.0);
+			let mut grad_k = Vector3::ZERO;
+			let mut grad_sum = 0.0;
+
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if !is_fluid(self.phases[j]) {
+					continue;
+				}
+
+				let r = pk - self.predicted[j];
+				density += kernel.poly6(r.length_sqr());
+
+				let grad = kernel.spiky_grad(r) * (1.0 / rest_density);
+				grad_k += grad;
+				grad_sum += grad.length_sqr();
+			}
+
+			// Only resist compression, cohesion takes care of holding the fluid together.
+			let constraint = (density / rest_density - 1.0).max(0.0);
+			self.lambdas[k] = -constraint / (grad_sum + grad_k.length_sqr() + epsilon);
+		}
+
+		let corr_w = kernel.poly6((CORR_DQ * kernel.h).powi(2));
+		for k in 0..n {
+			self.deltas[k] = Vector3::ZERO;
+			if !is_fluid(self.phases[k]) || self.imass[k] == 0.0 {
+				continue;
+			}
+
+			let pk = self.predicted[k];
+			let mut delta = Vector3::ZERO;
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if !is_fluid(self.phases[j]) {
+					continue;
+				}
+
+				let r = pk - self.predicted[j];
+				let corr = -CORR_K * kernel.h2 * (kernel.poly6(r.length_sqr()) / corr_w).powi(CORR_N);
+				delta += kernel.spiky_grad(r) * ((self.lambdas[k] + self.lambdas[j] + corr) / rest_density);
+			}
+
+			self.deltas[k] = delta * params.relaxationFactor;
+		}
+
+		for k in 0..n {
+			self.predicted[k] += self.deltas[k];
+		}
+	}
+
+	/// Keeps solid particles (and fluid touching solids) from overlapping
+	fn solve_contacts(&mut self, params: &NvFlexParams) {
+		let rest = solid_rest_distance(params);
+
+		for k in 0..self.predicted.len() {
+			self.deltas[k] = Vector3::ZERO;
+			if self.imass[k] == 0.0 {
+				continue;
+			}
+
+			let mut delta = Vector3::ZERO;
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if is_fluid(self.phases[k]) && is_fluid(self.phases[j]) {
+					continue;
+				}
+
+				let r = self.predicted[k] - self.predicted[j];
+				let dist = r.length();
+				if dist >= rest || dist <= f32::EPSILON {
+					continue;
+				}
+
+				let w = self.imass[k] / (self.imass[k] + self.imass[j]);
+				delta += r * (w * (rest - dist) / dist);
+			}
+
+			self.deltas[k] = delta * params.relaxationFactor;
+		}
+
+		for k in 0..self.predicted.len() {
+			self.predicted[k] += self.deltas[k];
+		}
+	}
+
+	/// Puts back rotational energy lost to damping
+	fn apply_vorticity(&mut self, params: &NvFlexParams, kernel: &Kernel, rest_density: f32, dt: f32) {
+		if params.vorticityConfinement <= 0.0 {
+			return;
+		}
+
+		let n = self.predicted.len();
+		for k in 0..n {
+			let mut omega = Vector3::ZERO;
+			if is_fluid(self.phases[k]) {
+				for &j in self.neighbors_of(k) {
+					let j = j as usize;
+					if is_fluid(self.phases[j]) {
+						let grad = kernel.spiky_grad(self.predicted[k] - self.predicted[j]) * (1.0 / rest_density);
+						omega += (self.velocities[j] - self.velocities[k]).cross(grad);
+					}
+				}
+			}
+			self.vorticity[k] = omega;
+		}
+
+		for k in 0..n {
+			if !is_fluid(self.phases[k]) || self.imass[k] == 0.0 {
+				continue;
+			}
+
+			let strength = self.vorticity[k].length();
+			let mut eta = Vector3::ZERO;
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if is_fluid(self.phases[j]) {
+					let grad = kernel.spiky_grad(self.predicted[k] - self.predicted[j]) * (1.0 / rest_density);
+					eta += grad * (self.vorticity[j].length() - strength);
+				}
+			}
+
+			let force = eta.normalize().cross(self.vorticity[k]) * (params.vorticityConfinement * VORTICITY_SCALE);
+			self.velocities[k] += force.clamp_length(params.maxAcceleration) * dt;
+		}
+	}
+
+	/// XSPH viscosity, blends each particle's velocity with its neighbors'
+	fn apply_viscosity(&mut self, params: &NvFlexParams, kernel: &Kernel, rest_density: f32, dt: f32) {
+		if params.viscosity <= 0.0 {
+			return;
+		}
+
+		let blend = (params.viscosity * dt).min(1.0);
+		for k in 0..self.predicted.len() {
+			self.deltas[k] = Vector3::ZERO;
+			if !is_fluid(self.phases[k]) || self.imass[k] == 0.0 {
+				continue;
+			}
+
+			let mut dv = Vector3::ZERO;
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if is_fluid(self.phases[j]) {
+					let w = kernel.poly6((self.predicted[k] - self.predicted[j]).length_sqr()) / rest_density;
+					dv += (self.velocities[j] - self.velocities[k]) * w;
+				}
+			}
+			self.deltas[k] = dv * blend;
+		}
+
+		for k in 0..self.predicted.len() {
+			self.velocities[k] += self.deltas[k];
+		}
+	}
+
+	/// Pulls fluid particles further apart than the rest distance back together
+	fn apply_cohesion(&mut self, params: &NvFlexParams, kernel: &Kernel, rest_density: f32, dt: f32) {
+		if params.cohesion <= 0.0 {
+			return;
+		}
+
+		let rest = fluid_rest_distance(params);
+		for k in 0..self.predicted.len() {
+			self.deltas[k] = Vector3::ZERO;
+			if !is_fluid(self.phases[k]) || self.imass[k] == 0.0 {
+				continue;
+			}
+
+			let mut dx = Vector3::ZERO;
+			for &j in self.neighbors_of(k) {
+				let j = j as usize;
+				if !is_fluid(self.phases[j]) {
+					continue;
+				}
+
+				let r = self.predicted[j] - self.predicted[k];
+				let dist = r.length();
+				if dist > rest {
+					let w = kernel.poly6(dist * dist) / rest_density;
+					dx += r * (w * (dist - rest) / dist);
+				}
+			}
+			self.deltas[k] = dx * (params.cohesion / dt);
+		}
+
+		for k in 0..self.predicted.len() {
+			self.velocities[k] += self.deltas[k];
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{config::PARAMS, helper::NvFlexMakePhase};
+
+	const DT: f32 = 1.0 / 60.0;
+
+	fn fluid() -> i32 {
+		NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid)
+	}
+
+	/// Steps a single particle with no neighbors or colliders, returning its position and velocity.
+	fn step_one(params: &NvFlexParams, velocity: Vector3) -> (Vector3, Vector3) {
+		let (mut positions, mut velocities) = ([Vector4(0.0, 0.0, 0.0, 1.0)], [velocity]);
+		Pbf::default().step(params, &mut positions, &mut velocities, &[fluid()], &[0], &[], DT);
+		(positions[0].xyz(), velocities[0])
+	}
+
+	#[test]
+	fn settled_column_reaches_rest_density() {
+		let (spacing, width) = (0.55, 5);
+		let size = width as f32 * spacing;
+
+		// A floor and four walls around the column
+		let mut params = NvFlexParams {
+			radius: 1.0,
+			numIterations: 4,
+			vorticityConfinement: 0.0,
+			cohesion: 0.0,
+			numPlanes: 5,
+			..PARAMS
+		};
+		params.planes[..5].copy_from_slice(&[
+			[0.0, 0.0, 1.0, 0.0],
+			[1.0, 0.0, 0.0, 0.0],
+			[-1.0, 0.0, 0.0, size],
+			[0.0, 1.0, 0.0, 0.0],
+			[0.0, -1.0, 0.0, size],
+		]);
+
+		// Dropped in loosely packed, so it has to settle
+		let mut positions = vec![];
+		for x in 0..width {
+			for y in 0..width {
+				for z in 0..8 {
+					let cell = Vector3(x as f32 + 0.5, y as f32 + 0.5, z as f32 * 1.2 + 0.5);
+					positions.push((cell * spacing).with_w(1.0));
+				}
+			}
+		}
+
+		let mut velocities = vec![Vector3::ZERO; positions.len()];
+		let phases = vec![fluid(); positions.len()];
+		let active = (0..positions.len()).collect::<Vec<_>>();
+
+		let mut pbf = Pbf::default();
+		for _ in 0..400 {
+			pbf.step(&params, &mut positions, &mut velocities, &phases, &active, &[], DT);
+		}
+
+		let kernel = Kernel::new(params.radius);
+		let rest_density = kernel.rest_density(spacing);
+		let density = |p: Vector4| positions.iter().map(|q| kernel.poly6((p.xyz() - q.xyz()).length_sqr())).sum::<f32>() / rest_density;
+
+		for &p in &positions {
+			assert!(density(p) < 1.05, "compressed to {} of rest density", density(p));
+		}
+
+		// Particles near the walls or the surface are missing neighbors, so only the ones deep inside are at rest density
+		let top = positions.iter().map(|p| p.2).fold(0.0, f32::max);
+		let inside = positions
+			.iter()
+			.filter(|p| p.2 < top / 2.0 && [p.0, p.1].iter().all(|&x| x > params.radius && x < size - params.radius))
+			.map(|&p| density(p))
+			.collect::<Vec<_>>();
+
+		assert!(!inside.is_empty());
+		let mean = inside.iter().sum::<f32>() / inside.len() as f32;
+		assert!((mean - 1.0).abs() < 0.03, "settled at {} of rest density", mean);
+	}
+
+	#[test]
+	fn max_speed_clamps_velocity() {
+		let params = NvFlexParams { maxSpeed: 10.0, ..PARAMS };
+		let (pos, vel) = step_one(&params, Vector3(1000.0, 0.0, 0.0));
+
+		assert!(vel.length() <= 10.0 + 1e-3, "moving at {}", vel.length());
+		assert!(pos.length() <= 10.0 * DT + 1e-3, "moved {}", pos.length());
+	}
+
+	#[test]
+	fn max_acceleration_clamps_gravity() {
+		let params = NvFlexParams { gravity: [0.0, 0.0, -1000.0], maxAcceleration: 5.0, ..PARAMS };
+		let (pos, vel) = step_one(&params, Vector3::ZERO);
+
+		assert!((vel - Vector3(0.0, 0.0, -5.0 * DT)).length() < 1e-4, "{:?}", vel);
+		assert!((pos - Vector3(0.0, 0.0, -5.0 * DT * DT)).length() < 1e-5, "{:?}", pos);
+	}
+}
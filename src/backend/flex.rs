@@ -0,0 +1,268 @@
+use std::ffi::c_void;
+use std::mem::MaybeUninit;
+use std::ops::Range;
+use std::sync::Arc;
+
+use nvflex_sys::*;
+
+use super::{Buffer, ParticleBuffers, ShapeBuffers, SolverBackend, VelocityFn};
+use crate::state::InitError;
+
+/// The FleX library, shared by every solver created from it.
+/// Shut down once the last solver using it is dropped.
+#[derive(Debug)]
+pub struct FlexLibrary {
+	lib: *mut NvFlexLibrary,
+}
+
+// FleX's library functions are thread safe.
+unsafe impl Send for FlexLibrary {}
+unsafe impl Sync for FlexLibrary {}
+
+impl FlexLibrary {
+	/// # Safety
+	/// FleX must be able to find its runtime libraries.
+	pub unsafe fn new(error_handler: NvFlexErrorCallback) -> Result<Self, InitError> {
+		let lib = NvFlexInit(NV_FLEX_VERSION as i32, error_handler, std::ptr::null_mut());
+		if lib.is_null() {
+			return Err(InitError::Library);
+		}
+
+		Ok(Self { lib })
+	}
+
+	/// Makes FleX's compute context current on the calling thread.
+	/// Needed before using FleX from any thread but the one that created the library.
+	pub fn acquire_context(&self) -> bool {
+		unsafe { NvFlexAcquireContext(self.lib) }
+	}
+
+	/// Creates a new solver, with its own particles, shapes and parameters.
+	pub fn create_solver(self: &Arc<Self>) -> Result<FlexBackend, InitError> {
+		unsafe {
+			// Create default solver settings
+			let mut solver_desc = MaybeUninit::<NvFlexSolverDesc>::uninit();
+			NvFlexSetSolverDescDefaults(solver_desc.as_mut_ptr());
+			let solver_desc = solver_desc.assume_init();
+
+			let solver = NvFlexCreateSolver(self.lib, &solver_desc);
+			if solver.is_null() {
+				return Err(InitError::Solver);
+			}
+
+			Ok(FlexBackend {
+				lib: Arc::clone(self),
+
+				solver_desc,
+				solver,
+
+				buffers: vec![],
+
+				params: None,
+				shapes: None,
+				triangles: None,
+			})
+		}
+	}
+}
+
+impl Drop for FlexLibrary {
+	fn drop(&mut self) {
+		unsafe { NvFlexShutdown(self.lib) }
+	}
+}
+
+/// Runs the simulation on the GPU through NVIDIA FleX.
+#[derive(Debug)]
+pub struct FlexBackend {
+	lib: Arc<FlexLibrary>,
+
+	solver_desc: NvFlexSolverDesc,
+	solver: *mut NvFlexSolver,
+
+	/// Indexed by [Buffer], null once freed.
+	buffers: Vec<*mut NvFlexBuffer>,
+
+	/// Last set on the solver, to set again if it has to be recreated
+	params: Option<NvFlexParams>,
+	shapes: Option<(ShapeBuffers, usize)>,
+	triangles: Option<(Buffer, Buffer, usize)>,
+}
+
+/// Copies `range` of a buffer to the same place on the solver, or [None] to copy all of it.
+fn copy_desc(range: Option<Range<usize>>) -> Option<NvFlexCopyDesc> {
+	range.map(|range| NvFlexCopyDesc {
+		srcOffset: range.start as i32,
+		dstOffset: range.start as i32,
+		elementCount: range.len() as i32,
+	})
+}
+
+/// FleX takes a null copy description to mean the whole buffer.
+fn desc_ptr(desc: &Option<NvFlexCopyDesc>) -> *const NvFlexCopyDesc {
+	desc.as_ref().map_or(std::ptr::null(), |desc| desc)
+}
+
+impl FlexBackend {
+	fn get(&self, buffer: Buffer) -> *mut NvFlexBuffer {
+		self.buffers[buffer.0]
+	}
+}
+
+impl SolverBackend for FlexBackend {
+	fn alloc(&mut self, count: usize, stride: usize) -> Option<Buffer> {
+		let ptr = unsafe { NvFlexAllocBuffer(self.lib.lib, count as i32, stride as i32, eNvFlexBufferHost) };
+		if ptr.is_null() {
+			return None;
+		}
+
+		// Reuse a freed slot if there is one
+		match self.buffers.iter().position(|b| b.is_null()) {
+			Some(slot) => {
+				self.buffers[slot] = ptr;
+				Some(Buffer(slot))
+			}
+			None => {
+				self.buffers.push(ptr);
+				Some(Buffer(self.buffers.len() - 1))
+			}
+		}
+	}
+
+	fn free(&mut self, buffer: Buffer) {
+		let ptr = std::mem::replace(&mut self.buffers[buffer.0], std::ptr::null_mut());
+		if !ptr.is_null() {
+			unsafe { NvFlexFreeBuffer(ptr) };
+		}
+	}
+
+	fn map(&mut self, buffer: Buffer) -> *mut c_void {
+		unsafe { NvFlexMap(self.get(buffer), eNvFlexMapWait) }
+	}
+
+	fn unmap(&mut self, buffer: Buffer) {
+		unsafe { NvFlexUnmap(self.get(buffer)) }
+	}
+
+	fn set_particles(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let desc = copy_desc(range);
+		unsafe { NvFlexSetParticles(self.solver, self.get(buffer), desc_ptr(&desc)) }
+	}
+
+	fn get_particles(&mut self, buffer: Buffer) {
+		unsafe { NvFlexGetParticles(self.solver, self.get(buffer), std::ptr::null()) }
+	}
+
+	fn set_velocities(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let desc = copy_desc(range);
+		unsafe { NvFlexSetVelocities(self.solver, self.get(buffer), desc_ptr(&desc)) }
+	}
+
+	fn get_velocities(&mut self, buffer: Buffer) {
+		unsafe { NvFlexGetVelocities(self.solver, self.get(buffer), std::ptr::null()) }
+	}
+
+	fn set_phases(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let desc = copy_desc(range);
+		unsafe { NvFlexSetPhases(self.solver, self.get(buffer), desc_ptr(&desc)) }
+	}
+
+	fn get_phases(&mut self, buffer: Buffer) {
+		unsafe { NvFlexGetPhases(self.solver, self.get(buffer), std::ptr::null()) }
+	}
+
+	fn is_current(&self, _buffer: Buffer) -> bool {
+		// Reading back always waits on the solver
+		true
+	}
+
+	fn reserve_particles(&mut self, count: usize) -> Result<(), InitError> {
+		if count <= self.solver_desc.maxParticles as usize {
+			return Ok(());
+		}
+
+		// maxParticles is fixed once the solver is created, so make a bigger one and carry everything over.
+		let mut solver_desc = self.solver_desc;
+		solver_desc.maxParticles = i32::try_from(count).map_err(|_| InitError::Solver)?;
+
+		let solver = unsafe { NvFlexCreateSolver(self.lib.lib, &solver_desc) };
+		if solver.is_null() {
+			return Err(InitError::Solver);
+		}
+
+		unsafe { NvFlexDestroySolver(self.solver) };
+		self.solver = solver;
+		self.solver_desc = solver_desc;
+
+		if let Some(params) = self.params {
+			self.set_params(&params);
+		}
+
+		if let Some((shapes, count)) = self.shapes {
+			self.set_shapes(&shapes, count);
+		}
+
+		if let Some((indices, normals, count)) = self.triangles {
+			self.set_triangles(indices, normals, count);
+		}
+
+		Ok(())
+	}
+
+	fn set_active(&mut self, buffer: Buffer, count: usize) {
+		unsafe {
+			NvFlexSetActive(self.solver, self.get(buffer), std::ptr::null());
+			NvFlexSetActiveCount(self.solver, count as i32);
+		}
+	}
+
+	fn set_shapes(&mut self, shapes: &ShapeBuffers, count: usize) {
+		self.shapes = Some((*shapes, count));
+		unsafe {
+			NvFlexSetShapes(
+				self.solver,
+				self.get(shapes.geometry),
+				self.get(shapes.positions),
+				self.get(shapes.rotations),
+				self.get(shapes.previous_positions),
+				self.get(shapes.previous_rotations),
+				self.get(shapes.flags),
+				count as i32,
+			);
+		}
+	}
+
+	fn set_triangles(&mut self, indices: Buffer, normals: Buffer, count: usize) {
+		self.triangles = Some((indices, normals, count));
+		unsafe { NvFlexSetDynamicTriangles(self.solver, self.get(indices), self.get(normals), count as i32) }
+	}
+
+	fn set_params(&mut self, params: &NvFlexParams) {
+		self.params = Some(*params);
+		unsafe { NvFlexSetParams(self.solver, params) }
+	}
+
+	/// Waits on the solver to read the particles back, use the threaded backend to keep that off the game thread.
+	fn update_velocities(&mut self, buffers: &ParticleBuffers, count: usize, f: VelocityFn) -> bool {
+		super::update_velocities_now(self, buffers, count, f);
+		true
+	}
+
+	fn update(&mut self, dt: f32, substeps: i32) {
+		unsafe { NvFlexUpdateSolver(self.solver, dt, substeps, false) }
+	}
+}
+
+impl Drop for FlexBackend {
+	/// Frees any buffers left over, then the solver.
+	/// The library is shut down along with the last solver using it.
+	fn drop(&mut self) {
+		unsafe {
+			for buffer in self.buffers.drain(..).filter(|b| !b.is_null()) {
+				NvFlexFreeBuffer(buffer);
+			}
+
+			NvFlexDestroySolver(self.solver);
+		}
+	}
+}
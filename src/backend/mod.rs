@@ -0,0 +1,116 @@
+// Solver backends. Everything in `state` talks to the simulation through [SolverBackend],
+// so it works the same whether FleX is running the show or the in-process CPU solver is.
+use std::ffi::c_void;
+use std::ops::Range;
+
+use crate::{
+	state::InitError,
+	sys::NvFlexParams,
+	types::{Vector3, Vector4},
+};
+
+pub mod cpu;
+pub mod threaded;
+
+#[cfg(feature = "nvflex-sys")]
+pub mod flex;
+
+/// Handle to a buffer allocated by a [SolverBackend].
+/// Only meaningful to the backend that created it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Buffer(pub(crate) usize);
+
+/// Buffers handed to [SolverBackend::set_shapes], mirroring the arguments of NvFlexSetShapes.
+#[derive(Debug, Clone, Copy)]
+pub struct ShapeBuffers {
+	pub geometry: Buffer,           // Vec<NvFlexCollisionGeometry>
+	pub positions: Buffer,          // Vec<Vector4>
+	pub rotations: Buffer,          // Vec<Quat>
+	pub previous_positions: Buffer, // Vec<Vector4>
+	pub previous_rotations: Buffer, // Vec<Quat>
+	pub flags: Buffer,              // Vec<i32>
+}
+
+/// Buffers of the particles handed to [SolverBackend::update_velocities], as last set on the solver.
+#[derive(Debug, Clone, Copy)]
+pub struct ParticleBuffers {
+	pub particles: Buffer,  // Vec<Vector4>
+	pub velocities: Buffer, // Vec<Vector3>
+	pub active: Buffer,     // Vec<i32>
+}
+
+/// Changes the velocity of a particle given its position (xyz + inverse mass), returning whether it did.
+pub type VelocityFn = Box<dyn FnMut(Vector4, &mut Vector3) -> bool + Send>;
+
+/// [SolverBackend::update_velocities] for backends that read the solver back right away, waiting on it if they have to.
+pub fn update_velocities_now(backend: &mut dyn SolverBackend, buffers: &ParticleBuffers, count: usize, mut f: VelocityFn) {
+	backend.get_particles(buffers.particles);
+	backend.get_velocities(buffers.velocities);
+
+	let particles = backend.map(buffers.particles) as *const Vector4;
+	let velocities = backend.map(buffers.velocities) as *mut Vector3;
+	let active = backend.map(buffers.active) as *const i32;
+
+	let mut changed = false;
+	for n in 0..count {
+		let i = unsafe { *active.add(n) } as usize;
+		changed |= unsafe { f(*particles.add(i), &mut *velocities.add(i)) };
+	}
+
+	backend.unmap(buffers.particles);
+	backend.unmap(buffers.velocities);
+	backend.unmap(buffers.active);
+
+	if changed {
+		backend.set_velocities(buffers.velocities, None);
+	}
+}
+
+pub trait SolverBackend: std::fmt::Debug {
+	/// Allocates a host buffer of `count` elements, each `stride` bytes large.
+	/// Returns [None] if the allocation failed.
+	fn alloc(&mut self, count: usize, stride: usize) -> Option<Buffer>;
+
+	/// Frees a buffer. Buffers still allocated when the backend is dropped are freed along with it.
+	fn free(&mut self, buffer: Buffer);
+
+	/// Maps a buffer so it can be read from / written to, waiting for the solver to be done with it.
+	/// The pointer stays valid until the buffer is freed, but should only be used until [SolverBackend::unmap] is called.
+	fn map(&mut self, buffer: Buffer) -> *mut c_void;
+	fn unmap(&mut self, buffer: Buffer);
+
+	/// Uploads the particle positions (xyz + inverse mass) in `buffer` to the solver.
+	/// Only the elements in `range` are uploaded if given, the rest of the solver's particles are left as they are.
+	fn set_particles(&mut self, buffer: Buffer, range: Option<Range<usize>>);
+	/// Reads the solver's particle positions back into `buffer`.
+	fn get_particles(&mut self, buffer: Buffer);
+
+	fn set_velocities(&mut self, buffer: Buffer, range: Option<Range<usize>>);
+	fn get_velocities(&mut self, buffer: Buffer);
+
+	fn set_phases(&mut self, buffer: Buffer, range: Option<Range<usize>>);
+	fn get_phases(&mut self, buffer: Buffer);
+
+	/// Whether what was last read back into `buffer` is still what the solver has.
+	/// Backends that don't wait on the solver can only read back an older copy while it's busy, which shouldn't be written back over it.
+	fn is_current(&self, buffer: Buffer) -> bool;
+
+	/// Makes sure the solver can hold at least `count` particles, recreating it with room for them if it can't.
+	/// Parameters, shapes and triangles carry over, but particles, velocities, phases and active indices have to be set again.
+	fn reserve_particles(&mut self, count: usize) -> Result<(), InitError>;
+
+	/// Runs `f` over each of the first `count` particles in `buffers.active`, setting the velocities it changed before the next update.
+	/// Returns false without running it if the next update is going to be dropped, so it isn't run twice for one step.
+	fn update_velocities(&mut self, buffers: &ParticleBuffers, count: usize, f: VelocityFn) -> bool;
+
+	/// Sets which particles are simulated, `buffer` holding `count` particle indices.
+	fn set_active(&mut self, buffer: Buffer, count: usize);
+
+	fn set_shapes(&mut self, shapes: &ShapeBuffers, count: usize);
+	fn set_triangles(&mut self, indices: Buffer, normals: Buffer, count: usize);
+
+	fn set_params(&mut self, params: &NvFlexParams);
+
+	/// Advances the simulation by `dt` seconds, split into `substeps` steps.
+	fn update(&mut self, dt: f32, substeps: i32);
+}
@@ -0,0 +1,584 @@
+// Runs another backend on a worker thread, so stepping the solver never stalls lua.
+use std::ffi::c_void;
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::JoinHandle;
+
+use crate::{state::InitError, sys::NvFlexParams};
+
+use super::cpu::CpuBuffer;
+use super::{Buffer, ParticleBuffers, ShapeBuffers, SolverBackend, VelocityFn};
+
+/// Creates the backend on the worker thread, since FleX solvers can't be handed between threads.
+pub type BackendFactory = Box<dyn FnOnce() -> Result<Box<dyn SolverBackend>, InitError> + Send>;
+
+/// Most updates that can be waiting on the worker, any past this are dropped until it catches up.
+const MAX_QUEUED_UPDATES: usize = 2;
+
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+enum Command {
+	Alloc(Buffer, usize, usize),
+	Free(Buffer),
+	/// Replaces the bytes of a buffer starting at the offset
+	Write(Buffer, usize, Vec<u8>),
+	/// Sets the elements in the range on the solver, or all of them
+	SetParticles(Buffer, Option<Range<usize>>),
+	SetVelocities(Buffer, Option<Range<usize>>),
+	SetPhases(Buffer, Option<Range<usize>>),
+	/// Replies with the result once the solver's grown
+	ReserveParticles(usize, mpsc::SyncSender<Result<(), InitError>>),
+	UpdateVelocities(ParticleBuffers, usize, #[derivative(Debug = "ignore")] VelocityFn),
+	SetActive(Buffer, usize),
+	SetShapes(ShapeBuffers, usize),
+	SetTriangles(Buffer, Buffer, usize),
+	SetParams(Box<NvFlexParams>),
+	Update(f32, i32),
+}
+
+/// A buffer of the [ThreadedBackend] and what the worker read back into it.
+type Readback = Option<(Buffer, Vec<u64>)>;
+
+/// Particle data read back after an update.
+#[derive(Debug, Default)]
+struct Snapshot {
+	/// Sequence number of the update this was taken after
+	seq: u64,
+	particles: Readback,
+	velocities: Readback,
+	phases: Readback,
+}
+
+/// Everything is done to host copies of the buffers, which are sent over to the worker whenever they're uploaded.
+/// Reading from the solver gives the latest snapshot the worker finished, instead of waiting on it.
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ThreadedBackend {
+	#[derivative(Debug = "ignore")]
+	buffers: Vec<Option<CpuBuffer>>,
+	/// Sequence number of the last upload of each buffer, so older snapshots can't overwrite newer data
+	written: Vec<u64>,
+	/// Sequence number of the snapshot each buffer was last read back from
+	synced: Vec<u64>,
+
+	seq: u64,
+	/// Sequence number of the last update sent to the worker
+	last_update: u64,
+	/// Taken on drop, to let the worker know to stop
+	commands: Option<mpsc::Sender<(u64, Command)>>,
+	queued_updates: Arc<AtomicUsize>,
+
+	#[derivative(Debug = "ignore")]
+	front: Arc<Mutex<Snapshot>>,
+	worker: Option<JoinHandle<()>>,
+}
+
+impl ThreadedBackend {
+	pub fn new(factory: BackendFactory) -> Result<Self, InitError> {
+		let (commands, receiver) = mpsc::channel();
+		let (ready, wait) = mpsc::sync_channel(1);
+
+		let front = Arc::new(Mutex::new(Snapshot::default()));
+		let queued_updates = Arc::new(AtomicUsize::new(0));
+
+		let worker = {
+			let front = Arc::clone(&front);
+			let queued_updates = Arc::clone(&queued_updates);
+
+			std::thread::Builder::new()
+				.name("gfluid-solver".to_owned())
+				.spawn(move || {
+					let backend = match factory() {
+						Ok(backend) => backend,
+						Err(why) => {
+							let _ = ready.send(Err(why));
+							return;
+						}
+					};
+
+					let _ = ready.send(Ok(()));
+
+					Worker {
+						backend,
+						buffers: vec![],
+						particles: None,
+						velocities: None,
+						phases: None,
+						back: Snapshot::default(),
+						front,
+						queued_updates,
+					}
+					.run(receiver);
+				})
+				.map_err(|_| InitError::Thread)?
+		};
+
+		wait.recv().map_err(|_| InitError::Thread)??;
+
+		Ok(Self {
+			buffers: vec![],
+			written: vec![],
+			synced: vec![],
+
+			seq: 0,
+			last_update: 0,
+			commands: Some(commands),
+			queued_updates,
+
+			front,
+			worker: Some(worker),
+		})
+	}
+
+	fn get(&self, buffer: Buffer) -> &CpuBuffer {
+		self.buffers[buffer.0].as_ref().expect("Use of freed buffer")
+	}
+
+	fn get_mut(&mut self, buffer: Buffer) -> &mut CpuBuffer {
+		self.buffers[buffer.0].as_mut().expect("Use of freed buffer")
+	}
+
+	fn send(&mut self, command: Command) {
+		self.seq += 1;
+		if let Some(commands) = &self.commands {
+			// Only fails if the worker is gone, in which case there's nothing left to simulate anyway.
+			let _ = commands.send((self.seq, command));
+		}
+	}
+
+	/// Sends the elements of a buffer in `range` over to the worker, or all of them.
+	fn upload(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		let (offset, data) = self.get(buffer).as_bytes(range);
+		let data = data.to_vec();
+
+		self.send(Command::Write(buffer, offset, data));
+		self.written[buffer.0] = self.seq;
+	}
+
+	/// Copies the latest snapshot of `buffer` into it, if it's newer than what was last uploaded.
+	fn download(&mut self, buffer: Buffer, pick: fn(&Snapshot) -> &Readback) {
+		let front = self.front.lock().unwrap();
+		if front.seq < self.written[buffer.0] {
+			return;
+		}
+
+		if let Some((source, data)) = pick(&front) {
+			if *source == buffer {
+				let dst = &mut self.buffers[buffer.0].as_mut().expect("Use of freed buffer").data;
+				let len = dst.len().min(data.len());
+				dst[..len].copy_from_slice(&data[..len]);
+
+				self.synced[buffer.0] = front.seq;
+			}
+		}
+	}
+}
+
+impl SolverBackend for ThreadedBackend {
+	fn alloc(&mut self, count: usize, stride: usize) -> Option<Buffer> {
+		let buffer = Some(CpuBuffer::new(count, stride));
+
+		let buffer = match self.buffers.iter().position(Option::is_none) {
+			Some(slot) => {
+				self.buffers[slot] = buffer;
+				self.written[slot] = 0;
+				self.synced[slot] = 0;
+				Buffer(slot)
+			}
+			None => {
+				self.buffers.push(buffer);
+				self.written.push(0);
+				self.synced.push(0);
+				Buffer(self.buffers.len() - 1)
+			}
+		};
+
+		self.send(Command::Alloc(buffer, count, stride));
+		Some(buffer)
+	}
+
+	fn free(&mut self, buffer: Buffer) {
+		self.buffers[buffer.0] = None;
+		self.send(Command::Free(buffer));
+	}
+
+	fn map(&mut self, buffer: Buffer) -> *mut c_void {
+		self.get_mut(buffer).data.as_mut_ptr() as *mut c_void
+	}
+
+	fn unmap(&mut self, _buffer: Buffer) {}
+
+	fn set_particles(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		self.upload(buffer, range.clone());
+		self.send(Command::SetParticles(buffer, range));
+	}
+
+	fn get_particles(&mut self, buffer: Buffer) {
+		self.download(buffer, |s| &s.particles);
+	}
+
+	fn set_velocities(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		self.upload(buffer, range.clone());
+		self.send(Command::SetVelocities(buffer, range));
+	}
+
+	fn get_velocities(&mut self, buffer: Buffer) {
+		self.download(buffer, |s| &s.velocities);
+	}
+
+	fn set_phases(&mut self, buffer: Buffer, range: Option<Range<usize>>) {
+		self.upload(buffer, range.clone());
+		self.send(Command::SetPhases(buffer, range));
+	}
+
+	fn get_phases(&mut self, buffer: Buffer) {
+		self.download(buffer, |s| &s.phases);
+	}
+
+	/// Current once the worker has published a snapshot from after the last update, and it's been read back.
+	/// Uploads since then don't matter, the worker applies them to its copy too.
+	fn is_current(&self, buffer: Buffer) -> bool {
+		self.synced[buffer.0] >= self.last_update
+	}
+
+	/// Waits on the worker to finish everything sent before and grow the solver.
+	/// The snapshot it publishes just before growing has all of that, so reading back afterwards is current.
+	fn reserve_particles(&mut self, count: usize) -> Result<(), InitError> {
+		let (reply, result) = mpsc::sync_channel(1);
+		self.send(Command::ReserveParticles(count, reply));
+		result.recv().map_err(|_| InitError::Thread)?
+	}
+
+	/// Runs on the worker once it's done with the updates before, so it always sees the latest particles.
+	fn update_velocities(&mut self, buffers: &ParticleBuffers, count: usize, f: VelocityFn) -> bool {
+		// Only the worker takes updates off the queue, so if there's room now there still is when the update is sent
+		if self.queued_updates.load(Ordering::Acquire) >= MAX_QUEUED_UPDATES {
+			return false;
+		}
+
+		self.send(Command::UpdateVelocities(*buffers, count, f));
+		true
+	}
+
+	fn set_active(&mut self, buffer: Buffer, count: usize) {
+		self.upload(buffer, Some(0..count));
+		self.send(Command::SetActive(buffer, count));
+	}
+
+	fn set_shapes(&mut self, shapes: &ShapeBuffers, count: usize) {
+		self.upload(shapes.geometry, None);
+		self.upload(shapes.positions, None);
+		self.upload(shapes.rotations, None);
+		self.upload(shapes.previous_positions, None);
+		self.upload(shapes.previous_rotations, None);
+		self.upload(shapes.flags, None);
+		self.send(Command::SetShapes(*shapes, count));
+	}
+
+	fn set_triangles(&mut self, indices: Buffer, normals: Buffer, count: usize) {
+		self.upload(indices, None);
+		self.upload(normals, None);
+		self.send(Command::SetTriangles(indices, normals, count));
+	}
+
+	fn set_params(&mut self, params: &NvFlexParams) {
+		self.send(Command::SetParams(Box::new(*params)));
+	}
+
+	fn update(&mut self, dt: f32, substeps: i32) {
+		// Drop steps while the worker is behind, rather than letting it fall further behind.
+		if self.queued_updates.load(Ordering::Acquire) >= MAX_QUEUED_UPDATES {
+			return;
+		}
+
+		self.queued_updates.fetch_add(1, Ordering::AcqRel);
+		self.send(Command::Update(dt, substeps));
+		self.last_update = self.seq;
+	}
+}
+
+impl Drop for ThreadedBackend {
+	/// Stops the worker, which frees the backend on its own thread.
+	fn drop(&mut self) {
+		self.commands.take();
+		if let Some(worker) = self.worker.take() {
+			let _ = worker.join();
+		}
+	}
+}
+
+/// Lives on the worker thread, applying commands to the real backend.
+struct Worker {
+	backend: Box<dyn SolverBackend>,
+	/// Buffer of the real backend and its size in bytes, indexed by the [ThreadedBackend]'s buffers.
+	buffers: Vec<Option<(Buffer, usize)>>,
+
+	/// Buffers last uploaded as particles, velocities and phases, which are read back after each update
+	particles: Option<Buffer>,
+	velocities: Option<Buffer>,
+	phases: Option<Buffer>,
+
+	back: Snapshot,
+	front: Arc<Mutex<Snapshot>>,
+	queued_updates: Arc<AtomicUsize>,
+}
+
+impl Worker {
+	fn run(mut self, commands: mpsc::Receiver<(u64, Command)>) {
+		for (seq, command) in commands {
+			let update = matches!(command, Command::Update(..));
+			if matches!(command, Command::ReserveParticles(..)) {
+				// Growing can recreate the solver without its particles, so hand them over before they're gone.
+				self.publish(seq);
+			}
+
+			self.apply(command);
+
+			if update {
+				self.publish(seq);
+				self.queued_updates.fetch_sub(1, Ordering::AcqRel);
+			}
+		}
+	}
+
+	/// Buffers the real backend failed to allocate are skipped over.
+	fn get(&self, buffer: Buffer) -> Option<Buffer> {
+		self.buffers.get(buffer.0).copied().flatten().map(|(b, _)| b)
+	}
+
+	fn apply(&mut self, command: Command) {
+		match command {
+			Command::Alloc(buffer, count, stride) => {
+				if self.buffers.len() <= buffer.0 {
+					self.buffers.resize(buffer.0 + 1, None);
+				}
+				self.buffers[buffer.0] = self.backend.alloc(count, stride).map(|b| (b, count * stride));
+			}
+			Command::Free(buffer) => {
+				if let Some((inner, _)) = self.buffers[buffer.0].take() {
+					self.backend.free(inner);
+				}
+			}
+			Command::Write(buffer, offset, data) => {
+				if let Some((inner, size)) = self.buffers[buffer.0].filter(|&(_, size)| offset < size) {
+					let ptr = self.backend.map(inner) as *mut u8;
+					unsafe { std::ptr::copy_nonoverlapping(data.as_ptr(), ptr.add(offset), data.len().min(size - offset)) };
+					self.backend.unmap(inner);
+				}
+			}
+			Command::SetParticles(buffer, range) => {
+				self.particles = Some(buffer);
+				if let Some(inner) = self.get(buffer) {
+					self.backend.set_particles(inner, range);
+				}
+			}
+			Command::SetVelocities(buffer, range) => {
+				self.velocities = Some(buffer);
+				if let Some(inner) = self.get(buffer) {
+					self.backend.set_velocities(inner, range);
+				}
+			}
+			Command::SetPhases(buffer, range) => {
+				self.phases = Some(buffer);
+				if let Some(inner) = self.get(buffer) {
+					self.backend.set_phases(inner, range);
+				}
+			}
+			Command::ReserveParticles(count, reply) => {
+				let _ = reply.send(self.backend.reserve_particles(count));
+			}
+			Command::UpdateVelocities(buffers, count, f) => {
+				let inner = (|| {
+					Some(ParticleBuffers {
+						particles: self.get(buffers.particles)?,
+						velocities: self.get(buffers.velocities)?,
+						active: self.get(buffers.active)?,
+					})
+				})();
+
+				if let Some(inner) = inner {
+					self.backend.update_velocities(&inner, count, f);
+				}
+			}
+			Command::SetActive(buffer, count) => {
+				if let Some(inner) = self.get(buffer) {
+					self.backend.set_active(inner, count);
+				}
+			}
+			Command::SetShapes(shapes, count) => {
+				let inner = (|| {
+					Some(ShapeBuffers {
+						geometry: self.get(shapes.geometry)?,
+						positions: self.get(shapes.positions)?,
+						rotations: self.get(shapes.rotations)?,
+						previous_positions: self.get(shapes.previous_positions)?,
+						previous_rotations: self.get(shapes.previous_rotations)?,
+						flags: self.get(shapes.flags)?,
+					})
+				})();
+
+				if let Some(inner) = inner {
+					self.backend.set_shapes(&inner, count);
+				}
+			}
+			Command::SetTriangles(indices, normals, count) => {
+				if let (Some(indices), Some(normals)) = (self.get(indices), self.get(normals)) {
+					self.backend.set_triangles(indices, normals, count);
+				}
+			}
+			Command::SetParams(params) => self.backend.set_params(&params),
+			Command::Update(dt, substeps) => self.backend.update(dt, substeps),
+		}
+	}
+
+	/// Reads particle data back into the back snapshot, then swaps it to the front.
+	fn publish(&mut self, seq: u64) {
+		fn read(worker: &mut Worker, buffer: Option<Buffer>, get: fn(&mut dyn SolverBackend, Buffer), out: &mut Readback) {
+			// Reuse the allocation from the snapshot before last
+			let mut data = out.take().map(|(_, data)| data).unwrap_or_default();
+
+			let Some(outer) = buffer else { return };
+			let Some((inner, size)) = worker.buffers.get(outer.0).copied().flatten() else { return };
+
+			get(worker.backend.as_mut(), inner);
+
+			data.clear();
+			data.resize(size.div_ceil(8), 0);
+
+			let ptr = worker.backend.map(inner) as *const u8;
+			unsafe { std::ptr::copy_nonoverlapping(ptr, data.as_mut_ptr() as *mut u8, size) };
+			worker.backend.unmap(inner);
+
+			*out = Some((outer, data));
+		}
+
+		let mut back = std::mem::take(&mut self.back);
+		read(self, self.particles, |b, buf| b.get_particles(buf), &mut back.particles);
+		read(self, self.velocities, |b, buf| b.get_velocities(buf), &mut back.velocities);
+		read(self, self.phases, |b, buf| b.get_phases(buf), &mut back.phases);
+		back.seq = seq;
+
+		if let Ok(mut front) = self.front.lock() {
+			std::mem::swap(&mut *front, &mut back);
+		}
+
+		self.back = back;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, types::{Vector3, Vector4}};
+
+	fn setup(count: usize) -> (ThreadedBackend, ParticleBuffers) {
+		let mut backend = ThreadedBackend::new(Box::new(|| Ok(Box::new(CpuBackend::new()) as Box<dyn SolverBackend>))).unwrap();
+		let buffers = ParticleBuffers {
+			particles: backend.alloc(count, std::mem::size_of::<Vector4>()).unwrap(),
+			velocities: backend.alloc(count, std::mem::size_of::<Vector3>()).unwrap(),
+			active: backend.alloc(count, std::mem::size_of::<i32>()).unwrap(),
+		};
+
+		let active = backend.get_mut(buffers.active).as_mut_slice::<i32>();
+		for (i, index) in active.iter_mut().enumerate() {
+			*index = i as i32;
+		}
+
+		backend.set_velocities(buffers.velocities, None);
+		backend.set_active(buffers.active, count);
+		(backend, buffers)
+	}
+
+	fn positions(backend: &mut ThreadedBackend, buffer: Buffer) -> &mut [Vector4] {
+		backend.get_mut(buffer).as_mut_slice::<Vector4>()
+	}
+
+	/// Blocks until the worker has run every update sent to it.
+	fn wait(backend: &ThreadedBackend) {
+		while backend.queued_updates.load(Ordering::Acquire) > 0 {
+			std::thread::yield_now();
+		}
+	}
+
+	#[test]
+	fn updates_are_read_back_once_finished() {
+		let (mut backend, buffers) = setup(1);
+		positions(&mut backend, buffers.particles)[0] = Vector4(0.0, 0.0, 0.0, 1.0);
+		backend.set_particles(buffers.particles, None);
+		assert!(backend.is_current(buffers.particles));
+
+		backend.update(1.0 / 60.0, 1);
+		assert!(!backend.is_current(buffers.particles));
+
+		wait(&backend);
+		backend.get_particles(buffers.particles);
+		assert!(backend.is_current(buffers.particles));
+
+		// Fell under gravity on the worker
+		assert!(positions(&mut backend, buffers.particles)[0].2 < 0.0);
+	}
+
+	#[test]
+	fn ranged_uploads_only_send_their_range() {
+		let (mut backend, buffers) = setup(2);
+		positions(&mut backend, buffers.particles).copy_from_slice(&[Vector4(0.0, 0.0, 0.0, 1.0), Vector4(10.0, 0.0, 0.0, 1.0)]);
+		backend.set_particles(buffers.particles, None);
+
+		positions(&mut backend, buffers.particles).copy_from_slice(&[Vector4(100.0, 0.0, 0.0, 1.0), Vector4(110.0, 0.0, 0.0, 1.0)]);
+		backend.set_particles(buffers.particles, Some(1..2));
+
+		backend.update(1.0 / 60.0, 1);
+		wait(&backend);
+		backend.get_particles(buffers.particles);
+
+		let particles = positions(&mut backend, buffers.particles);
+		assert_eq!((particles[0].0, particles[1].0), (0.0, 110.0));
+	}
+
+	#[test]
+	fn snapshots_dont_overwrite_newer_uploads() {
+		let (mut backend, buffers) = setup(1);
+		positions(&mut backend, buffers.particles)[0] = Vector4(0.0, 0.0, 0.0, 1.0);
+		backend.set_particles(buffers.particles, None);
+
+		backend.update(1.0 / 60.0, 1);
+		wait(&backend);
+
+		positions(&mut backend, buffers.particles)[0] = Vector4(5.0, 0.0, 0.0, 1.0);
+		backend.set_particles(buffers.particles, None);
+
+		// The snapshot is from before the upload, so it's ignored
+		backend.get_particles(buffers.particles);
+		assert_eq!(positions(&mut backend, buffers.particles)[0].0, 5.0);
+		assert!(!backend.is_current(buffers.particles));
+	}
+
+	#[test]
+	fn velocities_are_updated_after_the_updates_before() {
+		let (mut backend, buffers) = setup(1);
+		positions(&mut backend, buffers.particles)[0] = Vector4(0.0, 0.0, 0.0, 1.0);
+		backend.set_particles(buffers.particles, None);
+
+		// Sees where the particle fell to, without waiting on the update here
+		backend.update(1.0 / 60.0, 1);
+		let f = Box::new(|pos: Vector4, vel: &mut Vector3| {
+			*vel = Vector3(if pos.2 < 0.0 { 1.0 } else { -1.0 }, 0.0, 0.0);
+			true
+		});
+		assert!(backend.update_velocities(&buffers, 1, f));
+
+		backend.update(1.0 / 60.0, 1);
+		wait(&backend);
+		backend.get_velocities(buffers.velocities);
+		assert!(backend.get_mut(buffers.velocities).as_mut_slice::<Vector3>()[0].0 > 0.0);
+	}
+
+	#[test]
+	fn velocities_arent_updated_for_dropped_steps() {
+		let (mut backend, buffers) = setup(1);
+		backend.queued_updates.store(MAX_QUEUED_UPDATES, Ordering::Release);
+		assert!(!backend.update_velocities(&buffers, 1, Box::new(|_, _| panic!("ran for a dropped step"))));
+		backend.queued_updates.store(0, Ordering::Release);
+	}
+}
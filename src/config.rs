@@ -1,11 +1,37 @@
-use nvflex_sys::*;
+use std::sync::Mutex;
 
+use crate::sys::*;
+
+use crate::state::params::{self, ParamError, ParamValue};
 use crate::types::{Vector4, Quat};
 
+/// Where the config file is looked for, relative to the game's directory.
+pub const CONFIG_PATH: &str = "garrysmod/data/gfluid/config.toml";
+
 pub const MAX_PARTICLES: usize = 2000;
 pub const MAX_SHAPES: usize = 1000;
 pub const MAX_TRIANGLES: i32 = 1000;
 
+/// Most particles, shapes and triangles a world can grow to, the max above are only what it starts out with.
+pub const PARTICLE_CEILING: usize = 262144;
+pub const SHAPE_CEILING: usize = 16384;
+pub const TRIANGLE_CEILING: i32 = 65536;
+
+/// Fixed step the solver is advanced by, in seconds
+pub const TIME_STEP: f32 = 1.0 / 60.0;
+pub const SUBSTEPS: i32 = 2;
+/// Most steps simulated in one tick, to catch up after a hitch
+pub const MAX_STEPS: u32 = 4;
+
+/// Whether the default world is simulated on its own thread
+pub const THREADED: bool = false;
+
+/// Errors kept around for `flex.getErrors`, oldest are dropped past this.
+pub const MAX_ERRORS: usize = 64;
+
+/// Most planes FleX supports, the size of [NvFlexParams::planes]
+pub const MAX_PLANES: usize = 8;
+
 /// 32  =  2' 0"    ≈     60cm     width & length
 /// 36  =  2' 3"    ≈     70cm     height crouching
 /// 72  =  4' 6"    ≈    135cm     height standing
@@ -68,8 +94,305 @@ pub const PARAMS: NvFlexParams = NvFlexParams {
 		[0.0, 0.0, 0.0, 0.0],
 	],
 	numPlanes: 0,
-	relaxationMode: nvflex_sys::eNvFlexRelaxationLocal,
+	relaxationMode: eNvFlexRelaxationLocal,
 	relaxationFactor: 1.0,
 };
 
-const MAX_PLANES: i32 = 12;
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+	#[error("Couldn't read {}: {0}", CONFIG_PATH)]
+	Io(#[from] std::io::Error),
+
+	#[error("Couldn't parse {}: {0}", CONFIG_PATH)]
+	Parse(#[from] toml::de::Error),
+
+	#[error("Unknown config key: `{0}`")]
+	Unknown(String),
+
+	#[error("Expected {1} for `{0}`")]
+	Type(String, &'static str),
+
+	#[error("Invalid value for `{0}`: must be {1}")]
+	Invalid(String, &'static str),
+
+	#[error("In [params]: {0}")]
+	Param(#[from] ParamError),
+}
+
+/// Settings read from [CONFIG_PATH], anything missing from it falls back to the constants above.
+#[derive(Debug, Clone)]
+pub struct Config {
+	pub max_particles: usize,
+	pub max_shapes: usize,
+	pub max_triangles: i32,
+
+	pub particle_ceiling: usize,
+	pub shape_ceiling: usize,
+	pub triangle_ceiling: i32,
+
+	pub time_step: f32,
+	pub substeps: i32,
+	pub max_steps: u32,
+
+	pub threaded: bool,
+
+	/// Whether the default world gets a baseplate collider
+	pub baseplate: bool,
+	pub baseplate_pos: Vector4,
+	pub baseplate_rot: Quat,
+	pub baseplate_size: [f32; 3],
+
+	pub params: NvFlexParams,
+}
+
+impl Default for Config {
+	fn default() -> Self {
+		Self {
+			max_particles: MAX_PARTICLES,
+			max_shapes: MAX_SHAPES,
+			max_triangles: MAX_TRIANGLES,
+
+			particle_ceiling: PARTICLE_CEILING,
+			shape_ceiling: SHAPE_CEILING,
+			triangle_ceiling: TRIANGLE_CEILING,
+
+			time_step: TIME_STEP,
+			substeps: SUBSTEPS,
+			max_steps: MAX_STEPS,
+
+			threaded: THREADED,
+
+			baseplate: false,
+			baseplate_pos: BASEPLATE,
+			baseplate_rot: BASEPLATE_ROT,
+			baseplate_size: BASEPLATE_SIZE,
+
+			params: PARAMS,
+		}
+	}
+}
+
+static CURRENT: Mutex<Option<Config>> = Mutex::new(None);
+
+/// The config last loaded, or the defaults if it was never loaded.
+pub fn current() -> Config {
+	CURRENT.lock().unwrap().clone().unwrap_or_default()
+}
+
+pub fn set_current(config: Config) {
+	*CURRENT.lock().unwrap() = Some(config);
+}
+
+fn number(key: &str, value: &toml::Value) -> Result<f32, ConfigError> {
+	match value {
+		toml::Value::Float(x) => Ok(*x as f32),
+		toml::Value::Integer(x) => Ok(*x as f32),
+		_ => Err(ConfigError::Type(key.to_owned(), "a number")),
+	}
+}
+
+fn numbers<const N: usize>(key: &str, value: &toml::Value) -> Result<[f32; N], ConfigError> {
+	let array = match value.as_array() {
+		Some(array) if array.len() == N => array,
+		_ => return Err(ConfigError::Type(key.to_owned(), "an array of numbers")),
+	};
+
+	let mut out = [0.0; N];
+	for (x, value) in out.iter_mut().zip(array) {
+		*x = number(key, value)?;
+	}
+
+	Ok(out)
+}
+
+fn positive_integer(key: &str, value: &toml::Value) -> Result<i64, ConfigError> {
+	match value.as_integer() {
+		Some(x) if x > 0 && x <= i32::MAX as i64 => Ok(x),
+		Some(_) => Err(ConfigError::Invalid(key.to_owned(), "greater than zero")),
+		None => Err(ConfigError::Type(key.to_owned(), "an integer")),
+	}
+}
+
+fn integer(key: &str, value: &toml::Value) -> Result<i32, ConfigError> {
+	match value.as_integer() {
+		Some(x) => i32::try_from(x).map_err(|_| ConfigError::Invalid(key.to_owned(), "a 32-bit integer")),
+		None => Err(ConfigError::Type(key.to_owned(), "an integer")),
+	}
+}
+
+fn table<'a>(key: &str, value: &'a toml::Value) -> Result<&'a toml::value::Table, ConfigError> {
+	value.as_table().ok_or_else(|| ConfigError::Type(key.to_owned(), "a table"))
+}
+
+/// Reads the [params] table, starting from [PARAMS].
+fn load_params(tbl: &toml::value::Table) -> Result<NvFlexParams, ConfigError> {
+	let mut out = PARAMS;
+
+	for (key, value) in tbl {
+		if key == "planes" {
+			let planes = value
+				.as_array()
+				.ok_or_else(|| ConfigError::Type("planes".to_owned(), "an array of planes"))?
+				.iter()
+				.map(|plane| numbers::<4>("planes", plane))
+				.collect::<Result<Vec<_>, _>>()?;
+
+			params::set_planes(&mut out, &planes)?;
+			continue;
+		}
+
+		let value = match params::get(&out, key).ok_or_else(|| ParamError::Unknown(key.to_owned()))? {
+			ParamValue::Number(_) => ParamValue::Number(number(key, value)?),
+			ParamValue::Integer(_) => ParamValue::Integer(integer(key, value)?),
+			ParamValue::Vector(_) => ParamValue::Vector(numbers::<3>(key, value)?),
+		};
+
+		params::set(&mut out, key, value)?;
+	}
+
+	params::validate(&out)?;
+	Ok(out)
+}
+
+impl Config {
+	/// Parses a config file's contents.
+	pub fn parse(src: &str) -> Result<Self, ConfigError> {
+		let root: toml::value::Table = toml::from_str(src)?;
+		let mut config = Config::default();
+
+		for (key, value) in &root {
+			match key.as_str() {
+				"maxParticles" => config.max_particles = positive_integer(key, value)? as usize,
+				"maxShapes" => config.max_shapes = positive_integer(key, value)? as usize,
+				"maxTriangles" => config.max_triangles = positive_integer(key, value)? as i32,
+
+				"particleCeiling" => config.particle_ceiling = positive_integer(key, value)? as usize,
+				"shapeCeiling" => config.shape_ceiling = positive_integer(key, value)? as usize,
+				"triangleCeiling" => config.triangle_ceiling = positive_integer(key, value)? as i32,
+
+				"timeStep" => config.time_step = match number(key, value)? {
+					x if x > 0.0 && x.is_finite() => x,
+					_ => return Err(ConfigError::Invalid(key.to_owned(), "greater than zero")),
+				},
+				"substeps" => config.substeps = positive_integer(key, value)? as i32,
+				"maxSteps" => config.max_steps = positive_integer(key, value)? as u32,
+
+				"threaded" => config.threaded = value.as_bool().ok_or_else(|| ConfigError::Type(key.to_owned(), "a boolean"))?,
+
+				"baseplate" => {
+					for (key, value) in table(key, value)? {
+						match key.as_str() {
+							"enabled" => config.baseplate = value.as_bool().ok_or_else(|| ConfigError::Type("baseplate.enabled".to_owned(), "a boolean"))?,
+							"pos" => {
+								let [x, y, z] = numbers("baseplate.pos", value)?;
+								config.baseplate_pos = Vector4(x, y, z, config.baseplate_pos.3);
+							}
+							"rot" => {
+								let [x, y, z, w] = numbers("baseplate.rot", value)?;
+								config.baseplate_rot = Quat(x, y, z, w);
+							}
+							"size" => config.baseplate_size = numbers("baseplate.size", value)?,
+							_ => return Err(ConfigError::Unknown(format!("baseplate.{}", key))),
+						}
+					}
+				}
+
+				"params" => config.params = load_params(table(key, value)?)?,
+
+				_ => return Err(ConfigError::Unknown(key.clone())),
+			}
+		}
+
+		config.check_capacities()?;
+		Ok(config)
+	}
+
+	/// Checks that every starting capacity is within its ceiling.
+	pub fn check_capacities(&self) -> Result<(), ConfigError> {
+		if self.max_particles > self.particle_ceiling {
+			return Err(ConfigError::Invalid("maxParticles".to_owned(), "at most particleCeiling"));
+		}
+
+		if self.max_shapes > self.shape_ceiling {
+			return Err(ConfigError::Invalid("maxShapes".to_owned(), "at most shapeCeiling"));
+		}
+
+		if self.max_triangles > self.triangle_ceiling {
+			return Err(ConfigError::Invalid("maxTriangles".to_owned(), "at most triangleCeiling"));
+		}
+
+		Ok(())
+	}
+
+	/// Loads [CONFIG_PATH], or the defaults if there's no such file.
+	pub fn load() -> Result<Self, ConfigError> {
+		match std::fs::read_to_string(CONFIG_PATH) {
+			Ok(src) => Self::parse(&src),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn empty_config_is_the_default() {
+		let config = Config::parse("").unwrap();
+		assert_eq!(config.max_particles, MAX_PARTICLES);
+		assert_eq!(config.time_step, TIME_STEP);
+		assert_eq!(config.params.radius, PARAMS.radius);
+	}
+
+	#[test]
+	fn values_are_read() {
+		let config = Config::parse(r#"
+			maxParticles = 100
+			particleCeiling = 1000
+			timeStep = 0.02
+			substeps = 3
+			threaded = true
+
+			[baseplate]
+			enabled = true
+			pos = [1, 2, 3.5]
+
+			[params]
+			viscosity = 2
+			gravity = [0.0, 0.0, -20.0]
+			planes = [[0, 0, 1, 0]]
+		"#).unwrap();
+
+		assert_eq!((config.max_particles, config.particle_ceiling), (100, 1000));
+		assert_eq!((config.time_step, config.substeps), (0.02, 3));
+		assert!(config.threaded && config.baseplate);
+		assert_eq!((config.baseplate_pos.0, config.baseplate_pos.1, config.baseplate_pos.2, config.baseplate_pos.3), (1.0, 2.0, 3.5, 1.0));
+
+		assert_eq!(config.params.viscosity, 2.0);
+		assert_eq!(config.params.gravity, [0.0, 0.0, -20.0]);
+		assert_eq!((config.params.numPlanes, config.params.planes[0]), (1, [0.0, 0.0, 1.0, 0.0]));
+	}
+
+	#[test]
+	fn invalid_values_are_rejected() {
+		let invalid = |src: &str| Config::parse(src).unwrap_err();
+
+		assert!(matches!(invalid("nope = 1"), ConfigError::Unknown(_)));
+		assert!(matches!(invalid("[baseplate]\nnope = 1"), ConfigError::Unknown(_)));
+		assert!(matches!(invalid("maxParticles = 0"), ConfigError::Invalid(..)));
+		assert!(matches!(invalid("maxParticles = 4294967296"), ConfigError::Invalid(..)));
+		assert!(matches!(invalid("maxParticles = 1.5"), ConfigError::Type(..)));
+		assert!(matches!(invalid("timeStep = -1"), ConfigError::Invalid(..)));
+		assert!(matches!(invalid("threaded = 1"), ConfigError::Type(..)));
+		assert!(matches!(invalid("maxParticles = 10\nparticleCeiling = 5"), ConfigError::Invalid(..)));
+		assert!(matches!(invalid("[baseplate]\npos = [1, 2]"), ConfigError::Type(..)));
+		assert!(matches!(invalid("maxParticles ="), ConfigError::Parse(_)));
+
+		assert!(matches!(invalid("[params]\nnope = 1"), ConfigError::Param(ParamError::Unknown(_))));
+		assert!(matches!(invalid("[params]\nviscosity = -1"), ConfigError::Param(ParamError::Range(..))));
+		assert!(matches!(invalid("[params]\nnumIterations = 4294967296"), ConfigError::Invalid(..)));
+		assert!(matches!(invalid("[params]\nradius = 1\nfluidRestDistance = 2"), ConfigError::Param(ParamError::RestDistance)));
+	}
+}
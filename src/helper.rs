@@ -1,15 +1,49 @@
-use nvflex_sys::*;
+use crate::sys::*;
 
 #[inline(always)]
 #[allow(non_snake_case)]
 pub fn NvFlexMakePhase(group: i32, particle_flags: i32) -> i32 {
+	NvFlexMakePhaseWithChannels(group, particle_flags, eNvFlexPhaseShapeChannelMask)
+}
+
+/// Like [NvFlexMakePhase], but only colliding with shapes on one of `shape_channels`.
+#[inline(always)]
+#[allow(non_snake_case)]
+pub fn NvFlexMakePhaseWithChannels(group: i32, particle_flags: i32, shape_channels: i32) -> i32 {
 	(group & eNvFlexPhaseGroupMask)
 		| (particle_flags & eNvFlexPhaseFlagsMask)
-		| eNvFlexPhaseShapeChannelMask
+		| (shape_channels & eNvFlexPhaseShapeChannelMask)
 }
 
 #[inline(always)]
 #[allow(non_snake_case)]
 pub const fn NvFlexMakeShapeFlags(ty: NvFlexCollisionShapeType, dynamic: bool) -> i32 {
-	ty | (if dynamic { eNvFlexShapeFlagDynamic } else { 0 }) | eNvFlexPhaseShapeChannelMask
+	NvFlexMakeShapeFlagsWithChannels(ty, dynamic, eNvFlexPhaseShapeChannelMask)
+}
+
+/// Like [NvFlexMakeShapeFlags], but only colliding with particles on one of `shape_channels`.
+#[inline(always)]
+#[allow(non_snake_case)]
+pub const fn NvFlexMakeShapeFlagsWithChannels(ty: NvFlexCollisionShapeType, dynamic: bool, shape_channels: i32) -> i32 {
+	ty | (if dynamic { eNvFlexShapeFlagDynamic } else { 0 }) | (shape_channels & eNvFlexPhaseShapeChannelMask)
+}
+
+/// Distance fluid particles are spaced at when at rest density.
+/// Falls back to the same default the FleX demos use if `fluidRestDistance` isn't set.
+pub fn fluid_rest_distance(params: &NvFlexParams) -> f32 {
+	if params.fluidRestDistance > 0.0 {
+		params.fluidRestDistance
+	} else {
+		params.radius * 0.55
+	}
+}
+
+/// Distance solid particles are kept apart at.
+/// Falls back to the same default the FleX demos use if `solidRestDistance` isn't set.
+pub fn solid_rest_distance(params: &NvFlexParams) -> f32 {
+	if params.solidRestDistance > 0.0 {
+		params.solidRestDistance
+	} else {
+		params.radius
+	}
 }
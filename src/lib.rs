@@ -2,32 +2,142 @@
 
 use rglua::prelude::*;
 use std::sync::atomic::{AtomicPtr, Ordering};
+#[cfg(feature = "nvflex-sys")]
+use std::sync::{Arc, Mutex};
 
 mod lua;
+mod backend;
 mod config;
 mod helper;
 mod state;
+mod spatial;
+mod sys;
 mod types;
 
-use state::FlexState;
+use backend::SolverBackend;
+use config::{Config, ConfigError};
+use state::{FlexState, InitError};
 
+/// The default world, which the `flex.*` functions operate on.
 static STATE: AtomicPtr<FlexState> = AtomicPtr::new(std::ptr::null_mut());
 
-#[gmod_open]
-fn main(l: LuaState) -> i32 {
-	unsafe extern "C" fn error_handler(severity: i32, msg: LuaString, file: LuaString, line: i32) {
-		// In the future maybe this can cause a lua error?
-		eprintln!("Flex Error!: Severity: {}, Msg: [{}], File: [{}], line: [{}]", severity, rstr!(msg), rstr!(file), line);
+/// Shared by the solvers of every world.
+#[cfg(feature = "nvflex-sys")]
+static LIBRARY: Mutex<Option<Arc<backend::flex::FlexLibrary>>> = Mutex::new(None);
+
+/// Creates the solver for a new world.
+/// If `threaded`, it's stepped on a worker thread of its own.
+pub fn create_backend(threaded: bool) -> Result<Box<dyn SolverBackend>, InitError> {
+	if threaded {
+		let backend = backend::threaded::ThreadedBackend::new(Box::new(|| {
+			#[cfg(feature = "nvflex-sys")]
+			if let Some(lib) = LIBRARY.lock().unwrap().as_ref() {
+				lib.acquire_context();
+			}
+
+			create_backend(false)
+		}))?;
+
+		return Ok(Box::new(backend));
 	}
 
-	let flex_state = unsafe {
-		let mut flex = Box::new(FlexState::new(Some(error_handler)));
-		flex.init();
-		flex
+	#[cfg(feature = "nvflex-sys")]
+	{
+		let lib = LIBRARY.lock().unwrap().clone().ok_or(InitError::Library)?;
+		Ok(Box::new(lib.create_solver()?))
+	}
+
+	#[cfg(not(feature = "nvflex-sys"))]
+	Ok(Box::new(backend::cpu::CpuBackend::new()))
+}
+
+#[cfg(feature = "nvflex-sys")]
+unsafe extern "C" fn error_handler(severity: i32, msg: LuaString, file: LuaString, line: i32) {
+	// Can be called from any thread, so just queue it up for the next tick to report.
+	let to_string = |s: LuaString| match s.is_null() {
+		true => String::new(),
+		false => std::ffi::CStr::from_ptr(s).to_string_lossy().into_owned(),
+	};
+
+	let error = state::errors::FlexError {
+		severity,
+		message: to_string(msg),
+		file: to_string(file),
+		line,
 	};
 
-	let flex_ptr = Box::into_raw(flex_state);
-	STATE.store(flex_ptr, Ordering::Relaxed);
+	if let Ok(mut errors) = state::errors::ERRORS.lock() {
+		errors.push(error);
+	}
+}
+
+fn create_default_world(config: &Config) -> Result<Box<FlexState>, InitError> {
+	let mut flex_state = Box::new(FlexState::try_new(create_backend(config.threaded)?, config)?);
+	flex_state.init(config);
+
+	Ok(flex_state)
+}
+
+/// Starts up FleX and creates the default world.
+fn init(config: &Config) -> Result<Box<FlexState>, InitError> {
+	#[cfg(feature = "nvflex-sys")]
+	{
+		let lib = unsafe { backend::flex::FlexLibrary::new(Some(error_handler))? };
+		*LIBRARY.lock().unwrap() = Some(Arc::new(lib));
+	}
+
+	create_default_world(config)
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReloadError {
+	#[error("{0}")]
+	Config(#[from] ConfigError),
+
+	#[error("Failed to rebuild the solver: {0}")]
+	Init(#[from] InitError),
+}
+
+/// Reads the config file again and replaces the default world with one built from it.
+/// Nothing changes if the config is invalid.
+pub fn reload_config() -> Result<(), ReloadError> {
+	let config = Config::load()?;
+	let flex_state = create_default_world(&config)?;
+	config::set_current(config);
+
+	let old = STATE.swap(Box::into_raw(flex_state), Ordering::SeqCst);
+	if !old.is_null() {
+		std::mem::drop(unsafe { Box::from_raw(old) });
+	}
+
+	Ok(())
+}
+
+fn error_no_halt(l: LuaState, msg: &str) {
+	lua_getglobal(l, cstr!("ErrorNoHalt"));
+	lua::push_string(l, &format!("[gfluid] {}\n", msg));
+	lua_call(l, 1, 0);
+}
+
+#[gmod_open]
+fn main(l: LuaState) -> i32 {
+	let config = Config::load().unwrap_or_else(|why| {
+		error_no_halt(l, &format!("{}, using the default config", why));
+		Config::default()
+	});
+
+	config::set_current(config.clone());
+
+	match init(&config) {
+		Ok(flex_state) => {
+			let flex_ptr = Box::into_raw(flex_state);
+			STATE.store(flex_ptr, Ordering::Relaxed);
+		}
+		Err(why) => {
+			// Leave STATE null, so flex.* functions report that gfluid isn't initialized instead of taking the game down.
+			error_no_halt(l, &format!("Failed to initialize: {}", why));
+		}
+	}
 
 	lua::load(l);
 
@@ -36,12 +146,21 @@ fn main(l: LuaState) -> i32 {
 
 #[gmod_close]
 fn close(_l: LuaState) -> i32 {
-	let ptr = STATE.load(Ordering::SeqCst);
-	// Get box out of pointer to interact with flex state
-	// This will be dropped automagically
-	let flex_state = unsafe { Box::from_raw(ptr as *mut FlexState) };
+	lua::world::destroy_all();
+
+	let ptr = STATE.swap(std::ptr::null_mut(), Ordering::SeqCst);
+	if !ptr.is_null() {
+		// Get box out of pointer to interact with flex state
+		// This will be dropped automagically
+		let flex_state = unsafe { Box::from_raw(ptr) };
+
+		// All cleanup work will be done here
+		std::mem::drop(flex_state);
+	}
+
+	// Library shuts down once the last solver is gone
+	#[cfg(feature = "nvflex-sys")]
+	LIBRARY.lock().unwrap().take();
 
-	// All cleanup work will be done here
-	std::mem::drop(flex_state);
 	0
 }
@@ -0,0 +1,122 @@
+// Naming collision channels and putting particle groups and shapes on them.
+use rglua::prelude::*;
+
+use super::{params::type_name, push_string, world::get_state, GenericError};
+use crate::state::{channels, ChannelError, PhaseError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChannelsError {
+	#[error("{0}")]
+	Channel(#[from] ChannelError),
+
+	#[error("Expected a channel name for channel #{0}, got {1}")]
+	Type(usize, &'static str),
+
+	#[error("{0}")]
+	Group(#[from] PhaseError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// Reads an array of channel names at `idx` as a mask, every channel if it's nil.
+pub fn opt_channels(l: LuaState, idx: i32) -> Result<i32, ChannelsError> {
+	if matches!(lua_type(l, idx), TNONE | TNIL) {
+		return Ok(channels::ALL);
+	}
+
+	luaL_checktype(l, idx, TTABLE);
+
+	let count = lua_objlen(l, idx) as usize;
+	let mut names = Vec::with_capacity(count);
+	for i in 1..=count {
+		lua_rawgeti(l, idx, i as i32);
+		if lua_type(l, -1) != TSTRING {
+			return Err(ChannelsError::Type(i, type_name(l, -1)));
+		}
+
+		names.push(rstr!(lua_tostring(l, -1)).to_owned());
+		lua_pop(l, 1);
+	}
+
+	Ok(channels::mask(names.iter().map(String::as_str))?)
+}
+
+/// Pushes the names of the channels in `mask` as an array.
+pub fn push_channels(l: LuaState, mask: i32) {
+	let names = channels::names_of(mask);
+
+	lua_createtable(l, names.len() as i32, 0);
+	for (i, name) in names.iter().enumerate() {
+		push_string(l, name);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+}
+
+fn check_group(l: LuaState, idx: i32) -> Result<i32, PhaseError> {
+	let mut phase = crate::state::Phase::default();
+	phase.set_group(luaL_checkinteger(l, idx) as i64)?;
+	Ok(phase.get_group())
+}
+
+/// flex.registerChannel(name: string) -> index: integer
+/// Gives `name` one of the 8 collision channels, or returns the one it already has.
+#[lua_function]
+pub fn register_channel(l: LuaState) -> Result<i32, ChannelsError> {
+	let name = rstr!(luaL_checkstring(l, 1));
+	let index = channels::register(name)?;
+	lua_pushinteger(l, index as isize);
+
+	Ok(1)
+}
+
+/// flex.getChannels() -> array<string>, ordered by index
+#[lua_function]
+pub fn get_channels(l: LuaState) -> i32 {
+	push_channels(l, channels::ALL);
+	1
+}
+
+/// flex.setGroupChannels(group: integer, channels: array<string>?) -> count: integer
+/// Particles in `group`, now and created later, only collide with shapes sharing one of `channels`, or all shapes if it's nil.
+/// Returns how many particles are in the group.
+#[lua_function]
+pub fn set_group_channels(l: LuaState) -> Result<i32, ChannelsError> {
+	let (state, arg) = get_state(l)?;
+
+	let group = check_group(l, arg)?;
+	let channels = opt_channels(l, arg + 1)?;
+
+	let count = state.particles.set_group_channels(state.backend.as_mut(), group, channels);
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushinteger(l, count as isize);
+	Ok(1)
+}
+
+/// flex.getGroupChannels(group: integer) -> array<string>
+#[lua_function]
+pub fn get_group_channels(l: LuaState) -> Result<i32, ChannelsError> {
+	let (state, arg) = get_state(l)?;
+
+	let group = check_group(l, arg)?;
+	push_channels(l, state.particles.get_group_channels(group));
+
+	Ok(1)
+}
+
+/// flex.setShapeChannels(index: integer, channels: array<string>?) -> found: boolean
+/// `index` is the shape's position in `flex.getBoxes()`. Only particles sharing one of `channels` collide with it, or all if it's nil.
+#[lua_function]
+pub fn set_shape_channels(l: LuaState) -> Result<i32, ChannelsError> {
+	let (state, arg) = get_state(l)?;
+
+	let index = luaL_checkinteger(l, arg);
+	let channels = opt_channels(l, arg + 1)?;
+
+	let found = usize::try_from(index - 1).is_ok_and(|index| state.shapes.set_channels(state.backend.as_mut(), index, channels));
+	state.shapes.flush(state.backend.as_mut());
+
+	lua_pushboolean(l, found as i32);
+	Ok(1)
+}
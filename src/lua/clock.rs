@@ -0,0 +1,104 @@
+// Controlling how fast and how often a world is simulated.
+use rglua::prelude::*;
+
+use super::{world::get_state, GenericError};
+use crate::state::ClockError;
+
+#[derive(Debug, thiserror::Error)]
+pub enum TimeError {
+	#[error("{0}")]
+	Clock(#[from] ClockError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// flex.setTimeStep(dt: number, substeps: integer?, maxSteps: integer?)
+#[lua_function]
+pub fn set_time_step(l: LuaState) -> Result<i32, TimeError> {
+	let (state, arg) = get_state(l)?;
+
+	let step = luaL_checknumber(l, arg) as f32;
+	let substeps = luaL_optinteger(l, arg + 1, state.clock.get_substeps() as isize);
+	let max_steps = luaL_optinteger(l, arg + 2, state.clock.get_max_steps() as isize);
+
+	let substeps = i32::try_from(substeps).map_err(|_| ClockError::Substeps(substeps as i64))?;
+	let max_steps = u32::try_from(max_steps).map_err(|_| ClockError::MaxSteps(max_steps as i64))?;
+	state.clock.set_step(step, substeps, max_steps)?;
+
+	Ok(0)
+}
+
+/// flex.getTimeStep() -> dt: number, substeps: integer, maxSteps: integer
+#[lua_function]
+pub fn get_time_step(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	lua_pushnumber(l, state.clock.get_step() as f64);
+	lua_pushinteger(l, state.clock.get_substeps() as isize);
+	lua_pushinteger(l, state.clock.get_max_steps() as isize);
+
+	Ok(3)
+}
+
+/// flex.setTimeScale(scale: number)
+#[lua_function]
+pub fn set_time_scale(l: LuaState) -> Result<i32, TimeError> {
+	let (state, arg) = get_state(l)?;
+
+	state.clock.set_time_scale(luaL_checknumber(l, arg) as f32)?;
+
+	Ok(0)
+}
+
+/// flex.getTimeScale() -> number
+#[lua_function]
+pub fn get_time_scale(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	lua_pushnumber(l, state.clock.get_time_scale() as f64);
+
+	Ok(1)
+}
+
+/// flex.pause()
+#[lua_function]
+pub fn pause(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	state.clock.set_paused(true);
+
+	Ok(0)
+}
+
+/// flex.resume()
+#[lua_function]
+pub fn resume(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	state.clock.set_paused(false);
+
+	Ok(0)
+}
+
+/// flex.isPaused() -> boolean
+#[lua_function]
+pub fn is_paused(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	lua_pushboolean(l, state.clock.is_paused() as i32);
+
+	Ok(1)
+}
+
+/// flex.step(n: integer?), runs `n` (default 1) steps even while paused, starting on the next tick.
+/// Ticks run at most `maxSteps` of them each, see flex.setTimeStep.
+#[lua_function]
+pub fn step(l: LuaState) -> Result<i32, TimeError> {
+	let (state, arg) = get_state(l)?;
+
+	let n = luaL_optinteger(l, arg, 1);
+	state.clock.queue_steps(u32::try_from(n).map_err(|_| ClockError::Queue(n as i64))?);
+
+	Ok(0)
+}
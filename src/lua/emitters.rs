@@ -0,0 +1,220 @@
+// Emitters spawning particles every step, handed to lua as userdata.
+use rglua::prelude::*;
+
+use super::{
+	opts::{get_field, opt_bool, opt_integer, opt_number, opt_vector, OptionError},
+	params::type_name,
+	phase::{opt_phase, ReadPhaseError},
+	particles::{opt_lifetime, LifetimeError},
+	world::{find_state, get_state, get_world_id},
+	GenericError,
+};
+use crate::{
+	state::{Emitter, EmitterError, EmitterShape, FlexState, DEFAULT_IMASS},
+	types::Vector3,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmittersError {
+	#[error("{0}")]
+	Option(#[from] OptionError),
+
+	#[error("Unknown emitter shape: `{0}`, must be point, disc, cone or box")]
+	Shape(String),
+
+	#[error("Invalid maxTotal: `{0}`, must be zero or greater")]
+	MaxTotal(isize),
+
+	#[error("Emitter has been removed")]
+	Removed,
+
+	#[error("{0}")]
+	Lifetime(#[from] LifetimeError),
+
+	#[error("{0}")]
+	Phase(#[from] ReadPhaseError),
+
+	#[error("{0}")]
+	Emitter(#[from] EmitterError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// What an emitter userdata holds, the world being [None] for the default one.
+#[derive(Clone, Copy)]
+struct EmitterRef {
+	world: Option<u32>,
+	id: u32,
+}
+
+fn check_ref(l: LuaState, idx: i32) -> EmitterRef {
+	unsafe { *(luaL_checkudata(l, idx, cstr!("GFluidEmitter")) as *const EmitterRef) }
+}
+
+/// Gets the emitter the userdata at `idx` refers to.
+fn check_emitter<'flex>(l: LuaState, idx: i32) -> Result<&'flex mut Emitter, EmittersError> {
+	let emitter = check_ref(l, idx);
+	let state: &'flex mut FlexState = find_state(emitter.world)?;
+	state.emitters.get_mut(emitter.id).ok_or(EmittersError::Removed)
+}
+
+/// Reads `shape` along with whichever of `radius`, `angle` (in degrees) and `extents` it uses.
+fn opt_shape(l: LuaState, idx: i32) -> Result<EmitterShape, EmittersError> {
+	let name = match get_field(l, idx, cstr!("shape")) {
+		TSTRING => Ok(rstr!(lua_tostring(l, -1)).to_owned()),
+		TNIL => Ok(String::from("point")),
+		_ => Err(OptionError("shape", "string", type_name(l, -1))),
+	};
+	lua_pop(l, 1);
+
+	match name?.as_str() {
+		"point" => Ok(EmitterShape::Point),
+		"disc" => Ok(EmitterShape::Disc {
+			radius: opt_number(l, idx, "radius", cstr!("radius"), 0.0)?,
+		}),
+		"cone" => Ok(EmitterShape::Cone {
+			angle: opt_number(l, idx, "angle", cstr!("angle"), 0.0)?.to_radians(),
+		}),
+		"box" => Ok(EmitterShape::Box {
+			extents: opt_vector(l, idx, "extents", cstr!("extents"), Some(Vector3::ZERO))?,
+		}),
+		other => Err(EmittersError::Shape(other.to_owned())),
+	}
+}
+
+fn read_emitter(l: LuaState, idx: i32) -> Result<Emitter, EmittersError> {
+	let pos = opt_vector(l, idx, "pos", cstr!("pos"), None)?;
+	let dir = opt_vector(l, idx, "dir", cstr!("dir"), Some(Vector3(0.0, 0.0, -1.0)))?;
+
+	let mut emitter = Emitter::new(pos, dir, opt_shape(l, idx)?)?;
+	emitter.set_rate(opt_number(l, idx, "rate", cstr!("rate"), 0.0)?)?;
+	emitter.set_speed(opt_number(l, idx, "speed", cstr!("speed"), 0.0)?)?;
+	emitter.set_jitter(opt_number(l, idx, "jitter", cstr!("jitter"), 0.0)?)?;
+	emitter.set_imass(opt_number(l, idx, "imass", cstr!("imass"), DEFAULT_IMASS)?)?;
+
+	match get_field(l, idx, cstr!("lifetime")) {
+		TNUMBER | TNIL => emitter.set_lifetime(opt_lifetime(l, -1)?)?,
+		_ => return Err(OptionError("lifetime", "number", type_name(l, -1)).into()),
+	}
+	lua_pop(l, 1);
+
+	emitter.phase = opt_phase(l, idx)?.to_raw();
+
+	let max_total = opt_integer(l, idx, "maxTotal", cstr!("maxTotal"), 0)?;
+	emitter.set_max_total(u32::try_from(max_total).map_err(|_| EmittersError::MaxTotal(max_total))?);
+
+	emitter.enabled = opt_bool(l, idx, "enabled", cstr!("enabled"), emitter.enabled)?;
+
+	Ok(emitter)
+}
+
+/// flex.createEmitter(opts: { pos: Vector, dir: Vector?, shape: ("point" | "disc" | "cone" | "box")?, radius: number?, angle: number?, extents: Vector?,
+/// rate: number?, speed: number?, jitter: number?, phase: Phase?, imass: number?, lifetime: number?, maxTotal: integer?, enabled: boolean? }) -> Emitter
+/// `rate` is in particles per second, `angle` the cone's half angle in degrees and `extents` the box's half size.
+/// Emitted particles are removed after `lifetime` seconds, if given.
+/// Emitters stay around until removed, or until they've emitted `maxTotal` particles if it isn't 0.
+#[lua_function]
+pub fn create_emitter(l: LuaState) -> Result<i32, EmittersError> {
+	let (state, arg) = get_state(l)?;
+	luaL_checktype(l, arg, TTABLE);
+
+	let emitter = read_emitter(l, arg)?;
+	let id = state.emitters.add(emitter);
+
+	let ud = lua_newuserdata(l, std::mem::size_of::<EmitterRef>()) as *mut EmitterRef;
+	unsafe { ud.write(EmitterRef { world: get_world_id(l), id }) };
+
+	luaL_getmetatable(l, cstr!("GFluidEmitter"));
+	lua_setmetatable(l, -2);
+
+	Ok(1)
+}
+
+/// Emitter:setPos(pos: Vector)
+#[lua_function]
+fn set_pos(l: LuaState) -> Result<i32, EmittersError> {
+	let emitter = check_emitter(l, 1)?;
+	let pos = luaL_checkvector(l, 2);
+	emitter.set_pos(Vector3(pos.x, pos.y, pos.z))?;
+
+	Ok(0)
+}
+
+/// Emitter:setDir(dir: Vector)
+#[lua_function]
+fn set_dir(l: LuaState) -> Result<i32, EmittersError> {
+	let emitter = check_emitter(l, 1)?;
+	let dir = luaL_checkvector(l, 2);
+	emitter.set_dir(Vector3(dir.x, dir.y, dir.z))?;
+
+	Ok(0)
+}
+
+/// Emitter:setRate(rate: number), in particles per second
+#[lua_function]
+fn set_rate(l: LuaState) -> Result<i32, EmittersError> {
+	let emitter = check_emitter(l, 1)?;
+	emitter.set_rate(luaL_checknumber(l, 2) as f32)?;
+
+	Ok(0)
+}
+
+/// Emitter:setEnabled(enabled: boolean)
+#[lua_function]
+fn set_enabled(l: LuaState) -> Result<i32, EmittersError> {
+	let emitter = check_emitter(l, 1)?;
+	luaL_checktype(l, 2, TBOOLEAN);
+	emitter.enabled = lua_toboolean(l, 2) != 0;
+
+	Ok(0)
+}
+
+/// Emitter:getEmitted() -> integer
+#[lua_function]
+fn get_emitted(l: LuaState) -> Result<i32, EmittersError> {
+	let emitter = check_emitter(l, 1)?;
+	lua_pushinteger(l, emitter.get_emitted() as isize);
+
+	Ok(1)
+}
+
+/// Emitter:remove() -> removed: boolean
+/// Particles it already emitted are kept.
+#[lua_function]
+fn remove(l: LuaState) -> Result<i32, GenericError> {
+	let emitter = check_ref(l, 1);
+	let removed = find_state(emitter.world)?.emitters.remove(emitter.id);
+	lua_pushboolean(l, removed as i32);
+
+	Ok(1)
+}
+
+/// Emitter:isValid() -> boolean, false once removed or spent
+#[lua_function]
+fn is_valid(l: LuaState) -> i32 {
+	let valid = check_emitter(l, 1).is_ok();
+	lua_pushboolean(l, valid as i32);
+	1
+}
+
+/// Registers the emitter metatable.
+pub fn load(l: LuaState) {
+	let methods = reg! [
+		"setPos" => set_pos,
+		"setDir" => set_dir,
+		"setRate" => set_rate,
+		"setEnabled" => set_enabled,
+		"getEmitted" => get_emitted,
+		"remove" => remove,
+		"isValid" => is_valid
+	];
+
+	luaL_newmetatable(l, cstr!("GFluidEmitter"));
+
+	lua_createtable(l, 0, methods.len() as i32);
+	luaL_register(l, std::ptr::null(), methods.as_ptr());
+	lua_setfield(l, -2, cstr!("__index"));
+
+	lua_pop(l, 1);
+}
@@ -0,0 +1,106 @@
+// Getting FleX errors out to lua.
+use rglua::prelude::*;
+
+use super::push_string;
+use crate::state::errors::{self, FlexError, Policy, ERRORS};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ErrorPolicyError {
+	#[error("Unknown severity: `{0}`, expected error, warning, info or debug")]
+	UnknownSeverity(String),
+
+	#[error("Unknown policy: `{0}`, expected ignore, log or raise")]
+	UnknownPolicy(String),
+}
+
+/// A FleX error whose severity is set to raise.
+#[derive(Debug, thiserror::Error)]
+#[error("FleX {}: {} ({}:{})", errors::severity_name(.0.severity), .0.message, .0.file, .0.line)]
+pub struct RaisedError(FlexError);
+
+/// Pushes a table of { severity, message, file, line }
+fn push_error(l: LuaState, error: &FlexError) {
+	lua_createtable(l, 0, 4);
+
+	push_string(l, errors::severity_name(error.severity));
+	lua_setfield(l, -2, cstr!("severity"));
+
+	push_string(l, &error.message);
+	lua_setfield(l, -2, cstr!("message"));
+
+	push_string(l, &error.file);
+	lua_setfield(l, -2, cstr!("file"));
+
+	lua_pushinteger(l, error.line as isize);
+	lua_setfield(l, -2, cstr!("line"));
+}
+
+/// Runs the `GFluid_Error` hook for every error since the last tick, and applies their policies.
+pub fn report(l: LuaState) -> Result<(), RaisedError> {
+	let (pending, policies): (Vec<FlexError>, Vec<Policy>) = match ERRORS.lock() {
+		Ok(mut queue) => {
+			let pending = queue.take_pending();
+			let policies = pending.iter().map(|e| queue.get_policy(e.severity)).collect();
+			(pending, policies)
+		}
+		Err(_) => return Ok(()),
+	};
+
+	let mut raised = None;
+	for (error, policy) in pending.into_iter().zip(policies) {
+		lua_getglobal(l, cstr!("hook"));
+		lua_getfield(l, -1, cstr!("Run"));
+		lua_remove(l, -2);
+
+		lua_pushstring(l, cstr!("GFluid_Error"));
+		push_error(l, &error);
+		lua_call(l, 2, 0);
+
+		match policy {
+			Policy::Log => {
+				lua_getglobal(l, cstr!("print"));
+				push_string(l, &format!("[gfluid] {}", RaisedError(error)));
+				lua_call(l, 1, 0);
+			}
+			// Only the first is raised, the rest would never be seen anyway.
+			Policy::Raise if raised.is_none() => raised = Some(RaisedError(error)),
+			_ => (),
+		}
+	}
+
+	match raised {
+		Some(error) => Err(error),
+		None => Ok(()),
+	}
+}
+
+/// flex.getErrors() -> array<{ severity: string, message: string, file: string, line: integer }>, dropped: integer
+#[lua_function]
+pub fn get_errors(l: LuaState) -> i32 {
+	let (errors, dropped) = ERRORS.lock().map(|mut q| q.drain()).unwrap_or_default();
+
+	lua_createtable(l, errors.len() as i32, 0);
+	for (i, error) in errors.iter().enumerate() {
+		push_error(l, error);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	lua_pushinteger(l, dropped as isize);
+	2
+}
+
+/// flex.setErrorPolicy(severity: "error" | "warning" | "info" | "debug", policy: "ignore" | "log" | "raise")
+#[lua_function]
+pub fn set_error_policy(l: LuaState) -> Result<i32, ErrorPolicyError> {
+	let severity = rstr!(luaL_checkstring(l, 1));
+	let policy = rstr!(luaL_checkstring(l, 2));
+
+	let severity = errors::severity_from_name(severity).ok_or_else(|| ErrorPolicyError::UnknownSeverity(severity.to_owned()))?;
+	let policy = policy.parse::<Policy>().map_err(|_| ErrorPolicyError::UnknownPolicy(policy.to_owned()))?;
+
+	if let Ok(mut queue) = ERRORS.lock() {
+		queue.set_policy(severity, policy);
+	}
+
+	Ok(0)
+}
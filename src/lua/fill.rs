@@ -0,0 +1,115 @@
+// Filling boxes, spheres and capsules with particles.
+use rglua::prelude::*;
+
+use super::{
+	check_vector, opt_quat,
+	opts::{get_field, opt_number, opt_vector, OptionError},
+	params::type_name,
+	phase::{opt_phase, ReadPhaseError},
+	particles::{opt_lifetime, push_handle, LifetimeError},
+	world::get_state,
+	GenericError,
+};
+use crate::{
+	state::{Capsule, Cube, FillError, FillOptions, FlexState, Shape, Sphere},
+	types::Quat,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FillShapeError {
+	#[error("{0}")]
+	Fill(#[from] FillError),
+
+	#[error("{0}")]
+	Option(#[from] OptionError),
+
+	#[error("{0}")]
+	Lifetime(#[from] LifetimeError),
+
+	#[error("{0}")]
+	Phase(#[from] ReadPhaseError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// Reads `{ velocity: Vector?, jitter: number?, spacing: number?, imass: number?, phase: Phase?, lifetime: number? }` at `idx`, if there's a table there.
+fn read_options(l: LuaState, idx: i32, state: &FlexState) -> Result<FillOptions, FillShapeError> {
+	let mut opts = FillOptions::new(state.get_params());
+
+	if matches!(lua_type(l, idx), TNONE | TNIL) {
+		return Ok(opts);
+	}
+
+	luaL_checktype(l, idx, TTABLE);
+
+	opts.velocity = opt_vector(l, idx, "velocity", cstr!("velocity"), Some(opts.velocity))?;
+	opts.jitter = opt_number(l, idx, "jitter", cstr!("jitter"), opts.jitter)?;
+	opts.spacing = opt_number(l, idx, "spacing", cstr!("spacing"), opts.spacing)?;
+	opts.imass = opt_number(l, idx, "imass", cstr!("imass"), opts.imass)?;
+	opts.phase = opt_phase(l, idx)?.to_raw();
+
+	match get_field(l, idx, cstr!("lifetime")) {
+		TNUMBER | TNIL => opts.lifetime = opt_lifetime(l, -1)?,
+		_ => return Err(OptionError("lifetime", "number", type_name(l, -1)).into()),
+	}
+	lua_pop(l, 1);
+
+	Ok(opts)
+}
+
+/// Fills `shape` and returns the array of handles, the options being at `opts`.
+fn fill(l: LuaState, state: &mut FlexState, shape: Shape, opts: i32) -> Result<i32, FillShapeError> {
+	let opts = read_options(l, opts, state)?;
+	let handles = state.fill(&shape, &opts)?;
+
+	lua_createtable(l, handles.len() as i32, 0);
+	for (i, handle) in handles.into_iter().enumerate() {
+		push_handle(l, handle);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	Ok(1)
+}
+
+/// flex.fillBox(center: Vector, extents: Vector, rot: {x, y, z, w}?, opts: FillOptions?) -> array<id>
+/// Fills the same box as a `Cube` collider with half size `extents`, with particles at the fluid rest distance unless `opts.spacing` is given.
+/// `opts.jitter` moves each particle up to that far off the lattice in a random direction.
+#[lua_function]
+pub fn fill_box(l: LuaState) -> Result<i32, FillShapeError> {
+	let (state, arg) = get_state(l)?;
+
+	let center = check_vector(l, arg);
+	let extents = check_vector(l, arg + 1);
+	let rot = opt_quat(l, arg + 2);
+
+	let shape = Cube::new(center.with_w(0.0), rot, [extents.0, extents.1, extents.2]).into();
+	fill(l, state, shape, arg + 3)
+}
+
+/// flex.fillSphere(center: Vector, radius: number, opts: FillOptions?) -> array<id>
+#[lua_function]
+pub fn fill_sphere(l: LuaState) -> Result<i32, FillShapeError> {
+	let (state, arg) = get_state(l)?;
+
+	let center = check_vector(l, arg);
+	let radius = luaL_checknumber(l, arg + 1) as f32;
+
+	let shape = Sphere::new(center.with_w(0.0), Quat::IDENTITY, radius).into();
+	fill(l, state, shape, arg + 2)
+}
+
+/// flex.fillCapsule(center: Vector, radius: number, halfHeight: number, rot: {x, y, z, w}?, opts: FillOptions?) -> array<id>
+/// Like the `Capsule` collider, its axis runs along x before being rotated.
+#[lua_function]
+pub fn fill_capsule(l: LuaState) -> Result<i32, FillShapeError> {
+	let (state, arg) = get_state(l)?;
+
+	let center = check_vector(l, arg);
+	let radius = luaL_checknumber(l, arg + 1) as f32;
+	let half_height = luaL_checknumber(l, arg + 2) as f32;
+	let rot = opt_quat(l, arg + 3);
+
+	let shape = Capsule::new(center.with_w(0.0), rot, radius, half_height).into();
+	fill(l, state, shape, arg + 4)
+}
@@ -0,0 +1,196 @@
+// Force fields pushing particles around every step, handed to lua as userdata.
+use rglua::prelude::*;
+
+use super::{
+	check_quat, check_vector,
+	opts::{get_field, opt_bool, opt_number, opt_string, opt_vector, OptionError},
+	params::type_name,
+	world::{find_state, get_state, get_world_id},
+	GenericError,
+};
+use crate::{
+	state::{Falloff, FlexState, ForceError, ForceField, ForceKind},
+	types::{Quat, Vector3},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForcesError {
+	#[error("{0}")]
+	Option(#[from] OptionError),
+
+	#[error("Unknown force field type: `{0}`, must be impulse, attractor, vortex or wind")]
+	Kind(String),
+
+	#[error("Unknown falloff: `{0}`, must be constant, linear or quadratic")]
+	Falloff(String),
+
+	#[error("Force field has been removed")]
+	Removed,
+
+	#[error("{0}")]
+	Force(#[from] ForceError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// What a force field userdata holds, the world being [None] for the default one.
+#[derive(Clone, Copy)]
+struct ForceRef {
+	world: Option<u32>,
+	id: u32,
+}
+
+fn check_ref(l: LuaState, idx: i32) -> ForceRef {
+	unsafe { *(luaL_checkudata(l, idx, cstr!("GFluidForceField")) as *const ForceRef) }
+}
+
+/// Gets the force field the userdata at `idx` refers to.
+fn check_field<'flex>(l: LuaState, idx: i32) -> Result<&'flex mut ForceField, ForcesError> {
+	let field = check_ref(l, idx);
+	let state: &'flex mut FlexState = find_state(field.world)?;
+	state.forces.get_mut(field.id).ok_or(ForcesError::Removed)
+}
+
+/// Reads `type` along with whichever of `axis`, `height`, `velocity`, `extents` and `rot` it uses.
+fn opt_kind(l: LuaState, idx: i32) -> Result<ForceKind, ForcesError> {
+	match opt_string(l, idx, "type", cstr!("type"), "attractor")?.as_str() {
+		"impulse" => Ok(ForceKind::Impulse),
+		"attractor" => Ok(ForceKind::Attractor),
+		"vortex" => Ok(ForceKind::Vortex {
+			axis: opt_vector(l, idx, "axis", cstr!("axis"), Some(Vector3(0.0, 0.0, 1.0)))?,
+			height: opt_number(l, idx, "height", cstr!("height"), f32::MAX)?,
+		}),
+		"wind" => {
+			let rot = match get_field(l, idx, cstr!("rot")) {
+				TTABLE => Ok(check_quat(l, lua_gettop(l))),
+				TNIL => Ok(Quat::IDENTITY),
+				_ => Err(OptionError("rot", "table", type_name(l, -1))),
+			};
+			lua_pop(l, 1);
+
+			Ok(ForceKind::Wind {
+				velocity: opt_vector(l, idx, "velocity", cstr!("velocity"), None)?,
+				extents: opt_vector(l, idx, "extents", cstr!("extents"), None)?,
+				rot: rot?,
+			})
+		}
+		other => Err(ForcesError::Kind(other.to_owned())),
+	}
+}
+
+fn read_field(l: LuaState, idx: i32) -> Result<ForceField, ForcesError> {
+	let pos = opt_vector(l, idx, "pos", cstr!("pos"), None)?;
+
+	let mut field = ForceField::new(pos, opt_kind(l, idx)?)?;
+	field.set_radius(opt_number(l, idx, "radius", cstr!("radius"), 0.0)?)?;
+	field.set_strength(opt_number(l, idx, "strength", cstr!("strength"), 0.0)?)?;
+
+	field.set_falloff(match opt_string(l, idx, "falloff", cstr!("falloff"), "linear")?.as_str() {
+		"constant" => Falloff::Constant,
+		"linear" => Falloff::Linear,
+		"quadratic" => Falloff::Quadratic,
+		other => return Err(ForcesError::Falloff(other.to_owned())),
+	});
+
+	field.enabled = opt_bool(l, idx, "enabled", cstr!("enabled"), field.enabled)?;
+
+	Ok(field)
+}
+
+/// flex.createForceField(opts: { type: ("impulse" | "attractor" | "vortex" | "wind")?, pos: Vector, radius: number?, strength: number?,
+/// falloff: ("constant" | "linear" | "quadratic")?, axis: Vector?, height: number?, velocity: Vector?, extents: Vector?, rot: {x, y, z, w}?, enabled: boolean? }) -> ForceField
+/// Impulses add `strength` speed outwards once, on the next step, and are then removed. Attractors accelerate particles towards `pos` by `strength`, repelling if it's negative.
+/// Vortices accelerate particles around `axis` by `strength`, within `height` along it. All three reach `radius` away, fading out by `falloff`.
+/// Wind catches particles in the box centered on `pos` with half size `extents` up to `velocity`, `strength` being how quickly.
+#[lua_function]
+pub fn create_force_field(l: LuaState) -> Result<i32, ForcesError> {
+	let (state, arg) = get_state(l)?;
+	luaL_checktype(l, arg, TTABLE);
+
+	let field = read_field(l, arg)?;
+	let id = state.forces.add(field);
+
+	let ud = lua_newuserdata(l, std::mem::size_of::<ForceRef>()) as *mut ForceRef;
+	unsafe { ud.write(ForceRef { world: get_world_id(l), id }) };
+
+	luaL_getmetatable(l, cstr!("GFluidForceField"));
+	lua_setmetatable(l, -2);
+
+	Ok(1)
+}
+
+/// ForceField:setPos(pos: Vector)
+#[lua_function]
+fn set_pos(l: LuaState) -> Result<i32, ForcesError> {
+	let field = check_field(l, 1)?;
+	field.set_pos(check_vector(l, 2))?;
+
+	Ok(0)
+}
+
+/// ForceField:setStrength(strength: number)
+#[lua_function]
+fn set_strength(l: LuaState) -> Result<i32, ForcesError> {
+	let field = check_field(l, 1)?;
+	field.set_strength(luaL_checknumber(l, 2) as f32)?;
+
+	Ok(0)
+}
+
+/// ForceField:setRadius(radius: number)
+#[lua_function]
+fn set_radius(l: LuaState) -> Result<i32, ForcesError> {
+	let field = check_field(l, 1)?;
+	field.set_radius(luaL_checknumber(l, 2) as f32)?;
+
+	Ok(0)
+}
+
+/// ForceField:setEnabled(enabled: boolean)
+#[lua_function]
+fn set_enabled(l: LuaState) -> Result<i32, ForcesError> {
+	let field = check_field(l, 1)?;
+	luaL_checktype(l, 2, TBOOLEAN);
+	field.enabled = lua_toboolean(l, 2) != 0;
+
+	Ok(0)
+}
+
+/// ForceField:remove() -> removed: boolean
+#[lua_function]
+fn remove(l: LuaState) -> Result<i32, GenericError> {
+	let field = check_ref(l, 1);
+	let removed = find_state(field.world)?.forces.remove(field.id);
+	lua_pushboolean(l, removed as i32);
+
+	Ok(1)
+}
+
+/// ForceField:isValid() -> boolean, false once removed or applied if it's an impulse
+#[lua_function]
+fn is_valid(l: LuaState) -> i32 {
+	let valid = check_field(l, 1).is_ok();
+	lua_pushboolean(l, valid as i32);
+	1
+}
+
+/// Registers the force field metatable.
+pub fn load(l: LuaState) {
+	let methods = reg! [
+		"setPos" => set_pos,
+		"setStrength" => set_strength,
+		"setRadius" => set_radius,
+		"setEnabled" => set_enabled,
+		"remove" => remove,
+		"isValid" => is_valid
+	];
+
+	luaL_newmetatable(l, cstr!("GFluidForceField"));
+
+	lua_createtable(l, 0, methods.len() as i32);
+	luaL_register(l, std::ptr::null(), methods.as_ptr());
+	lua_setfield(l, -2, cstr!("__index"));
+
+	lua_pop(l, 1);
+}
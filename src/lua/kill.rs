@@ -0,0 +1,83 @@
+// Kill volumes removing the particles that enter them.
+use rglua::prelude::*;
+
+use super::{check_quat, check_vector, world::get_state, GenericError};
+use crate::{
+	state::{KillError, KillVolume},
+	types::Vector3,
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KillVolumeError {
+	#[error("{0}")]
+	Kill(#[from] KillError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// flex.addKillVolume(min: Vector, max: Vector) -> id: integer
+/// Particles that end up inside the box are removed at the end of each tick.
+#[lua_function]
+pub fn add_kill_volume(l: LuaState) -> Result<i32, KillVolumeError> {
+	let (state, arg) = get_state(l)?;
+
+	let (a, b) = (check_vector(l, arg), check_vector(l, arg + 1));
+	let min = Vector3(a.0.min(b.0), a.1.min(b.1), a.2.min(b.2));
+	let max = Vector3(a.0.max(b.0), a.1.max(b.1), a.2.max(b.2));
+
+	let id = state.kill.add(KillVolume::from_bounds(min, max)?);
+	lua_pushinteger(l, id as isize);
+
+	Ok(1)
+}
+
+/// flex.addOrientedKillVolume(pos: Vector, extents: Vector, rot: {x, y, z, w}) -> id: integer
+/// Like [add_kill_volume], for a box centered on `pos` with half size `extents` and rotated by `rot`.
+#[lua_function]
+pub fn add_oriented_kill_volume(l: LuaState) -> Result<i32, KillVolumeError> {
+	let (state, arg) = get_state(l)?;
+
+	let pos = check_vector(l, arg);
+	let extents = check_vector(l, arg + 1);
+	let rot = check_quat(l, arg + 2);
+
+	let id = state.kill.add(KillVolume::new(pos, rot, extents)?);
+	lua_pushinteger(l, id as isize);
+
+	Ok(1)
+}
+
+/// flex.removeKillVolume(id: integer) -> removed: boolean
+#[lua_function]
+pub fn remove_kill_volume(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let id = luaL_checkinteger(l, arg);
+	let removed = u32::try_from(id).is_ok_and(|id| state.kill.remove(id));
+	lua_pushboolean(l, removed as i32);
+
+	Ok(1)
+}
+
+/// flex.clearKillVolumes()
+#[lua_function]
+pub fn clear_kill_volumes(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+	state.kill.clear();
+
+	Ok(0)
+}
+
+/// flex.getRemovedCount() -> last: integer, total: integer
+/// How many particles ran out of lifetime or entered a kill volume during the last tick that stepped the simulation, and ever.
+#[lua_function]
+pub fn get_removed_count(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	let (last, total) = state.kill.get_removed();
+	lua_pushinteger(l, last as isize);
+	lua_pushinteger(l, total as isize);
+
+	Ok(2)
+}
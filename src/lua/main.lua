@@ -10,6 +10,7 @@ require("fluid")
 ---@field pos Vector
 ---@field rot table
 ---@field kind integer
+---@field channels string[]
 
 ---@type table<number, Particle>
 local Particles = {}
@@ -2,58 +2,93 @@
 use rglua::{prelude::*, lua};
 use crate::STATE;
 
-use crate::state::{FlexState, Cube};
+use crate::state::{FlexState, Cube, Phase, DEFAULT_IMASS};
 use crate::{
 	config,
 	helper::*,
 	types::{Particle, Quat, Vector3, Vector4},
 };
 
-use nvflex_sys::*;
+use crate::sys::*;
 use std::sync::atomic::Ordering;
 
+pub mod world;
+mod errors;
+mod clock;
+mod params;
+mod presets;
+mod planes;
+mod particles;
+mod opts;
+mod emitters;
+mod fill;
+mod phase;
+mod kill;
+mod channels;
+mod query;
+mod forces;
+use world::get_state;
+
 #[derive(Debug, thiserror::Error)]
 pub enum GenericError {
-	#[error("Couldn't get global FleX state.")]
-	NoState
+	#[error("gfluid is not initialized, see the console for why")]
+	NotInitialized,
+
+	#[error("World has been destroyed")]
+	Destroyed
+}
+
+/// Pushes a rust string, dropping any nul bytes in it.
+pub fn push_string(l: LuaState, s: &str) {
+	let s = std::ffi::CString::new(s.replace('\0', "")).unwrap_or_default();
+	lua_pushstring(l, s.as_ptr());
+}
+
+pub fn check_vector(l: LuaState, idx: i32) -> Vector3 {
+	let v = luaL_checkvector(l, idx);
+	Vector3(v.x, v.y, v.z)
+}
+
+/// Reads a `{x, y, z, w}` quaternion table at `idx`, missing components being 0.
+pub fn check_quat(l: LuaState, idx: i32) -> Quat {
+	luaL_checktype(l, idx, TTABLE);
+
+	let mut quat = [0.0; 4];
+	for (i, x) in quat.iter_mut().enumerate() {
+		lua_rawgeti(l, idx, i as i32 + 1);
+		*x = luaL_optnumber(l, -1, 0.0) as f32;
+		lua_pop(l, 1);
+	}
+
+	Quat(quat[0], quat[1], quat[2], quat[3])
+}
+
+/// Like [check_quat], but no rotation if there's nothing at `idx`.
+pub fn opt_quat(l: LuaState, idx: i32) -> Quat {
+	match lua_type(l, idx) {
+		TNONE | TNIL => Quat::IDENTITY,
+		_ => check_quat(l, idx),
+	}
 }
 
 pub fn get_global_state<'flex>() -> Result<&'flex mut FlexState, GenericError> {
 	let ptr = STATE.load(Ordering::Relaxed);
-	unsafe { ptr.as_mut() }.ok_or(GenericError::NoState)
+	unsafe { ptr.as_mut() }.ok_or(GenericError::NotInitialized)
 }
 
 #[lua_function]
 pub fn get_particles(l: LuaState) -> Result<i32, GenericError> {
-	let state = get_global_state()?;
+	let (state, _) = get_state(l)?;
 
-	if let Some(data) = unsafe { state.particles.get(state.solver) } {
+	if let Some(data) = state.particles.get(state.backend.as_mut()) {
 		lua_createtable(l, data.len() as i32, 0);
 		for (i, particle) in data.iter().enumerate() {
-			lua_createtable(l, 0, 4); // -3 particle = {}
-
-			lua_pushstring(l, cstr!("phase")); // -2
-			lua_pushnumber(l, *particle.phase as f64); // -1
-			lua_rawset(l, -3);
-
-			lua_pushstring(l, cstr!("imass")); // -2
-			lua_pushnumber(l, particle.pdata.3 as f64); // -1
-			lua_rawset(l, -3);
-
-			lua_pushstring(l, cstr!("velocity"));
-			lua_pushvector(l, Vector::new( particle.velocity.0, particle.velocity.1, particle.velocity.2 )); // -2
-			lua_rawset(l, -3); // t.velocity = stack[ #stack - 1 ]
-
-			lua_pushstring(l, cstr!("position"));
-			lua_pushvector(l, Vector::new( particle.pdata.0, particle.pdata.1, particle.pdata.2 )); // -2
-			lua_rawset(l, -3); // t.position = stack[ #stack - 1 ]
-
+			particles::push_particle(l, particle);
 			lua_rawseti(l, -2, i as i32 + 1); // particles[i + 1] = stack[#stack] (aka particle)
 		}
 		return Ok(1);
 	}
 
-	state.particles.unmap();
 	Ok(0)
 }
 
@@ -65,79 +100,130 @@ enum CreateShapeError {
 	#[error("Failed to create: {0}")]
 	Create(#[from] crate::state::CreateError),
 
+	#[error("{0}")]
+	Channels(#[from] channels::ChannelsError),
+
 	#[error("{0}")]
 	Generic(#[from] GenericError)
 }
 
+/// flex.createBox(pos: Vector, extents: Vector, rot: {x, y, z, w}, channels: array<string>?) -> index: integer
+/// Returns the box's index in `flex.getBoxes()`. Only particles sharing one of `channels` collide with it, or all if it's nil.
 #[lua_function]
 fn create_box(l: LuaState) -> Result<i32, CreateShapeError> {
-	let pos = luaL_checkvector(l, 1);
-	let obbs = luaL_checkvector(l, 2);
-	luaL_checktype(l, 3, TTABLE);
+	let (state, arg) = get_state(l)?;
 
-	lua_rawgeti(l, 3, 1);
+	let pos = luaL_checkvector(l, arg);
+	let obbs = luaL_checkvector(l, arg + 1);
+	luaL_checktype(l, arg + 2, TTABLE);
+
+	lua_rawgeti(l, arg + 2, 1);
 	let x = luaL_optnumber(l, -1, 0.0) as f32;
 
-	lua_rawgeti(l, 3, 2);
+	lua_rawgeti(l, arg + 2, 2);
 	let y = luaL_optnumber(l, -1, 0.0) as f32;
 
-	lua_rawgeti(l, 3, 3);
+	lua_rawgeti(l, arg + 2, 3);
 	let z = luaL_optnumber(l, -1, 0.0) as f32;
 
-	lua_rawgeti(l, 3, 4);
+	lua_rawgeti(l, arg + 2, 4);
 	let w = luaL_optnumber(l, -1, 0.0) as f32;
 
-
-	let state = get_global_state()?;
+	let channels = channels::opt_channels(l, arg + 3)?;
 
 	let the_box = Cube::new(Vector4(pos.x, pos.y, pos.z, 0.0), Quat(x, y, z, w), [obbs.x, obbs.y, obbs.z] );
-	state.shapes.register(the_box.into())?;
+	state.shapes.register(state.backend.as_mut(), the_box.into())?;
 
-	Ok(0)
+	let index = state.shapes.get_count();
+	if channels != crate::state::channels::ALL {
+		state.shapes.set_channels(state.backend.as_mut(), index - 1, channels);
+		state.shapes.flush(state.backend.as_mut());
+	}
+
+	lua_pushinteger(l, index as isize);
+	Ok(1)
 }
 
-#[lua_function]
-fn create_particle(l: LuaState) -> Result<i32, GenericError> {
-	let state = get_global_state()?;
+#[derive(Debug, thiserror::Error)]
+enum CreateParticleError {
+	#[error("Failed to create: {0}")]
+	Create(#[from] crate::state::CreateError),
 
-	let pos = luaL_checkvector(l, 1);
-	let velocity = luaL_checkvector(l, 2);
-	let imass = luaL_optnumber(l, 3, 2.0);
+	#[error("{0}")]
+	Lifetime(#[from] particles::LifetimeError),
 
-	let fluid = NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid);
+	#[error("{0}")]
+	Phase(#[from] phase::ReadPhaseError),
 
-	state.particles.create( Vector4(pos.x, pos.y, pos.z, imass as f32), Vector3(velocity.x, velocity.y, velocity.z), fluid, true );
-	state.particles.flush(state.solver);
+	#[error("{0}")]
+	Generic(#[from] GenericError)
+}
 
+#[lua_function]
+fn create_particle(l: LuaState) -> Result<i32, CreateParticleError> {
+	let (state, arg) = get_state(l)?;
+
+	let pos = luaL_checkvector(l, arg);
+	let velocity = luaL_checkvector(l, arg + 1);
+	let imass = luaL_optnumber(l, arg + 2, DEFAULT_IMASS as f64);
+	let lifetime = particles::opt_lifetime(l, arg + 3)?;
+	let phase = match lua_type(l, arg + 4) {
+		TNONE | TNIL => Phase::default(),
+		_ => {
+			luaL_checktype(l, arg + 4, TTABLE);
+			phase::read_phase(l, arg + 4)?
+		}
+	};
+
+	let handle = state.particles.create( state.backend.as_mut(), Vector4(pos.x, pos.y, pos.z, imass as f32), Vector3(velocity.x, velocity.y, velocity.z), phase.to_raw(), true )?;
+	state.particles.set_lifetime(handle, lifetime);
+	state.particles.flush(state.backend.as_mut());
+
+	particles::push_handle(l, handle);
 	Ok(1)
 }
 
 #[lua_function]
 fn flush(l: LuaState) -> Result<i32, GenericError> {
-	let state = get_global_state()?;
+	let (state, _) = get_state(l)?;
 
-	state.particles.flush(state.solver);
+	state.particles.flush(state.backend.as_mut());
 
 	Ok(0)
 }
 
 #[lua_function]
-fn tick(_l: LuaState) -> i32 {
+fn tick(l: LuaState) -> Result<i32, errors::RaisedError> {
 	if let Ok(flex) = get_global_state() {
 		flex.tick();
 	}
 
-	0
+	world::tick_all();
+
+	errors::report(l)?;
+
+	Ok(0)
 }
 
+/// flex.reloadConfig(), rebuilds the default world from the config file.
+#[lua_function]
+fn reload_config(_l: LuaState) -> Result<i32, crate::ReloadError> {
+	crate::reload_config()?;
+	Ok(0)
+}
+
+/// flex.getBoxes() -> array<Shape>
+/// Every shape as `{ kind, pos, rot, channels }`, at the index flex.createBox returned for it.
 #[lua_function]
 fn get_boxes(l: LuaState) -> Result<i32, GenericError> {
-	let state = get_global_state()?;
+	let (state, _) = get_state(l)?;
 
 	let shapes = state.shapes.get_list();
 	lua_createtable(l, shapes.len() as i32, 0);
 
 	for (k, shape) in shapes.iter().enumerate() {
+		lua_createtable(l, 0, 4);
+
 		lua_pushinteger(l, shape.kind() as isize);
 		lua_setfield(l, -2, cstr!("kind"));
 
@@ -160,29 +246,16 @@ fn get_boxes(l: LuaState) -> Result<i32, GenericError> {
 		lua_rawseti(l, -2, 4);
 
 		lua_setfield(l, -2, cstr!("rot"));
+
+		channels::push_channels(l, state.shapes.get_channels(k).unwrap_or(crate::state::channels::ALL));
+		lua_setfield(l, -2, cstr!("channels"));
+
+		lua_rawseti(l, -2, k as i32 + 1);
 	}
 
 	Ok(1)
 }
 
-/*#[lua_function]
-fn particle_factory(l: LuaState) -> i32 {
-	let state = STATE.load(Ordering::Relaxed);
-	if let Some(state) = unsafe { state.as_mut() } {
-		luaL_checktype(l, 1, TFUNCTION);
-
-		state.particles.factory(|factory| {
-			lua_pushcfunction(l, create_particle_fast);
-			lua_call(l, 1, 0);
-		});
-
-		state.particles.flush(state.solver);
-
-		return 1;
-	}
-	0
-}*/
-
 pub fn load(l: LuaState) {
 	let r = reg! [
 		// function getParticles() -> array<Particle>
@@ -193,10 +266,75 @@ pub fn load(l: LuaState) {
 		"createBox" => create_box,
 		// "createShape" => create_shape,
 		"createParticle" => create_particle,
+		"createParticles" => particles::create_particles,
+		"getParticle" => particles::get_particle,
+		"isParticleValid" => particles::is_particle_valid,
+		"removeParticle" => particles::remove_particle,
+		"setParticleActive" => particles::set_particle_active,
+		"removeParticlesInBox" => particles::remove_particles_in_box,
+		"setParticleLifetime" => particles::set_particle_lifetime,
+		"setPositions" => particles::set_positions,
+		"setVelocities" => particles::set_velocities,
+		"fillBox" => fill::fill_box,
+		"fillSphere" => fill::fill_sphere,
+		"fillCapsule" => fill::fill_capsule,
+		"getPhases" => phase::get_phases,
+		"setPhases" => phase::set_phases,
+
+		"flush" => flush,
+
+		"setTimeStep" => clock::set_time_step,
+		"getTimeStep" => clock::get_time_step,
+		"setTimeScale" => clock::set_time_scale,
+		"getTimeScale" => clock::get_time_scale,
+		"pause" => clock::pause,
+		"resume" => clock::resume,
+		"isPaused" => clock::is_paused,
+		"step" => clock::step,
+
+		"getParams" => params::get_params,
+		"setParams" => params::set_params,
+		"applyPreset" => presets::apply_preset,
+
+		"addPlane" => planes::add_plane,
+		"removePlane" => planes::remove_plane,
+		"getPlanes" => planes::get_planes,
+		"clearPlanes" => planes::clear_planes,
+
+		"createEmitter" => emitters::create_emitter,
+		"createForceField" => forces::create_force_field,
+
+		"addKillVolume" => kill::add_kill_volume,
+		"addOrientedKillVolume" => kill::add_oriented_kill_volume,
+		"removeKillVolume" => kill::remove_kill_volume,
+		"clearKillVolumes" => kill::clear_kill_volumes,
+		"getRemovedCount" => kill::get_removed_count,
+
+		"setGroupChannels" => channels::set_group_channels,
+		"getGroupChannels" => channels::get_group_channels,
+		"setShapeChannels" => channels::set_shape_channels,
+
+		"queryRadius" => query::query_radius,
+		"queryBox" => query::query_box,
+		"countInBox" => query::count_in_box,
+		"queryNearest" => query::query_nearest,
+		"traceRay" => query::trace_ray,
+		"sweepSphere" => query::sweep_sphere
+	];
 
-		"flush" => flush
+	// Shared by every world, so these are only on the flex table.
+	let library = reg! [
+		"getErrors" => errors::get_errors,
+		"setErrorPolicy" => errors::set_error_policy,
+		"reloadConfig" => reload_config,
 
-		//"particleFactory" => particle_factory
+		"registerPreset" => presets::register_preset,
+		"getPreset" => presets::get_preset,
+		"getPresets" => presets::get_presets,
+		"blendPresets" => presets::blend_presets,
+
+		"registerChannel" => channels::register_channel,
+		"getChannels" => channels::get_channels
 	];
 
 	lua_getglobal(l, cstr!("hook"));
@@ -213,4 +351,12 @@ pub fn load(l: LuaState) {
 	lua_call(l, 3, 0);
 
 	luaL_register(l, cstr!("flex"), r.as_ptr());
+	luaL_register(l, std::ptr::null(), library.as_ptr());
+
+	// The same functions work on other worlds, as world:getParticles() etc.
+	world::load(l, &r);
+	emitters::load(l);
+	forces::load(l);
+
+	lua_pop(l, 1);
 }
\ No newline at end of file
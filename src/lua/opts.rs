@@ -0,0 +1,75 @@
+// Reading optional fields out of option tables.
+use rglua::prelude::*;
+
+use super::params::type_name;
+use crate::types::Vector3;
+
+#[derive(Debug, thiserror::Error)]
+#[error("Expected {1} for `{0}`, got {2}")]
+pub struct OptionError(pub &'static str, pub &'static str, pub &'static str);
+
+/// Pushes field `field` of the table at `idx`, returning its type.
+pub fn get_field(l: LuaState, idx: i32, field: LuaString) -> i32 {
+	lua_getfield(l, idx, field);
+	lua_type(l, -1)
+}
+
+pub fn opt_number(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: f32) -> Result<f32, OptionError> {
+	let value = match get_field(l, idx, field) {
+		TNUMBER => Ok(lua_tonumber(l, -1) as f32),
+		TNIL => Ok(default),
+		_ => Err(OptionError(key, "number", type_name(l, -1))),
+	};
+
+	lua_pop(l, 1);
+	value
+}
+
+pub fn opt_integer(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: isize) -> Result<isize, OptionError> {
+	let value = match get_field(l, idx, field) {
+		TNUMBER => Ok(lua_tointeger(l, -1)),
+		TNIL => Ok(default),
+		_ => Err(OptionError(key, "integer", type_name(l, -1))),
+	};
+
+	lua_pop(l, 1);
+	value
+}
+
+pub fn opt_bool(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: bool) -> Result<bool, OptionError> {
+	let value = match get_field(l, idx, field) {
+		TBOOLEAN => Ok(lua_toboolean(l, -1) != 0),
+		TNIL => Ok(default),
+		_ => Err(OptionError(key, "boolean", type_name(l, -1))),
+	};
+
+	lua_pop(l, 1);
+	value
+}
+
+/// Reads the string option `key`, or `default` if it's nil.
+pub fn opt_string(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: &str) -> Result<String, OptionError> {
+	let value = match get_field(l, idx, field) {
+		TSTRING => Ok(rstr!(lua_tostring(l, -1)).to_owned()),
+		TNIL => Ok(default.to_owned()),
+		_ => Err(OptionError(key, "string", type_name(l, -1))),
+	};
+
+	lua_pop(l, 1);
+	value
+}
+
+/// Reads a vector field, which is required if there's no `default`.
+pub fn opt_vector(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: Option<Vector3>) -> Result<Vector3, OptionError> {
+	let value = match (get_field(l, idx, field), default) {
+		(TUSERDATA, _) => {
+			let v = luaL_checkvector(l, -1);
+			Ok(Vector3(v.x, v.y, v.z))
+		}
+		(TNIL, Some(default)) => Ok(default),
+		_ => Err(OptionError(key, "Vector", type_name(l, -1))),
+	};
+
+	lua_pop(l, 1);
+	value
+}
@@ -0,0 +1,158 @@
+// Reading and editing a world's solver parameters.
+use rglua::prelude::*;
+
+use super::{push_string, world::get_state, GenericError};
+use crate::state::params::{self, ParamError, ParamValue};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParamsError {
+	#[error("{0}")]
+	Param(#[from] ParamError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// Name of the type of the value at `idx`, for error messages.
+pub fn type_name(l: LuaState, idx: i32) -> &'static str {
+	match lua_type(l, idx) {
+		TNIL => "nil",
+		TBOOLEAN => "boolean",
+		TNUMBER => "number",
+		TSTRING => "string",
+		TTABLE => "table",
+		TFUNCTION => "function",
+		TUSERDATA => "userdata",
+		_ => "unknown",
+	}
+}
+
+pub fn push_value(l: LuaState, value: ParamValue) {
+	match value {
+		ParamValue::Number(x) => lua_pushnumber(l, x as f64),
+		ParamValue::Integer(x) => lua_pushinteger(l, x as isize),
+		ParamValue::Vector([x, y, z]) => lua_pushvector(l, Vector::new(x, y, z)),
+	}
+}
+
+/// Reads the value at `idx` as the same kind of value as `current`.
+pub fn read_value(l: LuaState, idx: i32, name: &'static str, current: ParamValue) -> Result<ParamValue, ParamError> {
+	let wrong_type = || ParamError::Type(name, current.kind(), type_name(l, idx));
+
+	match current {
+		ParamValue::Number(_) if lua_type(l, idx) == TNUMBER => Ok(ParamValue::Number(lua_tonumber(l, idx) as f32)),
+		ParamValue::Integer(_) if lua_type(l, idx) == TNUMBER => {
+			let x = lua_tonumber(l, idx);
+			if x.fract() != 0.0 || x < i32::MIN as f64 || x > i32::MAX as f64 {
+				return Err(wrong_type());
+			}
+
+			Ok(ParamValue::Integer(x as i32))
+		}
+		ParamValue::Vector(_) if lua_type(l, idx) == TUSERDATA => {
+			let v = luaL_checkvector(l, idx);
+			Ok(ParamValue::Vector([v.x, v.y, v.z]))
+		}
+		_ => Err(wrong_type()),
+	}
+}
+
+/// Reads an array of `{a, b, c, d}` planes at `idx`.
+fn read_planes(l: LuaState, idx: i32) -> Result<Vec<[f32; 4]>, ParamError> {
+	if lua_type(l, idx) != TTABLE {
+		return Err(ParamError::Type("planes", "table", type_name(l, idx)));
+	}
+
+	let mut planes = vec![];
+	for i in 1..=lua_objlen(l, idx) as i32 {
+		lua_rawgeti(l, idx, i);
+		if lua_type(l, -1) != TTABLE {
+			return Err(ParamError::Type("planes", "table", type_name(l, -1)));
+		}
+
+		let mut plane = [0.0; 4];
+		for (j, x) in plane.iter_mut().enumerate() {
+			lua_rawgeti(l, -1, j as i32 + 1);
+			if lua_type(l, -1) != TNUMBER {
+				return Err(ParamError::Type("planes", "number", type_name(l, -1)));
+			}
+			*x = lua_tonumber(l, -1) as f32;
+			lua_pop(l, 1);
+		}
+
+		lua_pop(l, 1);
+		planes.push(plane);
+	}
+
+	Ok(planes)
+}
+
+/// flex.getParams() -> table, with every parameter by name
+#[lua_function]
+pub fn get_params(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+	let current = state.get_params();
+
+	lua_createtable(l, 0, params::NAMES.len() as i32 + 2);
+	for name in params::NAMES {
+		if let Some(value) = params::get(current, name) {
+			push_string(l, name);
+			push_value(l, value);
+			lua_rawset(l, -3);
+		}
+	}
+
+	lua_pushinteger(l, current.numPlanes as isize);
+	lua_setfield(l, -2, cstr!("numPlanes"));
+
+	lua_createtable(l, current.numPlanes, 0);
+	for (i, plane) in current.planes.iter().take(current.numPlanes as usize).enumerate() {
+		lua_createtable(l, 4, 0);
+		for (j, x) in plane.iter().enumerate() {
+			lua_pushnumber(l, *x as f64);
+			lua_rawseti(l, -2, j as i32 + 1);
+		}
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+	lua_setfield(l, -2, cstr!("planes"));
+
+	Ok(1)
+}
+
+/// flex.setParams(params: table)
+/// Only the parameters in the table are changed. Nothing is applied if any of them are invalid.
+#[lua_function]
+pub fn set_params(l: LuaState) -> Result<i32, ParamsError> {
+	let (state, arg) = get_state(l)?;
+	luaL_checktype(l, arg, TTABLE);
+
+	let mut new = *state.get_params();
+
+	lua_pushnil(l);
+	while lua_next(l, arg) != 0 {
+		// key at -2, value at -1
+		let value = lua_gettop(l);
+
+		if lua_type(l, -2) != TSTRING {
+			return Err(ParamError::Unknown(format!("<{}>", type_name(l, -2))).into());
+		}
+
+		let key = rstr!(lua_tostring(l, -2));
+		match params::NAMES.iter().find(|name| **name == key) {
+			Some(name) => {
+				let current = params::get(&new, name).ok_or_else(|| ParamError::Unknown(key.to_owned()))?;
+				params::set(&mut new, name, read_value(l, value, name, current)?)?;
+			}
+			None if key == "planes" => params::set_planes(&mut new, &read_planes(l, value)?)?,
+			None if key == "numPlanes" => return Err(ParamError::ReadOnly("numPlanes").into()),
+			None => return Err(ParamError::Unknown(key.to_owned()).into()),
+		}
+
+		lua_pop(l, 1);
+	}
+
+	params::validate(&new)?;
+	state.set_params(new);
+
+	Ok(0)
+}
@@ -0,0 +1,419 @@
+// Looking up and removing particles by handle, changing them in bulk, and turning their simulation on and off.
+use rglua::prelude::*;
+
+use super::{
+	params::type_name,
+	phase::{opt_phase, read_phase, ReadPhaseError},
+	world::get_state,
+	GenericError,
+};
+use crate::{
+	state::{CreateError, Phase, DEFAULT_IMASS},
+	types::{Particle, ParticleHandle, Vector3, Vector4},
+};
+
+#[derive(Debug, thiserror::Error)]
+#[error("Invalid lifetime: `{0}`, must be greater than zero")]
+pub struct LifetimeError(f64);
+
+#[derive(Debug, thiserror::Error)]
+pub enum CreateParticlesError {
+	#[error("Expected {1} for `{0}` of particle #{2}, got {3}")]
+	Type(&'static str, &'static str, usize, &'static str),
+
+	#[error("Expected a table for `positions`, got {0}")]
+	Positions(&'static str),
+
+	#[error("Invalid particle count: `{0}`, must be zero or greater")]
+	Count(isize),
+
+	#[error("Error in particle generator: {0}")]
+	Generator(String),
+
+	#[error("{0}")]
+	Lifetime(#[from] LifetimeError),
+
+	#[error("{0}")]
+	Phase(#[from] ReadPhaseError),
+
+	#[error("Failed to create: {0}")]
+	Create(#[from] CreateError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+pub fn push_handle(l: LuaState, handle: ParticleHandle) {
+	lua_pushinteger(l, handle.to_bits() as isize);
+}
+
+/// Reads a particle handle, giving [None] for numbers that can't be one.
+fn check_handle(l: LuaState, idx: i32) -> Option<ParticleHandle> {
+	to_handle(luaL_checknumber(l, idx))
+}
+
+fn to_handle(bits: f64) -> Option<ParticleHandle> {
+	if bits < 0.0 || bits.fract() != 0.0 || bits > u64::MAX as f64 {
+		return None;
+	}
+
+	ParticleHandle::from_bits(bits as u64)
+}
+
+/// Reads an array of particle handles at `idx`, giving [None] for anything that can't be one.
+pub fn check_handles(l: LuaState, idx: i32) -> Vec<Option<ParticleHandle>> {
+	luaL_checktype(l, idx, TTABLE);
+
+	let count = lua_objlen(l, idx) as usize;
+	let mut handles = Vec::with_capacity(count);
+	for i in 1..=count {
+		lua_rawgeti(l, idx, i as i32);
+		handles.push(match lua_type(l, -1) {
+			TNUMBER => to_handle(lua_tonumber(l, -1)),
+			_ => None,
+		});
+		lua_pop(l, 1);
+	}
+
+	handles
+}
+
+/// Pushes a particle as `{ id, active, phase, imass, velocity, position }`, `id` being its handle.
+pub fn push_particle(l: LuaState, particle: &Particle) {
+	lua_createtable(l, 0, 6);
+
+	push_handle(l, particle.handle);
+	lua_setfield(l, -2, cstr!("id"));
+
+	lua_pushboolean(l, particle.active as i32);
+	lua_setfield(l, -2, cstr!("active"));
+
+	lua_pushnumber(l, *particle.phase as f64);
+	lua_setfield(l, -2, cstr!("phase"));
+
+	lua_pushnumber(l, particle.pdata.3 as f64);
+	lua_setfield(l, -2, cstr!("imass"));
+
+	lua_pushvector(l, Vector::new(particle.velocity.0, particle.velocity.1, particle.velocity.2));
+	lua_setfield(l, -2, cstr!("velocity"));
+
+	lua_pushvector(l, Vector::new(particle.pdata.0, particle.pdata.1, particle.pdata.2));
+	lua_setfield(l, -2, cstr!("position"));
+}
+
+/// Reads the vector at `idx`, or `default` if it's nil.
+fn read_vector(l: LuaState, idx: i32, name: &'static str, i: usize, default: Option<Vector3>) -> Result<Vector3, CreateParticlesError> {
+	match (lua_type(l, idx), default) {
+		(TUSERDATA, _) => {
+			let v = luaL_checkvector(l, idx);
+			Ok(Vector3(v.x, v.y, v.z))
+		}
+		(TNIL, Some(default)) => Ok(default),
+		_ => Err(CreateParticlesError::Type(name, "Vector", i, type_name(l, idx))),
+	}
+}
+
+/// Reads the array of vectors at `idx`, `name` being what they're for in errors.
+fn read_vectors(l: LuaState, idx: i32, name: &'static str) -> Result<Vec<Vector3>, CreateParticlesError> {
+	luaL_checktype(l, idx, TTABLE);
+
+	let count = lua_objlen(l, idx) as usize;
+	let mut list = Vec::with_capacity(count);
+	for i in 1..=count {
+		lua_rawgeti(l, idx, i as i32);
+		let v = read_vector(l, -1, name, i, None);
+		lua_pop(l, 1);
+
+		list.push(v?);
+	}
+
+	Ok(list)
+}
+
+fn read_imass(l: LuaState, idx: i32, i: usize) -> Result<f32, CreateParticlesError> {
+	match lua_type(l, idx) {
+		TNUMBER => Ok(lua_tonumber(l, idx) as f32),
+		TNIL => Ok(DEFAULT_IMASS),
+		_ => Err(CreateParticlesError::Type("imass", "number", i, type_name(l, idx))),
+	}
+}
+
+/// Reads the optional lifetime in seconds at `idx`, [None] meaning the particle lives until removed.
+pub fn opt_lifetime(l: LuaState, idx: i32) -> Result<Option<f32>, LifetimeError> {
+	if matches!(lua_type(l, idx), TNONE | TNIL) {
+		return Ok(None);
+	}
+
+	let lifetime = luaL_checknumber(l, idx);
+	if lifetime <= 0.0 || !lifetime.is_finite() {
+		return Err(LifetimeError(lifetime));
+	}
+
+	Ok(Some(lifetime as f32))
+}
+
+fn read_lifetime(l: LuaState, idx: i32, i: usize) -> Result<Option<f32>, CreateParticlesError> {
+	match lua_type(l, idx) {
+		TNUMBER | TNIL => Ok(opt_lifetime(l, idx)?),
+		_ => Err(CreateParticlesError::Type("lifetime", "number", i, type_name(l, idx))),
+	}
+}
+
+/// Pushes element `i` of the table at `idx`, or the value itself if it isn't a table so it applies to every particle.
+fn push_element(l: LuaState, idx: i32, i: usize) {
+	if lua_type(l, idx) == TTABLE {
+		lua_rawgeti(l, idx, i as i32);
+	} else {
+		lua_pushvalue(l, idx);
+	}
+}
+
+/// Particle to create, with its lifetime.
+type Entry = (Vector4, Vector3, Option<f32>);
+
+/// Reads `{ positions = array<Vector>, velocities = Vector | array<Vector>?, imass = number | array<number>?, lifetime = number | array<number>? }` at `idx`.
+fn read_list(l: LuaState, idx: i32) -> Result<Vec<Entry>, CreateParticlesError> {
+	lua_getfield(l, idx, cstr!("positions"));
+	let positions = lua_gettop(l);
+	if lua_type(l, positions) != TTABLE {
+		return Err(CreateParticlesError::Positions(type_name(l, positions)));
+	}
+
+	lua_getfield(l, idx, cstr!("velocities"));
+	let velocities = lua_gettop(l);
+	lua_getfield(l, idx, cstr!("imass"));
+	let imass = lua_gettop(l);
+	lua_getfield(l, idx, cstr!("lifetime"));
+	let lifetime = lua_gettop(l);
+
+	let count = lua_objlen(l, positions) as usize;
+	let mut list = Vec::with_capacity(count);
+
+	for i in 1..=count {
+		lua_rawgeti(l, positions, i as i32);
+		let pos = read_vector(l, -1, "positions", i, None)?;
+
+		push_element(l, velocities, i);
+		let vel = read_vector(l, -1, "velocities", i, Some(Vector3::ZERO))?;
+
+		push_element(l, imass, i);
+		let w = read_imass(l, -1, i)?;
+
+		push_element(l, lifetime, i);
+		let life = read_lifetime(l, -1, i)?;
+
+		lua_pop(l, 4);
+		list.push((pos.with_w(w), vel, life));
+	}
+
+	lua_pop(l, 4);
+	Ok(list)
+}
+
+/// Calls the generator at `idx` `count` times, each call giving `pos: Vector, vel: Vector?, imass: number?, lifetime: number?`.
+fn read_generator(l: LuaState, idx: i32, count: usize) -> Result<Vec<Entry>, CreateParticlesError> {
+	let mut list = Vec::with_capacity(count);
+
+	for i in 1..=count {
+		lua_pushvalue(l, idx);
+		lua_pushinteger(l, i as isize);
+
+		if lua_pcall(l, 1, 4, 0) != 0 {
+			let why = match lua_type(l, -1) {
+				TSTRING => rstr!(lua_tostring(l, -1)).to_owned(),
+				_ => type_name(l, -1).to_owned(),
+			};
+			lua_pop(l, 1);
+
+			return Err(CreateParticlesError::Generator(why));
+		}
+
+		let pos = read_vector(l, -4, "pos", i, None)?;
+		let vel = read_vector(l, -3, "vel", i, Some(Vector3::ZERO))?;
+		let w = read_imass(l, -2, i)?;
+		let life = read_lifetime(l, -1, i)?;
+
+		lua_pop(l, 4);
+		list.push((pos.with_w(w), vel, life));
+	}
+
+	Ok(list)
+}
+
+/// flex.createParticles(list: { positions: array<Vector>, velocities: Vector | array<Vector>?, imass: number | array<number>?, lifetime: number | array<number>?, phase: Phase? }) -> array<id>
+/// flex.createParticles(generator: function(i) -> pos: Vector, vel: Vector?, imass: number?, lifetime: number?, count: integer, phase: Phase?) -> array<id>
+/// Everything is read before any particle is created, so a bad entry or generator error creates none of them.
+#[lua_function]
+pub fn create_particles(l: LuaState) -> Result<i32, CreateParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let (list, phase) = match lua_type(l, arg) {
+		TFUNCTION => {
+			let count = luaL_checkinteger(l, arg + 1);
+			let count = usize::try_from(count).map_err(|_| CreateParticlesError::Count(count))?;
+
+			// Checked before calling the generator at all, so a huge count can't run it forever or fail to allocate.
+			let ceiling = state.particles.get_ceiling();
+			if count > ceiling.saturating_sub(state.particles.get_count()) {
+				return Err(CreateError::Max("particles", ceiling).into());
+			}
+
+			let phase = match lua_type(l, arg + 2) {
+				TNONE | TNIL => Phase::default(),
+				_ => {
+					luaL_checktype(l, arg + 2, TTABLE);
+					read_phase(l, arg + 2)?
+				}
+			};
+
+			(read_generator(l, arg, count)?, phase)
+		}
+		_ => {
+			luaL_checktype(l, arg, TTABLE);
+			(read_list(l, arg)?, opt_phase(l, arg)?)
+		}
+	};
+
+	// The generator could have destroyed the world, so it's only looked up once it's done.
+	let (state, _) = get_state(l)?;
+
+	let phase = phase.to_raw();
+
+	state.particles.reserve_more(state.backend.as_mut(), list.len())?;
+
+	let mut handles = Vec::with_capacity(list.len());
+	let created = state.particles.factory(state.backend.as_mut(), |factory| {
+		for (pos, vel, lifetime) in list {
+			let handle = factory.create(pos, vel, phase, true)?;
+			factory.set_lifetime(handle, lifetime);
+			handles.push(handle);
+		}
+
+		Ok(())
+	});
+
+	state.particles.flush(state.backend.as_mut());
+	created?;
+
+	lua_createtable(l, handles.len() as i32, 0);
+	for (i, handle) in handles.into_iter().enumerate() {
+		push_handle(l, handle);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	Ok(1)
+}
+
+/// flex.getParticle(id: integer) -> Particle?, nil once the particle is removed
+#[lua_function]
+pub fn get_particle(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let particle = check_handle(l, arg).and_then(|handle| state.particles.get_particle(state.backend.as_mut(), handle));
+	match particle {
+		Some(particle) => push_particle(l, &particle),
+		None => lua_pushnil(l),
+	}
+
+	Ok(1)
+}
+
+/// flex.isParticleValid(id: integer) -> boolean
+#[lua_function]
+pub fn is_particle_valid(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let valid = check_handle(l, arg).is_some_and(|handle| state.particles.is_valid(handle));
+	lua_pushboolean(l, valid as i32);
+
+	Ok(1)
+}
+
+/// flex.removeParticle(id: integer) -> removed: boolean
+#[lua_function]
+pub fn remove_particle(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let removed = check_handle(l, arg).is_some_and(|handle| state.particles.remove(handle));
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushboolean(l, removed as i32);
+	Ok(1)
+}
+
+/// flex.setParticleActive(id: integer, active: boolean) -> found: boolean
+/// Inactive particles stay around, but aren't simulated until they're made active again.
+#[lua_function]
+pub fn set_particle_active(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	luaL_checktype(l, arg + 1, TBOOLEAN);
+	let active = lua_toboolean(l, arg + 1) != 0;
+
+	let found = check_handle(l, arg).is_some_and(|handle| state.particles.set_active(handle, active));
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushboolean(l, found as i32);
+	Ok(1)
+}
+
+/// flex.setParticleLifetime(id: integer, lifetime: number?) -> found: boolean
+/// The particle is removed after `lifetime` more seconds of simulation, or never if it's nil.
+#[lua_function]
+pub fn set_particle_lifetime(l: LuaState) -> Result<i32, CreateParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let lifetime = opt_lifetime(l, arg + 1)?;
+	let found = check_handle(l, arg).is_some_and(|handle| state.particles.set_lifetime(handle, lifetime));
+
+	lua_pushboolean(l, found as i32);
+	Ok(1)
+}
+
+/// flex.removeParticlesInBox(min: Vector, max: Vector) -> removed: integer
+#[lua_function]
+pub fn remove_particles_in_box(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let min = luaL_checkvector(l, arg);
+	let max = luaL_checkvector(l, arg + 1);
+
+	let removed = state.particles.remove_in_box(
+		state.backend.as_mut(),
+		Vector3(min.x.min(max.x), min.y.min(max.y), min.z.min(max.z)),
+		Vector3(min.x.max(max.x), min.y.max(max.y), min.z.max(max.z)),
+	);
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushinteger(l, removed as isize);
+	Ok(1)
+}
+
+/// flex.setPositions(startId: integer, positions: array<Vector>) -> count: integer
+/// Moves the particle `startId` and the ones after it in flex.getParticles() order, one per position. Returns how many were moved.
+/// Only the part of the buffer holding them is uploaded to the solver.
+#[lua_function]
+pub fn set_positions(l: LuaState) -> Result<i32, CreateParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let positions = read_vectors(l, arg + 1, "positions")?;
+	let count = check_handle(l, arg).map_or(0, |handle| state.particles.set_positions(state.backend.as_mut(), handle, &positions));
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushinteger(l, count as isize);
+	Ok(1)
+}
+
+/// flex.setVelocities(startId: integer, velocities: array<Vector>) -> count: integer
+/// Like flex.setPositions, for velocities.
+#[lua_function]
+pub fn set_velocities(l: LuaState) -> Result<i32, CreateParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let velocities = read_vectors(l, arg + 1, "velocities")?;
+	let count = check_handle(l, arg).map_or(0, |handle| state.particles.set_velocities(state.backend.as_mut(), handle, &velocities));
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushinteger(l, count as isize);
+	Ok(1)
+}
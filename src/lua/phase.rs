@@ -0,0 +1,142 @@
+// Reading and changing which group particles are in and how they collide.
+use rglua::prelude::*;
+
+use super::{
+	opts::{get_field, opt_bool, opt_integer, OptionError},
+	params::type_name,
+	particles::check_handles,
+	world::get_state,
+	GenericError,
+};
+use crate::state::{Phase, PhaseError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum ReadPhaseError {
+	#[error("{0}")]
+	Option(#[from] OptionError),
+
+	#[error("{0}")]
+	Phase(#[from] PhaseError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PhasesError {
+	#[error("{0}")]
+	Phase(#[from] ReadPhaseError),
+
+	#[error("Expected a phase for particle #{0}, got {1}")]
+	Missing(usize, &'static str),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// Reads a `{ group: integer?, fluid: boolean?, selfCollide: boolean?, selfCollideFilter: boolean? }` table at `idx`.
+/// Anything left out is the same as for [Phase::default].
+pub fn read_phase(l: LuaState, idx: i32) -> Result<Phase, ReadPhaseError> {
+	let mut phase = Phase::default();
+
+	phase.set_group(opt_integer(l, idx, "group", cstr!("group"), 0)? as i64)?;
+	phase.fluid = opt_bool(l, idx, "fluid", cstr!("fluid"), phase.fluid)?;
+	phase.self_collide = opt_bool(l, idx, "selfCollide", cstr!("selfCollide"), phase.self_collide)?;
+	phase.self_collide_filter = opt_bool(l, idx, "selfCollideFilter", cstr!("selfCollideFilter"), phase.self_collide_filter)?;
+
+	Ok(phase)
+}
+
+/// Reads the `phase` field of the options table at `idx`, see [read_phase].
+pub fn opt_phase(l: LuaState, idx: i32) -> Result<Phase, ReadPhaseError> {
+	let phase = match get_field(l, idx, cstr!("phase")) {
+		TTABLE => read_phase(l, lua_gettop(l)),
+		TNIL => Ok(Phase::default()),
+		_ => Err(OptionError("phase", "table", type_name(l, -1)).into()),
+	};
+
+	lua_pop(l, 1);
+	phase
+}
+
+pub fn push_phase(l: LuaState, phase: Phase) {
+	lua_createtable(l, 0, 4);
+
+	lua_pushinteger(l, phase.get_group() as isize);
+	lua_setfield(l, -2, cstr!("group"));
+
+	lua_pushboolean(l, phase.fluid as i32);
+	lua_setfield(l, -2, cstr!("fluid"));
+
+	lua_pushboolean(l, phase.self_collide as i32);
+	lua_setfield(l, -2, cstr!("selfCollide"));
+
+	lua_pushboolean(l, phase.self_collide_filter as i32);
+	lua_setfield(l, -2, cstr!("selfCollideFilter"));
+}
+
+/// flex.getPhases(ids: array<id>) -> array<Phase | false>, false for particles that were removed
+#[lua_function]
+pub fn get_phases(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let ids = check_handles(l, arg);
+	let handles: Vec<_> = ids.iter().flatten().copied().collect();
+	let mut phases = state.particles.get_phases(state.backend.as_mut(), &handles).into_iter();
+
+	// Ids that aren't handles at all are as good as stale, and have no phase read for them
+	lua_createtable(l, ids.len() as i32, 0);
+	for (i, id) in ids.iter().enumerate() {
+		match id.and_then(|_| phases.next().flatten()) {
+			Some(raw) => push_phase(l, Phase::from_raw(raw)),
+			None => lua_pushboolean(l, 0),
+		}
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	Ok(1)
+}
+
+/// flex.setPhases(ids: array<id>, phase: Phase | array<Phase>) -> found: integer
+/// Gives every particle the same phase, or each the phase at the same index. Returns how many particles weren't removed.
+#[lua_function]
+pub fn set_phases(l: LuaState) -> Result<i32, PhasesError> {
+	let (state, arg) = get_state(l)?;
+
+	let handles = check_handles(l, arg);
+	luaL_checktype(l, arg + 1, TTABLE);
+
+	// An array of phases starts with a table, a single phase doesn't
+	lua_rawgeti(l, arg + 1, 1);
+	let each = lua_type(l, -1) == TTABLE;
+	lua_pop(l, 1);
+
+	let single = match each {
+		true => None,
+		false => Some(read_phase(l, arg + 1)?.to_raw()),
+	};
+
+	let mut phases = Vec::with_capacity(handles.len());
+	for (i, handle) in handles.into_iter().enumerate() {
+		let phase = match single {
+			Some(phase) => phase,
+			None => {
+				lua_rawgeti(l, arg + 1, i as i32 + 1);
+				if lua_type(l, -1) != TTABLE {
+					return Err(PhasesError::Missing(i + 1, type_name(l, -1)));
+				}
+
+				let phase = read_phase(l, lua_gettop(l))?.to_raw();
+				lua_pop(l, 1);
+				phase
+			}
+		};
+
+		if let Some(handle) = handle {
+			phases.push((handle, phase));
+		}
+	}
+
+	let found = state.particles.set_phases(state.backend.as_mut(), &phases);
+	state.particles.flush(state.backend.as_mut());
+
+	lua_pushinteger(l, found as isize);
+	Ok(1)
+}
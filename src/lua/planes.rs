@@ -0,0 +1,74 @@
+// Adding and removing a world's collision planes.
+use rglua::prelude::*;
+
+use super::{world::get_state, GenericError};
+use crate::{state::PlaneError, types::Vector3};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlanesError {
+	#[error("{0}")]
+	Plane(#[from] PlaneError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+/// flex.addPlane(normal: Vector, distance: number) -> id: integer
+/// Particles are kept on the side the normal points to, `distance` being how far the plane is from the origin along it.
+#[lua_function]
+pub fn add_plane(l: LuaState) -> Result<i32, PlanesError> {
+	let (state, arg) = get_state(l)?;
+
+	let normal = luaL_checkvector(l, arg);
+	let distance = luaL_checknumber(l, arg + 1) as f32;
+
+	let id = state.add_plane(Vector3(normal.x, normal.y, normal.z), distance)?;
+	lua_pushinteger(l, id as isize);
+
+	Ok(1)
+}
+
+/// flex.removePlane(id: integer) -> removed: boolean
+#[lua_function]
+pub fn remove_plane(l: LuaState) -> Result<i32, GenericError> {
+	let (state, arg) = get_state(l)?;
+
+	let id = luaL_checkinteger(l, arg);
+	let removed = u32::try_from(id).is_ok_and(|id| state.remove_plane(id));
+	lua_pushboolean(l, removed as i32);
+
+	Ok(1)
+}
+
+/// flex.getPlanes() -> array<{ id: integer, normal: Vector, distance: number }>
+#[lua_function]
+pub fn get_planes(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+
+	lua_createtable(l, 0, 0);
+	for (i, (id, [a, b, c, d])) in state.get_planes().enumerate() {
+		lua_createtable(l, 0, 3);
+
+		lua_pushinteger(l, id as isize);
+		lua_setfield(l, -2, cstr!("id"));
+
+		lua_pushvector(l, Vector::new(a, b, c));
+		lua_setfield(l, -2, cstr!("normal"));
+
+		lua_pushnumber(l, -d as f64);
+		lua_setfield(l, -2, cstr!("distance"));
+
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	Ok(1)
+}
+
+/// flex.clearPlanes()
+#[lua_function]
+pub fn clear_planes(l: LuaState) -> Result<i32, GenericError> {
+	let (state, _) = get_state(l)?;
+	state.clear_planes();
+
+	Ok(0)
+}
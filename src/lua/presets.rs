@@ -0,0 +1,141 @@
+// Applying, registering and blending parameter presets.
+use rglua::prelude::*;
+
+use super::{
+	params::{push_value, read_value, type_name},
+	push_string,
+	world::get_state,
+	GenericError,
+};
+use crate::{
+	config,
+	state::params::{self, ParamError},
+	state::presets::{self, Preset, PresetError},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PresetsError {
+	#[error("{0}")]
+	Preset(#[from] PresetError),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+impl From<ParamError> for PresetsError {
+	fn from(e: ParamError) -> Self {
+		Self::Preset(e.into())
+	}
+}
+
+/// Reads a table of parameter values at `idx`, like the one given to `flex.setParams`.
+fn read_preset(l: LuaState, idx: i32) -> Result<Preset, ParamError> {
+	let mut preset = Preset::default();
+
+	lua_pushnil(l);
+	while lua_next(l, idx) != 0 {
+		let value = lua_gettop(l);
+
+		if lua_type(l, -2) != TSTRING {
+			return Err(ParamError::Unknown(format!("<{}>", type_name(l, -2))));
+		}
+
+		let key = rstr!(lua_tostring(l, -2));
+		let name = params::NAMES.iter().find(|name| **name == key).ok_or_else(|| ParamError::Unknown(key.to_owned()))?;
+		let default = params::get(&config::PARAMS, name).ok_or_else(|| ParamError::Unknown(key.to_owned()))?;
+
+		preset.set(name, read_value(l, value, name, default)?)?;
+
+		lua_pop(l, 1);
+	}
+
+	Ok(preset)
+}
+
+/// Reads a preset at `idx`, either by name or as a table of values.
+fn check_preset(l: LuaState, idx: i32) -> Result<Preset, PresetError> {
+	match lua_type(l, idx) {
+		TSTRING => presets::get(rstr!(lua_tostring(l, idx))),
+		TTABLE => Ok(read_preset(l, idx)?),
+		_ => Err(ParamError::Type("preset", "string or table", type_name(l, idx)).into()),
+	}
+}
+
+fn push_preset(l: LuaState, preset: &Preset) {
+	lua_createtable(l, 0, 0);
+	for (name, value) in preset.values() {
+		push_string(l, name);
+		push_value(l, value);
+		lua_rawset(l, -3);
+	}
+}
+
+/// flex.applyPreset(preset: string | table, overrides: table?)
+#[lua_function]
+pub fn apply_preset(l: LuaState) -> Result<i32, PresetsError> {
+	let (state, arg) = get_state(l)?;
+
+	let mut preset = check_preset(l, arg)?;
+	if lua_type(l, arg + 1) == TTABLE {
+		preset = preset.with(&read_preset(l, arg + 1)?);
+	}
+
+	let mut new = *state.get_params();
+	preset.apply(&mut new)?;
+	state.set_params(new);
+
+	Ok(0)
+}
+
+/// flex.registerPreset(name: string, values: table, base: string?)
+/// If given a base, the preset starts out as a copy of it.
+#[lua_function]
+pub fn register_preset(l: LuaState) -> Result<i32, PresetsError> {
+	let name = rstr!(luaL_checkstring(l, 1));
+	luaL_checktype(l, 2, TTABLE);
+
+	let values = read_preset(l, 2)?;
+	let preset = match lua_type(l, 3) {
+		TSTRING => presets::get(rstr!(lua_tostring(l, 3)))?.with(&values),
+		_ => values,
+	};
+
+	presets::register(name, preset);
+
+	Ok(0)
+}
+
+/// flex.getPreset(name: string) -> table
+#[lua_function]
+pub fn get_preset(l: LuaState) -> Result<i32, PresetsError> {
+	let preset = presets::get(rstr!(luaL_checkstring(l, 1)))?;
+	push_preset(l, &preset);
+
+	Ok(1)
+}
+
+/// flex.getPresets() -> array<string>
+#[lua_function]
+pub fn get_presets(l: LuaState) -> i32 {
+	let names: Vec<String> = presets::registry().keys().cloned().collect();
+
+	lua_createtable(l, names.len() as i32, 0);
+	for (i, name) in names.iter().enumerate() {
+		push_string(l, name);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	1
+}
+
+/// flex.blendPresets(a: string | table, b: string | table, t: number) -> table
+#[lua_function]
+pub fn blend_presets(l: LuaState) -> Result<i32, PresetsError> {
+	let a = check_preset(l, 1)?;
+	let b = check_preset(l, 2)?;
+	let t = luaL_checknumber(l, 3) as f32;
+
+	push_preset(l, &a.blend(&b, t)?);
+
+	Ok(1)
+}
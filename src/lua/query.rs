@@ -0,0 +1,173 @@
+// Finding particles in an area of the world or along a line through it.
+use rglua::prelude::*;
+
+use super::{check_vector, opt_quat, particles::push_handle, world::get_state, GenericError};
+use crate::{
+	state::{QueryError, QueryHit, QueryStats, TraceHit},
+	types::{Quat, Vector3},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryParticlesError {
+	#[error("{0}")]
+	Query(#[from] QueryError),
+
+	#[error("Invalid k: `{0}`, must be zero or greater")]
+	Count(isize),
+
+	#[error("{0}")]
+	Generic(#[from] GenericError),
+}
+
+fn push_vector(l: LuaState, v: Vector3) {
+	lua_pushvector(l, Vector::new(v.0, v.1, v.2));
+}
+
+/// Pushes the ids of `hits` as an array, then a `{ count: integer, centroid: Vector, velocity: Vector, volume: number }` table totalling them.
+fn push_hits(l: LuaState, hits: &[QueryHit], particle_volume: f32) {
+	lua_createtable(l, hits.len() as i32, 0);
+	for (i, hit) in hits.iter().enumerate() {
+		push_handle(l, hit.handle);
+		lua_rawseti(l, -2, i as i32 + 1);
+	}
+
+	let stats = QueryStats::new(hits, particle_volume);
+	lua_createtable(l, 0, 4);
+
+	lua_pushinteger(l, stats.count as isize);
+	lua_setfield(l, -2, cstr!("count"));
+
+	push_vector(l, stats.centroid);
+	lua_setfield(l, -2, cstr!("centroid"));
+
+	push_vector(l, stats.velocity);
+	lua_setfield(l, -2, cstr!("velocity"));
+
+	lua_pushnumber(l, stats.volume as f64);
+	lua_setfield(l, -2, cstr!("volume"));
+}
+
+/// Pushes a `{ id: id, pos: Vector, particlePos: Vector, distance: number, normal: Vector }` table, or nil if nothing was hit.
+fn push_trace(l: LuaState, hit: Option<TraceHit>) {
+	let Some(hit) = hit else {
+		lua_pushnil(l);
+		return;
+	};
+
+	lua_createtable(l, 0, 5);
+
+	push_handle(l, hit.particle.handle);
+	lua_setfield(l, -2, cstr!("id"));
+
+	push_vector(l, hit.pos);
+	lua_setfield(l, -2, cstr!("pos"));
+
+	push_vector(l, hit.particle.pos);
+	lua_setfield(l, -2, cstr!("particlePos"));
+
+	lua_pushnumber(l, hit.distance as f64);
+	lua_setfield(l, -2, cstr!("distance"));
+
+	push_vector(l, hit.normal);
+	lua_setfield(l, -2, cstr!("normal"));
+}
+
+/// Reads `min: Vector, max: Vector, rot: {x, y, z, w}?` starting at `idx`, as the center, rotation and half size of the box.
+fn check_box(l: LuaState, idx: i32) -> (Vector3, Quat, Vector3) {
+	let (a, b) = (check_vector(l, idx), check_vector(l, idx + 1));
+	let rot = opt_quat(l, idx + 2);
+
+	let min = Vector3(a.0.min(b.0), a.1.min(b.1), a.2.min(b.2));
+	let max = Vector3(a.0.max(b.0), a.1.max(b.1), a.2.max(b.2));
+
+	((min + max) * 0.5, rot, (max - min) * 0.5)
+}
+
+/// flex.queryRadius(center: Vector, radius: number) -> ids: array<id>, stats: QueryStats
+/// Active particles within `radius` of `center`, as of the latest step.
+#[lua_function]
+pub fn query_radius(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let center = check_vector(l, arg);
+	let radius = luaL_checknumber(l, arg + 1) as f32;
+
+	let hits = state.query_radius(center, radius)?;
+	push_hits(l, &hits, state.get_particle_volume());
+
+	Ok(2)
+}
+
+/// flex.queryBox(min: Vector, max: Vector, rot: {x, y, z, w}?) -> ids: array<id>, stats: QueryStats
+/// Active particles in the box from `min` to `max`, rotated about its center by `rot` if given.
+#[lua_function]
+pub fn query_box(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let (pos, rot, extents) = check_box(l, arg);
+
+	let hits = state.query_box(pos, rot, extents)?;
+	push_hits(l, &hits, state.get_particle_volume());
+
+	Ok(2)
+}
+
+/// flex.countInBox(min: Vector, max: Vector, rot: {x, y, z, w}?) -> count: integer
+/// Like flex.queryBox, without building the list of ids.
+#[lua_function]
+pub fn count_in_box(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let (pos, rot, extents) = check_box(l, arg);
+
+	let count = state.count_in_box(pos, rot, extents)?;
+	lua_pushinteger(l, count as isize);
+
+	Ok(1)
+}
+
+/// flex.queryNearest(point: Vector, k: integer, maxDistance: number?) -> ids: array<id>, stats: QueryStats
+/// Up to `k` active particles closest to `point`, nearest first.
+#[lua_function]
+pub fn query_nearest(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let point = check_vector(l, arg);
+	let k = luaL_checkinteger(l, arg + 1);
+	let k = usize::try_from(k).map_err(|_| QueryParticlesError::Count(k))?;
+	let max_distance = luaL_optnumber(l, arg + 2, f64::INFINITY) as f32;
+
+	let hits = state.query_nearest(point, k, max_distance)?;
+	push_hits(l, &hits, state.get_particle_volume());
+
+	Ok(2)
+}
+
+/// flex.traceRay(start: Vector, dir: Vector, maxDist: number) -> TraceResult?
+/// First active particle along the ray, as of the latest step. `pos` is where the ray hit it, 0 distance if `start` is inside it.
+#[lua_function]
+pub fn trace_ray(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let start = check_vector(l, arg);
+	let dir = check_vector(l, arg + 1);
+	let max_distance = luaL_checknumber(l, arg + 2) as f32;
+
+	push_trace(l, state.trace_ray(start, dir, max_distance)?);
+	Ok(1)
+}
+
+/// flex.sweepSphere(start: Vector, dir: Vector, maxDist: number, radius: number) -> TraceResult?
+/// Like flex.traceRay for a sphere moving along the ray, `pos` being the center of the sphere when it touched the particle.
+#[lua_function]
+pub fn sweep_sphere(l: LuaState) -> Result<i32, QueryParticlesError> {
+	let (state, arg) = get_state(l)?;
+
+	let start = check_vector(l, arg);
+	let dir = check_vector(l, arg + 1);
+	let max_distance = luaL_checknumber(l, arg + 2) as f32;
+	let radius = luaL_checknumber(l, arg + 3) as f32;
+
+	push_trace(l, state.sweep_sphere(start, dir, max_distance, radius)?);
+	Ok(1)
+}
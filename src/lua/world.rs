@@ -0,0 +1,240 @@
+// Worlds besides the default one, exposed to lua as userdata.
+use rglua::prelude::*;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+
+use super::{get_global_state, GenericError};
+use crate::{config::{self, ConfigError}, state::{FlexState, InitError}};
+
+thread_local! {
+	/// Every world created through `flex.createWorld`, by id.
+	static WORLDS: RefCell<BTreeMap<u32, Box<FlexState>>> = const { RefCell::new(BTreeMap::new()) };
+	static NEXT_ID: Cell<u32> = const { Cell::new(0) };
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum WorldError {
+	#[error("Invalid {0}: `{1}`, must be greater than zero")]
+	InvalidCapacity(&'static str, isize),
+
+	#[error("{0}")]
+	Capacity(#[from] ConfigError),
+
+	#[error("Failed to create world: {0}")]
+	Init(#[from] InitError),
+}
+
+/// Returns whether the value at `idx` is a world userdata.
+fn is_world(l: LuaState, idx: i32) -> bool {
+	if lua_type(l, idx) != TUSERDATA || lua_getmetatable(l, idx) == 0 {
+		return false;
+	}
+
+	luaL_getmetatable(l, cstr!("GFluidWorld"));
+	let equal = lua_rawequal(l, -1, -2) != 0;
+	lua_pop(l, 2);
+
+	equal
+}
+
+fn to_id(l: LuaState, idx: i32) -> u32 {
+	unsafe { *(lua_touserdata(l, idx) as *const u32) }
+}
+
+fn check_id(l: LuaState, idx: i32) -> u32 {
+	unsafe { *(luaL_checkudata(l, idx, cstr!("GFluidWorld")) as *const u32) }
+}
+
+fn get_world<'flex>(id: u32) -> Option<&'flex mut FlexState> {
+	// Worlds are boxed, so they stay put until removed from the map.
+	WORLDS.with(|worlds| {
+		worlds.borrow_mut().get_mut(&id).map(|world| unsafe { &mut *(world.as_mut() as *mut FlexState) })
+	})
+}
+
+/// Gets the world a function was called on, along with the index of its first argument.
+/// Called as a method (`world:fn(...)`) this is the world itself, otherwise the default world.
+pub fn get_state<'flex>(l: LuaState) -> Result<(&'flex mut FlexState, i32), GenericError> {
+	if is_world(l, 1) {
+		let world = get_world(to_id(l, 1)).ok_or(GenericError::Destroyed)?;
+		return Ok((world, 2));
+	}
+
+	Ok((get_global_state()?, 1))
+}
+
+/// Id of the world a function was called on, [None] for the default world. See [get_state].
+pub fn get_world_id(l: LuaState) -> Option<u32> {
+	is_world(l, 1).then(|| to_id(l, 1))
+}
+
+/// Gets a world by the id from [get_world_id].
+pub fn find_state<'flex>(id: Option<u32>) -> Result<&'flex mut FlexState, GenericError> {
+	match id {
+		Some(id) => get_world(id).ok_or(GenericError::Destroyed),
+		None => get_global_state(),
+	}
+}
+
+/// Adds a world, returning the id it's given. Ids aren't reused, so ones of destroyed worlds stay invalid.
+fn insert_world(world: FlexState) -> u32 {
+	let id = NEXT_ID.with(|next| {
+		let id = next.get();
+		next.set(id.wrapping_add(1));
+		id
+	});
+
+	WORLDS.with(|worlds| worlds.borrow_mut().insert(id, Box::new(world)));
+	id
+}
+
+/// Destroys a world, returning false if it already was.
+fn remove_world(id: u32) -> bool {
+	WORLDS.with(|worlds| worlds.borrow_mut().remove(&id)).is_some()
+}
+
+fn world_exists(id: u32) -> bool {
+	WORLDS.with(|worlds| worlds.borrow().contains_key(&id))
+}
+
+/// Ticks every created world.
+pub fn tick_all() {
+	let ids: Vec<u32> = WORLDS.with(|worlds| worlds.borrow().keys().copied().collect());
+	for id in ids {
+		if let Some(world) = get_world(id) {
+			world.tick();
+		}
+	}
+}
+
+/// Destroys every created world, to be called before the library shuts down.
+pub fn destroy_all() {
+	let worlds = WORLDS.with(|worlds| std::mem::take(&mut *worlds.borrow_mut()));
+	std::mem::drop(worlds);
+}
+
+fn opt_capacity(l: LuaState, idx: i32, key: &'static str, field: LuaString, default: usize) -> Result<usize, WorldError> {
+	if lua_type(l, idx) != TTABLE {
+		return Ok(default);
+	}
+
+	lua_getfield(l, idx, field);
+	let value = luaL_optinteger(l, -1, default as isize);
+	lua_pop(l, 1);
+
+	if value <= 0 {
+		return Err(WorldError::InvalidCapacity(key, value));
+	}
+
+	Ok(value as usize)
+}
+
+#[lua_function]
+fn create_world(l: LuaState) -> Result<i32, WorldError> {
+	let mut config = config::current();
+	config.max_particles = opt_capacity(l, 1, "maxParticles", cstr!("maxParticles"), config.max_particles)?;
+	config.max_shapes = opt_capacity(l, 1, "maxShapes", cstr!("maxShapes"), config.max_shapes)?;
+	config.max_triangles = opt_capacity(l, 1, "maxTriangles", cstr!("maxTriangles"), config.max_triangles as usize)? as i32;
+	config.check_capacities()?;
+
+	let threaded = lua_type(l, 1) == TTABLE && {
+		lua_getfield(l, 1, cstr!("threaded"));
+		let threaded = lua_toboolean(l, -1) != 0;
+		lua_pop(l, 1);
+		threaded
+	};
+
+	let world = FlexState::try_new(crate::create_backend(threaded)?, &config)?;
+	let id = insert_world(world);
+
+	let ud = lua_newuserdata(l, std::mem::size_of::<u32>()) as *mut u32;
+	unsafe { ud.write(id) };
+
+	luaL_getmetatable(l, cstr!("GFluidWorld"));
+	lua_setmetatable(l, -2);
+
+	Ok(1)
+}
+
+#[lua_function]
+fn destroy(l: LuaState) -> i32 {
+	remove_world(check_id(l, 1));
+	0
+}
+
+#[lua_function]
+fn is_valid(l: LuaState) -> i32 {
+	lua_pushboolean(l, world_exists(check_id(l, 1)) as i32);
+	1
+}
+
+/// Registers `flex.createWorld` into the table on top of the stack, and the world metatable.
+/// `methods` are shared with the `flex` table, see [get_state].
+pub fn load(l: LuaState, methods: &[LuaReg]) {
+	lua_pushcfunction(l, create_world);
+	lua_setfield(l, -2, cstr!("createWorld"));
+
+	luaL_newmetatable(l, cstr!("GFluidWorld"));
+
+	lua_createtable(l, 0, methods.len() as i32 + 2);
+	luaL_register(l, std::ptr::null(), methods.as_ptr());
+
+	lua_pushcfunction(l, destroy);
+	lua_setfield(l, -2, cstr!("destroy"));
+
+	lua_pushcfunction(l, is_valid);
+	lua_setfield(l, -2, cstr!("isValid"));
+
+	lua_setfield(l, -2, cstr!("__index"));
+
+	// Worlds nothing refers to anymore can't be used again
+	lua_pushcfunction(l, destroy);
+	lua_setfield(l, -2, cstr!("__gc"));
+
+	lua_pop(l, 1);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, config::Config, types::{Vector3, Vector4}};
+
+	fn new_world() -> FlexState {
+		FlexState::try_new(Box::new(CpuBackend::new()), &Config::default()).unwrap()
+	}
+
+	#[test]
+	fn destroyed_worlds_stay_invalid() {
+		let (a, b) = (insert_world(new_world()), insert_world(new_world()));
+		assert_ne!(a, b);
+		assert!(world_exists(a) && world_exists(b));
+
+		assert!(remove_world(a));
+		assert!(!remove_world(a));
+		assert!(!world_exists(a));
+		assert!(matches!(find_state(Some(a)), Err(GenericError::Destroyed)));
+
+		// The id of a destroyed world isn't handed out again
+		let c = insert_world(new_world());
+		assert_ne!(c, a);
+		assert!(find_state(Some(b)).is_ok());
+
+		destroy_all();
+		assert!(!world_exists(b) && !world_exists(c));
+	}
+
+	#[test]
+	fn worlds_are_independent() {
+		let (a, b) = (insert_world(new_world()), insert_world(new_world()));
+
+		let world = get_world(a).unwrap();
+		world.particles.create(world.backend.as_mut(), Vector4(0.0, 0.0, 0.0, 1.0), Vector3::ZERO, 0, true).unwrap();
+		world.flush();
+
+		tick_all();
+		assert_eq!(get_world(a).unwrap().particles.get_count(), 1);
+		assert_eq!(get_world(b).unwrap().particles.get_count(), 0);
+
+		destroy_all();
+	}
+}
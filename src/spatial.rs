@@ -0,0 +1,181 @@
+// Spatial hashing over points (particle positions), for finding neighbors without comparing every pair.
+use crate::types::Vector3;
+
+/// Uniform grid hashed into a fixed size table.
+/// Each entry keeps the cell it's in, so cells sharing a table slot are told apart and queries are still exact.
+#[derive(Debug, Default)]
+pub struct SpatialHash {
+	spacing: f32,
+
+	/// `cell_start[h] .. cell_start[h + 1]` is the range of `entries` in table slot `h`.
+	cell_start: Vec<u32>,
+	entries: Vec<u32>,
+	/// Cell of the point in the same place in `entries`
+	entry_cells: Vec<[i32; 3]>,
+}
+
+impl SpatialHash {
+	pub fn new(spacing: f32) -> Self {
+		Self {
+			spacing,
+			cell_start: vec![],
+			entries: vec![],
+			entry_cells: vec![],
+		}
+	}
+
+	pub fn get_spacing(&self) -> f32 {
+		self.spacing
+	}
+
+	#[inline]
+	fn cell(&self, pos: Vector3) -> [i32; 3] {
+		[
+			(pos.0 / self.spacing).floor() as i32,
+			(pos.1 / self.spacing).floor() as i32,
+			(pos.2 / self.spacing).floor() as i32,
+		]
+	}
+
+	#[inline]
+	fn hash(&self, cell: [i32; 3]) -> usize {
+		let h = (cell[0].wrapping_mul(92837111)) ^ (cell[1].wrapping_mul(689287499)) ^ (cell[2].wrapping_mul(283923481));
+		h.unsigned_abs() as usize % (self.cell_start.len() - 1)
+	}
+
+	/// Rebuilds the table from `points`, with a cell size of `spacing`.
+	/// Queries then report indices into `points`.
+	pub fn build(&mut self, spacing: f32, points: &[Vector3]) {
+		self.spacing = spacing;
+
+		let table_size = (points.len() * 2).max(1);
+		self.cell_start.clear();
+		self.cell_start.resize(table_size + 1, 0);
+		self.entries.clear();
+		self.entries.resize(points.len(), 0);
+		self.entry_cells.clear();
+		self.entry_cells.resize(points.len(), [0; 3]);
+
+		// Counting sort points into table slots
+		for &point in points {
+			let h = self.hash(self.cell(point));
+			self.cell_start[h] += 1;
+		}
+
+		let mut start = 0;
+		for count in self.cell_start.iter_mut() {
+			start += *count;
+			*count = start;
+		}
+
+		for (i, &point) in points.iter().enumerate() {
+			let cell = self.cell(point);
+			let h = self.hash(cell);
+			self.cell_start[h] -= 1;
+			self.entries[self.cell_start[h] as usize] = i as u32;
+			self.entry_cells[self.cell_start[h] as usize] = cell;
+		}
+	}
+
+	/// Calls `f` with the index of every point within `radius` of `center`.
+	/// `points` must be the same slice the table was built from.
+	pub fn query<F: FnMut(usize)>(&self, points: &[Vector3], center: Vector3, radius: f32, mut f: F) {
+		self.query_aabb(center - Vector3(radius, radius, radius), center + Vector3(radius, radius, radius), |i| {
+			if (points[i] - center).length_sqr() <= radius * radius {
+				f(i)
+			}
+		});
+	}
+
+	/// Calls `f` with every point in the cells overlapping `min..max`.
+	/// This is conservative, points near the edges may lie outside of the box.
+	pub fn query_aabb<F: FnMut(usize)>(&self, min: Vector3, max: Vector3, mut f: F) {
+		if self.entries.is_empty() {
+			return;
+		}
+
+		let (lo, hi) = (self.cell(min), self.cell(max));
+		let table_size = self.cell_start.len() - 1;
+
+		// Huge queries have more cells than there are points, so going through the points is faster.
+		let cells = (0..3)
+			.map(|a| (hi[a] as i64 - lo[a] as i64 + 1).max(0) as u64)
+			.fold(1u64, u64::saturating_mul);
+
+		if cells >= table_size as u64 {
+			for (&i, cell) in self.entries.iter().zip(&self.entry_cells) {
+				if (0..3).all(|a| (lo[a]..=hi[a]).contains(&cell[a])) {
+					f(i as usize);
+				}
+			}
+
+			return;
+		}
+
+		for x in lo[0]..=hi[0] {
+			for y in lo[1]..=hi[1] {
+				for z in lo[2]..=hi[2] {
+					let cell = [x, y, z];
+					let h = self.hash(cell);
+
+					// Other cells hashed to the same slot are skipped, they're either outside of the box or visited on their own.
+					for j in self.cell_start[h] as usize..self.cell_start[h + 1] as usize {
+						if self.entry_cells[j] == cell {
+							f(self.entries[j] as usize);
+						}
+					}
+				}
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Deterministic points spread over a box `size` wide, bunched up in one corner so some cells are crowded.
+	fn points(count: usize, size: f32) -> Vec<Vector3> {
+		let mut seed = 12345u32;
+		let mut next = || {
+			seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+			(seed >> 8) as f32 / (1 << 24) as f32
+		};
+
+		(0..count)
+			.map(|i| {
+				let scale = if i % 4 == 0 { size * 0.1 } else { size };
+				Vector3(next() * scale, next() * scale, next() * scale - size / 2.0)
+			})
+			.collect()
+	}
+
+	fn brute_force(points: &[Vector3], center: Vector3, radius: f32) -> Vec<usize> {
+		(0..points.len()).filter(|&i| (points[i] - center).length_sqr() <= radius * radius).collect()
+	}
+
+	#[test]
+	fn query_matches_brute_force() {
+		let points = points(500, 20.0);
+		let mut grid = SpatialHash::default();
+		grid.build(1.5, &points);
+
+		// Small radii go through cells, huge ones through every point
+		for radius in [0.0, 0.5, 1.5, 4.0, 100.0] {
+			for &center in points.iter().step_by(7).chain(&[Vector3(-50.0, 0.0, 0.0), Vector3(5.0, 5.0, 0.0)]) {
+				let mut found = vec![];
+				grid.query(&points, center, radius, |i| found.push(i));
+				found.sort_unstable();
+
+				assert_eq!(found, brute_force(&points, center, radius), "radius {} around {:?}", radius, center);
+			}
+		}
+	}
+
+	#[test]
+	fn empty_table_finds_nothing() {
+		let mut grid = SpatialHash::default();
+		grid.build(1.0, &[]);
+		grid.query(&[], Vector3::ZERO, 10.0, |_| panic!("Found a point in an empty table"));
+	}
+}
@@ -0,0 +1,81 @@
+// Growing the buffers substates keep on the backend.
+use crate::backend::{Buffer, SolverBackend};
+
+/// Capacity to grow to so there's room for `needed` elements, doubling so growing one at a time stays cheap.
+/// Returns [None] if `needed` is past the `ceiling`.
+pub fn grow_capacity(capacity: usize, needed: usize, ceiling: usize) -> Option<usize> {
+	if needed > ceiling {
+		return None;
+	}
+
+	Some(needed.max(capacity.saturating_mul(2)).min(ceiling))
+}
+
+/// Moves each `(buffer, stride)` over to a new buffer of `capacity` elements, copying the first `count` and freeing the old one.
+/// Either every buffer is moved or, if an allocation fails, none of them are.
+pub fn realloc(backend: &mut dyn SolverBackend, buffers: &mut [(&mut Buffer, usize)], count: usize, capacity: usize) -> bool {
+	let mut new = Vec::with_capacity(buffers.len());
+	for (_, stride) in buffers.iter() {
+		match backend.alloc(capacity, *stride) {
+			Some(buffer) => new.push(buffer),
+			None => {
+				for buffer in new {
+					backend.free(buffer);
+				}
+				return false;
+			}
+		}
+	}
+
+	for ((old, stride), new) in buffers.iter_mut().zip(new) {
+		let src = backend.map(**old) as *const u8;
+		let dst = backend.map(new) as *mut u8;
+		unsafe { std::ptr::copy_nonoverlapping(src, dst, count * *stride) };
+		backend.unmap(new);
+		backend.unmap(**old);
+
+		backend.free(**old);
+		**old = new;
+	}
+
+	true
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::cpu::CpuBackend;
+
+	#[test]
+	fn capacity_doubles_up_to_the_ceiling() {
+		assert_eq!(grow_capacity(4, 5, 100), Some(8));
+		assert_eq!(grow_capacity(4, 20, 100), Some(20));
+		assert_eq!(grow_capacity(64, 65, 100), Some(100));
+		assert_eq!(grow_capacity(0, 1, 100), Some(1));
+		assert_eq!(grow_capacity(4, 101, 100), None);
+		assert_eq!(grow_capacity(usize::MAX, usize::MAX, usize::MAX), Some(usize::MAX));
+	}
+
+	#[test]
+	fn realloc_keeps_the_first_elements() {
+		let mut backend = CpuBackend::new();
+		let (mut a, mut b) = (backend.alloc(2, 4).unwrap(), backend.alloc(2, 8).unwrap());
+
+		unsafe {
+			(backend.map(a) as *mut [i32; 2]).write([1, 2]);
+			(backend.map(b) as *mut [f64; 2]).write([3.0, 4.0]);
+		}
+
+		let (old_a, old_b) = (a, b);
+		assert!(realloc(&mut backend, &mut [(&mut a, 4), (&mut b, 8)], 1, 4));
+		assert!(a != old_a && b != old_b);
+
+		let (a, b) = unsafe {
+			(*(backend.map(a) as *const [i32; 4]), *(backend.map(b) as *const [f64; 4]))
+		};
+
+		// Only `count` elements are copied, the rest start out zeroed
+		assert_eq!(a, [1, 0, 0, 0]);
+		assert_eq!(b, [3.0, 0.0, 0.0, 0.0]);
+	}
+}
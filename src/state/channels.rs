@@ -0,0 +1,171 @@
+// Named collision channels, deciding which particles collide with which shapes.
+// A particle only collides with a shape if they share a channel.
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use crate::sys::eNvFlexPhaseShapeChannelMask;
+
+/// How many channels FleX has room for in phases and shape flags
+pub const MAX_CHANNELS: usize = 8;
+
+/// Every channel, what particles and shapes are on unless told otherwise
+pub const ALL: i32 = eNvFlexPhaseShapeChannelMask;
+
+/// Bit of the first channel in phases and shape flags
+const SHIFT: u32 = eNvFlexPhaseShapeChannelMask.trailing_zeros();
+
+#[derive(Debug, thiserror::Error)]
+pub enum ChannelError {
+	#[error("Unknown channel: `{0}`, see flex.registerChannel")]
+	Unknown(String),
+
+	#[error("Reached the maximum of {} channels", MAX_CHANNELS)]
+	Max,
+
+	#[error("Invalid channel name, must not be empty")]
+	Name,
+}
+
+/// Channel names, by index. Shared between worlds so the same name means the same channel everywhere.
+fn registry() -> MutexGuard<'static, Vec<String>> {
+	static CHANNELS: OnceLock<Mutex<Vec<String>>> = OnceLock::new();
+
+	CHANNELS
+		.get_or_init(|| Mutex::new(vec![]))
+		.lock()
+		.unwrap_or_else(|e| e.into_inner())
+}
+
+/// Gives `name` a channel if it doesn't have one yet, returning its index.
+pub fn register(name: &str) -> Result<usize, ChannelError> {
+	if name.is_empty() {
+		return Err(ChannelError::Name);
+	}
+
+	let mut channels = registry();
+	if let Some(index) = channels.iter().position(|n| n == name) {
+		return Ok(index);
+	}
+
+	if channels.len() >= MAX_CHANNELS {
+		return Err(ChannelError::Max);
+	}
+
+	channels.push(name.to_owned());
+	Ok(channels.len() - 1)
+}
+
+pub fn names() -> Vec<String> {
+	registry().clone()
+}
+
+/// Mask of the channels with the given names, in the bits phases and shape flags keep them in.
+pub fn mask<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<i32, ChannelError> {
+	let channels = registry();
+
+	names.into_iter().try_fold(0, |mask, name| {
+		let index = channels.iter().position(|n| n == name).ok_or_else(|| ChannelError::Unknown(name.to_owned()))?;
+		Ok(mask | 1 << (SHIFT + index as u32))
+	})
+}
+
+/// Names of the registered channels in `mask`.
+pub fn names_of(mask: i32) -> Vec<String> {
+	registry()
+		.iter()
+		.enumerate()
+		.filter(|(index, _)| mask & 1 << (SHIFT + *index as u32) != 0)
+		.map(|(_, name)| name.clone())
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{
+		backend::cpu::CpuBackend,
+		config::Config,
+		helper::NvFlexMakePhase,
+		state::{Cube, FlexState},
+		sys::eNvFlexPhaseFluid,
+		types::{Quat, Vector3, Vector4},
+	};
+
+	/// Bit of the channel at `index`, without registering a name for it
+	fn channel(index: u32) -> i32 {
+		1 << (SHIFT + index)
+	}
+
+	// The registry is shared by every test, so it's only touched here.
+	#[test]
+	fn names_map_to_channels() {
+		let water = register("water").unwrap();
+		assert_eq!(register("water").unwrap(), water);
+		assert!(matches!(register(""), Err(ChannelError::Name)));
+
+		assert_eq!(mask(["water"]).unwrap(), channel(water as u32));
+		assert_eq!(names_of(mask(["water"]).unwrap()), vec!["water".to_owned()]);
+		assert!(matches!(mask(["water", "lava"]), Err(ChannelError::Unknown(name)) if name == "lava"));
+
+		let mut n = 0;
+		while register(&format!("channel {}", n)).is_ok() {
+			n += 1;
+		}
+
+		assert!(matches!(register("one too many"), Err(ChannelError::Max)));
+		assert_eq!(names().len(), MAX_CHANNELS);
+		assert_eq!(names_of(ALL), names());
+	}
+
+	#[test]
+	fn groups_keep_their_channels() {
+		let mut state = FlexState::try_new(Box::new(CpuBackend::new()), &Config::default()).unwrap();
+		let backend = state.backend.as_mut();
+		let particles = &mut state.particles;
+
+		let a = particles.create(backend, Vector4(0.0, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(1, eNvFlexPhaseFluid), true).unwrap();
+		let b = particles.create(backend, Vector4(1.0, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(2, eNvFlexPhaseFluid), true).unwrap();
+		particles.flush(backend);
+
+		assert_eq!(particles.set_group_channels(backend, 1, channel(0)), 1);
+		assert_eq!(particles.get_group_channels(1), channel(0));
+
+		// Including particles created later, whatever channels their phase said
+		let c = particles.create(backend, Vector4(2.0, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(1, eNvFlexPhaseFluid), true).unwrap();
+		particles.flush(backend);
+
+		let phases = particles.get_phases(backend, &[a, b, c]);
+		let channels = phases.iter().map(|phase| phase.unwrap() & ALL).collect::<Vec<_>>();
+		assert_eq!(channels, vec![channel(0), ALL, channel(0)]);
+
+		assert_eq!(particles.set_group_channels(backend, 1, ALL), 2);
+		assert_eq!(particles.get_phases(backend, &[a])[0].unwrap() & ALL, ALL);
+	}
+
+	#[test]
+	fn particles_only_collide_with_shapes_they_share_a_channel_with() {
+		let mut state = FlexState::try_new(Box::new(CpuBackend::new()), &Config::default()).unwrap();
+		let backend = state.backend.as_mut();
+
+		let floor = Cube::new(Vector4(0.0, 0.0, -10.0, 1.0), Quat::IDENTITY, [100.0, 100.0, 5.0]);
+		state.shapes.register(backend, floor.into()).unwrap();
+		assert!(state.shapes.set_channels(backend, 0, channel(0)));
+		assert!(!state.shapes.set_channels(backend, 1, channel(0)));
+		assert_eq!(state.shapes.get_channels(0), Some(channel(0)));
+
+		let particles = &mut state.particles;
+		let same = particles.create(backend, Vector4(0.0, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(1, eNvFlexPhaseFluid), true).unwrap();
+		let other = particles.create(backend, Vector4(50.0, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(2, eNvFlexPhaseFluid), true).unwrap();
+		particles.set_group_channels(backend, 1, channel(0));
+		particles.set_group_channels(backend, 2, channel(1));
+		state.flush();
+
+		for _ in 0..120 {
+			state.backend.update(1.0 / 60.0, 2);
+		}
+
+		let mut z = |handle| state.particles.get_particle(state.backend.as_mut(), handle).unwrap().pdata.2;
+		let (same, other) = (z(same), z(other));
+		assert!(same > -6.0, "Fell through its own channel's floor to {}", same);
+		assert!(other < -6.0, "Landed on a floor on another channel at {}", other);
+	}
+}
@@ -0,0 +1,240 @@
+// Turns wall-clock time into a whole number of fixed size solver steps.
+use std::time::Instant;
+
+use crate::config;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ClockError {
+	#[error("Invalid time step: `{0}`, must be greater than zero")]
+	Step(f32),
+
+	#[error("Invalid substep count: `{0}`, must be from 1 to {}", i32::MAX)]
+	Substeps(i64),
+
+	#[error("Invalid max steps: `{0}`, must be from 1 to {}", u32::MAX)]
+	MaxSteps(i64),
+
+	#[error("Invalid step count: `{0}`, must be from 0 to {}", u32::MAX)]
+	Queue(i64),
+
+	#[error("Invalid time scale: `{0}`, must be zero or greater")]
+	TimeScale(f32),
+}
+
+#[derive(Debug)]
+pub struct Clock {
+	last: Instant,
+	/// Scaled time that hasn't been simulated yet
+	accumulator: f32,
+
+	step: f32,
+	substeps: i32,
+	max_steps: u32,
+	time_scale: f32,
+
+	paused: bool,
+	/// Steps requested through [Clock::queue_steps] that haven't run yet, run even while paused
+	queued: u32,
+}
+
+impl Clock {
+	pub fn new(step: f32, substeps: i32, max_steps: u32) -> Self {
+		Self {
+			last: Instant::now(),
+			accumulator: 0.0,
+
+			step,
+			substeps,
+			max_steps,
+			time_scale: 1.0,
+
+			paused: false,
+			queued: 0,
+		}
+	}
+
+	/// Returns how many steps of [Clock::get_step] to simulate for the time passed since the last call, at most `max_steps`.
+	/// Real time past that is dropped so a hitch can't snowball, queued steps past it wait for the next call.
+	pub fn advance(&mut self) -> u32 {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last).as_secs_f32();
+		self.last = now;
+
+		self.advance_by(elapsed)
+	}
+
+	/// Like [Clock::advance], with `elapsed` seconds passed.
+	fn advance_by(&mut self, elapsed: f32) -> u32 {
+		let steps = match self.paused {
+			true => 0,
+			false => {
+				self.accumulator += elapsed * self.time_scale;
+
+				let steps = ((self.accumulator / self.step) as u32).min(self.max_steps);
+				self.accumulator -= steps as f32 * self.step;
+
+				if steps == self.max_steps {
+					self.accumulator = self.accumulator.min(self.step);
+				}
+
+				steps
+			}
+		};
+
+		let queued = self.queued.min(self.max_steps - steps);
+		self.queued -= queued;
+
+		steps + queued
+	}
+
+	pub fn get_step(&self) -> f32 {
+		self.step
+	}
+
+	pub fn get_substeps(&self) -> i32 {
+		self.substeps
+	}
+
+	pub fn get_max_steps(&self) -> u32 {
+		self.max_steps
+	}
+
+	pub fn get_time_scale(&self) -> f32 {
+		self.time_scale
+	}
+
+	pub fn is_paused(&self) -> bool {
+		self.paused
+	}
+
+	pub fn set_step(&mut self, step: f32, substeps: i32, max_steps: u32) -> Result<(), ClockError> {
+		if !(step.is_finite() && step > 0.0) {
+			return Err(ClockError::Step(step));
+		}
+
+		if substeps < 1 {
+			return Err(ClockError::Substeps(substeps as i64));
+		}
+
+		if max_steps < 1 {
+			return Err(ClockError::MaxSteps(max_steps as i64));
+		}
+
+		self.step = step;
+		self.substeps = substeps;
+		self.max_steps = max_steps;
+		Ok(())
+	}
+
+	pub fn set_time_scale(&mut self, scale: f32) -> Result<(), ClockError> {
+		if !(scale.is_finite() && scale >= 0.0) {
+			return Err(ClockError::TimeScale(scale));
+		}
+
+		self.time_scale = scale;
+		Ok(())
+	}
+
+	pub fn set_paused(&mut self, paused: bool) {
+		if !paused {
+			// Don't catch up on the time spent paused
+			self.accumulator = 0.0;
+		}
+
+		self.paused = paused;
+	}
+
+	/// Runs `n` extra steps, whether or not the clock is paused.
+	/// They're spread over as many [Clock::advance]s as it takes to stay under `max_steps` each.
+	pub fn queue_steps(&mut self, n: u32) {
+		self.queued = self.queued.saturating_add(n);
+	}
+}
+
+impl Default for Clock {
+	fn default() -> Self {
+		Self::new(config::TIME_STEP, config::SUBSTEPS, config::MAX_STEPS)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn clock(step: f32, max_steps: u32) -> Clock {
+		Clock::new(step, 1, max_steps)
+	}
+
+	#[test]
+	fn steps_follow_elapsed_time() {
+		let mut clock = clock(0.1, 4);
+		assert_eq!(clock.advance_by(0.25), 2);
+		// What was left over carries into the next call
+		assert_eq!(clock.advance_by(0.06), 1);
+		assert_eq!(clock.advance_by(0.0), 0);
+	}
+
+	#[test]
+	fn hitches_are_capped_at_max_steps() {
+		let mut clock = clock(0.1, 4);
+		assert_eq!(clock.advance_by(10.0), 4);
+		// At most one step of the hitch is kept
+		assert_eq!(clock.advance_by(0.0), 1);
+		assert_eq!(clock.advance_by(0.0), 0);
+	}
+
+	#[test]
+	fn time_scale_slows_steps() {
+		let mut clock = clock(0.1, 8);
+		clock.set_time_scale(0.5).unwrap();
+		assert_eq!(clock.advance_by(0.45), 2);
+
+		clock.set_time_scale(0.0).unwrap();
+		assert_eq!(clock.advance_by(10.0), 0);
+	}
+
+	#[test]
+	fn paused_time_isnt_caught_up() {
+		let mut clock = clock(0.1, 4);
+		clock.set_paused(true);
+		assert_eq!(clock.advance_by(1.0), 0);
+
+		clock.set_paused(false);
+		assert_eq!(clock.advance_by(0.0), 0);
+		assert_eq!(clock.advance_by(0.1), 1);
+	}
+
+	#[test]
+	fn queued_steps_are_capped_per_tick() {
+		let mut clock = clock(0.1, 4);
+		clock.set_paused(true);
+		clock.queue_steps(10);
+		assert_eq!([clock.advance_by(0.0), clock.advance_by(0.0), clock.advance_by(0.0), clock.advance_by(0.0)], [4, 4, 2, 0]);
+
+		// Real time steps count towards the cap too
+		clock.set_paused(false);
+		clock.queue_steps(3);
+		assert_eq!(clock.advance_by(0.35), 4);
+		assert_eq!(clock.advance_by(0.0), 2);
+	}
+
+	#[test]
+	fn invalid_settings_are_rejected() {
+		let mut clock = clock(0.1, 4);
+		for step in [0.0, -1.0, f32::NAN, f32::INFINITY] {
+			assert!(matches!(clock.set_step(step, 1, 4), Err(ClockError::Step(_))));
+		}
+		assert!(matches!(clock.set_step(0.1, 0, 4), Err(ClockError::Substeps(0))));
+		assert!(matches!(clock.set_step(0.1, 1, 0), Err(ClockError::MaxSteps(0))));
+
+		for scale in [-1.0, f32::NAN, f32::INFINITY] {
+			assert!(matches!(clock.set_time_scale(scale), Err(ClockError::TimeScale(_))));
+		}
+
+		// Nothing changed
+		assert_eq!((clock.get_step(), clock.get_substeps(), clock.get_max_steps(), clock.get_time_scale()), (0.1, 1, 4, 1.0));
+
+		clock.set_step(0.05, 3, 2).unwrap();
+		assert_eq!((clock.get_step(), clock.get_substeps(), clock.get_max_steps()), (0.05, 3, 2));
+	}
+}
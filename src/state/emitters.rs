@@ -0,0 +1,409 @@
+// Emitters spawning particles on their own as the world is stepped.
+use std::collections::BTreeMap;
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::{particle::{ParticleState, DEFAULT_IMASS}, rng::Rng};
+use crate::{
+	backend::SolverBackend,
+	helper::NvFlexMakePhase,
+	sys::{eNvFlexPhaseFluid, eNvFlexPhaseSelfCollide},
+	types::Vector3,
+};
+
+/// Shared between worlds, so a handle to an emitter can't end up referring to one in another world.
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, thiserror::Error)]
+pub enum EmitterError {
+	#[error("Invalid emitter {0}: `{1}`, must be {2}")]
+	Invalid(&'static str, f32, &'static str),
+
+	#[error("Invalid emitter direction, must be a finite vector with a length")]
+	Direction,
+}
+
+/// Where particles are spawned, relative to the emitter's position and direction.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmitterShape {
+	Point,
+	/// Facing the emitter's direction
+	Disc { radius: f32 },
+	/// Spreading out from the emitter's direction by up to `angle` radians
+	Cone { angle: f32 },
+	/// Axis aligned, with the given half extents
+	Box { extents: Vector3 },
+}
+
+/// Two unit vectors perpendicular to `dir` and each other.
+fn basis(dir: Vector3) -> (Vector3, Vector3) {
+	let up = if dir.2.abs() < 0.9 { Vector3(0.0, 0.0, 1.0) } else { Vector3(1.0, 0.0, 0.0) };
+	let u = dir.cross(up).normalize();
+	(u, dir.cross(u))
+}
+
+fn check(name: &'static str, value: f32, valid: bool, expected: &'static str) -> Result<f32, EmitterError> {
+	match valid && value.is_finite() {
+		true => Ok(value),
+		false => Err(EmitterError::Invalid(name, value, expected)),
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Emitter {
+	pos: Vector3,
+	dir: Vector3,
+	shape: EmitterShape,
+
+	/// Particles per second
+	rate: f32,
+	speed: f32,
+	/// Most speed added in a random direction to each particle
+	jitter: f32,
+	pub phase: i32,
+	imass: f32,
+	/// Seconds each emitted particle lives for, [None] for them to stay until removed
+	lifetime: Option<f32>,
+
+	/// Removed once this many particles were emitted, 0 to never stop
+	max_total: u32,
+	pub enabled: bool,
+
+	emitted: u32,
+	/// Particles owed, carried over between steps
+	accumulator: f32,
+	rng: Rng,
+}
+
+impl Emitter {
+	/// Creates an emitter at `pos` facing `dir`, spawning fluid particles.
+	/// It doesn't emit anything until given a rate, see [Emitter::set_rate].
+	pub fn new(pos: Vector3, dir: Vector3, shape: EmitterShape) -> Result<Self, EmitterError> {
+		let mut emitter = Self {
+			pos,
+			dir: Vector3(0.0, 0.0, -1.0),
+			shape: EmitterShape::Point,
+
+			rate: 0.0,
+			speed: 0.0,
+			jitter: 0.0,
+			phase: NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid),
+			imass: DEFAULT_IMASS,
+			lifetime: None,
+
+			max_total: 0,
+			enabled: true,
+
+			emitted: 0,
+			accumulator: 0.0,
+			rng: Rng::new(NEXT_ID.load(Ordering::Relaxed)),
+		};
+
+		emitter.set_pos(pos)?;
+		emitter.set_dir(dir)?;
+		emitter.set_shape(shape)?;
+
+		Ok(emitter)
+	}
+
+	pub fn set_pos(&mut self, pos: Vector3) -> Result<(), EmitterError> {
+		for x in [pos.0, pos.1, pos.2] {
+			check("position", x, true, "finite")?;
+		}
+
+		self.pos = pos;
+		Ok(())
+	}
+
+	pub fn set_dir(&mut self, dir: Vector3) -> Result<(), EmitterError> {
+		let dir = dir.normalize();
+		if dir == Vector3::ZERO || !dir.length().is_finite() {
+			return Err(EmitterError::Direction);
+		}
+
+		self.dir = dir;
+		Ok(())
+	}
+
+	pub fn set_shape(&mut self, shape: EmitterShape) -> Result<(), EmitterError> {
+		match shape {
+			EmitterShape::Point => (),
+			EmitterShape::Disc { radius } => {
+				check("radius", radius, radius >= 0.0, "zero or greater")?;
+			}
+			EmitterShape::Cone { angle } => {
+				check("angle", angle, (0.0..=std::f32::consts::PI).contains(&angle), "between 0 and 180 degrees")?;
+			}
+			EmitterShape::Box { extents } => {
+				for x in [extents.0, extents.1, extents.2] {
+					check("size", x, x >= 0.0, "zero or greater")?;
+				}
+			}
+		}
+
+		self.shape = shape;
+		Ok(())
+	}
+
+	pub fn set_rate(&mut self, rate: f32) -> Result<(), EmitterError> {
+		self.rate = check("rate", rate, rate >= 0.0, "zero or greater")?;
+		Ok(())
+	}
+
+	pub fn set_speed(&mut self, speed: f32) -> Result<(), EmitterError> {
+		self.speed = check("speed", speed, true, "finite")?;
+		Ok(())
+	}
+
+	pub fn set_jitter(&mut self, jitter: f32) -> Result<(), EmitterError> {
+		self.jitter = check("jitter", jitter, jitter >= 0.0, "zero or greater")?;
+		Ok(())
+	}
+
+	pub fn set_imass(&mut self, imass: f32) -> Result<(), EmitterError> {
+		self.imass = check("imass", imass, imass >= 0.0, "zero or greater")?;
+		Ok(())
+	}
+
+	pub fn set_lifetime(&mut self, lifetime: Option<f32>) -> Result<(), EmitterError> {
+		if let Some(lifetime) = lifetime {
+			check("lifetime", lifetime, lifetime > 0.0, "greater than zero")?;
+		}
+
+		self.lifetime = lifetime;
+		Ok(())
+	}
+
+	pub fn set_max_total(&mut self, max_total: u32) {
+		self.max_total = max_total;
+	}
+
+	pub fn get_pos(&self) -> Vector3 {
+		self.pos
+	}
+
+	pub fn get_emitted(&self) -> u32 {
+		self.emitted
+	}
+
+	/// Whether the emitter has emitted everything it's allowed to.
+	pub fn is_spent(&self) -> bool {
+		self.max_total != 0 && self.emitted >= self.max_total
+	}
+
+	/// Where the next particle spawns and how fast it's going.
+	fn sample(&mut self) -> (Vector3, Vector3) {
+		let (u, v) = basis(self.dir);
+
+		let (offset, dir) = match self.shape {
+			EmitterShape::Point => (Vector3::ZERO, self.dir),
+			EmitterShape::Disc { radius } => {
+				let r = radius * self.rng.next().sqrt();
+				let theta = self.rng.next() * TAU;
+				(u * (r * theta.cos()) + v * (r * theta.sin()), self.dir)
+			}
+			EmitterShape::Cone { angle } => {
+				let cos = 1.0 - self.rng.next() * (1.0 - angle.cos());
+				let sin = (1.0 - cos * cos).max(0.0).sqrt();
+				let phi = self.rng.next() * TAU;
+				(Vector3::ZERO, self.dir * cos + (u * phi.cos() + v * phi.sin()) * sin)
+			}
+			EmitterShape::Box { extents } => {
+				let offset = Vector3(self.rng.signed() * extents.0, self.rng.signed() * extents.1, self.rng.signed() * extents.2);
+				(offset, self.dir)
+			}
+		};
+
+		let velocity = dir * self.speed + self.rng.in_sphere() * self.jitter;
+		(self.pos + offset, velocity)
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct EmitterState {
+	emitters: BTreeMap<u32, Emitter>,
+}
+
+impl EmitterState {
+	/// Adds an emitter, returning its id.
+	pub fn add(&mut self, emitter: Emitter) -> u32 {
+		let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+		self.emitters.insert(id, emitter);
+		id
+	}
+
+	pub fn get_mut(&mut self, id: u32) -> Option<&mut Emitter> {
+		self.emitters.get_mut(&id)
+	}
+
+	pub fn remove(&mut self, id: u32) -> bool {
+		self.emitters.remove(&id).is_some()
+	}
+
+	pub fn get_count(&self) -> usize {
+		self.emitters.len()
+	}
+
+	/// Spawns whatever each emitter owes for `dt` seconds passing, removing the ones that are spent.
+	/// Particles spawned partway through the step are moved along as if they'd been emitted then, so they don't all stack up.
+	pub fn emit(&mut self, particles: &mut ParticleState, backend: &mut dyn SolverBackend, dt: f32) {
+		let mut owed = vec![];
+		for (id, emitter) in self.emitters.iter_mut() {
+			if !emitter.enabled || emitter.rate <= 0.0 {
+				continue;
+			}
+
+			emitter.accumulator += emitter.rate * dt;
+			let mut n = emitter.accumulator as u32;
+			emitter.accumulator -= n as f32;
+
+			if emitter.max_total != 0 {
+				n = n.min(emitter.max_total - emitter.emitted);
+			}
+
+			if n > 0 {
+				owed.push((*id, n));
+			}
+		}
+
+		if owed.is_empty() {
+			return;
+		}
+
+		// Stops at the particle ceiling, the emitters just try again next step.
+		let _ = particles.factory(backend, |factory| {
+			for &(id, n) in &owed {
+				let emitter = self.emitters.get_mut(&id).expect("Emitter removed while emitting");
+
+				for k in 0..n {
+					let (pos, velocity) = emitter.sample();
+					let age = dt * (k as f32 + 0.5) / n as f32;
+
+					let handle = factory.create((pos + velocity * age).with_w(emitter.imass), velocity, emitter.phase, true)?;
+					factory.set_lifetime(handle, emitter.lifetime);
+					emitter.emitted += 1;
+				}
+			}
+
+			Ok(())
+		});
+
+		self.emitters.retain(|_, emitter| !emitter.is_spent());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::backend::cpu::CpuBackend;
+
+	fn setup(ceiling: usize) -> (CpuBackend, ParticleState) {
+		let mut backend = CpuBackend::new();
+		let particles = ParticleState::new(&mut backend, 4, ceiling).unwrap();
+		(backend, particles)
+	}
+
+	fn emitter(rate: f32) -> Emitter {
+		let mut emitter = Emitter::new(Vector3::ZERO, Vector3(0.0, 0.0, -1.0), EmitterShape::Point).unwrap();
+		emitter.set_rate(rate).unwrap();
+		emitter
+	}
+
+	#[test]
+	fn emits_at_its_rate() {
+		let (mut backend, mut particles) = setup(64);
+		let mut emitters = EmitterState::default();
+		emitters.add(emitter(10.0));
+
+		let mut counts = vec![];
+		for _ in 0..4 {
+			emitters.emit(&mut particles, &mut backend, 0.25);
+			counts.push(particles.get_count());
+		}
+
+		// Partial particles carry over to the next step
+		assert_eq!(counts, vec![2, 5, 7, 10]);
+	}
+
+	#[test]
+	fn spent_emitters_are_removed() {
+		let (mut backend, mut particles) = setup(64);
+		let mut emitters = EmitterState::default();
+
+		let mut limited = emitter(100.0);
+		limited.set_max_total(5);
+		let id = emitters.add(limited);
+
+		emitters.emit(&mut particles, &mut backend, 1.0);
+		assert_eq!(particles.get_count(), 5);
+		assert_eq!(emitters.get_count(), 0);
+		assert!(emitters.get_mut(id).is_none());
+	}
+
+	#[test]
+	fn disabled_emitters_wait() {
+		let (mut backend, mut particles) = setup(64);
+		let mut emitters = EmitterState::default();
+		let id = emitters.add(emitter(10.0));
+		emitters.add(emitter(0.0));
+
+		emitters.get_mut(id).unwrap().enabled = false;
+		emitters.emit(&mut particles, &mut backend, 1.0);
+		assert_eq!(particles.get_count(), 0);
+
+		// Time spent disabled isn't owed afterwards
+		emitters.get_mut(id).unwrap().enabled = true;
+		emitters.emit(&mut particles, &mut backend, 0.1);
+		assert_eq!(particles.get_count(), 1);
+	}
+
+	#[test]
+	fn emitting_stops_at_the_ceiling() {
+		let (mut backend, mut particles) = setup(8);
+		let mut emitters = EmitterState::default();
+		let id = emitters.add(emitter(100.0));
+
+		emitters.emit(&mut particles, &mut backend, 1.0);
+		assert_eq!(particles.get_count(), 8);
+		assert_eq!(emitters.get_mut(id).unwrap().get_emitted(), 8);
+	}
+
+	#[test]
+	fn samples_stay_within_the_shape() {
+		let mut disc = Emitter::new(Vector3(1.0, 2.0, 3.0), Vector3(0.0, 0.0, 2.0), EmitterShape::Disc { radius: 2.0 }).unwrap();
+		let mut cone = Emitter::new(Vector3::ZERO, Vector3(1.0, 0.0, 0.0), EmitterShape::Cone { angle: 0.5 }).unwrap();
+		let mut cube = Emitter::new(Vector3::ZERO, Vector3(1.0, 0.0, 0.0), EmitterShape::Box { extents: Vector3(1.0, 2.0, 3.0) }).unwrap();
+		for emitter in [&mut disc, &mut cone, &mut cube] {
+			emitter.set_speed(1.0).unwrap();
+		}
+
+		for _ in 0..100 {
+			let (pos, velocity) = disc.sample();
+			let offset = pos - Vector3(1.0, 2.0, 3.0);
+			assert!(offset.length() <= 2.0 + 1e-4 && offset.2.abs() < 1e-4);
+			assert!((velocity - Vector3(0.0, 0.0, 1.0)).length() < 1e-4);
+
+			let (pos, velocity) = cone.sample();
+			assert_eq!(pos, Vector3::ZERO);
+			assert!((velocity.length() - 1.0).abs() < 1e-4 && velocity.0 >= 0.5f32.cos() - 1e-4);
+
+			let (pos, _) = cube.sample();
+			assert!(pos.0.abs() <= 1.0 && pos.1.abs() <= 2.0 && pos.2.abs() <= 3.0);
+		}
+	}
+
+	#[test]
+	fn invalid_settings_are_rejected() {
+		assert!(matches!(Emitter::new(Vector3::ZERO, Vector3::ZERO, EmitterShape::Point), Err(EmitterError::Direction)));
+		assert!(Emitter::new(Vector3(f32::NAN, 0.0, 0.0), Vector3(0.0, 0.0, 1.0), EmitterShape::Point).is_err());
+		assert!(Emitter::new(Vector3::ZERO, Vector3(0.0, 0.0, 1.0), EmitterShape::Cone { angle: 4.0 }).is_err());
+
+		let mut emitter = emitter(1.0);
+		assert!(emitter.set_rate(-1.0).is_err());
+		assert!(emitter.set_rate(f32::INFINITY).is_err());
+		assert!(emitter.set_jitter(-1.0).is_err());
+		assert!(emitter.set_lifetime(Some(0.0)).is_err());
+		assert!(emitter.set_shape(EmitterShape::Box { extents: Vector3(1.0, -1.0, 1.0) }).is_err());
+		assert_eq!((emitter.rate, emitter.jitter, emitter.lifetime, emitter.shape), (1.0, 0.0, None, EmitterShape::Point));
+	}
+}
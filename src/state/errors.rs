@@ -0,0 +1,138 @@
+// Errors reported by FleX, kept until lua gets around to them.
+use std::collections::VecDeque;
+use std::sync::Mutex;
+
+use crate::{config, sys::*};
+
+/// FleX only has one error callback for the whole library, so errors from every world end up here.
+pub static ERRORS: Mutex<ErrorQueue> = Mutex::new(ErrorQueue::new());
+
+#[derive(Debug, Clone)]
+pub struct FlexError {
+	pub severity: NvFlexErrorSeverity,
+	pub message: String,
+	pub file: String,
+	pub line: i32,
+}
+
+/// What to do with errors of a given severity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Policy {
+	/// Drop the error entirely
+	Ignore,
+	/// Keep it for `flex.getErrors` and print it to the console
+	Log,
+	/// Keep it for `flex.getErrors` and raise a lua error from the tick
+	Raise,
+}
+
+impl std::str::FromStr for Policy {
+	type Err = ();
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"ignore" => Ok(Self::Ignore),
+			"log" => Ok(Self::Log),
+			"raise" => Ok(Self::Raise),
+			_ => Err(()),
+		}
+	}
+}
+
+pub fn severity_name(severity: NvFlexErrorSeverity) -> &'static str {
+	match severity {
+		s if s == eNvFlexLogError => "error",
+		s if s == eNvFlexLogWarning => "warning",
+		s if s == eNvFlexLogInfo => "info",
+		s if s == eNvFlexLogDebug => "debug",
+		_ => "unknown",
+	}
+}
+
+pub fn severity_from_name(name: &str) -> Option<NvFlexErrorSeverity> {
+	match name {
+		"error" => Some(eNvFlexLogError),
+		"warning" => Some(eNvFlexLogWarning),
+		"info" => Some(eNvFlexLogInfo),
+		"debug" => Some(eNvFlexLogDebug),
+		_ => None,
+	}
+}
+
+#[derive(Debug)]
+pub struct ErrorQueue {
+	/// Drained by `flex.getErrors`
+	errors: VecDeque<FlexError>,
+	/// Drained every tick, to run hooks and apply policies
+	pending: VecDeque<FlexError>,
+	/// How many errors were pushed out of `errors` since it was last drained
+	dropped: usize,
+
+	/// Indexed by [policy_slot]
+	policies: [Policy; 4],
+}
+
+/// Treats anything unexpected as an error.
+fn policy_slot(severity: NvFlexErrorSeverity) -> usize {
+	match severity {
+		s if s == eNvFlexLogWarning => 1,
+		s if s == eNvFlexLogInfo => 2,
+		s if s == eNvFlexLogDebug => 3,
+		_ => 0,
+	}
+}
+
+impl ErrorQueue {
+	pub const fn new() -> Self {
+		Self {
+			errors: VecDeque::new(),
+			pending: VecDeque::new(),
+			dropped: 0,
+
+			// error, warning, info, debug
+			policies: [Policy::Log, Policy::Log, Policy::Ignore, Policy::Ignore],
+		}
+	}
+
+	pub fn get_policy(&self, severity: NvFlexErrorSeverity) -> Policy {
+		self.policies[policy_slot(severity)]
+	}
+
+	pub fn set_policy(&mut self, severity: NvFlexErrorSeverity, policy: Policy) {
+		self.policies[policy_slot(severity)] = policy;
+	}
+
+	pub fn push(&mut self, error: FlexError) {
+		if self.get_policy(error.severity) == Policy::Ignore {
+			return;
+		}
+
+		if self.errors.len() >= config::MAX_ERRORS {
+			self.errors.pop_front();
+			self.dropped += 1;
+		}
+
+		if self.pending.len() >= config::MAX_ERRORS {
+			self.pending.pop_front();
+		}
+
+		self.pending.push_back(error.clone());
+		self.errors.push_back(error);
+	}
+
+	/// Takes every stored error, along with how many were dropped for going over [config::MAX_ERRORS].
+	pub fn drain(&mut self) -> (Vec<FlexError>, usize) {
+		(self.errors.drain(..).collect(), std::mem::take(&mut self.dropped))
+	}
+
+	/// Takes the errors that haven't been reported by the tick yet.
+	pub fn take_pending(&mut self) -> Vec<FlexError> {
+		self.pending.drain(..).collect()
+	}
+}
+
+impl Default for ErrorQueue {
+	fn default() -> Self {
+		Self::new()
+	}
+}
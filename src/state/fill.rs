@@ -0,0 +1,223 @@
+// Filling the volume of a shape with particles.
+use super::{rng::Rng, CreateError, FlexState, Shape, DEFAULT_IMASS};
+use crate::{
+	helper::{fluid_rest_distance, NvFlexMakePhase},
+	sys::{eNvFlexPhaseFluid, eNvFlexPhaseSelfCollide, NvFlexParams},
+	types::{ParticleHandle, Vector3},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum FillError {
+	#[error("Invalid fill {0}: `{1}`, must be {2}")]
+	Invalid(&'static str, f32, &'static str),
+
+	#[error("Failed to fill: {0}")]
+	Create(#[from] CreateError),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FillOptions {
+	/// Distance between particles, see [fluid_rest_distance]
+	pub spacing: f32,
+	/// Furthest each particle is moved off the lattice in a random direction
+	pub jitter: f32,
+	pub velocity: Vector3,
+	pub imass: f32,
+	pub phase: i32,
+	pub lifetime: Option<f32>,
+}
+
+impl FillOptions {
+	/// Still fluid at the rest spacing of `params`.
+	pub fn new(params: &NvFlexParams) -> Self {
+		Self {
+			spacing: fluid_rest_distance(params),
+			jitter: 0.0,
+			velocity: Vector3::ZERO,
+			imass: DEFAULT_IMASS,
+			phase: NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid),
+			lifetime: None,
+		}
+	}
+
+	fn check(&self) -> Result<(), FillError> {
+		let check = |name, value: f32, valid: bool, expected| match valid && value.is_finite() {
+			true => Ok(()),
+			false => Err(FillError::Invalid(name, value, expected)),
+		};
+
+		check("spacing", self.spacing, self.spacing > 0.0, "greater than zero")?;
+		check("jitter", self.jitter, self.jitter >= 0.0, "zero or greater")?;
+		check("imass", self.imass, self.imass >= 0.0, "zero or greater")?;
+		for x in [self.velocity.0, self.velocity.1, self.velocity.2] {
+			check("velocity", x, true, "finite")?;
+		}
+
+		if let Some(lifetime) = self.lifetime {
+			check("lifetime", lifetime, lifetime > 0.0, "greater than zero")?;
+		}
+
+		Ok(())
+	}
+}
+
+impl FlexState {
+	/// Fills the volume `shape` would collide with with a lattice of particles, aligned to the shape's rotation.
+	/// Nothing is created if they wouldn't all fit under the particle ceiling. Returns handles to them.
+	pub fn fill(&mut self, shape: &Shape, opts: &FillOptions) -> Result<Vec<ParticleHandle>, FillError> {
+		opts.check()?;
+
+		let ceiling = self.particles.get_ceiling();
+		let available = ceiling.saturating_sub(self.particles.get_count());
+
+		let (pos, rot, extents) = (shape.get_pos().xyz(), *shape.get_rot(), shape.local_extents());
+		for x in [extents.0, extents.1, extents.2] {
+			if x < 0.0 || !x.is_finite() {
+				return Err(FillError::Invalid("size", x, "zero or greater"));
+			}
+		}
+
+		// Centered in the shape, so the gap left at the edges is even on both sides
+		let cells = |extent: f32| (2.0 * extent / opts.spacing).floor().max(0.0) as u64 + 1;
+		let (nx, ny, nz) = (cells(extents.0), cells(extents.1), cells(extents.2));
+		let start = |n: u64| (n - 1) as f32 * opts.spacing / -2.0;
+
+		// Every shape fills about half of its box or more, so a lattice this big can't fit and would take forever to go through
+		if nx.saturating_mul(ny).saturating_mul(nz) > 8 * available as u64 {
+			return Err(CreateError::Max("particles", ceiling).into());
+		}
+
+		let mut rng = Rng::new(self.particles.get_count() as u32);
+		let mut points = vec![];
+		for x in 0..nx {
+			for y in 0..ny {
+				for z in 0..nz {
+					let local = Vector3(
+						start(nx) + x as f32 * opts.spacing,
+						start(ny) + y as f32 * opts.spacing,
+						start(nz) + z as f32 * opts.spacing,
+					);
+
+					let point = pos + rot.rotate(local);
+					if shape.signed_distance(point).0 > 0.0 {
+						continue;
+					}
+
+					if points.len() >= available {
+						return Err(CreateError::Max("particles", ceiling).into());
+					}
+
+					points.push(point + rng.in_sphere() * opts.jitter);
+				}
+			}
+		}
+
+		self.particles.reserve_more(self.backend.as_mut(), points.len())?;
+
+		let mut handles = Vec::with_capacity(points.len());
+		let created = self.particles.factory(self.backend.as_mut(), |factory| {
+			for point in points {
+				let handle = factory.create(point.with_w(opts.imass), opts.velocity, opts.phase, true)?;
+				factory.set_lifetime(handle, opts.lifetime);
+				handles.push(handle);
+			}
+
+			Ok(())
+		});
+
+		self.particles.flush(self.backend.as_mut());
+		created?;
+
+		Ok(handles)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, config::{Config, PARAMS}, state::{Cube, Sphere}, types::{Quat, Vector4}};
+
+	fn new_state(ceiling: usize) -> FlexState {
+		let config = Config { max_particles: 16, particle_ceiling: ceiling, ..Config::default() };
+		FlexState::try_new(Box::new(CpuBackend::new()), &config).unwrap()
+	}
+
+	fn cube(extents: [f32; 3]) -> Shape {
+		Cube::new(Vector4(1.0, 2.0, 3.0, 1.0), Quat::IDENTITY, extents).into()
+	}
+
+	fn opts(spacing: f32) -> FillOptions {
+		FillOptions { spacing, ..FillOptions::new(&PARAMS) }
+	}
+
+	fn positions(state: &mut FlexState) -> Vec<Vector3> {
+		state.particles.get(state.backend.as_mut()).unwrap().iter().map(|p| p.pdata.xyz()).collect()
+	}
+
+	#[test]
+	fn lattice_is_centered_in_the_shape() {
+		let mut state = new_state(1024);
+		let handles = state.fill(&cube([7.0, 0.0, 0.0]), &opts(5.0)).unwrap();
+		assert_eq!(handles.len(), 3);
+
+		let xs = positions(&mut state).iter().map(|p| p.0).collect::<Vec<_>>();
+		assert_eq!(xs, vec![-4.0, 1.0, 6.0]);
+	}
+
+	#[test]
+	fn only_the_inside_is_filled() {
+		let mut state = new_state(1024);
+		assert_eq!(state.fill(&cube([10.0; 3]), &opts(5.0)).unwrap().len(), 125);
+
+		let sphere: Shape = Sphere::new(Vector4(0.0, 0.0, 100.0, 1.0), Quat::IDENTITY, 10.0).into();
+		let filled = state.fill(&sphere, &opts(5.0)).unwrap();
+		// Lattice points within the radius, out of the 125 in the sphere's box
+		assert_eq!(filled.len(), 33);
+
+		for pos in &positions(&mut state)[125..] {
+			assert!(sphere.signed_distance(*pos).0 <= 0.0);
+		}
+	}
+
+	#[test]
+	fn options_are_given_to_every_particle() {
+		let mut state = new_state(1024);
+		let opts = FillOptions { velocity: Vector3(0.0, 0.0, 5.0), imass: 0.5, lifetime: Some(1.0), ..opts(5.0) };
+		let handles = state.fill(&cube([5.0; 3]), &opts).unwrap();
+
+		for particle in state.particles.get(state.backend.as_mut()).unwrap() {
+			assert_eq!((particle.velocity.2, particle.pdata.3, *particle.phase), (5.0, 0.5, opts.phase));
+		}
+
+		// Removed once their lifetime runs out
+		state.particles.age(1.0);
+		assert!(handles.iter().all(|&handle| !state.particles.is_valid(handle)));
+	}
+
+	#[test]
+	fn nothing_is_created_past_the_ceiling() {
+		let mut state = new_state(100);
+		assert!(matches!(state.fill(&cube([10.0; 3]), &opts(5.0)), Err(FillError::Create(CreateError::Max(..)))));
+		assert_eq!(state.particles.get_count(), 0);
+
+		// Far too many to go through at all
+		assert!(matches!(state.fill(&cube([10.0; 3]), &opts(0.001)), Err(FillError::Create(CreateError::Max(..)))));
+	}
+
+	#[test]
+	fn invalid_options_are_rejected() {
+		let mut state = new_state(1024);
+		for opts in [
+			opts(0.0),
+			opts(f32::INFINITY),
+			FillOptions { jitter: -1.0, ..opts(5.0) },
+			FillOptions { imass: f32::NAN, ..opts(5.0) },
+			FillOptions { lifetime: Some(0.0), ..opts(5.0) },
+		] {
+			assert!(matches!(state.fill(&cube([5.0; 3]), &opts), Err(FillError::Invalid(..))));
+		}
+
+		assert!(matches!(state.fill(&cube([-5.0, 5.0, 5.0]), &opts(5.0)), Err(FillError::Invalid("size", ..))));
+		assert_eq!(state.particles.get_count(), 0);
+	}
+}
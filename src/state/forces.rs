@@ -0,0 +1,398 @@
+// Force fields changing the velocity of particles around them as the world is stepped.
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use super::particle::ParticleState;
+use crate::{
+	backend::SolverBackend,
+	types::{Quat, Vector3},
+};
+
+/// Shared between worlds, so a handle to a force field can't end up referring to one in another world.
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, thiserror::Error)]
+pub enum ForceError {
+	#[error("Invalid force field {0}: `{1}`, must be {2}")]
+	Invalid(&'static str, f32, &'static str),
+
+	#[error("Invalid force field axis, must be a finite vector with a length")]
+	Axis,
+}
+
+/// How the strength of a field fades out towards its radius.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Falloff {
+	Constant,
+	Linear,
+	Quadratic,
+}
+
+impl Falloff {
+	fn scale(self, distance: f32, radius: f32) -> f32 {
+		let t = match radius > 0.0 {
+			true => 1.0 - (distance / radius).min(1.0),
+			false => 1.0,
+		};
+
+		match self {
+			Falloff::Constant => 1.0,
+			Falloff::Linear => t,
+			Falloff::Quadratic => t * t,
+		}
+	}
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum ForceKind {
+	/// Pushes particles away from the field once, on the next step, after which the field is removed
+	Impulse,
+	/// Pulls particles towards the field, pushing them away if the strength is negative
+	Attractor,
+	/// Spins particles around `axis` through the field by the right hand rule. Only reaches `height` along the axis
+	Vortex { axis: Vector3, height: f32 },
+	/// Drags particles inside the box centered on the field towards `velocity`. Ignores the radius and falloff
+	Wind { velocity: Vector3, extents: Vector3, rot: Quat },
+}
+
+fn check(name: &'static str, value: f32, valid: bool, expected: &'static str) -> Result<f32, ForceError> {
+	match valid && value.is_finite() {
+		true => Ok(value),
+		false => Err(ForceError::Invalid(name, value, expected)),
+	}
+}
+
+fn check_vector(name: &'static str, v: Vector3) -> Result<(), ForceError> {
+	for x in [v.0, v.1, v.2] {
+		check(name, x, true, "finite")?;
+	}
+
+	Ok(())
+}
+
+#[derive(Debug, Clone)]
+pub struct ForceField {
+	pos: Vector3,
+	kind: ForceKind,
+
+	/// How far impulses, attractors and vortices reach
+	radius: f32,
+	/// Speed given by impulses, acceleration of attractors and vortices, and how quickly wind catches particles up to its speed
+	strength: f32,
+	falloff: Falloff,
+	pub enabled: bool,
+}
+
+impl ForceField {
+	/// Creates a field at `pos`, doing nothing until given a radius and strength.
+	pub fn new(pos: Vector3, kind: ForceKind) -> Result<Self, ForceError> {
+		let mut field = Self {
+			pos,
+			kind: ForceKind::Attractor,
+
+			radius: 0.0,
+			strength: 0.0,
+			falloff: Falloff::Linear,
+			enabled: true,
+		};
+
+		field.set_pos(pos)?;
+		field.set_kind(kind)?;
+
+		Ok(field)
+	}
+
+	pub fn set_pos(&mut self, pos: Vector3) -> Result<(), ForceError> {
+		check_vector("position", pos)?;
+
+		self.pos = pos;
+		Ok(())
+	}
+
+	pub fn set_kind(&mut self, kind: ForceKind) -> Result<(), ForceError> {
+		let kind = match kind {
+			ForceKind::Impulse | ForceKind::Attractor => kind,
+			ForceKind::Vortex { axis, height } => {
+				let axis = axis.normalize();
+				if axis == Vector3::ZERO || !axis.length().is_finite() {
+					return Err(ForceError::Axis);
+				}
+
+				ForceKind::Vortex { axis, height: check("height", height, height >= 0.0, "zero or greater")? }
+			}
+			ForceKind::Wind { velocity, extents, rot } => {
+				check_vector("velocity", velocity)?;
+				for x in [extents.0, extents.1, extents.2] {
+					check("size", x, x >= 0.0, "zero or greater")?;
+				}
+
+				for x in [rot.0, rot.1, rot.2, rot.3] {
+					check("rotation", x, true, "finite")?;
+				}
+
+				check("strength", self.strength, self.strength >= 0.0, "zero or greater for wind")?;
+
+				ForceKind::Wind { velocity, extents, rot: rot.normalize() }
+			}
+		};
+
+		self.kind = kind;
+		Ok(())
+	}
+
+	pub fn set_radius(&mut self, radius: f32) -> Result<(), ForceError> {
+		self.radius = check("radius", radius, radius >= 0.0, "zero or greater")?;
+		Ok(())
+	}
+
+	pub fn set_strength(&mut self, strength: f32) -> Result<(), ForceError> {
+		self.strength = match self.kind {
+			ForceKind::Wind { .. } => check("strength", strength, strength >= 0.0, "zero or greater for wind")?,
+			_ => check("strength", strength, true, "finite")?,
+		};
+
+		Ok(())
+	}
+
+	pub fn set_falloff(&mut self, falloff: Falloff) {
+		self.falloff = falloff;
+	}
+
+	pub fn get_pos(&self) -> Vector3 {
+		self.pos
+	}
+
+	pub fn get_kind(&self) -> ForceKind {
+		self.kind
+	}
+
+	/// Change in velocity of a particle at `pos` going `vel`, over `dt` seconds.
+	fn delta(&self, pos: Vector3, vel: Vector3, dt: f32) -> Vector3 {
+		let offset = pos - self.pos;
+
+		match self.kind {
+			ForceKind::Impulse | ForceKind::Attractor => {
+				let distance = offset.length();
+				if distance > self.radius || distance == 0.0 {
+					return Vector3::ZERO;
+				}
+
+				let push = offset * (self.strength * self.falloff.scale(distance, self.radius) / distance);
+				match self.kind {
+					ForceKind::Impulse => push,
+					_ => -push * dt,
+				}
+			}
+			ForceKind::Vortex { axis, height } => {
+				let along = offset.dot(axis);
+				let radial = offset - axis * along;
+				let distance = radial.length();
+				if along.abs() > height || distance > self.radius || distance == 0.0 {
+					return Vector3::ZERO;
+				}
+
+				axis.cross(radial) * (self.strength * self.falloff.scale(distance, self.radius) * dt / distance)
+			}
+			ForceKind::Wind { velocity, extents, rot } => {
+				let local = rot.conjugate().rotate(offset);
+				if local.0.abs() > extents.0 || local.1.abs() > extents.1 || local.2.abs() > extents.2 {
+					return Vector3::ZERO;
+				}
+
+				// Eases towards the wind speed the same however the time is split up
+				(velocity - vel) * (1.0 - (-self.strength * dt).exp())
+			}
+		}
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct ForceState {
+	fields: BTreeMap<u32, ForceField>,
+}
+
+impl ForceState {
+	/// Adds a force field, returning its id.
+	pub fn add(&mut self, field: ForceField) -> u32 {
+		let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+		self.fields.insert(id, field);
+		id
+	}
+
+	pub fn get_mut(&mut self, id: u32) -> Option<&mut ForceField> {
+		self.fields.get_mut(&id)
+	}
+
+	pub fn remove(&mut self, id: u32) -> bool {
+		self.fields.remove(&id).is_some()
+	}
+
+	pub fn get_count(&self) -> usize {
+		self.fields.len()
+	}
+
+	/// Changes the velocity of particles by what each enabled field does to them over `dt` seconds, then removes the impulses that were applied.
+	/// Particles with infinite mass are left alone. Worked out by the backend on the particles it's about to step, so it doesn't wait on the solver.
+	/// Skipped along with the step if the backend is going to drop it, like the solver skips steps while it's behind.
+	pub fn apply(&mut self, particles: &mut ParticleState, backend: &mut dyn SolverBackend, dt: f32) {
+		let fields: Vec<ForceField> = self.fields.values().filter(|field| field.enabled).cloned().collect();
+		if fields.is_empty() {
+			return;
+		}
+
+		let applied = particles.update_velocities(
+			backend,
+			Box::new(move |pos, vel| {
+				if pos.3 == 0.0 {
+					return false;
+				}
+
+				let delta = fields.iter().fold(Vector3::ZERO, |delta, field| delta + field.delta(pos.xyz(), *vel, dt));
+				*vel += delta;
+
+				delta != Vector3::ZERO
+			}),
+		);
+
+		// Impulses wait for a step they got applied in
+		if !applied {
+			return;
+		}
+
+		self.fields.retain(|_, field| !(field.enabled && matches!(field.kind, ForceKind::Impulse)));
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, helper::NvFlexMakePhase, sys::eNvFlexPhaseFluid, types::Vector4};
+
+	fn setup(positions: &[Vector4]) -> (CpuBackend, ParticleState) {
+		let mut backend = CpuBackend::new();
+		let mut particles = ParticleState::new(&mut backend, 8, 8).unwrap();
+		for &pos in positions {
+			particles.create(&mut backend, pos, Vector3::ZERO, NvFlexMakePhase(0, eNvFlexPhaseFluid), true).unwrap();
+		}
+
+		(backend, particles)
+	}
+
+	fn velocities(backend: &mut CpuBackend, particles: &ParticleState) -> Vec<Vector3> {
+		let velocities = backend.map(particles.velocities) as *const Vector3;
+		let copy = (0..particles.get_count()).map(|i| unsafe { *velocities.add(i) }).collect();
+		backend.unmap(particles.velocities);
+		copy
+	}
+
+	fn close(a: Vector3, b: Vector3) -> bool {
+		(a - b).length() < 1e-5
+	}
+
+	fn field(kind: ForceKind, radius: f32, strength: f32, falloff: Falloff) -> ForceField {
+		let mut field = ForceField::new(Vector3::ZERO, kind).unwrap();
+		field.set_radius(radius).unwrap();
+		field.set_strength(strength).unwrap();
+		field.set_falloff(falloff);
+		field
+	}
+
+	#[test]
+	fn attractors_pull_within_their_radius() {
+		let (mut backend, mut particles) = setup(&[Vector4(5.0, 0.0, 0.0, 1.0), Vector4(20.0, 0.0, 0.0, 1.0), Vector4(5.0, 0.0, 0.0, 0.0)]);
+
+		let mut forces = ForceState::default();
+		forces.add(field(ForceKind::Attractor, 10.0, 2.0, Falloff::Linear));
+		forces.apply(&mut particles, &mut backend, 0.5);
+
+		// Halfway out, so at half strength. Too far away or with infinite mass is left alone
+		let v = velocities(&mut backend, &particles);
+		assert!(close(v[0], Vector3(-0.5, 0.0, 0.0)), "{:?}", v[0]);
+		assert_eq!(v[1], Vector3::ZERO);
+		assert_eq!(v[2], Vector3::ZERO);
+		assert_eq!(forces.get_count(), 1);
+	}
+
+	#[test]
+	fn impulses_are_applied_once() {
+		let (mut backend, mut particles) = setup(&[Vector4(2.0, 0.0, 0.0, 1.0)]);
+
+		let mut forces = ForceState::default();
+		forces.add(field(ForceKind::Impulse, 10.0, 3.0, Falloff::Constant));
+		forces.apply(&mut particles, &mut backend, 0.5);
+		assert!(close(velocities(&mut backend, &particles)[0], Vector3(3.0, 0.0, 0.0)));
+		assert_eq!(forces.get_count(), 0);
+
+		forces.apply(&mut particles, &mut backend, 0.5);
+		assert!(close(velocities(&mut backend, &particles)[0], Vector3(3.0, 0.0, 0.0)));
+	}
+
+	#[test]
+	fn vortices_spin_around_their_axis() {
+		let (mut backend, mut particles) = setup(&[Vector4(2.0, 0.0, 0.0, 1.0), Vector4(2.0, 0.0, 5.0, 1.0)]);
+
+		let mut forces = ForceState::default();
+		let vortex = ForceKind::Vortex { axis: Vector3(0.0, 0.0, 2.0), height: 1.0 };
+		forces.add(field(vortex, 10.0, 1.0, Falloff::Constant));
+		forces.apply(&mut particles, &mut backend, 1.0);
+
+		// Counter clockwise looking down the axis, and nothing past the height
+		let v = velocities(&mut backend, &particles);
+		assert!(close(v[0], Vector3(0.0, 1.0, 0.0)), "{:?}", v[0]);
+		assert_eq!(v[1], Vector3::ZERO);
+	}
+
+	#[test]
+	fn wind_eases_the_same_however_the_time_is_split() {
+		let wind = ForceKind::Wind { velocity: Vector3(10.0, 0.0, 0.0), extents: Vector3(5.0, 5.0, 5.0), rot: Quat::IDENTITY };
+		let speed = |steps: usize| {
+			let (mut backend, mut particles) = setup(&[Vector4(0.0, 0.0, 0.0, 1.0), Vector4(6.0, 0.0, 0.0, 1.0)]);
+			let mut forces = ForceState::default();
+			forces.add(field(wind, 0.0, 1.0, Falloff::Constant));
+			for _ in 0..steps {
+				forces.apply(&mut particles, &mut backend, 1.0 / steps as f32);
+			}
+
+			velocities(&mut backend, &particles)
+		};
+
+		let (once, split) = (speed(1), speed(4));
+		assert!(close(once[0], Vector3(10.0 * (1.0 - (-1.0f32).exp()), 0.0, 0.0)), "{:?}", once[0]);
+		assert!(close(once[0], split[0]), "{:?} {:?}", once[0], split[0]);
+		assert_eq!(once[1], Vector3::ZERO);
+	}
+
+	#[test]
+	fn disabled_fields_are_ignored() {
+		let (mut backend, mut particles) = setup(&[Vector4(2.0, 0.0, 0.0, 1.0)]);
+
+		let mut forces = ForceState::default();
+		let id = forces.add(field(ForceKind::Impulse, 10.0, 3.0, Falloff::Constant));
+		forces.get_mut(id).unwrap().enabled = false;
+		forces.apply(&mut particles, &mut backend, 0.5);
+
+		// Still waiting to go off once enabled
+		assert_eq!(velocities(&mut backend, &particles)[0], Vector3::ZERO);
+		assert_eq!(forces.get_count(), 1);
+		assert!(forces.remove(id));
+	}
+
+	#[test]
+	fn invalid_fields_are_rejected() {
+		assert!(matches!(ForceField::new(Vector3(f32::NAN, 0.0, 0.0), ForceKind::Attractor), Err(ForceError::Invalid("position", ..))));
+		assert!(matches!(ForceField::new(Vector3::ZERO, ForceKind::Vortex { axis: Vector3::ZERO, height: 1.0 }), Err(ForceError::Axis)));
+		assert!(matches!(
+			ForceField::new(Vector3::ZERO, ForceKind::Vortex { axis: Vector3(0.0, 0.0, 1.0), height: -1.0 }),
+			Err(ForceError::Invalid("height", ..))
+		));
+
+		let mut field = ForceField::new(Vector3::ZERO, ForceKind::Attractor).unwrap();
+		assert!(field.set_radius(-1.0).is_err());
+		assert!(field.set_strength(f32::INFINITY).is_err());
+		assert!(field.set_strength(-1.0).is_ok());
+
+		// Wind can't push away from its own speed
+		let wind = ForceKind::Wind { velocity: Vector3::ZERO, extents: Vector3(1.0, 1.0, 1.0), rot: Quat::IDENTITY };
+		assert!(matches!(field.set_kind(wind), Err(ForceError::Invalid("strength", ..))));
+	}
+}
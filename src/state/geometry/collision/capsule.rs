@@ -1,5 +1,5 @@
-use crate::types::{Vector4, Quat};
-use nvflex_sys::{NvFlexCollisionGeometry, NvFlexCollisionShapeType, NvFlexCapsuleGeometry, eNvFlexShapeCapsule};
+use crate::types::{Vector3, Vector4, Quat};
+use crate::sys::{NvFlexCollisionGeometry, NvFlexCollisionShapeType, NvFlexCapsuleGeometry, eNvFlexShapeCapsule};
 
 #[derive(Debug)]
 pub struct Capsule {
@@ -20,6 +20,21 @@ impl Capsule {
 		}
 	}
 
+	/// Signed distance from `point` to the surface of the capsule, and the outward normal closest to it.
+	/// Like in FleX, the capsule's axis is its local x axis.
+	pub fn signed_distance(&self, point: Vector3) -> (f32, Vector3) {
+		let local = self.rot.conjugate().rotate(point - self.pos.xyz());
+		let offset = local - Vector3(local.0.clamp(-self.half_height, self.half_height), 0.0, 0.0);
+
+		// Pick any direction if the point is right on the center
+		let normal = match offset.normalize() {
+			n if n == Vector3::ZERO => Vector3(0.0, 0.0, 1.0),
+			n => n,
+		};
+
+		(offset.length() - self.radius, self.rot.rotate(normal))
+	}
+
 	pub fn as_union(&self) -> NvFlexCollisionGeometry {
 		NvFlexCollisionGeometry {
 			capsule: {
@@ -1,5 +1,5 @@
-use crate::types::{Vector4, Quat};
-use nvflex_sys::{NvFlexCollisionGeometry, NvFlexBoxGeometry, NvFlexCollisionShapeType, eNvFlexShapeBox};
+use crate::types::{Vector3, Vector4, Quat};
+use crate::sys::{NvFlexCollisionGeometry, NvFlexBoxGeometry, NvFlexCollisionShapeType, eNvFlexShapeBox};
 
 #[derive(Debug)]
 pub struct Cube {
@@ -17,6 +17,28 @@ impl Cube {
 		}
 	}
 
+	/// Signed distance from `point` to the surface of the box, and the outward normal closest to it.
+	pub fn signed_distance(&self, point: Vector3) -> (f32, Vector3) {
+		let local = self.rot.conjugate().rotate(point - self.pos.xyz());
+		let (p, e) = ([local.0, local.1, local.2], self.extents);
+
+		let q = [p[0].abs() - e[0], p[1].abs() - e[1], p[2].abs() - e[2]];
+		let outside = Vector3(q[0].max(0.0), q[1].max(0.0), q[2].max(0.0));
+
+		let (distance, normal) = if outside.length_sqr() > 0.0 {
+			let n = Vector3(outside.0.copysign(p[0]), outside.1.copysign(p[1]), outside.2.copysign(p[2]));
+			(outside.length(), n.normalize())
+		} else {
+			// Inside, push out through the closest face
+			let axis = (0..3).fold(0, |best, a| if q[a] > q[best] { a } else { best });
+			let mut n = [0.0; 3];
+			n[axis] = 1.0f32.copysign(p[axis]);
+			(q[axis], Vector3::from(n))
+		};
+
+		(distance, self.rot.rotate(normal))
+	}
+
 	pub fn as_union(&self) -> NvFlexCollisionGeometry {
 		NvFlexCollisionGeometry {
 			box_: {
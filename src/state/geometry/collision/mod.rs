@@ -1,12 +1,13 @@
-use nvflex_sys::*;
+use crate::sys::*;
 use std::mem::size_of;
 
 use crate::{
+	backend::{ShapeBuffers, SolverBackend},
 	config,
-	types::{Quat, Vector3, Vector4}, helper::NvFlexMakeShapeFlags,
+	types::{Quat, Vector3, Vector4}, helper::{NvFlexMakeShapeFlags, NvFlexMakeShapeFlagsWithChannels},
 };
 
-use crate::{FlexState, state::CreateError};
+use crate::{FlexState, state::{buffers, channels, CreateError, InitError}};
 
 pub mod cube;
 pub mod capsule;
@@ -55,6 +56,40 @@ impl Shape {
 			Shape::Sphere(sphere) => &sphere.rot,
 		}
 	}
+
+	/// Signed distance from `point` to the surface of the shape (negative inside), and the outward normal closest to it.
+	pub fn signed_distance(&self, point: Vector3) -> (f32, Vector3) {
+		match self {
+			Shape::Cube(cube) => cube.signed_distance(point),
+			Shape::Capsule(capsule) => capsule.signed_distance(point),
+			Shape::Sphere(sphere) => sphere.signed_distance(point),
+		}
+	}
+
+	/// Half size of the box around the shape, along its own axes.
+	pub fn local_extents(&self) -> Vector3 {
+		match self {
+			Shape::Cube(cube) => Vector3::from(cube.extents),
+			Shape::Capsule(capsule) => Vector3(capsule.half_height + capsule.radius, capsule.radius, capsule.radius),
+			Shape::Sphere(sphere) => Vector3(sphere.radius, sphere.radius, sphere.radius),
+		}
+	}
+
+	/// Reads a shape back out of the buffers FleX is given, see [ShapeState::register].
+	/// Returns [None] for shape types gfluid doesn't create.
+	#[allow(non_upper_case_globals)]
+	pub fn from_raw(geometry: &NvFlexCollisionGeometry, flags: i32, pos: Vector4, rot: Quat) -> Option<Self> {
+		let shape = unsafe {
+			match flags & eNvFlexShapeFlagTypeMask {
+				eNvFlexShapeBox => Cube::new(pos, rot, geometry.box_.halfExtents).into(),
+				eNvFlexShapeSphere => Sphere::new(pos, rot, geometry.sphere.radius).into(),
+				eNvFlexShapeCapsule => Capsule::new(pos, rot, geometry.capsule.radius, geometry.capsule.halfHeight).into(),
+				_ => return None,
+			}
+		};
+
+		Some(shape)
+	}
 }
 
 // kind: NvFlexCollisionShapeType, pos: Vector4, rot: Quat
@@ -64,53 +99,40 @@ impl Shape {
 pub struct ShapeState {
 	#[derivative(Debug = "ignore")]
 	shapes: Vec<Shape>,
+	/// Collision channels of each shape, see [crate::state::channels]
+	channels: Vec<i32>,
 	has_changes: bool,
 
-	pub buffer: *mut NvFlexBuffer,
-	pub positions: *mut NvFlexBuffer,          // Vec<Vec4>
-	pub rotations: *mut NvFlexBuffer,          // Vec<Quat>
-	pub previous_positions: *mut NvFlexBuffer, // Vec<Vec4>
-	pub previous_rotations: *mut NvFlexBuffer, // Vec<Quat>
-	pub flags: *mut NvFlexBuffer,              // Vec<i32>
+	/// How many shapes the buffers have room for, grown as needed up to the ceiling
+	capacity: usize,
+	ceiling: usize,
+
+	pub buffers: ShapeBuffers,
 }
 
 impl ShapeState {
 	/// Allocates buffers used by the geometry state
-	/// # Safety
-	/// Do not call this function more than once
-	pub unsafe fn new(flex: *mut NvFlexLibrary, max: usize) -> Self {
-		Self {
-			has_changes: false,
-
-			shapes: Vec::with_capacity(max),
+	pub fn new(backend: &mut dyn SolverBackend, max: usize, ceiling: usize) -> Result<Self, InitError> {
+		let mut alloc = |stride| backend.alloc(max, stride).ok_or(InitError::Alloc("shape"));
 
-			buffer: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<NvFlexCollisionGeometry>() as i32,
-				eNvFlexBufferHost,
-			),
-
-			positions: NvFlexAllocBuffer(flex, max as i32, size_of::<Vector4>() as i32, eNvFlexBufferHost),
-
-			rotations: NvFlexAllocBuffer(flex, max as i32, size_of::<Quat>() as i32, eNvFlexBufferHost),
-
-			previous_positions: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<Vector4>() as i32,
-				eNvFlexBufferHost,
-			),
-
-			previous_rotations: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<Quat>() as i32,
-				eNvFlexBufferHost,
-			),
+		Ok(Self {
+			has_changes: false,
 
-			flags: NvFlexAllocBuffer(flex, max as i32, size_of::<i32>() as i32, eNvFlexBufferHost),
-		}
+			capacity: max,
+			ceiling,
+
+			shapes: vec![],
+			channels: vec![],
+
+			buffers: ShapeBuffers {
+				geometry: alloc(size_of::<NvFlexCollisionGeometry>())?,
+				positions: alloc(size_of::<Vector4>())?,
+				rotations: alloc(size_of::<Quat>())?,
+				previous_positions: alloc(size_of::<Vector4>())?,
+				previous_rotations: alloc(size_of::<Quat>())?,
+				flags: alloc(size_of::<i32>())?,
+			},
+		})
 	}
 
 	pub fn get_count(&self) -> usize {
@@ -121,23 +143,54 @@ impl ShapeState {
 		&self.shapes
 	}
 
-	pub fn register(&mut self, shape: Shape) -> Result<(), CreateError> {
-		let count = self.get_count();
+	pub fn get_capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Makes room for `count` shapes, growing the buffers up to the ceiling.
+	pub fn reserve(&mut self, backend: &mut dyn SolverBackend, count: usize) -> Result<(), CreateError> {
+		if count <= self.capacity {
+			return Ok(());
+		}
 
-		if count >= self.shapes.capacity() {
-			return Err( CreateError::Max );
+		let capacity = buffers::grow_capacity(self.capacity, count, self.ceiling)
+			.ok_or(CreateError::Max("shapes", self.ceiling))?;
+
+		let count = self.get_count();
+		let grown = buffers::realloc(backend, &mut [
+			(&mut self.buffers.geometry, size_of::<NvFlexCollisionGeometry>()),
+			(&mut self.buffers.positions, size_of::<Vector4>()),
+			(&mut self.buffers.rotations, size_of::<Quat>()),
+			(&mut self.buffers.previous_positions, size_of::<Vector4>()),
+			(&mut self.buffers.previous_rotations, size_of::<Quat>()),
+			(&mut self.buffers.flags, size_of::<i32>()),
+		], count, capacity);
+
+		if !grown {
+			return Err(CreateError::Alloc("shape"));
 		}
 
+		self.capacity = capacity;
+
+		// The old buffers are gone, so the solver has to be pointed at the new ones right away.
+		self.has_changes = true;
+		self.flush(backend);
+
+		Ok(())
+	}
+
+	pub fn register(&mut self, backend: &mut dyn SolverBackend, shape: Shape) -> Result<(), CreateError> {
+		let count = self.get_count();
+		self.reserve(backend, count + 1)?;
+
 		unsafe {
-			let geometry = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut NvFlexCollisionGeometry;
-			let positions = NvFlexMap(self.positions, eNvFlexMapWait) as *mut Vector4;
-			let rotations = NvFlexMap(self.rotations, eNvFlexMapWait) as *mut Quat;
-			let previous_positions =
-				NvFlexMap(self.previous_positions, eNvFlexMapWait) as *mut Vector4;
-			let previous_rotations =
-				NvFlexMap(self.previous_rotations, eNvFlexMapWait) as *mut Quat;
-
-			let flags = NvFlexMap(self.flags, eNvFlexMapWait) as *mut i32;
+			let geometry = backend.map(self.buffers.geometry) as *mut NvFlexCollisionGeometry;
+			let positions = backend.map(self.buffers.positions) as *mut Vector4;
+			let rotations = backend.map(self.buffers.rotations) as *mut Quat;
+			let previous_positions = backend.map(self.buffers.previous_positions) as *mut Vector4;
+			let previous_rotations = backend.map(self.buffers.previous_rotations) as *mut Quat;
+
+			let flags = backend.map(self.buffers.flags) as *mut i32;
 			let flag = NvFlexMakeShapeFlags(shape.kind(), false);
 
 			geometry.add(count).write(shape.as_union());
@@ -148,62 +201,55 @@ impl ShapeState {
 			previous_rotations.add(count).write(*shape.get_rot());
 
 			flags.add(count).write(flag);
-
-			self.unmap();
 		}
 
+		self.unmap(backend);
 		self.shapes.push(shape);
+		self.channels.push(channels::ALL);
 
 		self.has_changes = true;
 
 		Ok(())
 	}
 
-	pub fn unmap(&self) {
-		unsafe {
-			NvFlexUnmap(self.buffer);
-			NvFlexUnmap(self.positions);
-			NvFlexUnmap(self.rotations);
-			NvFlexUnmap(self.previous_positions);
-			NvFlexUnmap(self.previous_rotations);
-			NvFlexUnmap(self.flags);
-		}
+	/// Collision channels of the shape at `index`, or [None] if there's no shape there.
+	pub fn get_channels(&self, index: usize) -> Option<i32> {
+		self.channels.get(index).copied()
+	}
+
+	/// Puts the shape at `index` on the collision `channels`, returning false if there's no shape there.
+	pub fn set_channels(&mut self, backend: &mut dyn SolverBackend, index: usize, channels: i32) -> bool {
+		let Some(shape) = self.shapes.get(index) else {
+			return false;
+		};
+
+		let flags = backend.map(self.buffers.flags) as *mut i32;
+		unsafe { flags.add(index).write(NvFlexMakeShapeFlagsWithChannels(shape.kind(), false, channels)) };
+		backend.unmap(self.buffers.flags);
+
+		self.channels[index] = channels;
+		self.has_changes = true;
+
+		true
 	}
 
-	/// Pushes shape changes to the FleX state
-	/// # Safety
-	/// This is safe, assuming you don't manually map the buffers
-	pub fn flush(&mut self, solver: *mut NvFlexSolver) {
+	pub fn unmap(&self, backend: &mut dyn SolverBackend) {
+		backend.unmap(self.buffers.geometry);
+		backend.unmap(self.buffers.positions);
+		backend.unmap(self.buffers.rotations);
+		backend.unmap(self.buffers.previous_positions);
+		backend.unmap(self.buffers.previous_rotations);
+		backend.unmap(self.buffers.flags);
+	}
+
+	/// Pushes shape changes to the solver
+	pub fn flush(&mut self, backend: &mut dyn SolverBackend) {
 		if !self.has_changes {
 			return;
 		}
 
-		unsafe {
-			NvFlexSetShapes(
-				solver,
-				self.buffer,
-				self.positions,
-				self.rotations,
-				self.previous_positions,
-				self.previous_rotations,
-				self.flags,
-				self.get_count() as i32,
-			);
-		}
+		backend.set_shapes(&self.buffers, self.get_count());
 
 		self.has_changes = false;
 	}
 }
-
-impl Drop for ShapeState {
-	fn drop(&mut self) {
-		unsafe {
-			NvFlexFreeBuffer(self.buffer);
-			NvFlexFreeBuffer(self.positions);
-			NvFlexFreeBuffer(self.rotations);
-			NvFlexFreeBuffer(self.previous_positions);
-			NvFlexFreeBuffer(self.previous_rotations);
-			NvFlexFreeBuffer(self.flags);
-		}
-	}
-}
@@ -1,5 +1,5 @@
-use crate::types::{Vector4, Quat};
-use nvflex_sys::{NvFlexCollisionGeometry, NvFlexCollisionShapeType, NvFlexCapsuleGeometry, eNvFlexShapeSphere, NvFlexSphereGeometry};
+use crate::types::{Vector3, Vector4, Quat};
+use crate::sys::{NvFlexCollisionGeometry, NvFlexCollisionShapeType, NvFlexCapsuleGeometry, eNvFlexShapeSphere, NvFlexSphereGeometry};
 
 #[derive(Debug)]
 pub struct Sphere {
@@ -18,6 +18,18 @@ impl Sphere {
 		}
 	}
 
+	/// Signed distance from `point` to the surface of the sphere, and the outward normal closest to it.
+	pub fn signed_distance(&self, point: Vector3) -> (f32, Vector3) {
+		let offset = point - self.pos.xyz();
+		// Pick any direction if the point is right on the center
+		let normal = match offset.normalize() {
+			n if n == Vector3::ZERO => Vector3(0.0, 0.0, 1.0),
+			n => n,
+		};
+
+		(offset.length() - self.radius, normal)
+	}
+
 	pub fn as_union(&self) -> NvFlexCollisionGeometry {
 		NvFlexCollisionGeometry {
 			sphere: {
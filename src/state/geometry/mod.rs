@@ -1,8 +1,8 @@
-use nvflex_sys::*;
+use crate::sys::*;
 
 mod collision;
-pub use collision::ShapeState;
-pub use collision::cube::Cube;
+pub use collision::{Shape, ShapeState};
+pub use collision::{cube::Cube, capsule::Capsule, sphere::Sphere};
 
 mod triangles;
 pub use triangles::TriangleState;
@@ -1,77 +1,95 @@
-use nvflex_sys::*;
 use std::mem::size_of;
 
 use crate::{
+	backend::{Buffer, SolverBackend},
 	config,
 	types::{Quat, Vector3, Vector4},
 };
 
-use crate::FlexState;
+use crate::{FlexState, state::{buffers, CreateError, InitError}};
 
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct TriangleState {
-	max: i32,
+	/// How many triangles the buffers have room for, grown as needed up to the ceiling
+	capacity: i32,
+	ceiling: i32,
 	count: i32,
 	has_changes: bool,
 
-	pub buffer: *mut NvFlexBuffer,  // Vec<i32>
-	pub normals: *mut NvFlexBuffer, // Vec<Vector3>
-	pub uvs: *mut NvFlexBuffer,     // Vec<Vector3>
+	pub buffer: Buffer,  // Vec<i32>
+	pub normals: Buffer, // Vec<Vector3>
+	pub uvs: Buffer,     // Vec<Vector3>
 }
 
 impl TriangleState {
 	/// Allocates buffers used by the geometry state
-	/// # Safety
-	/// Do not call this function more than once
-	pub fn new(flex: *mut NvFlexLibrary, max: i32) -> Self {
-		unsafe {
-			Self {
-				max,
-				count: 0,
-				has_changes: false,
-
-				buffer: NvFlexAllocBuffer(flex, max, size_of::<i32>() as i32, eNvFlexBufferHost),
-				normals: NvFlexAllocBuffer(flex, max, size_of::<Vector3>() as i32, eNvFlexBufferHost),
-				uvs: NvFlexAllocBuffer(flex, max, size_of::<Vector3>() as i32, eNvFlexBufferHost),
-			}
-		}
+	pub fn new(backend: &mut dyn SolverBackend, max: i32, ceiling: i32) -> Result<Self, InitError> {
+		let mut alloc = |stride| backend.alloc(max as usize, stride).ok_or(InitError::Alloc("triangle"));
+
+		Ok(Self {
+			capacity: max,
+			ceiling,
+			count: 0,
+			has_changes: false,
+
+			buffer: alloc(size_of::<i32>())?,
+			normals: alloc(size_of::<Vector3>())?,
+			uvs: alloc(size_of::<Vector3>())?,
+		})
 	}
 
 	pub fn get_count(&self) -> i32 {
 		self.count
 	}
 
-	pub fn unmap(&self) {
-		unsafe {
-			NvFlexUnmap(self.buffer);
-			NvFlexUnmap(self.normals);
-			NvFlexUnmap(self.uvs);
-		}
+	pub fn get_capacity(&self) -> i32 {
+		self.capacity
 	}
 
-	/// Pushes shape changes to the FleX state
-	/// # Safety
-	/// Same as [ShapeState]
-	pub fn flush(&mut self, solver: *mut NvFlexSolver) {
-		if !self.has_changes {
-			return;
+	/// Makes room for `count` triangles, growing the buffers up to the ceiling.
+	pub fn reserve(&mut self, backend: &mut dyn SolverBackend, count: i32) -> Result<(), CreateError> {
+		if count <= self.capacity {
+			return Ok(());
 		}
 
-		unsafe {
-			NvFlexSetDynamicTriangles(solver, self.buffer, self.normals, self.count);
+		let capacity = buffers::grow_capacity(self.capacity as usize, count as usize, self.ceiling as usize)
+			.ok_or(CreateError::Max("triangles", self.ceiling as usize))?;
+
+		let grown = buffers::realloc(backend, &mut [
+			(&mut self.buffer, size_of::<i32>()),
+			(&mut self.normals, size_of::<Vector3>()),
+			(&mut self.uvs, size_of::<Vector3>()),
+		], self.count as usize, capacity);
+
+		if !grown {
+			return Err(CreateError::Alloc("triangle"));
 		}
 
-		self.has_changes = false;
+		// Can't be past the ceiling, which is an i32
+		self.capacity = capacity as i32;
+
+		// The old buffers are gone, so the solver has to be pointed at the new ones right away.
+		self.has_changes = true;
+		self.flush(backend);
+
+		Ok(())
 	}
-}
 
-impl Drop for TriangleState {
-	fn drop(&mut self) {
-		unsafe {
-			NvFlexFreeBuffer(self.buffer);
-			NvFlexFreeBuffer(self.normals);
-			NvFlexFreeBuffer(self.uvs);
+	pub fn unmap(&self, backend: &mut dyn SolverBackend) {
+		backend.unmap(self.buffer);
+		backend.unmap(self.normals);
+		backend.unmap(self.uvs);
+	}
+
+	/// Pushes triangle changes to the solver
+	pub fn flush(&mut self, backend: &mut dyn SolverBackend) {
+		if !self.has_changes {
+			return;
 		}
+
+		backend.set_triangles(self.buffer, self.normals, self.count as usize);
+
+		self.has_changes = false;
 	}
 }
@@ -0,0 +1,188 @@
+// Removing particles once their lifetime runs out or they end up inside a kill volume.
+use std::collections::BTreeMap;
+
+use super::particle::ParticleState;
+use crate::{
+	backend::SolverBackend,
+	types::{Quat, Vector3},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum KillError {
+	#[error("Invalid kill volume {0}, must be finite")]
+	NotFinite(&'static str),
+
+	#[error("Invalid kill volume extents, must all be zero or greater")]
+	Extents,
+}
+
+/// Box removing any particle inside it, axis aligned when its rotation is [Quat::IDENTITY].
+#[derive(Debug, Clone, Copy)]
+pub struct KillVolume {
+	pos: Vector3,
+	rot: Quat,
+	/// Half the size of the box along each of its axes
+	extents: Vector3,
+}
+
+impl KillVolume {
+	pub fn new(pos: Vector3, rot: Quat, extents: Vector3) -> Result<Self, KillError> {
+		let finite = |v: Vector3| v.0.is_finite() && v.1.is_finite() && v.2.is_finite();
+
+		if !finite(pos) {
+			return Err(KillError::NotFinite("position"));
+		}
+
+		if ![rot.0, rot.1, rot.2, rot.3].iter().all(|x| x.is_finite()) {
+			return Err(KillError::NotFinite("rotation"));
+		}
+
+		if !finite(extents) || extents.0 < 0.0 || extents.1 < 0.0 || extents.2 < 0.0 {
+			return Err(KillError::Extents);
+		}
+
+		Ok(Self { pos, rot: rot.normalize(), extents })
+	}
+
+	/// Axis aligned box from `min` to `max`.
+	pub fn from_bounds(min: Vector3, max: Vector3) -> Result<Self, KillError> {
+		Self::new((min + max) * 0.5, Quat::IDENTITY, (max - min) * 0.5)
+	}
+
+	pub fn contains(&self, point: Vector3) -> bool {
+		let local = self.rot.conjugate().rotate(point - self.pos);
+		local.0.abs() <= self.extents.0 && local.1.abs() <= self.extents.1 && local.2.abs() <= self.extents.2
+	}
+}
+
+#[derive(Debug, Default)]
+pub struct KillState {
+	volumes: BTreeMap<u32, KillVolume>,
+	next_id: u32,
+
+	/// Particles removed by the last [KillState::cull] that got to run
+	removed_last: usize,
+	removed_total: u64,
+}
+
+impl KillState {
+	/// Adds a kill volume, returning its id.
+	pub fn add(&mut self, volume: KillVolume) -> u32 {
+		let id = self.next_id;
+		self.next_id = self.next_id.wrapping_add(1);
+
+		self.volumes.insert(id, volume);
+		id
+	}
+
+	pub fn remove(&mut self, id: u32) -> bool {
+		self.volumes.remove(&id).is_some()
+	}
+
+	pub fn clear(&mut self) {
+		self.volumes.clear();
+	}
+
+	pub fn get_count(&self) -> usize {
+		self.volumes.len()
+	}
+
+	/// Particles removed by the last tick that stepped the simulation, and in total.
+	pub fn get_removed(&self) -> (usize, u64) {
+		(self.removed_last, self.removed_total)
+	}
+
+	/// Ages particles by `dt` seconds, then removes any inside a kill volume. Returns how many were removed.
+	/// Removal only updates which particles are active, so still needs [ParticleState::flush]ing.
+	pub fn cull(&mut self, particles: &mut ParticleState, backend: &mut dyn SolverBackend, dt: f32) -> usize {
+		let mut removed = particles.age(dt);
+
+		// Saves reading back every particle when there's nothing to check them against
+		if !self.volumes.is_empty() {
+			removed += particles.remove_where(backend, |pos| self.volumes.values().any(|volume| volume.contains(pos)));
+		}
+
+		self.removed_last = removed;
+		self.removed_total += removed as u64;
+
+		removed
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, helper::NvFlexMakePhase, sys::eNvFlexPhaseFluid, types::{ParticleHandle, Vector4}};
+
+	fn setup() -> (CpuBackend, ParticleState) {
+		let mut backend = CpuBackend::new();
+		let particles = ParticleState::new(&mut backend, 8, 8).unwrap();
+		(backend, particles)
+	}
+
+	fn create(backend: &mut CpuBackend, particles: &mut ParticleState, x: f32, active: bool) -> ParticleHandle {
+		particles.create(backend, Vector4(x, 0.0, 0.0, 1.0), Vector3::ZERO, NvFlexMakePhase(0, eNvFlexPhaseFluid), active).unwrap()
+	}
+
+	#[test]
+	fn volumes_can_be_rotated() {
+		let aligned = KillVolume::new(Vector3::ZERO, Quat::IDENTITY, Vector3(2.0, 1.0, 1.0)).unwrap();
+		assert!(aligned.contains(Vector3(1.5, 0.0, 0.0)));
+		assert!(!aligned.contains(Vector3(0.0, 1.5, 0.0)));
+
+		// Turned a quarter around z, so it's long along y instead
+		let half = std::f32::consts::FRAC_1_SQRT_2;
+		let turned = KillVolume::new(Vector3::ZERO, Quat(0.0, 0.0, half, half), Vector3(2.0, 1.0, 1.0)).unwrap();
+		assert!(!turned.contains(Vector3(1.5, 0.0, 0.0)));
+		assert!(turned.contains(Vector3(0.0, 1.5, 0.0)));
+
+		let bounds = KillVolume::from_bounds(Vector3(1.0, 1.0, 1.0), Vector3(3.0, 2.0, 2.0)).unwrap();
+		assert!(bounds.contains(Vector3(3.0, 1.5, 1.0)));
+		assert!(!bounds.contains(Vector3(0.5, 1.5, 1.5)));
+	}
+
+	#[test]
+	fn invalid_volumes_are_rejected() {
+		assert!(matches!(KillVolume::new(Vector3(f32::NAN, 0.0, 0.0), Quat::IDENTITY, Vector3::ZERO), Err(KillError::NotFinite("position"))));
+		assert!(matches!(KillVolume::new(Vector3::ZERO, Quat(f32::INFINITY, 0.0, 0.0, 1.0), Vector3::ZERO), Err(KillError::NotFinite("rotation"))));
+		assert!(matches!(KillVolume::new(Vector3::ZERO, Quat::IDENTITY, Vector3(1.0, -1.0, 1.0)), Err(KillError::Extents)));
+		assert!(matches!(KillVolume::from_bounds(Vector3(1.0, 0.0, 0.0), Vector3::ZERO), Err(KillError::Extents)));
+	}
+
+	#[test]
+	fn culls_active_particles_inside_volumes() {
+		let (mut backend, mut particles) = setup();
+		for (x, active) in [(0.0, true), (5.0, true), (5.5, false), (10.0, true)] {
+			create(&mut backend, &mut particles, x, active);
+		}
+		particles.flush(&mut backend);
+
+		let mut kill = KillState::default();
+		kill.add(KillVolume::from_bounds(Vector3(4.0, -1.0, -1.0), Vector3(6.0, 1.0, 1.0)).unwrap());
+		let id = kill.add(KillVolume::from_bounds(Vector3(9.0, -1.0, -1.0), Vector3(11.0, 1.0, 1.0)).unwrap());
+		assert!(kill.remove(id));
+
+		// The inactive particle isn't being simulated, so it's left alone
+		assert_eq!(kill.cull(&mut particles, &mut backend, 0.1), 1);
+		assert_eq!((particles.get_count(), particles.get_active_count()), (3, 2));
+
+		assert_eq!(kill.cull(&mut particles, &mut backend, 0.1), 0);
+		assert_eq!(kill.get_removed(), (0, 1));
+	}
+
+	#[test]
+	fn lifetimes_run_out_while_active() {
+		let (mut backend, mut particles) = setup();
+		let handles = [(0.0, true), (1.0, true), (2.0, false)].map(|(x, active)| create(&mut backend, &mut particles, x, active));
+		particles.set_lifetime(handles[0], Some(0.15));
+		particles.set_lifetime(handles[2], Some(0.15));
+
+		let mut kill = KillState::default();
+		assert_eq!(kill.cull(&mut particles, &mut backend, 0.1), 0);
+		assert_eq!(kill.cull(&mut particles, &mut backend, 0.1), 1);
+
+		assert!(!particles.is_valid(handles[0]));
+		assert!(particles.is_valid(handles[1]) && particles.is_valid(handles[2]));
+		assert_eq!(kill.get_removed(), (1, 1));
+	}
+}
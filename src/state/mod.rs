@@ -1,130 +1,194 @@
-use std::mem::MaybeUninit;
-use std::time::Instant;
-
 // State holding all of the data for FleX.
 use crate::{
-	config,
+	backend::SolverBackend,
+	config::{self, Config},
 	helper::*,
+	sys::*,
 	types::{Particle, Quat, Vector3, Vector4},
 };
 
-use nvflex_sys::*;
-
 mod geometry;
 pub use geometry::*;
 
 mod particle;
 use particle::ParticleState;
+pub use particle::{Phase, PhaseError, DEFAULT_IMASS};
+
+mod buffers;
+
+pub mod errors;
+
+mod clock;
+pub use clock::{Clock, ClockError};
+
+pub mod params;
+pub mod presets;
+
+mod planes;
+pub use planes::{planes_of, PlaneError};
+
+mod rng;
+
+pub mod channels;
+pub use channels::ChannelError;
+
+mod emitters;
+pub use emitters::{Emitter, EmitterError, EmitterShape, EmitterState};
+
+mod fill;
+pub use fill::{FillError, FillOptions};
+
+mod forces;
+pub use forces::{Falloff, ForceError, ForceField, ForceKind, ForceState};
+
+mod kill;
+pub use kill::{KillError, KillState, KillVolume};
+
+mod query;
+pub use query::{QueryError, QueryHit, QueryState, QueryStats, TraceHit};
 
 #[derive(Debug, thiserror::Error)]
 pub enum CreateError {
-	#[error("Reached maximum number of shapes")]
-	Max
+	#[error("Reached the maximum of {1} {0}")]
+	Max(&'static str, usize),
+
+	#[error("Failed to grow {0} buffers")]
+	Alloc(&'static str),
+
+	#[error("Failed to grow the solver: {0}")]
+	Solver(#[from] InitError),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum InitError {
+	#[error("Failed to initialize the FleX library")]
+	Library,
+
+	#[error("Failed to create solver")]
+	Solver,
+
+	#[error("Failed to allocate {0} buffers")]
+	Alloc(&'static str),
+
+	#[error("Failed to start simulation thread")]
+	Thread,
 }
 
 #[derive(Debug)]
 pub struct FlexState {
 	/* Shared */
-	instant: Instant,
-	lib: *mut NvFlexLibrary,
-
-	/// Note this will most likely be null.
-	desc: *mut NvFlexInitDesc,
-
-	solver_desc: NvFlexSolverDesc,
-	pub solver: *mut NvFlexSolver,
+	pub clock: Clock,
+	pub backend: Box<dyn SolverBackend>,
+	params: NvFlexParams,
+	/// Id of each plane in `params.planes`, see [FlexState::add_plane]
+	plane_ids: Vec<u32>,
+	next_plane_id: u32,
 
 	pub particles: ParticleState,
+	pub emitters: EmitterState,
+	pub forces: ForceState,
+	pub kill: KillState,
+	pub query: QueryState,
 
 	pub shapes: ShapeState,
 	pub triangles: TriangleState,
 }
 
 impl FlexState {
-	pub unsafe fn new(error_handler: Option<unsafe extern "C" fn(type_: NvFlexErrorSeverity, msg: *const i8, file: *const i8, line: i32)>) -> Self {
-		use std::mem::size_of;
+	pub fn try_new(mut backend: Box<dyn SolverBackend>, config: &Config) -> Result<Self, InitError> {
+		backend.reserve_particles(config.max_particles)?;
 
-		let flex = NvFlexInit(NV_FLEX_VERSION as i32, error_handler, std::ptr::null_mut());
-		if flex.is_null() {
-			// Should never happen. If this does happen this should return a Result<Self, E> again
-			panic!("Failed to create Flex Library");
-		}
+		let particles = ParticleState::new(backend.as_mut(), config.max_particles, config.particle_ceiling)?;
+		let shapes = ShapeState::new(backend.as_mut(), config.max_shapes, config.shape_ceiling)?;
+		let triangles = TriangleState::new(backend.as_mut(), config.max_triangles, config.triangle_ceiling)?;
 
-		// Create default solver settings
-		let mut solver_desc = MaybeUninit::<NvFlexSolverDesc>::uninit();
-		NvFlexSetSolverDescDefaults(solver_desc.as_mut_ptr());
-		let solver_desc = solver_desc.assume_init();
+		backend.set_params(&config.params);
 
-		let solver = NvFlexCreateSolver(flex, &solver_desc);
-		let particles = ParticleState::new(flex, config::MAX_PARTICLES);
-		let shapes = ShapeState::new(flex, config::MAX_SHAPES);
-		let triangles = TriangleState::new(flex, config::MAX_TRIANGLES);
-
-		NvFlexSetParams(solver, &config::PARAMS);
-
-		Self {
-			instant: Instant::now(),
-			lib: flex,
-
-			desc: std::ptr::null_mut(),
-
-			solver_desc,
-			solver,
+		let mut state = Self {
+			clock: Clock::new(config.time_step, config.substeps, config.max_steps),
+			backend,
+			params: config.params,
+			plane_ids: vec![],
+			next_plane_id: 0,
 
 			particles,
+			emitters: EmitterState::default(),
+			forces: ForceState::default(),
+			kill: KillState::default(),
+			query: QueryState::default(),
 
 			shapes,
 			triangles,
-		}
+		};
+
+		// Planes from the config need ids too
+		state.sync_plane_ids(&config.params);
+
+		Ok(state)
 	}
 
 	/// Loads default objects / scene
-	pub fn init(&mut self) {
-		let baseplate = Cube::new( config::BASEPLATE, config::BASEPLATE_ROT, config::BASEPLATE_SIZE );
-
-		let fluid = NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid);
-
-		self.particles.factory(|mut factory| {
-			for x in 0..5 {
-				for y in 0..5 {
-					for z in 0..5 {
-						factory.create(
-							Vector4(50.0 * x as f32, 50.0 * y as f32, 5000.0 - (z as f32 * 50.0), 2.0),
-							Vector3(0.0, 0.0, -5.0),
-							fluid,
-							true
-						);
-					}
-				}
-			}
-		});
-
-		// This will call all of the NvFlexSet* functions
-		self.particles.flush(self.solver);
-		self.shapes.flush(self.solver);
-		self.triangles.flush(self.solver);
+	pub fn init(&mut self, config: &Config) {
+		if config.baseplate {
+			let baseplate = Cube::new( config.baseplate_pos, config.baseplate_rot, config.baseplate_size );
+			// Can't hit the shape limit, there's nothing else yet
+			let _ = self.shapes.register(self.backend.as_mut(), baseplate.into());
+		}
+
+		// 5x5x5 lattice of fluid falling from the sky
+		let lattice = Cube::new(Vector4(100.0, 100.0, 4900.0, 0.0), Quat::IDENTITY, [100.0, 100.0, 100.0]);
+		let opts = FillOptions {
+			spacing: 50.0,
+			velocity: Vector3(0.0, 0.0, -5.0),
+			..FillOptions::new(&self.params)
+		};
+
+		// Only fails past the particle ceiling, in which case there's just no fluid.
+		let _ = self.fill(&lattice.into(), &opts);
+
+		// This will upload everything to the solver
+		self.flush();
 	}
 
-	pub fn tick(&mut self) {
-		let dt = self.instant.elapsed();
-		self.instant = Instant::now();
-		unsafe {
-			NvFlexUpdateSolver(self.solver, dt.as_secs_f32(), 1, false);
-		}
+	pub fn get_params(&self) -> &NvFlexParams {
+		&self.params
 	}
 
-	#[inline(always)]
-	pub unsafe fn get(&self) -> Option<Vec<Particle>> {
-		self.particles.get(self.solver)
+	/// Replaces the solver parameters, see [params] for validating them.
+	pub fn set_params(&mut self, params: NvFlexParams) {
+		let old = std::mem::replace(&mut self.params, params);
+		self.sync_plane_ids(&old);
+		self.backend.set_params(&self.params);
 	}
-}
 
-impl Drop for FlexState {
-	/// Consumes the FlexState, properly releasing allocated resources.
-	fn drop(&mut self) {
-		unsafe {
-			NvFlexDestroySolver(self.solver);
-			NvFlexShutdown(self.lib);
+	/// Pushes all pending particle, shape and triangle changes to the solver
+	pub fn flush(&mut self) {
+		self.particles.flush(self.backend.as_mut());
+		self.shapes.flush(self.backend.as_mut());
+		self.triangles.flush(self.backend.as_mut());
+	}
+
+	pub fn tick(&mut self) {
+		let steps = self.clock.advance();
+		for _ in 0..steps {
+			// Emitted particles need to be in the solver before the step that moves them
+			self.emitters.emit(&mut self.particles, self.backend.as_mut(), self.clock.get_step());
+			self.forces.apply(&mut self.particles, self.backend.as_mut(), self.clock.get_step());
+			self.particles.flush(self.backend.as_mut());
+
+			self.backend.update(self.clock.get_step(), self.clock.get_substeps());
+		}
+
+		if steps > 0 {
+			self.kill.cull(&mut self.particles, self.backend.as_mut(), self.clock.get_step() * steps as f32);
+			self.particles.flush(self.backend.as_mut());
+
+			self.query.invalidate();
 		}
 	}
+
+	#[inline(always)]
+	pub fn get(&mut self) -> Option<Vec<Particle<'_>>> {
+		self.particles.get(self.backend.as_mut())
+	}
 }
@@ -0,0 +1,246 @@
+// Reading and validating solver parameters by name, for editing them at runtime.
+use crate::{config::MAX_PLANES, sys::*};
+
+/// A single parameter's value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ParamValue {
+	Number(f32),
+	Integer(i32),
+	Vector([f32; 3]),
+}
+
+impl ParamValue {
+	pub fn kind(&self) -> &'static str {
+		match self {
+			Self::Number(_) => "number",
+			Self::Integer(_) => "integer",
+			Self::Vector(_) => "vector",
+		}
+	}
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParamError {
+	#[error("Unknown parameter: `{0}`")]
+	Unknown(String),
+
+	#[error("`{0}` is read only")]
+	ReadOnly(&'static str),
+
+	#[error("Expected {1} for `{0}`, got {2}")]
+	Type(&'static str, &'static str, &'static str),
+
+	#[error("Invalid value for `{0}`: must be {1}")]
+	Range(&'static str, &'static str),
+
+	#[error("Too many planes: `{0}`, at most {} are supported", MAX_PLANES)]
+	TooManyPlanes(usize),
+
+	#[error("Rest distances must not be greater than the radius")]
+	RestDistance,
+}
+
+fn finite(x: f32) -> bool {
+	x.is_finite()
+}
+
+fn positive(x: f32) -> bool {
+	x.is_finite() && x > 0.0
+}
+
+fn non_negative(x: f32) -> bool {
+	x.is_finite() && x >= 0.0
+}
+
+fn unit(x: f32) -> bool {
+	(0.0..=1.0).contains(&x)
+}
+
+macro_rules! params {
+	($($name:ident: $kind:ident = $check:ident => $msg:literal),* $(,)?) => {
+		/// Every parameter that can be set by name.
+		/// Planes aren't included, see [set_planes].
+		pub const NAMES: &[&str] = &[$(stringify!($name)),*];
+
+		/// Returns the value of a parameter by name, or [None] if there's no such parameter.
+		pub fn get(params: &NvFlexParams, name: &str) -> Option<ParamValue> {
+			match name {
+				$(stringify!($name) => Some(ParamValue::$kind(params.$name)),)*
+				"numPlanes" => Some(ParamValue::Integer(params.numPlanes)),
+				_ => None,
+			}
+		}
+
+		/// Sets a parameter by name, checking that the value is in range.
+		pub fn set(params: &mut NvFlexParams, name: &str, value: ParamValue) -> Result<(), ParamError> {
+			match name {
+				$(stringify!($name) => {
+					let expected = ParamValue::$kind(Default::default()).kind();
+					let ParamValue::$kind(value) = value else {
+						return Err(ParamError::Type(stringify!($name), expected, value.kind()));
+					};
+
+					if !$check(value) {
+						return Err(ParamError::Range(stringify!($name), $msg));
+					}
+
+					params.$name = value;
+					Ok(())
+				})*
+				"numPlanes" => Err(ParamError::ReadOnly("numPlanes")),
+				_ => Err(ParamError::Unknown(name.to_owned())),
+			}
+		}
+	};
+}
+
+fn iterations(x: i32) -> bool {
+	(1..=100).contains(&x)
+}
+
+fn relaxation_mode(x: i32) -> bool {
+	x == eNvFlexRelaxationGlobal || x == eNvFlexRelaxationLocal
+}
+
+fn vector(v: [f32; 3]) -> bool {
+	v.iter().all(|x| x.is_finite())
+}
+
+fn non_negative_int(x: i32) -> bool {
+	x >= 0
+}
+
+params! {
+	numIterations: Integer = iterations => "between 1 and 100",
+	gravity: Vector = vector => "finite",
+	radius: Number = positive => "finite and greater than zero",
+	solidRestDistance: Number = non_negative => "finite and zero or greater",
+	fluidRestDistance: Number = non_negative => "finite and zero or greater",
+	dynamicFriction: Number = non_negative => "finite and zero or greater",
+	staticFriction: Number = non_negative => "finite and zero or greater",
+	particleFriction: Number = non_negative => "finite and zero or greater",
+	restitution: Number = unit => "between 0 and 1",
+	adhesion: Number = non_negative => "finite and zero or greater",
+	sleepThreshold: Number = non_negative => "finite and zero or greater",
+	maxSpeed: Number = positive => "finite and greater than zero",
+	maxAcceleration: Number = positive => "finite and greater than zero",
+	shockPropagation: Number = non_negative => "finite and zero or greater",
+	dissipation: Number = non_negative => "finite and zero or greater",
+	damping: Number = non_negative => "finite and zero or greater",
+	wind: Vector = vector => "finite",
+	drag: Number = non_negative => "finite and zero or greater",
+	lift: Number = non_negative => "finite and zero or greater",
+	cohesion: Number = non_negative => "finite and zero or greater",
+	surfaceTension: Number = non_negative => "finite and zero or greater",
+	viscosity: Number = non_negative => "finite and zero or greater",
+	vorticityConfinement: Number = non_negative => "finite and zero or greater",
+	anisotropyScale: Number = non_negative => "finite and zero or greater",
+	anisotropyMin: Number = non_negative => "finite and zero or greater",
+	anisotropyMax: Number = non_negative => "finite and zero or greater",
+	smoothing: Number = unit => "between 0 and 1",
+	solidPressure: Number = non_negative => "finite and zero or greater",
+	freeSurfaceDrag: Number = non_negative => "finite and zero or greater",
+	buoyancy: Number = finite => "finite",
+	diffuseThreshold: Number = non_negative => "finite and zero or greater",
+	diffuseBuoyancy: Number = finite => "finite",
+	diffuseDrag: Number = non_negative => "finite and zero or greater",
+	diffuseBallistic: Integer = non_negative_int => "zero or greater",
+	diffuseLifetime: Number = non_negative => "finite and zero or greater",
+	collisionDistance: Number = non_negative => "finite and zero or greater",
+	particleCollisionMargin: Number = non_negative => "finite and zero or greater",
+	shapeCollisionMargin: Number = non_negative => "finite and zero or greater",
+	relaxationMode: Integer = relaxation_mode => "0 (global) or 1 (local)",
+	relaxationFactor: Number = non_negative => "finite and zero or greater",
+}
+
+/// Replaces the collision planes, each being `[a, b, c, d]` of the plane equation `ax + by + cz + d = 0`.
+pub fn set_planes(params: &mut NvFlexParams, planes: &[[f32; 4]]) -> Result<(), ParamError> {
+	if planes.len() > MAX_PLANES {
+		return Err(ParamError::TooManyPlanes(planes.len()));
+	}
+
+	if planes.iter().flatten().any(|x| !x.is_finite()) {
+		return Err(ParamError::Range("planes", "finite"));
+	}
+
+	params.planes = [[0.0; 4]; MAX_PLANES];
+	params.planes[..planes.len()].copy_from_slice(planes);
+	params.numPlanes = planes.len() as i32;
+
+	Ok(())
+}
+
+/// Checks constraints between parameters, after they've each been set.
+pub fn validate(params: &NvFlexParams) -> Result<(), ParamError> {
+	if params.solidRestDistance > params.radius || params.fluidRestDistance > params.radius {
+		return Err(ParamError::RestDistance);
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::PARAMS;
+
+	#[test]
+	fn non_finite_numbers_are_rejected() {
+		let mut params = PARAMS;
+		for name in NAMES {
+			for x in [f32::NAN, f32::INFINITY, f32::NEG_INFINITY] {
+				let value = match get(&params, name).unwrap() {
+					ParamValue::Number(_) => ParamValue::Number(x),
+					ParamValue::Vector(_) => ParamValue::Vector([0.0, x, 0.0]),
+					ParamValue::Integer(_) => continue,
+				};
+
+				assert!(matches!(set(&mut params, name, value), Err(ParamError::Range(..))), "{} accepted {}", name, x);
+			}
+		}
+
+		assert_eq!(params.radius, PARAMS.radius);
+		assert_eq!(params.maxSpeed, PARAMS.maxSpeed);
+	}
+
+	#[test]
+	fn values_are_checked_by_name() {
+		let mut params = PARAMS;
+		set(&mut params, "viscosity", ParamValue::Number(0.5)).unwrap();
+		assert_eq!(get(&params, "viscosity"), Some(ParamValue::Number(0.5)));
+
+		assert!(matches!(set(&mut params, "viscosity", ParamValue::Number(-1.0)), Err(ParamError::Range("viscosity", _))));
+		assert!(matches!(set(&mut params, "restitution", ParamValue::Number(1.5)), Err(ParamError::Range("restitution", _))));
+		assert!(matches!(set(&mut params, "numIterations", ParamValue::Integer(0)), Err(ParamError::Range("numIterations", _))));
+		assert!(matches!(set(&mut params, "viscosity", ParamValue::Integer(1)), Err(ParamError::Type("viscosity", "number", "integer"))));
+		assert!(matches!(set(&mut params, "numPlanes", ParamValue::Integer(1)), Err(ParamError::ReadOnly(_))));
+		assert!(matches!(set(&mut params, "nope", ParamValue::Number(1.0)), Err(ParamError::Unknown(_))));
+		assert_eq!(get(&params, "nope"), None);
+
+		assert_eq!(params.viscosity, 0.5);
+	}
+
+	#[test]
+	fn planes_replace_the_old_ones() {
+		let mut params = PARAMS;
+		set_planes(&mut params, &[[0.0, 0.0, 1.0, 0.0], [1.0, 0.0, 0.0, 5.0]]).unwrap();
+		set_planes(&mut params, &[[0.0, 1.0, 0.0, 2.0]]).unwrap();
+		assert_eq!(params.numPlanes, 1);
+		assert_eq!(params.planes[0], [0.0, 1.0, 0.0, 2.0]);
+		assert_eq!(params.planes[1], [0.0; 4]);
+
+		assert!(matches!(set_planes(&mut params, &[[0.0; 4]; MAX_PLANES + 1]), Err(ParamError::TooManyPlanes(_))));
+		assert!(matches!(set_planes(&mut params, &[[f32::NAN, 0.0, 1.0, 0.0]]), Err(ParamError::Range("planes", _))));
+		assert_eq!(params.numPlanes, 1);
+	}
+
+	#[test]
+	fn rest_distances_are_within_the_radius() {
+		let mut params = PARAMS;
+		params.fluidRestDistance = params.radius;
+		assert!(validate(&params).is_ok());
+
+		params.solidRestDistance = params.radius * 2.0;
+		assert!(matches!(validate(&params), Err(ParamError::RestDistance)));
+	}
+}
@@ -0,0 +1,40 @@
+// Tracking which parts of a buffer changed since they were last uploaded.
+use std::ops::Range;
+
+/// Spans of a buffer covering every element changed since it was last uploaded, sorted and apart from each other.
+/// Spans that touch are merged, but ones further apart are kept separate so nothing between them gets uploaded.
+/// What's between them may be older than what the solver has, and uploading it would undo the simulation.
+#[derive(Debug, Default, Clone)]
+pub struct DirtyRanges(Vec<Range<usize>>);
+
+impl DirtyRanges {
+	pub fn mark(&mut self, range: Range<usize>) {
+		if range.is_empty() {
+			return;
+		}
+
+		// Spans touching the new one, which get merged into it
+		let first = self.0.partition_point(|dirty| dirty.end < range.start);
+		let last = self.0.partition_point(|dirty| dirty.start <= range.end);
+
+		let merged = match &self.0[first..last] {
+			[] => range,
+			[head, .., tail] | [head @ tail] => head.start.min(range.start)..tail.end.max(range.end),
+		};
+
+		self.0.splice(first..last, [merged]);
+	}
+
+	pub fn mark_index(&mut self, index: usize) {
+		self.mark(index..index + 1);
+	}
+
+	pub fn is_dirty(&self) -> bool {
+		!self.0.is_empty()
+	}
+
+	/// Takes the spans to upload, leaving the buffer clean.
+	pub fn take(&mut self) -> Vec<Range<usize>> {
+		std::mem::take(&mut self.0)
+	}
+}
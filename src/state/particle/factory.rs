@@ -1,45 +1,104 @@
-use crate::types::*;
+use crate::{backend::SolverBackend, types::*};
 
-#[derive(Debug)]
-pub struct ParticleFactory {
+use super::{CreateError, ParticleState};
+
+/// Keeps the particle buffers mapped while creating particles, see [ParticleState::factory].
+#[derive(derivative::Derivative)]
+#[derivative(Debug)]
+pub struct ParticleFactory<'a> {
 	pub nparticles: usize,
 
+	state: &'a mut ParticleState,
+	#[derivative(Debug = "ignore")]
+	backend: &'a mut dyn SolverBackend,
+
 	/// Return values from NvFlexMap(...)
 	buffer: *mut Vector4,
 	velocities: *mut Vector3,
 	phases: *mut i32,
-	active_indices: *mut i32,
 }
 
-impl ParticleFactory {
-	pub fn new(
-		offset: Option<usize>,
-		buffer: *mut Vector4,
-		velocities: *mut Vector3,
-		phases: *mut i32,
-		indices: *mut i32,
-	) -> Self {
-		Self {
-			nparticles: offset.unwrap_or(0),
-
-			buffer,
-			velocities,
-			phases,
-			active_indices: indices,
-		}
+impl<'a> ParticleFactory<'a> {
+	pub fn new(state: &'a mut ParticleState, backend: &'a mut dyn SolverBackend) -> Self {
+		let mut factory = Self {
+			nparticles: 0,
+
+			state,
+			backend,
+
+			buffer: std::ptr::null_mut(),
+			velocities: std::ptr::null_mut(),
+			phases: std::ptr::null_mut(),
+		};
+
+		factory.map();
+		factory
+	}
+
+	fn map(&mut self) {
+		self.buffer = self.backend.map(self.state.buffer) as *mut Vector4;
+		self.velocities = self.backend.map(self.state.velocities) as *mut Vector3;
+		self.phases = self.backend.map(self.state.phases) as *mut i32;
+	}
+
+	fn unmap(&mut self) {
+		self.backend.unmap(self.state.buffer);
+		self.backend.unmap(self.state.velocities);
+		self.backend.unmap(self.state.phases);
 	}
 
-	pub fn create(&mut self, pos: Vector4, velocity: Vector3, phase: i32, _active: bool) {
-		let index = self.nparticles;
+	/// Writes a new particle into a free slot, growing the buffers first if there are none. Returns a handle to it.
+	/// The collision channels of `phase` are replaced with the ones of its group, see [ParticleState::set_group_channels].
+	pub fn create(&mut self, pos: Vector4, velocity: Vector3, phase: i32, active: bool) -> Result<ParticleHandle, CreateError> {
+		let index = match self.state.free.pop() {
+			Some(index) => index,
+			None => {
+				let index = self.state.particles.len();
+				if index >= self.state.capacity {
+					// Growing replaces the buffers, so they need to be mapped again after.
+					self.unmap();
+					let grown = self.state.reserve(self.backend, index + 1);
+					self.map();
+					grown?;
+				}
+
+				self.state.particles.push(None);
+				self.state.generations.push(0);
+				self.state.lifetimes.push(None);
+				index
+			}
+		};
 
 		unsafe {
 			self.buffer.add(index).write(pos);
 			self.velocities.add(index).write(velocity);
-			self.phases.add(index).write(phase);
+			self.phases.add(index).write(self.state.with_channels(phase));
+		}
 
-			// Assumes particle is active for now.
-			self.active_indices.add(index).write(index as i32);
+		self.state.particles[index] = Some(active);
+		self.state.lifetimes[index] = None;
+		if active {
+			self.state.active_count += 1;
+			self.state.active_changed = true;
 		}
+
+		// Only the slot written to gets uploaded, so the rest of the solver's particles carry on as they were.
+		self.state.dirty_positions.mark_index(index);
+		self.state.dirty_velocities.mark_index(index);
+		self.state.dirty_phases.mark_index(index);
 		self.nparticles += 1;
+
+		Ok(self.state.handle(index))
+	}
+
+	/// Gives a particle created by this factory a lifetime, see [ParticleState::set_lifetime].
+	pub fn set_lifetime(&mut self, handle: ParticleHandle, lifetime: Option<f32>) -> bool {
+		self.state.set_lifetime(handle, lifetime)
+	}
+}
+
+impl Drop for ParticleFactory<'_> {
+	fn drop(&mut self) {
+		self.unmap();
 	}
 }
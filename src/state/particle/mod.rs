@@ -1,194 +1,789 @@
-use nvflex_sys::*;
-
-use crate::{config, types::*};
+use crate::{
+	backend::{Buffer, ParticleBuffers, SolverBackend, VelocityFn},
+	config,
+	types::*,
+};
+use super::{buffers, channels, CreateError, InitError};
+use crate::sys::{eNvFlexPhaseGroupMask, eNvFlexPhaseShapeChannelMask};
+use std::collections::BTreeMap;
 use std::mem::size_of;
 
 mod factory;
 
+mod phase;
+pub use phase::{Phase, PhaseError};
+
+mod dirty;
+use dirty::DirtyRanges;
+
+/// Inverse mass of particles created without one
+pub const DEFAULT_IMASS: f32 = 2.0;
+
 #[derive(derivative::Derivative)]
 #[derivative(Debug)]
 pub struct ParticleState {
+	/// The buffers were replaced, so everything in them has to be uploaded again
 	has_changes: bool,
 
-	// (Index, Active)
-	particles: Vec<(usize, bool)>,
-
-	pub buffer: *mut NvFlexBuffer,
-	pub velocities: *mut NvFlexBuffer,
-	pub phases: *mut NvFlexBuffer,
-	pub active_indices: *mut NvFlexBuffer,
+	/// How many particles the buffers have room for, grown as needed up to the ceiling
+	capacity: usize,
+	ceiling: usize,
+
+	/// Only which particles are active changed, so the particle data doesn't need uploading again
+	active_changed: bool,
+	/// Parts of the buffers changed on their own, uploaded without the rest unless `has_changes` uploads everything anyway
+	dirty_positions: DirtyRanges,
+	dirty_velocities: DirtyRanges,
+	dirty_phases: DirtyRanges,
+
+	/// Whether the particle in each slot of the buffers is active, [None] for free slots
+	particles: Vec<Option<bool>>,
+	/// Generation of each slot, bumped whenever its particle is removed so old handles to it go stale
+	generations: Vec<u32>,
+	/// Seconds each particle has left before it's removed, [None] for ones that live until removed
+	lifetimes: Vec<Option<f32>>,
+	/// Slots of removed particles, reused before the buffers grow
+	free: Vec<usize>,
+	active_count: usize,
+	/// Bumped every time changes are flushed to the solver, so copies of the particles can tell they're outdated
+	revision: u64,
+
+	/// Collision channels of each group that isn't on all of them, see [channels]
+	group_channels: BTreeMap<i32, i32>,
+
+	pub buffer: Buffer,
+	pub velocities: Buffer,
+	pub phases: Buffer,
+	pub active_indices: Buffer,
 }
 
 impl ParticleState {
-	/// # Safety
-	/// Do not call this function more than once
-	pub unsafe fn new(flex: *mut NvFlexLibrary, max: usize) -> Self {
-		Self {
+	pub fn new(backend: &mut dyn SolverBackend, max: usize, ceiling: usize) -> Result<Self, InitError> {
+		let mut alloc = |stride| backend.alloc(max, stride).ok_or(InitError::Alloc("particle"));
+
+		Ok(Self {
 			has_changes: false,
 
+			capacity: max,
+			ceiling,
+
+			active_changed: false,
+			dirty_positions: DirtyRanges::default(),
+			dirty_velocities: DirtyRanges::default(),
+			dirty_phases: DirtyRanges::default(),
+
 			particles: Vec::with_capacity(max),
-			// active: vec![],
-
-			buffer: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<Vector4>() as i32,
-				eNvFlexBufferHost,
-			),
-
-			velocities: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<Vector3>() as i32,
-				eNvFlexBufferHost,
-			),
-
-			phases: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<i32>() as i32,
-				eNvFlexBufferHost,
-			),
-
-			active_indices: NvFlexAllocBuffer(
-				flex,
-				max as i32,
-				size_of::<i32>() as i32,
-				eNvFlexBufferHost,
-			),
-		}
+			generations: Vec::with_capacity(max),
+			lifetimes: Vec::with_capacity(max),
+			free: vec![],
+			active_count: 0,
+			revision: 0,
+
+			group_channels: BTreeMap::new(),
+
+			buffer: alloc(size_of::<Vector4>())?,
+			velocities: alloc(size_of::<Vector3>())?,
+			phases: alloc(size_of::<i32>())?,
+			active_indices: alloc(size_of::<i32>())?,
+		})
+	}
+
+	pub fn get_revision(&self) -> u64 {
+		self.revision
 	}
 
+	/// Number of particles, active or not
 	pub fn get_count(&self) -> usize {
-		self.particles.len()
+		self.particles.len() - self.free.len()
+	}
+
+	pub fn get_capacity(&self) -> usize {
+		self.capacity
+	}
+
+	/// Most particles the buffers can grow to hold
+	pub fn get_ceiling(&self) -> usize {
+		self.ceiling
 	}
 
 	pub fn get_active_count(&self) -> usize {
-		// self.particles.iter().filter(|(_, active)| *active).count()
-		self.particles.len()
+		self.active_count
+	}
+
+	/// Returns whether the particle a handle refers to is still around.
+	pub fn is_valid(&self, handle: ParticleHandle) -> bool {
+		self.resolve(handle).is_some()
+	}
+
+	/// Slot of the particle a handle refers to, or [None] if the handle is stale.
+	fn resolve(&self, handle: ParticleHandle) -> Option<usize> {
+		let index = handle.index as usize;
+		match self.particles.get(index) {
+			Some(Some(_)) if self.generations[index] == handle.generation => Some(index),
+			_ => None,
+		}
+	}
+
+	fn handle(&self, index: usize) -> ParticleHandle {
+		ParticleHandle {
+			index: index as u32,
+			generation: self.generations[index],
+		}
 	}
 
-	/// Adds a particle to FleX
+	/// Adds a particle to FleX, growing the buffers if they're full. Returns a handle to it.
 	/// Note the changes won't be applied to flex immediately, you need to call [self.flush]
-	/// Also this is very inefficient since it maps and unmaps every call..
-	pub fn create(&mut self, pos: Vector4, vel: Vector3, phase: i32, active: bool) {
-		let count = self.get_count();
-		unsafe {
-			let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
-			let velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3;
-			let phases = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
-			let active_indices = NvFlexMap(self.active_indices, eNvFlexMapWait) as *mut i32;
+	/// Also this is very inefficient since it maps and unmaps every call, see [self.factory] for creating many.
+	pub fn create(&mut self, backend: &mut dyn SolverBackend, pos: Vector4, vel: Vector3, phase: i32, active: bool) -> Result<ParticleHandle, CreateError> {
+		let mut handle = None;
+		self.factory(backend, |factory| {
+			handle = Some(factory.create(pos, vel, phase, active)?);
+			Ok(())
+		})?;
+
+		Ok(handle.expect("Factory didn't create the particle"))
+	}
+
+	/// Sets whether a particle is simulated, returning false if the handle is stale.
+	pub fn set_active(&mut self, handle: ParticleHandle, active: bool) -> bool {
+		let Some(index) = self.resolve(handle) else {
+			return false;
+		};
+
+		if self.particles[index] != Some(active) {
+			self.particles[index] = Some(active);
+			if active {
+				self.active_count += 1;
+			} else {
+				self.active_count -= 1;
+			}
+
+			self.active_changed = true;
+		}
+
+		true
+	}
 
-			particles
-				.add(count)
-				.write(Vector4(50.0 * count as f32, 0.0, 5000.0, 2.0));
+	/// Sets how many seconds of simulation a particle has left, or [None] for it to live until removed.
+	/// Returns false if the handle is stale.
+	pub fn set_lifetime(&mut self, handle: ParticleHandle, lifetime: Option<f32>) -> bool {
+		match self.resolve(handle) {
+			Some(index) => {
+				self.lifetimes[index] = lifetime;
+				true
+			}
+			None => false,
+		}
+	}
 
-			velocities.add(count).write(Vector3(0.0, 0.0, -5.0));
-			phases.add(count).write(phase);
+	/// Counts `dt` seconds off the lifetimes of active particles, removing the ones that run out. Returns how many were removed.
+	pub fn age(&mut self, dt: f32) -> usize {
+		let mut expired = vec![];
+		for (index, lifetime) in self.lifetimes.iter_mut().enumerate() {
+			if self.particles[index] != Some(true) {
+				continue;
+			}
 
-			// Assume active for now.
-			active_indices.add(count).write(count as i32);
+			if let Some(left) = lifetime {
+				*left -= dt;
+				if *left <= 0.0 {
+					expired.push(index);
+				}
+			}
+		}
 
-			self.unmap();
+		for &index in &expired {
+			self.remove_index(index);
 		}
 
-		self.particles.push( (count, active) );
-		self.has_changes = true;
+		expired.len()
 	}
 
-	pub fn unmap(&self) {
-		unsafe {
-			NvFlexUnmap(self.buffer);
-			NvFlexUnmap(self.velocities);
-			NvFlexUnmap(self.phases);
-			NvFlexUnmap(self.active_indices);
+	/// Reads back the phase of each particle, [None] for stale handles.
+	pub fn get_phases(&mut self, backend: &mut dyn SolverBackend, handles: &[ParticleHandle]) -> Vec<Option<i32>> {
+		self.read_back(backend);
+
+		let phases = backend.map(self.phases) as *const i32;
+		let list = handles.iter()
+			.map(|&handle| self.resolve(handle).map(|index| unsafe { *phases.add(index) }))
+			.collect();
+		backend.unmap(self.phases);
+
+		list
+	}
+
+	/// Collision channels the particles in `group` are on.
+	pub fn get_group_channels(&self, group: i32) -> i32 {
+		self.group_channels.get(&group).copied().unwrap_or(channels::ALL)
+	}
+
+	/// Puts every particle in `group` on the collision `channels`, including ones created later.
+	/// Returns how many particles are in the group.
+	pub fn set_group_channels(&mut self, backend: &mut dyn SolverBackend, group: i32, channels: i32) -> usize {
+		match channels & eNvFlexPhaseShapeChannelMask {
+			channels::ALL => self.group_channels.remove(&group),
+			channels => self.group_channels.insert(group, channels),
+		};
+
+		self.read_back(backend);
+
+		let phases = backend.map(self.phases) as *mut i32;
+		let mut found = 0;
+		for index in (0..self.particles.len()).filter(|&i| self.particles[i].is_some()) {
+			unsafe {
+				let phase = phases.add(index);
+				if *phase & eNvFlexPhaseGroupMask == group {
+					*phase = self.with_channels(*phase);
+					self.dirty_phases.mark_index(index);
+					found += 1;
+				}
+			}
 		}
+		backend.unmap(self.phases);
+
+		found
+	}
+
+	/// Replaces the collision channels of `phase` with the ones of its group.
+	fn with_channels(&self, phase: i32) -> i32 {
+		(phase & !eNvFlexPhaseShapeChannelMask) | self.get_group_channels(phase & eNvFlexPhaseGroupMask)
 	}
 
-	pub unsafe fn get(&self, solver: *mut NvFlexSolver) -> Option<Vec<Particle>> {
-		NvFlexGetParticles(solver, self.buffer, std::ptr::null());
-		NvFlexGetVelocities(solver, self.velocities, std::ptr::null());
-		NvFlexGetPhases(solver, self.velocities, std::ptr::null());
+	/// Gives each particle a new phase, returning how many of the handles weren't stale.
+	/// The collision channels of the phases are replaced with the ones of their group.
+	/// Note the changes won't be applied to flex immediately, you need to call [self.flush], which only uploads the phases written to.
+	pub fn set_phases(&mut self, backend: &mut dyn SolverBackend, phases: &[(ParticleHandle, i32)]) -> usize {
+		let buffer = backend.map(self.phases) as *mut i32;
+		let mut found = 0;
+		for &(handle, phase) in phases {
+			if let Some(index) = self.resolve(handle) {
+				unsafe { buffer.add(index).write(self.with_channels(phase)) };
+				self.dirty_phases.mark_index(index);
+				found += 1;
+			}
+		}
+		backend.unmap(self.phases);
 
-		let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
-		let velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3;
-		let phases = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
+		found
+	}
 
-		let mut pvec = vec![];
-		for i in 0 .. self.get_count() {
-			let particle = particles.add(i);
-			if particle.is_null() {
-				break;
+	/// Removes a particle, freeing its slot for the next one created. Returns false if the handle is stale.
+	pub fn remove(&mut self, handle: ParticleHandle) -> bool {
+		match self.resolve(handle) {
+			Some(index) => {
+				self.remove_index(index);
+				true
 			}
+			None => false,
+		}
+	}
+
+	fn remove_index(&mut self, index: usize) {
+		if self.particles[index] == Some(true) {
+			self.active_count -= 1;
+		}
+
+		// Its data is left in the buffers, but it won't be simulated without being in the active indices.
+		self.particles[index] = None;
+		self.lifetimes[index] = None;
+		self.generations[index] = self.generations[index].wrapping_add(1) & ParticleHandle::GENERATION_MASK;
+		self.free.push(index);
+		self.active_changed = true;
+	}
+
+	/// Removes every particle inside the box from `min` to `max`, active or not, returning how many were removed.
+	pub fn remove_in_box(&mut self, backend: &mut dyn SolverBackend, min: Vector3, max: Vector3) -> usize {
+		self.remove_matching(backend, false, |pos| {
+			(min.0..=max.0).contains(&pos.0) && (min.1..=max.1).contains(&pos.1) && (min.2..=max.2).contains(&pos.2)
+		})
+	}
+
+	/// Removes every active particle whose position `inside` returns true for, returning how many were removed.
+	/// Inactive particles aren't being simulated, so are left alone like [ParticleState::age] does.
+	pub fn remove_where(&mut self, backend: &mut dyn SolverBackend, inside: impl Fn(Vector3) -> bool) -> usize {
+		self.remove_matching(backend, true, inside)
+	}
 
-			let velocity = velocities.add(i);
-			let phase = phases.add(i);
+	fn remove_matching(&mut self, backend: &mut dyn SolverBackend, only_active: bool, inside: impl Fn(Vector3) -> bool) -> usize {
+		self.read_back(backend);
 
-			pvec.push(Particle {
-				pdata: particle.as_ref()?,
-				velocity: velocity.as_ref()?,
-				phase: phase.as_ref()?,
-			});
+		let positions = backend.map(self.buffer) as *const Vector4;
+		let removed: Vec<usize> = (0..self.particles.len())
+			.filter(|&i| self.particles[i] == Some(true) || (!only_active && self.particles[i].is_some()))
+			.filter(|&i| inside(unsafe { *positions.add(i) }.xyz()))
+			.collect();
+		backend.unmap(self.buffer);
+
+		for &index in &removed {
+			self.remove_index(index);
 		}
 
-		NvFlexUnmap(self.buffer);
-		NvFlexUnmap(self.velocities);
-		NvFlexUnmap(self.phases);
+		removed.len()
+	}
+
+	/// Lets `f` change the velocity of each active particle given its position and inverse mass, returning true if it did.
+	/// It's run by the backend on the particles the solver has before its next update, so changes made here are flushed first.
+	/// Returns false if the next update is going to be dropped, in which case `f` isn't run, see [SolverBackend::update_velocities].
+	pub fn update_velocities(&mut self, backend: &mut dyn SolverBackend, f: VelocityFn) -> bool {
+		self.flush(backend);
+
+		let buffers = ParticleBuffers {
+			particles: self.buffer,
+			velocities: self.velocities,
+			active: self.active_indices,
+		};
+
+		backend.update_velocities(&buffers, self.active_count, f)
+	}
+
+	/// Slots of the particle `start` and the ones after it, up to `count` of them, skipping free slots.
+	fn run_from(&self, start: ParticleHandle, count: usize) -> Vec<usize> {
+		let Some(first) = self.resolve(start) else {
+			return vec![];
+		};
+
+		(first..self.particles.len())
+			.filter(|&i| self.particles[i].is_some())
+			.take(count)
+			.collect()
+	}
+
+	/// Moves the particle `start` and the ones after it in [self.get] order to `positions`, keeping their inverse mass.
+	/// Returns how many were moved, fewer than given if it ran out of particles and 0 if `start` is stale.
+	/// Only the slots written to are uploaded by the next [self.flush].
+	pub fn set_positions(&mut self, backend: &mut dyn SolverBackend, start: ParticleHandle, positions: &[Vector3]) -> usize {
+		let slots = self.run_from(start, positions.len());
+
+		let buffer = backend.map(self.buffer) as *mut Vector4;
+		for (&i, pos) in slots.iter().zip(positions) {
+			unsafe {
+				let particle = buffer.add(i);
+				*particle = pos.with_w((*particle).3);
+			}
+			self.dirty_positions.mark_index(i);
+		}
+		backend.unmap(self.buffer);
+
+		slots.len()
+	}
+
+	/// Like [self.set_positions], for velocities.
+	pub fn set_velocities(&mut self, backend: &mut dyn SolverBackend, start: ParticleHandle, velocities: &[Vector3]) -> usize {
+		let slots = self.run_from(start, velocities.len());
+
+		let buffer = backend.map(self.velocities) as *mut Vector3;
+		for (&i, &vel) in slots.iter().zip(velocities) {
+			unsafe { buffer.add(i).write(vel) };
+			self.dirty_velocities.mark_index(i);
+		}
+		backend.unmap(self.velocities);
+
+		slots.len()
+	}
 
+	fn is_dirty(&self) -> bool {
+		self.dirty_positions.is_dirty() || self.dirty_velocities.is_dirty() || self.dirty_phases.is_dirty()
+	}
+
+	/// Uploads the parts of the buffers that were changed on their own.
+	fn upload_dirty(&mut self, backend: &mut dyn SolverBackend) {
+		for range in self.dirty_positions.take() {
+			backend.set_particles(self.buffer, Some(range));
+		}
+
+		for range in self.dirty_velocities.take() {
+			backend.set_velocities(self.velocities, Some(range));
+		}
+
+		for range in self.dirty_phases.take() {
+			backend.set_phases(self.phases, Some(range));
+		}
+	}
+
+	/// Brings the buffers up to date with the solver, unless they have changes it doesn't have yet.
+	/// Parts changed on their own are uploaded first, so reading back doesn't undo them.
+	fn read_back(&mut self, backend: &mut dyn SolverBackend) {
+		if self.has_changes {
+			return;
+		}
+
+		if self.is_dirty() {
+			self.upload_dirty(backend);
+			self.revision += 1;
+		}
+
+		backend.get_particles(self.buffer);
+		backend.get_velocities(self.velocities);
+		backend.get_phases(self.phases);
+	}
+
+	/// Makes room for `count` particles, growing the buffers and the solver up to the ceiling.
+	/// Anything already in the buffers is kept, and set on the solver again.
+	pub fn reserve(&mut self, backend: &mut dyn SolverBackend, count: usize) -> Result<(), CreateError> {
+		if count <= self.capacity {
+			return Ok(());
+		}
+
+		let capacity = buffers::grow_capacity(self.capacity, count, self.ceiling)
+			.ok_or(CreateError::Max("particles", self.ceiling))?;
+
+		// Growing the solver can recreate it, so bring over what it's simulated since the last flush.
+		self.read_back(backend);
+
+		backend.reserve_particles(capacity)?;
+
+		// What was read back may be from before the solver's latest update, which would rewind it once set again.
+		// Growing finishes that update first, so it can be read back now.
+		if !backend.is_current(self.buffer) {
+			self.read_back(backend);
+		}
+
+		let count = self.particles.len();
+		let grown = buffers::realloc(backend, &mut [
+			(&mut self.buffer, size_of::<Vector4>()),
+			(&mut self.velocities, size_of::<Vector3>()),
+			(&mut self.phases, size_of::<i32>()),
+			(&mut self.active_indices, size_of::<i32>()),
+		], count, capacity);
+
+		if !grown {
+			return Err(CreateError::Alloc("particle"));
+		}
+
+		self.capacity = capacity;
+
+		// The old buffers are gone, so the solver has to be pointed at the new ones right away.
+		self.has_changes = true;
+		self.flush(backend);
+
+		Ok(())
+	}
+
+	/// Makes room for `n` more particles, counting the free slots they'd fill first.
+	pub fn reserve_more(&mut self, backend: &mut dyn SolverBackend, n: usize) -> Result<(), CreateError> {
+		let slots = self.particles.len() + n.saturating_sub(self.free.len());
+		self.reserve(backend, slots)
+	}
+
+	pub fn unmap(&self, backend: &mut dyn SolverBackend) {
+		backend.unmap(self.buffer);
+		backend.unmap(self.velocities);
+		backend.unmap(self.phases);
+		backend.unmap(self.active_indices);
+	}
+
+	/// Maps the particle, velocity and phase buffers, or [None] with them unmapped again if the backend couldn't map one.
+	fn map_all(&self, backend: &mut dyn SolverBackend) -> Option<(*const Vector4, *const Vector3, *const i32)> {
+		let particles = backend.map(self.buffer) as *const Vector4;
+		let velocities = backend.map(self.velocities) as *const Vector3;
+		let phases = backend.map(self.phases) as *const i32;
+
+		if particles.is_null() || velocities.is_null() || phases.is_null() {
+			self.unmap_all(backend);
+			return None;
+		}
+
+		Some((particles, velocities, phases))
+	}
+
+	fn unmap_all(&self, backend: &mut dyn SolverBackend) {
+		backend.unmap(self.buffer);
+		backend.unmap(self.velocities);
+		backend.unmap(self.phases);
+	}
+
+	/// Reads back every particle, active or not. Changes that haven't been flushed yet are kept.
+	pub fn get(&mut self, backend: &mut dyn SolverBackend) -> Option<Vec<Particle<'_>>> {
+		self.read_back(backend);
+
+		let (particles, velocities, phases) = self.map_all(backend)?;
+		let pvec = self.particles.iter().enumerate().filter_map(|(i, slot)| {
+			let active = (*slot)?;
+			Some(Particle {
+				handle: self.handle(i),
+				active,
+				pdata: unsafe { &*particles.add(i) },
+				velocity: unsafe { &*velocities.add(i) },
+				phase: unsafe { &*phases.add(i) },
+			})
+		}).collect();
+
+		self.unmap_all(backend);
 		Some(pvec)
 	}
 
-	pub fn flush(&mut self, solver: *mut NvFlexSolver) -> bool {
-		if !self.has_changes {
+	/// Reads back a single particle, or [None] if the handle is stale.
+	pub fn get_particle(&mut self, backend: &mut dyn SolverBackend, handle: ParticleHandle) -> Option<Particle<'_>> {
+		let index = self.resolve(handle)?;
+		self.read_back(backend);
+
+		let (particles, velocities, phases) = self.map_all(backend)?;
+		let particle = Particle {
+			handle,
+			active: self.particles[index] == Some(true),
+			pdata: unsafe { &*particles.add(index) },
+			velocity: unsafe { &*velocities.add(index) },
+			phase: unsafe { &*phases.add(index) },
+		};
+
+		self.unmap_all(backend);
+		Some(particle)
+	}
+
+	pub fn flush(&mut self, backend: &mut dyn SolverBackend) -> bool {
+		if !self.has_changes && !self.active_changed && !self.is_dirty() {
 			return false;
 		}
 
-		unsafe {
-			NvFlexSetParticles(solver, self.buffer, std::ptr::null_mut());
-			NvFlexSetVelocities(solver, self.velocities, std::ptr::null_mut());
-			NvFlexSetPhases(solver, self.phases, std::ptr::null_mut());
-			NvFlexSetActive(solver, self.active_indices, std::ptr::null_mut());
-			NvFlexSetActiveCount(solver, self.get_active_count() as i32); // All are active for now.
+		if self.has_changes {
+			backend.set_particles(self.buffer, None);
+			backend.set_velocities(self.velocities, None);
+			backend.set_phases(self.phases, None);
+
+			// Already uploaded along with everything else
+			self.dirty_positions.take();
+			self.dirty_velocities.take();
+			self.dirty_phases.take();
+		} else {
+			self.upload_dirty(backend);
+		}
+
+		self.revision += 1;
+
+		if !self.has_changes && !self.active_changed {
+			return true;
 		}
 
+		let indices = backend.map(self.active_indices) as *mut i32;
+		let active = self.particles.iter().enumerate().filter(|(_, slot)| **slot == Some(true));
+		for (n, (i, _)) in active.enumerate() {
+			unsafe { indices.add(n).write(i as i32) };
+		}
+		backend.unmap(self.active_indices);
+
+		backend.set_active(self.active_indices, self.active_count);
+
 		self.has_changes = false;
+		self.active_changed = false;
 
 		true
 	}
 
-	/// Creates an environment to safely and efficiently create new particles.
-	/// They will be properly mapped and unmapped, however, you still need to [flush] these changes.
-	pub fn factory<F: Fn(&mut factory::ParticleFactory)>(&mut self, generator: F) {
-		let mut factory = unsafe {
-			let particles = NvFlexMap(self.buffer, eNvFlexMapWait) as *mut Vector4;
-			let velocities = NvFlexMap(self.velocities, eNvFlexMapWait) as *mut Vector3;
-			let phases = NvFlexMap(self.phases, eNvFlexMapWait) as *mut i32;
-			let active_indices = NvFlexMap(self.active_indices, eNvFlexMapWait) as *mut i32;
+	/// Creates an environment to safely and efficiently create new particles, filling the slots of removed ones first.
+	/// They will be properly mapped and unmapped, however, you still need to [flush] these changes, which only uploads the slots written to.
+	/// Particles created before the generator returns an error are kept.
+	pub fn factory<F>(&mut self, backend: &mut dyn SolverBackend, generator: F) -> Result<(), CreateError>
+	where
+		F: FnOnce(&mut factory::ParticleFactory) -> Result<(), CreateError>,
+	{
+		generator(&mut factory::ParticleFactory::new(self, backend))
+	}
+}
 
-			factory::ParticleFactory::new(None, particles, velocities, phases, active_indices)
-		};
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::{cpu::CpuBackend, threaded::ThreadedBackend}, helper::NvFlexMakePhase, sys::eNvFlexPhaseFluid};
 
-		generator(&mut factory);
+	fn fluid() -> i32 {
+		NvFlexMakePhase(0, eNvFlexPhaseFluid)
+	}
 
-		if factory.nparticles > 0 {
-			let count = self.get_count();
-			for i in 0 .. factory.nparticles {
-				self.particles.push( (count + i, true) );
-			}
-			self.has_changes = true;
-		}
+	fn at(x: f32) -> Vector4 {
+		Vector4(x, 0.0, 0.0, 1.0)
+	}
 
-		self.unmap();
+	fn setup(max: usize, ceiling: usize) -> (CpuBackend, ParticleState) {
+		let mut backend = CpuBackend::new();
+		let state = ParticleState::new(&mut backend, max, ceiling).unwrap();
+		(backend, state)
 	}
-}
 
-impl Drop for ParticleState {
-	fn drop(&mut self) {
-		unsafe {
-			NvFlexFreeBuffer(self.buffer);
-			NvFlexFreeBuffer(self.velocities);
-			NvFlexFreeBuffer(self.phases);
-			NvFlexFreeBuffer(self.active_indices);
+	/// Where the solver has a particle along x, as read back from it.
+	fn solver_x(state: &mut ParticleState, backend: &mut CpuBackend, handle: ParticleHandle) -> f32 {
+		state.get_particle(backend, handle).expect("Stale handle").pdata.0
+	}
+
+	#[test]
+	fn removed_slots_are_reused() {
+		let (mut backend, mut state) = setup(4, 16);
+		let handles = (0..3)
+			.map(|i| state.create(&mut backend, at(i as f32), Vector3::ZERO, fluid(), true).unwrap())
+			.collect::<Vec<_>>();
+		assert_eq!(state.get_count(), 3);
+
+		assert!(state.remove(handles[1]));
+		assert_eq!(state.get_count(), 2);
+
+		let reused = state.create(&mut backend, at(10.0), Vector3::ZERO, fluid(), true).unwrap();
+		assert_eq!(reused.index, handles[1].index);
+		assert_eq!(reused.generation, handles[1].generation + 1);
+		assert_eq!(state.get_count(), 3);
+
+		// The next one goes after the others again
+		let next = state.create(&mut backend, at(11.0), Vector3::ZERO, fluid(), true).unwrap();
+		assert_eq!(next.index, 3);
+	}
+
+	#[test]
+	fn stale_handles_are_rejected() {
+		let (mut backend, mut state) = setup(4, 16);
+		let old = state.create(&mut backend, at(1.0), Vector3::ZERO, fluid(), true).unwrap();
+		assert!(state.remove(old));
+
+		let new = state.create(&mut backend, at(2.0), Vector3::ZERO, fluid(), true).unwrap();
+		state.flush(&mut backend);
+
+		assert!(!state.is_valid(old));
+		assert!(!state.remove(old));
+		assert!(!state.set_active(old, false));
+		assert!(!state.set_lifetime(old, Some(1.0)));
+		assert!(state.get_particle(&mut backend, old).is_none());
+
+		assert!(state.is_valid(new));
+		assert_eq!(solver_x(&mut state, &mut backend, new), 2.0);
+	}
+
+	#[test]
+	fn active_count_follows_changes() {
+		let (mut backend, mut state) = setup(4, 16);
+		let a = state.create(&mut backend, at(0.0), Vector3::ZERO, fluid(), true).unwrap();
+		let b = state.create(&mut backend, at(1.0), Vector3::ZERO, fluid(), true).unwrap();
+		let c = state.create(&mut backend, at(2.0), Vector3::ZERO, fluid(), false).unwrap();
+		assert_eq!(state.get_active_count(), 2);
+
+		// Setting it to what it already is doesn't count twice
+		assert!(state.set_active(c, true));
+		assert!(state.set_active(c, true));
+		assert_eq!(state.get_active_count(), 3);
+
+		assert!(state.set_active(a, false));
+		assert_eq!(state.get_active_count(), 2);
+
+		// Removing an inactive particle leaves the count alone
+		state.remove(a);
+		assert_eq!(state.get_active_count(), 2);
+		state.remove(b);
+		assert_eq!(state.get_active_count(), 1);
+
+		state.flush(&mut backend);
+		let active = state.get(&mut backend).unwrap().iter().filter(|p| p.active).map(|p| p.handle).collect::<Vec<_>>();
+		assert_eq!(active, vec![c]);
+	}
+
+	#[test]
+	fn grows_past_capacity_up_to_the_ceiling() {
+		let (mut backend, mut state) = setup(2, 8);
+		let handles = (0..8)
+			.map(|i| state.create(&mut backend, at(i as f32), Vector3(0.0, 0.0, i as f32), fluid(), true).unwrap())
+			.collect::<Vec<_>>();
+
+		assert!(state.get_capacity() >= 8);
+		assert!(matches!(
+			state.create(&mut backend, at(8.0), Vector3::ZERO, fluid(), true),
+			Err(CreateError::Max("particles", 8))
+		));
+
+		// Particles from before each grow are still there, on both sides
+		state.flush(&mut backend);
+		for (i, &handle) in handles.iter().enumerate() {
+			let particle = state.get_particle(&mut backend, handle).unwrap();
+			assert_eq!(particle.pdata.0, i as f32);
+			assert_eq!(*particle.velocity, Vector3(0.0, 0.0, i as f32));
 		}
 	}
+
+	#[test]
+	fn reading_back_keeps_unflushed_changes() {
+		let (mut backend, mut state) = setup(4, 4);
+		let handles = (0..3)
+			.map(|i| state.create(&mut backend, at(i as f32), Vector3::ZERO, fluid(), true).unwrap())
+			.collect::<Vec<_>>();
+		state.flush(&mut backend);
+		backend.update(0.1, 1);
+
+		state.set_positions(&mut backend, handles[1], &[Vector3(5.0, 0.0, 0.0)]);
+		state.remove(handles[2]);
+
+		let particles = state.get(&mut backend).unwrap();
+		assert_eq!(particles.len(), 2);
+		assert!(particles[0].pdata.2 < 0.0);
+		assert_eq!(particles[1].pdata.xyz(), Vector3(5.0, 0.0, 0.0));
+
+		assert_eq!(state.get_particle(&mut backend, handles[1]).unwrap().pdata.xyz(), Vector3(5.0, 0.0, 0.0));
+		assert!(state.get_particle(&mut backend, handles[2]).is_none());
+	}
+
+	#[test]
+	fn growing_doesnt_rewind_a_threaded_solver() {
+		let mut backend = ThreadedBackend::new(Box::new(|| Ok(Box::new(CpuBackend::new()) as Box<dyn SolverBackend>))).unwrap();
+		let mut state = ParticleState::new(&mut backend, 2, 8).unwrap();
+		let handle = state.create(&mut backend, at(0.0), Vector3::ZERO, fluid(), true).unwrap();
+		state.flush(&mut backend);
+
+		// Still running on the worker when the buffers grow
+		backend.update(0.1, 1);
+		state.reserve(&mut backend, 4).unwrap();
+
+		let z = state.get_particle(&mut backend, handle).unwrap().pdata.2;
+		assert!(z < 0.0, "Solver was rewound to z = {}", z);
+	}
+
+	#[test]
+	fn flush_only_uploads_dirty_slots() {
+		let (mut backend, mut state) = setup(8, 16);
+		let handles = (0..4)
+			.map(|i| state.create(&mut backend, at(i as f32), Vector3::ZERO, fluid(), true).unwrap())
+			.collect::<Vec<_>>();
+		state.flush(&mut backend);
+
+		// Scribble over a particle in the host buffer without marking it, like a copy older than the solver's would be.
+		let scribble = |backend: &mut CpuBackend, state: &ParticleState| {
+			let buffer = backend.map(state.buffer) as *mut Vector4;
+			unsafe { buffer.add(handles[2].index as usize).write(at(-1.0)) };
+			backend.unmap(state.buffer);
+		};
+
+		// Moving its neighbors doesn't upload it
+		scribble(&mut backend, &state);
+		state.set_positions(&mut backend, handles[1], &[Vector3(10.0, 0.0, 0.0)]);
+		state.set_positions(&mut backend, handles[3], &[Vector3(30.0, 0.0, 0.0)]);
+		state.flush(&mut backend);
+
+		assert_eq!(solver_x(&mut state, &mut backend, handles[1]), 10.0);
+		assert_eq!(solver_x(&mut state, &mut backend, handles[2]), 2.0);
+		assert_eq!(solver_x(&mut state, &mut backend, handles[3]), 30.0);
+
+		// Neither does creating a particle
+		scribble(&mut backend, &state);
+		let created = state.create(&mut backend, at(4.0), Vector3::ZERO, fluid(), true).unwrap();
+		state.flush(&mut backend);
+
+		assert_eq!(solver_x(&mut state, &mut backend, created), 4.0);
+		assert_eq!(solver_x(&mut state, &mut backend, handles[2]), 2.0);
+
+		// Nothing left to upload
+		assert!(!state.flush(&mut backend));
+	}
+
+	#[test]
+	fn dirty_ranges_merge_only_when_touching() {
+		let mut dirty = DirtyRanges::default();
+		assert!(!dirty.is_dirty());
+
+		dirty.mark_index(5);
+		dirty.mark_index(7);
+		dirty.mark(10..12);
+		dirty.mark_index(1);
+		assert_eq!(dirty.take(), vec![1..2, 5..6, 7..8, 10..12]);
+		assert!(!dirty.is_dirty());
+
+		dirty.mark_index(5);
+		dirty.mark_index(7);
+		dirty.mark_index(6);
+		dirty.mark(9..11);
+		dirty.mark(8..9);
+		dirty.mark(3..4);
+		assert_eq!(dirty.take(), vec![3..4, 5..11]);
+	}
 }
@@ -0,0 +1,123 @@
+// Building and picking apart the phase FleX gives each particle.
+use crate::{helper::NvFlexMakePhase, sys::*};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PhaseError {
+	#[error("Invalid phase group: `{0}`, must be between 0 and {max}", max = eNvFlexPhaseGroupMask)]
+	Group(i64),
+}
+
+/// What a particle's phase says about how it's simulated.
+/// Particles only collide with other groups, unless they self collide.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Phase {
+	group: i32,
+	pub fluid: bool,
+	pub self_collide: bool,
+	/// Keeps self colliding particles from colliding with ones they started out overlapping
+	pub self_collide_filter: bool,
+}
+
+impl Default for Phase {
+	/// Self colliding fluid in group 0, what particles are created as unless told otherwise.
+	fn default() -> Self {
+		Self {
+			group: 0,
+			fluid: true,
+			self_collide: true,
+			self_collide_filter: false,
+		}
+	}
+}
+
+impl Phase {
+	pub fn get_group(&self) -> i32 {
+		self.group
+	}
+
+	pub fn set_group(&mut self, group: i64) -> Result<(), PhaseError> {
+		if !(0..=eNvFlexPhaseGroupMask as i64).contains(&group) {
+			return Err(PhaseError::Group(group));
+		}
+
+		self.group = group as i32;
+		Ok(())
+	}
+
+	pub fn to_raw(self) -> i32 {
+		let flag = |set: bool, flag: i32| if set { flag } else { 0 };
+
+		NvFlexMakePhase(
+			self.group,
+			flag(self.fluid, eNvFlexPhaseFluid) | flag(self.self_collide, eNvFlexPhaseSelfCollide) | flag(self.self_collide_filter, eNvFlexPhaseSelfCollideFilter),
+		)
+	}
+
+	pub fn from_raw(raw: i32) -> Self {
+		Self {
+			group: raw & eNvFlexPhaseGroupMask,
+			fluid: raw & eNvFlexPhaseFluid != 0,
+			self_collide: raw & eNvFlexPhaseSelfCollide != 0,
+			self_collide_filter: raw & eNvFlexPhaseSelfCollideFilter != 0,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, state::particle::ParticleState, types::{Vector3, Vector4}};
+
+	#[test]
+	fn packing_round_trips() {
+		for (group, fluid, self_collide, self_collide_filter) in [(0, true, true, false), (7, false, true, true), (eNvFlexPhaseGroupMask, true, false, false)] {
+			let mut phase = Phase { fluid, self_collide, self_collide_filter, ..Phase::default() };
+			phase.set_group(group as i64).unwrap();
+
+			let raw = phase.to_raw();
+			assert_eq!(raw & eNvFlexPhaseGroupMask, group);
+			assert_eq!(Phase::from_raw(raw), phase);
+		}
+
+		assert_eq!(Phase::default().to_raw(), NvFlexMakePhase(0, eNvFlexPhaseSelfCollide | eNvFlexPhaseFluid));
+	}
+
+	#[test]
+	fn channels_arent_part_of_the_phase() {
+		let raw = NvFlexMakePhase(3, eNvFlexPhaseFluid) & !eNvFlexPhaseShapeChannelMask;
+		let phase = Phase::from_raw(raw);
+		assert_eq!((phase.get_group(), phase.fluid, phase.self_collide), (3, true, false));
+
+		// Packing puts particles back on every channel, the particle state narrows it down per group
+		assert_eq!(phase.to_raw() & eNvFlexPhaseShapeChannelMask, eNvFlexPhaseShapeChannelMask);
+	}
+
+	#[test]
+	fn groups_are_range_checked() {
+		let mut phase = Phase::default();
+		for group in [-1, eNvFlexPhaseGroupMask as i64 + 1, i64::MAX] {
+			assert!(matches!(phase.set_group(group), Err(PhaseError::Group(g)) if g == group));
+		}
+		assert_eq!(phase.get_group(), 0);
+	}
+
+	#[test]
+	fn phases_are_set_per_particle() {
+		let mut backend = CpuBackend::new();
+		let mut state = ParticleState::new(&mut backend, 4, 4).unwrap();
+		let fluid = Phase::default().to_raw();
+		let handles = (0..2)
+			.map(|i| state.create(&mut backend, Vector4(i as f32, 0.0, 0.0, 1.0), Vector3::ZERO, fluid, true).unwrap())
+			.collect::<Vec<_>>();
+		state.flush(&mut backend);
+
+		let solid = Phase { group: 2, fluid: false, ..Phase::default() }.to_raw();
+		assert_eq!(state.set_phases(&mut backend, &[(handles[1], solid)]), 1);
+		state.flush(&mut backend);
+
+		let stale = handles[0];
+		state.remove(stale);
+		assert_eq!(state.set_phases(&mut backend, &[(stale, solid)]), 0);
+		assert_eq!(state.get_phases(&mut backend, &handles), vec![None, Some(solid)]);
+	}
+}
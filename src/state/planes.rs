@@ -0,0 +1,161 @@
+// Infinite collision planes, kept in the solver parameters and given ids so they can be removed again.
+use super::FlexState;
+use crate::{config::MAX_PLANES, sys::NvFlexParams, types::Vector3};
+
+#[derive(Debug, thiserror::Error)]
+pub enum PlaneError {
+	#[error("Invalid plane normal, must be a finite vector with a length")]
+	Normal,
+
+	#[error("Invalid plane distance: `{0}`, must be finite")]
+	Distance(f32),
+
+	#[error("Reached the maximum of {} planes", MAX_PLANES)]
+	Max,
+}
+
+/// The planes in use out of [NvFlexParams::planes].
+pub fn planes_of(params: &NvFlexParams) -> &[[f32; 4]] {
+	&params.planes[..params.numPlanes.clamp(0, MAX_PLANES as i32) as usize]
+}
+
+impl FlexState {
+	/// Adds a plane facing `normal`, `distance` units from the origin along it.
+	/// Particles are kept on the side the normal points to. Returns the plane's id.
+	pub fn add_plane(&mut self, normal: Vector3, distance: f32) -> Result<u32, PlaneError> {
+		let count = planes_of(&self.params).len();
+		if count >= MAX_PLANES {
+			return Err(PlaneError::Max);
+		}
+
+		let normal = normal.normalize();
+		if normal == Vector3::ZERO || !normal.length().is_finite() {
+			return Err(PlaneError::Normal);
+		}
+
+		if !distance.is_finite() {
+			return Err(PlaneError::Distance(distance));
+		}
+
+		let id = self.next_plane_id;
+		self.next_plane_id = self.next_plane_id.wrapping_add(1);
+
+		self.params.planes[count] = [normal.0, normal.1, normal.2, -distance];
+		self.params.numPlanes += 1;
+		self.plane_ids.push(id);
+
+		self.backend.set_params(&self.params);
+		Ok(id)
+	}
+
+	/// Removes a plane by id, returning whether there was one.
+	pub fn remove_plane(&mut self, id: u32) -> bool {
+		let Some(index) = self.plane_ids.iter().position(|&i| i == id) else {
+			return false;
+		};
+
+		let count = planes_of(&self.params).len();
+		self.params.planes.copy_within(index + 1..count, index);
+		self.params.planes[count - 1] = [0.0; 4];
+		self.params.numPlanes -= 1;
+		self.plane_ids.remove(index);
+
+		self.backend.set_params(&self.params);
+		true
+	}
+
+	/// Every plane as `(id, [a, b, c, d])`, see [params::set_planes](super::params::set_planes).
+	pub fn get_planes(&self) -> impl Iterator<Item = (u32, [f32; 4])> + '_ {
+		self.plane_ids.iter().copied().zip(planes_of(&self.params).iter().copied())
+	}
+
+	pub fn clear_planes(&mut self) {
+		self.params.planes = [[0.0; 4]; MAX_PLANES];
+		self.params.numPlanes = 0;
+		self.plane_ids.clear();
+
+		self.backend.set_params(&self.params);
+	}
+
+	/// Gives every plane a new id if they're different from the ones in `old`,
+	/// for when they've been replaced wholesale through [FlexState::set_params].
+	pub(super) fn sync_plane_ids(&mut self, old: &NvFlexParams) {
+		if planes_of(old) == planes_of(&self.params) && self.plane_ids.len() == planes_of(old).len() {
+			return;
+		}
+
+		self.plane_ids.clear();
+		for _ in 0..planes_of(&self.params).len() {
+			self.plane_ids.push(self.next_plane_id);
+			self.next_plane_id = self.next_plane_id.wrapping_add(1);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, config::Config, state::params};
+
+	fn new_state() -> FlexState {
+		FlexState::try_new(Box::new(CpuBackend::new()), &Config::default()).unwrap()
+	}
+
+	fn up() -> Vector3 {
+		Vector3(0.0, 0.0, 1.0)
+	}
+
+	#[test]
+	fn ids_follow_their_planes() {
+		let mut state = new_state();
+		let ids = [0.0, 1.0, 2.0].map(|d| state.add_plane(up(), d).unwrap());
+
+		assert!(state.remove_plane(ids[1]));
+		assert!(!state.remove_plane(ids[1]));
+		assert_eq!(state.get_planes().collect::<Vec<_>>(), vec![(ids[0], [0.0, 0.0, 1.0, 0.0]), (ids[2], [0.0, 0.0, 1.0, -2.0])]);
+		assert_eq!(state.get_params().numPlanes, 2);
+
+		// Ids of removed planes aren't handed out again
+		let new = state.add_plane(Vector3(2.0, 0.0, 0.0), 3.0).unwrap();
+		assert!(!ids.contains(&new));
+		assert_eq!(state.get_planes().last(), Some((new, [1.0, 0.0, 0.0, -3.0])));
+
+		state.clear_planes();
+		assert_eq!(state.get_planes().count(), 0);
+		assert_eq!(state.get_params().numPlanes, 0);
+	}
+
+	#[test]
+	fn invalid_planes_are_rejected() {
+		let mut state = new_state();
+		assert!(matches!(state.add_plane(Vector3::ZERO, 0.0), Err(PlaneError::Normal)));
+		assert!(matches!(state.add_plane(Vector3(f32::NAN, 0.0, 1.0), 0.0), Err(PlaneError::Normal)));
+		assert!(matches!(state.add_plane(up(), f32::INFINITY), Err(PlaneError::Distance(_))));
+
+		for _ in 0..MAX_PLANES {
+			state.add_plane(up(), 0.0).unwrap();
+		}
+		assert!(matches!(state.add_plane(up(), 0.0), Err(PlaneError::Max)));
+		assert_eq!(state.get_planes().count(), MAX_PLANES);
+	}
+
+	#[test]
+	fn replacing_params_resyncs_ids() {
+		let mut state = new_state();
+		let id = state.add_plane(up(), 0.0).unwrap();
+
+		// Setting the same planes again keeps their ids
+		state.set_params(*state.get_params());
+		assert_eq!(state.get_planes().map(|(id, _)| id).collect::<Vec<_>>(), vec![id]);
+
+		let mut new = *state.get_params();
+		params::set_planes(&mut new, &[[0.0, 0.0, 1.0, 0.0], [1.0, 0.0, 0.0, 0.0]]).unwrap();
+		state.set_params(new);
+
+		let ids = state.get_planes().map(|(id, _)| id).collect::<Vec<_>>();
+		assert_eq!(ids.len(), 2);
+		assert!(!ids.contains(&id));
+		assert!(state.remove_plane(ids[1]));
+		assert_eq!(state.get_planes().collect::<Vec<_>>(), vec![(ids[0], [0.0, 0.0, 1.0, 0.0])]);
+	}
+}
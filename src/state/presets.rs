@@ -0,0 +1,285 @@
+// Named sets of parameters, so fluids can be picked by what they are rather than by tuning numbers.
+use std::collections::BTreeMap;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+
+use super::params::{self, ParamError, ParamValue};
+use crate::{config, sys::NvFlexParams};
+
+/// Parameters describing what a fluid is made of.
+/// Applying a preset resets these to the configured defaults first, so presets don't leak into each other.
+pub const MATERIAL: &[&str] = &[
+	"viscosity",
+	"cohesion",
+	"surfaceTension",
+	"adhesion",
+	"buoyancy",
+	"vorticityConfinement",
+	"dynamicFriction",
+	"staticFriction",
+	"particleFriction",
+	"restitution",
+	"dissipation",
+	"damping",
+	"drag",
+	"lift",
+	"solidPressure",
+	"freeSurfaceDrag",
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum PresetError {
+	#[error("Unknown preset: `{0}`")]
+	Unknown(String),
+
+	#[error("Invalid blend amount: `{0}`, must be between 0 and 1")]
+	Blend(f32),
+
+	#[error("{0}")]
+	Param(#[from] ParamError),
+}
+
+/// Values for some of the parameters, the rest are left alone.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Preset {
+	values: BTreeMap<&'static str, ParamValue>,
+}
+
+impl Preset {
+	/// Adds a value to the preset, checking it the same as [params::set] would.
+	pub fn set(&mut self, name: &str, value: ParamValue) -> Result<(), ParamError> {
+		let mut scratch = config::current().params;
+		params::set(&mut scratch, name, value)?;
+
+		let name = params::NAMES.iter().find(|n| **n == name).expect("Set a parameter that doesn't exist");
+		self.values.insert(name, value);
+		Ok(())
+	}
+
+	pub fn values(&self) -> impl Iterator<Item = (&'static str, ParamValue)> + '_ {
+		self.values.iter().map(|(k, v)| (*k, *v))
+	}
+
+	/// Returns this preset with every value in `overrides` replacing its own.
+	pub fn with(&self, overrides: &Preset) -> Preset {
+		let mut values = self.values.clone();
+		values.extend(overrides.values.iter());
+		Preset { values }
+	}
+
+	/// Interpolates between two presets, `t` of 0 being `self` and 1 being `other`.
+	/// Values only one of them has are blended with the default from the config, see [config::current].
+	pub fn blend(&self, other: &Preset, t: f32) -> Result<Preset, PresetError> {
+		if !(0.0..=1.0).contains(&t) {
+			return Err(PresetError::Blend(t));
+		}
+
+		let lerp = |a: f32, b: f32| a + (b - a) * t;
+		let defaults = config::current().params;
+
+		let values = self.values.keys()
+			.chain(other.values.keys())
+			.map(|&name| {
+				let default = || params::get(&defaults, name).expect("Preset has an unknown parameter");
+				let a = self.values.get(name).copied().unwrap_or_else(default);
+				let b = other.values.get(name).copied().unwrap_or_else(default);
+
+				let value = match (a, b) {
+					(ParamValue::Number(a), ParamValue::Number(b)) => ParamValue::Number(lerp(a, b)),
+					(ParamValue::Integer(a), ParamValue::Integer(b)) => ParamValue::Integer(lerp(a as f32, b as f32).round() as i32),
+					(ParamValue::Vector(a), ParamValue::Vector(b)) => ParamValue::Vector([lerp(a[0], b[0]), lerp(a[1], b[1]), lerp(a[2], b[2])]),
+					// Both come from the same parameter, so they're always the same kind.
+					_ => unreachable!(),
+				};
+
+				(name, value)
+			})
+			.collect();
+
+		Ok(Preset { values })
+	}
+
+	/// Resets the [MATERIAL] parameters to the configured defaults, then sets everything in the preset.
+	pub fn apply(&self, params: &mut NvFlexParams) -> Result<(), ParamError> {
+		let defaults = config::current().params;
+
+		let mut new = *params;
+		for name in MATERIAL {
+			if let Some(default) = params::get(&defaults, name) {
+				params::set(&mut new, name, default)?;
+			}
+		}
+
+		for (name, value) in self.values() {
+			params::set(&mut new, name, value)?;
+		}
+
+		params::validate(&new)?;
+		*params = new;
+		Ok(())
+	}
+}
+
+macro_rules! preset {
+	($($name:ident: $value:expr),* $(,)?) => {{
+		let mut preset = Preset::default();
+		$( preset.set(stringify!($name), ParamValue::Number($value)).expect("Invalid built-in preset"); )*
+		preset
+	}};
+}
+
+fn builtin() -> BTreeMap<String, Preset> {
+	let mut presets = BTreeMap::new();
+
+	presets.insert("water".to_owned(), preset! {
+		viscosity: 0.0,
+		cohesion: 0.025,
+		surfaceTension: 0.0,
+		vorticityConfinement: 40.0,
+		buoyancy: 1.0,
+	});
+
+	presets.insert("honey".to_owned(), preset! {
+		viscosity: 20.0,
+		cohesion: 0.05,
+		adhesion: 0.1,
+		surfaceTension: 0.1,
+		vorticityConfinement: 0.0,
+		dynamicFriction: 0.2,
+	});
+
+	presets.insert("oil".to_owned(), preset! {
+		viscosity: 2.0,
+		cohesion: 0.01,
+		adhesion: 0.02,
+		vorticityConfinement: 10.0,
+		buoyancy: 0.9,
+	});
+
+	// Meant for particles without the fluid phase flag, so they pile up instead of flowing.
+	presets.insert("sand".to_owned(), preset! {
+		viscosity: 0.0,
+		cohesion: 0.0,
+		vorticityConfinement: 0.0,
+		dynamicFriction: 0.6,
+		staticFriction: 0.8,
+		particleFriction: 0.4,
+		dissipation: 0.1,
+		damping: 0.1,
+	});
+
+	// Rises instead of falling, and slows down in the air.
+	presets.insert("smoke".to_owned(), preset! {
+		viscosity: 0.0,
+		cohesion: 0.0,
+		vorticityConfinement: 20.0,
+		buoyancy: -0.2,
+		drag: 0.1,
+		damping: 0.5,
+	});
+
+	presets.insert("goo".to_owned(), preset! {
+		viscosity: 40.0,
+		cohesion: 0.2,
+		adhesion: 0.3,
+		surfaceTension: 0.5,
+		vorticityConfinement: 0.0,
+		dynamicFriction: 0.4,
+		staticFriction: 0.4,
+	});
+
+	presets
+}
+
+/// Every preset by name, starting out with the built-in ones.
+pub fn registry() -> MutexGuard<'static, BTreeMap<String, Preset>> {
+	static PRESETS: OnceLock<Mutex<BTreeMap<String, Preset>>> = OnceLock::new();
+
+	PRESETS
+		.get_or_init(|| Mutex::new(builtin()))
+		.lock()
+		.unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn get(name: &str) -> Result<Preset, PresetError> {
+	registry().get(name).cloned().ok_or_else(|| PresetError::Unknown(name.to_owned()))
+}
+
+/// Adds a preset, replacing any existing one with the same name (including built-in ones).
+pub fn register(name: &str, preset: Preset) {
+	registry().insert(name.to_owned(), preset);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn number(preset: &Preset, name: &str) -> Option<f32> {
+		preset.values().find(|(n, _)| *n == name).map(|(_, value)| match value {
+			ParamValue::Number(x) => x,
+			_ => panic!("{} isn't a number", name),
+		})
+	}
+
+	#[test]
+	fn builtin_presets_apply() {
+		for (name, preset) in builtin() {
+			let mut params = config::PARAMS;
+			assert!(preset.apply(&mut params).is_ok(), "{} doesn't apply", name);
+		}
+	}
+
+	#[test]
+	fn applying_resets_the_last_material() {
+		let mut params = config::PARAMS;
+		params.radius = 5.0;
+
+		get("honey").unwrap().apply(&mut params).unwrap();
+		assert_eq!(params.adhesion, 0.1);
+
+		get("water").unwrap().apply(&mut params).unwrap();
+		assert_eq!(params.adhesion, config::PARAMS.adhesion);
+		assert_eq!(params.vorticityConfinement, 40.0);
+		// Not part of the material
+		assert_eq!(params.radius, 5.0);
+	}
+
+	#[test]
+	fn invalid_values_are_rejected() {
+		let mut preset = Preset::default();
+		assert!(preset.set("viscosity", ParamValue::Number(-1.0)).is_err());
+		assert!(preset.set("viscosity", ParamValue::Integer(1)).is_err());
+		assert!(preset.set("nope", ParamValue::Number(1.0)).is_err());
+		assert_eq!(preset, Preset::default());
+	}
+
+	#[test]
+	fn overrides_replace_values() {
+		let mut overrides = Preset::default();
+		overrides.set("viscosity", ParamValue::Number(5.0)).unwrap();
+
+		let preset = get("honey").unwrap().with(&overrides);
+		assert_eq!(number(&preset, "viscosity"), Some(5.0));
+		assert_eq!(number(&preset, "cohesion"), Some(0.05));
+	}
+
+	#[test]
+	fn blending_falls_back_to_defaults() {
+		let (water, honey) = (get("water").unwrap(), get("honey").unwrap());
+
+		let blend = water.blend(&honey, 0.5).unwrap();
+		assert_eq!(number(&blend, "viscosity"), Some(10.0));
+		// Only honey has adhesion, water's is the default of zero
+		assert_eq!(number(&blend, "adhesion"), Some(0.05));
+
+		assert_eq!(number(&water.blend(&honey, 0.0).unwrap(), "vorticityConfinement"), Some(40.0));
+		assert_eq!(number(&water.blend(&honey, 1.0).unwrap(), "vorticityConfinement"), Some(0.0));
+	}
+
+	#[test]
+	fn blend_amount_is_between_0_and_1() {
+		let (water, honey) = (get("water").unwrap(), get("honey").unwrap());
+		for t in [-0.1, 1.1, f32::NAN, f32::INFINITY] {
+			assert!(matches!(water.blend(&honey, t), Err(PresetError::Blend(_))));
+		}
+	}
+}
@@ -0,0 +1,581 @@
+// Finding particles in an area or along a line, over a copy of them taken after the latest step.
+use super::{particle::ParticleState, FlexState};
+use crate::{
+	backend::SolverBackend,
+	helper::fluid_rest_distance,
+	spatial::SpatialHash,
+	types::{ParticleHandle, Quat, Vector3},
+};
+
+#[derive(Debug, thiserror::Error)]
+pub enum QueryError {
+	#[error("Invalid query {0}, must be finite")]
+	NotFinite(&'static str),
+
+	#[error("Invalid query {0}: `{1}`, must be zero or greater")]
+	Negative(&'static str, f32),
+
+	#[error("Invalid trace direction, must not be zero")]
+	Direction,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct QueryHit {
+	pub handle: ParticleHandle,
+	pub pos: Vector3,
+	pub velocity: Vector3,
+}
+
+/// First particle a trace ran into.
+#[derive(Debug, Clone, Copy)]
+pub struct TraceHit {
+	pub particle: QueryHit,
+	/// How far along the trace it stopped, 0 if it started inside the particle
+	pub distance: f32,
+	/// Where the trace stopped, the center of the sphere for sweeps
+	pub pos: Vector3,
+	/// Surface normal of the fluid where it was hit, estimated from the particles around it
+	pub normal: Vector3,
+}
+
+/// Totals over the particles a query found.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct QueryStats {
+	pub count: usize,
+	/// Average position, zero if nothing was found
+	pub centroid: Vector3,
+	/// Average velocity, zero if nothing was found
+	pub velocity: Vector3,
+	/// Roughly how much space the particles take up, if at rest
+	pub volume: f32,
+}
+
+impl QueryStats {
+	/// Totals `hits`, each particle taking up `particle_volume`.
+	pub fn new(hits: &[QueryHit], particle_volume: f32) -> Self {
+		let Some(scale) = (!hits.is_empty()).then(|| 1.0 / hits.len() as f32) else {
+			return Self::default();
+		};
+
+		let (pos, vel) = hits.iter().fold((Vector3::ZERO, Vector3::ZERO), |(pos, vel), hit| (pos + hit.pos, vel + hit.velocity));
+
+		Self {
+			count: hits.len(),
+			centroid: pos * scale,
+			velocity: vel * scale,
+			volume: hits.len() as f32 * particle_volume,
+		}
+	}
+}
+
+/// Active particles as of the latest step, hashed by position.
+#[derive(Debug, Default)]
+pub struct QueryState {
+	hash: SpatialHash,
+	handles: Vec<ParticleHandle>,
+	positions: Vec<Vector3>,
+	velocities: Vec<Vector3>,
+
+	/// Bounds of `positions`, for knowing when a nearest search has looked everywhere
+	min: Vector3,
+	max: Vector3,
+
+	/// Particle revision the copy was taken at, [None] once the simulation has stepped since
+	revision: Option<u64>,
+}
+
+impl QueryState {
+	/// Makes the next query take a new copy of the particles.
+	pub fn invalidate(&mut self) {
+		self.revision = None;
+	}
+
+	/// Takes a new copy of the particles if they've changed, hashed into cells of size `spacing`.
+	fn refresh(&mut self, particles: &mut ParticleState, backend: &mut dyn SolverBackend, spacing: f32) {
+		if self.revision == Some(particles.get_revision()) && self.hash.get_spacing() == spacing {
+			return;
+		}
+
+		self.handles.clear();
+		self.positions.clear();
+		self.velocities.clear();
+
+		for particle in particles.get(backend).unwrap_or_default().iter().filter(|p| p.active) {
+			self.handles.push(particle.handle);
+			self.positions.push(particle.pdata.xyz());
+			self.velocities.push(*particle.velocity);
+		}
+
+		let (min, max) = self.positions.iter().fold((Vector3(f32::MAX, f32::MAX, f32::MAX), Vector3(f32::MIN, f32::MIN, f32::MIN)), |(min, max), p| {
+			(Vector3(min.0.min(p.0), min.1.min(p.1), min.2.min(p.2)), Vector3(max.0.max(p.0), max.1.max(p.1), max.2.max(p.2)))
+		});
+		(self.min, self.max) = (min, max);
+
+		self.hash.build(spacing, &self.positions);
+		self.revision = Some(particles.get_revision());
+	}
+
+	fn hit(&self, index: usize) -> QueryHit {
+		QueryHit {
+			handle: self.handles[index],
+			pos: self.positions[index],
+			velocity: self.velocities[index],
+		}
+	}
+
+	/// Calls `f` with every particle in the box centered on `pos` with half size `extents`, rotated by `rot`.
+	fn for_each_in_box(&self, pos: Vector3, rot: Quat, extents: Vector3, mut f: impl FnMut(usize)) {
+		// Bounds of the rotated box, to narrow down which cells to look in
+		let axes = [
+			rot.rotate(Vector3(extents.0, 0.0, 0.0)),
+			rot.rotate(Vector3(0.0, extents.1, 0.0)),
+			rot.rotate(Vector3(0.0, 0.0, extents.2)),
+		];
+		let half = axes.iter().fold(Vector3::ZERO, |half, a| half + Vector3(a.0.abs(), a.1.abs(), a.2.abs()));
+
+		let inverse = rot.conjugate();
+		self.hash.query_aabb(pos - half, pos + half, |i| {
+			let local = inverse.rotate(self.positions[i] - pos);
+			if local.0.abs() <= extents.0 && local.1.abs() <= extents.1 && local.2.abs() <= extents.2 {
+				f(i);
+			}
+		});
+	}
+}
+
+/// Distance along the normalized `dir` from `start` to where it enters the sphere at `center`, 0 if it starts inside.
+fn ray_sphere(start: Vector3, dir: Vector3, center: Vector3, radius: f32) -> Option<f32> {
+	let m = start - center;
+	let (b, c) = (m.dot(dir), m.length_sqr() - radius * radius);
+
+	// Outside and pointing away
+	if c > 0.0 && b > 0.0 {
+		return None;
+	}
+
+	let discriminant = b * b - c;
+	if discriminant < 0.0 {
+		return None;
+	}
+
+	Some((-b - discriminant.sqrt()).max(0.0))
+}
+
+/// Part of `0..max` the ray spends in the box `min..max`, or [None] if it misses it.
+fn clip_ray(start: Vector3, dir: Vector3, max_distance: f32, min: Vector3, max: Vector3) -> Option<(f32, f32)> {
+	let (mut near, mut far) = (0.0f32, max_distance);
+	for (s, d, lo, hi) in [(start.0, dir.0, min.0, max.0), (start.1, dir.1, min.1, max.1), (start.2, dir.2, min.2, max.2)] {
+		if d == 0.0 {
+			if s < lo || s > hi {
+				return None;
+			}
+
+			continue;
+		}
+
+		let (a, b) = ((lo - s) / d, (hi - s) / d);
+		near = near.max(a.min(b));
+		far = far.min(a.max(b));
+	}
+
+	(near <= far).then_some((near, far))
+}
+
+fn check_finite(name: &'static str, v: Vector3) -> Result<(), QueryError> {
+	match v.0.is_finite() && v.1.is_finite() && v.2.is_finite() {
+		true => Ok(()),
+		false => Err(QueryError::NotFinite(name)),
+	}
+}
+
+fn check_box(pos: Vector3, rot: Quat, extents: Vector3) -> Result<Quat, QueryError> {
+	check_finite("position", pos)?;
+	check_finite("extents", extents)?;
+
+	if ![rot.0, rot.1, rot.2, rot.3].iter().all(|x| x.is_finite()) {
+		return Err(QueryError::NotFinite("rotation"));
+	}
+
+	for x in [extents.0, extents.1, extents.2] {
+		if x < 0.0 {
+			return Err(QueryError::Negative("extents", x));
+		}
+	}
+
+	Ok(rot.normalize())
+}
+
+impl FlexState {
+	fn refresh_query(&mut self) {
+		// The interaction radius keeps cells small enough to skip most particles, without queries spanning too many of them
+		let spacing = match self.params.radius > 0.0 {
+			true => self.params.radius,
+			false => 1.0,
+		};
+
+		self.query.refresh(&mut self.particles, self.backend.as_mut(), spacing);
+	}
+
+	/// Roughly how much space a particle takes up at rest, for [QueryStats::volume].
+	pub fn get_particle_volume(&self) -> f32 {
+		fluid_rest_distance(&self.params).powi(3)
+	}
+
+	/// How big particles are for traces, half the rest distance so settled fluid has no gaps between them.
+	pub fn get_particle_radius(&self) -> f32 {
+		fluid_rest_distance(&self.params) * 0.5
+	}
+
+	/// First active particle along the ray from `start` in `dir`, up to `max_distance` away.
+	pub fn trace_ray(&mut self, start: Vector3, dir: Vector3, max_distance: f32) -> Result<Option<TraceHit>, QueryError> {
+		self.sweep_sphere(start, dir, max_distance, 0.0)
+	}
+
+	/// Like [FlexState::trace_ray], for a sphere of `radius` moving along the ray.
+	pub fn sweep_sphere(&mut self, start: Vector3, dir: Vector3, max_distance: f32, radius: f32) -> Result<Option<TraceHit>, QueryError> {
+		check_finite("start", start)?;
+		check_finite("direction", dir)?;
+		if dir.length_sqr() == 0.0 {
+			return Err(QueryError::Direction);
+		}
+
+		for (name, x) in [("max distance", max_distance), ("radius", radius)] {
+			if x.is_nan() || x < 0.0 {
+				return Err(QueryError::Negative(name, x));
+			}
+		}
+
+		if radius.is_infinite() {
+			return Err(QueryError::NotFinite("radius"));
+		}
+
+		self.refresh_query();
+
+		let dir = dir.normalize();
+		let reach = radius + self.get_particle_radius();
+		let query = &self.query;
+
+		// Nothing to hit outside of where the particles are
+		let margin = Vector3(reach, reach, reach);
+		let Some((near, far)) = clip_ray(start, dir, max_distance, query.min - margin, query.max + margin) else {
+			return Ok(None);
+		};
+
+		// Walks the ray a few cells at a time. Anything it enters within a chunk is near enough to be found by that chunk's
+		// lookup, so once the closest hit so far is within the chunks walked, nothing further along can be closer.
+		let chunk = query.hash.get_spacing() * 4.0;
+		let mut best: Option<(f32, usize)> = None;
+		let mut from = near;
+		while from <= far {
+			let to = (from + chunk).min(far);
+			let (a, b) = (start + dir * from, start + dir * to);
+
+			let min = Vector3(a.0.min(b.0), a.1.min(b.1), a.2.min(b.2)) - margin;
+			let max = Vector3(a.0.max(b.0), a.1.max(b.1), a.2.max(b.2)) + margin;
+			query.hash.query_aabb(min, max, |i| {
+				let Some(t) = ray_sphere(start, dir, query.positions[i], reach) else {
+					return;
+				};
+
+				if t <= max_distance && best.is_none_or(|(best, _)| t < best) {
+					best = Some((t, i));
+				}
+			});
+
+			if best.is_some_and(|(t, _)| t <= to) || to >= far {
+				break;
+			}
+
+			from = to;
+		}
+
+		let Some((distance, index)) = best else {
+			return Ok(None);
+		};
+
+		let pos = start + dir * distance;
+		let particle = query.hit(index);
+
+		// Where the swept sphere touches the particle, falling back to the ray when they overlap exactly
+		let toward = particle.pos - pos;
+		let contact = match toward.length_sqr() > 0.0 {
+			true => pos + toward.normalize() * radius.min(toward.length()),
+			false => pos,
+		};
+
+		Ok(Some(TraceHit {
+			particle,
+			distance,
+			pos,
+			normal: self.estimate_normal(contact, -toward, -dir),
+		}))
+	}
+
+	/// Surface normal of the fluid at `point`, pointing away from the particles within the interaction radius of it, closer ones counting more.
+	/// Falls back to `toward` and then `fallback` if the particles around it are balanced out.
+	fn estimate_normal(&self, point: Vector3, toward: Vector3, fallback: Vector3) -> Vector3 {
+		let range = self.params.radius.max(self.get_particle_radius() * 2.0);
+
+		let mut sum = Vector3::ZERO;
+		self.query.hash.query(&self.query.positions, point, range, |i| {
+			let offset = point - self.query.positions[i];
+			let distance = offset.length();
+			if distance > 0.0 {
+				sum += offset * ((1.0 - distance / range) / distance);
+			}
+		});
+
+		[sum, toward, fallback]
+			.into_iter()
+			.find(|n| n.length_sqr() > 1e-12)
+			.map_or(fallback, Vector3::normalize)
+	}
+
+	/// Active particles within `radius` of `center`.
+	pub fn query_radius(&mut self, center: Vector3, radius: f32) -> Result<Vec<QueryHit>, QueryError> {
+		check_finite("center", center)?;
+		if !radius.is_finite() {
+			return Err(QueryError::NotFinite("radius"));
+		}
+
+		if radius < 0.0 {
+			return Err(QueryError::Negative("radius", radius));
+		}
+
+		self.refresh_query();
+
+		let mut hits = vec![];
+		self.query.hash.query(&self.query.positions, center, radius, |i| hits.push(self.query.hit(i)));
+
+		Ok(hits)
+	}
+
+	/// Active particles in the box centered on `pos` with half size `extents`, rotated by `rot`.
+	pub fn query_box(&mut self, pos: Vector3, rot: Quat, extents: Vector3) -> Result<Vec<QueryHit>, QueryError> {
+		let rot = check_box(pos, rot, extents)?;
+		self.refresh_query();
+
+		let mut hits = vec![];
+		self.query.for_each_in_box(pos, rot, extents, |i| hits.push(self.query.hit(i)));
+
+		Ok(hits)
+	}
+
+	/// Like [FlexState::query_box], only counting the particles.
+	pub fn count_in_box(&mut self, pos: Vector3, rot: Quat, extents: Vector3) -> Result<usize, QueryError> {
+		let rot = check_box(pos, rot, extents)?;
+		self.refresh_query();
+
+		let mut count = 0;
+		self.query.for_each_in_box(pos, rot, extents, |_| count += 1);
+
+		Ok(count)
+	}
+
+	/// Up to `k` active particles closest to `point`, nearest first, ignoring any further than `max_distance`.
+	pub fn query_nearest(&mut self, point: Vector3, k: usize, max_distance: f32) -> Result<Vec<QueryHit>, QueryError> {
+		check_finite("point", point)?;
+		if max_distance.is_nan() {
+			return Err(QueryError::NotFinite("max distance"));
+		}
+
+		if max_distance < 0.0 {
+			return Err(QueryError::Negative("max distance", max_distance));
+		}
+
+		self.refresh_query();
+
+		let query = &self.query;
+		if k == 0 || query.positions.is_empty() {
+			return Ok(vec![]);
+		}
+
+		// Past the furthest corner of the bounds, growing the search can't find anything more
+		let far = Vector3(
+			(point.0 - query.min.0).abs().max((point.0 - query.max.0).abs()),
+			(point.1 - query.min.1).abs().max((point.1 - query.max.1).abs()),
+			(point.2 - query.min.2).abs().max((point.2 - query.max.2).abs()),
+		);
+		let limit = max_distance.min(far.length());
+
+		// Everything within the radius is found, so once there's k of them the nearest k are among them
+		let mut radius = query.hash.get_spacing().min(limit);
+		loop {
+			let mut found = vec![];
+			query.hash.query(&query.positions, point, radius, |i| found.push(((query.positions[i] - point).length_sqr(), i)));
+
+			if found.len() >= k || radius >= limit {
+				found.sort_unstable_by(|a, b| a.0.total_cmp(&b.0));
+				return Ok(found.into_iter().take(k).map(|(_, i)| query.hit(i)).collect());
+			}
+
+			radius = (radius * 2.0).min(limit);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::{backend::cpu::CpuBackend, config::Config, helper::NvFlexMakePhase, sys::eNvFlexPhaseFluid, types::Vector4};
+
+	/// World with a particle every 10 units along x from 0 to 90, and an inactive one at x = 5.
+	fn line() -> (FlexState, Vec<ParticleHandle>) {
+		let mut state = FlexState::try_new(Box::new(CpuBackend::new()), &Config::default()).unwrap();
+		let backend = state.backend.as_mut();
+
+		let phase = NvFlexMakePhase(0, eNvFlexPhaseFluid);
+		let handles = (0..10)
+			.map(|i| state.particles.create(backend, Vector4(i as f32 * 10.0, 0.0, 0.0, 1.0), Vector3::ZERO, phase, true).unwrap())
+			.collect();
+		state.particles.create(backend, Vector4(5.0, 0.0, 0.0, 1.0), Vector3::ZERO, phase, false).unwrap();
+		state.flush();
+
+		(state, handles)
+	}
+
+	fn xs(hits: &[QueryHit]) -> Vec<f32> {
+		hits.iter().map(|hit| hit.pos.0).collect()
+	}
+
+	fn sorted_xs(hits: &[QueryHit]) -> Vec<f32> {
+		let mut xs = xs(hits);
+		xs.sort_by(f32::total_cmp);
+		xs
+	}
+
+	#[test]
+	fn radius_finds_active_particles() {
+		let (mut state, handles) = line();
+		assert_eq!(sorted_xs(&state.query_radius(Vector3(5.0, 0.0, 0.0), 5.0).unwrap()), vec![0.0, 10.0]);
+		assert_eq!(sorted_xs(&state.query_radius(Vector3(45.0, 3.0, 0.0), 20.0).unwrap()), vec![30.0, 40.0, 50.0, 60.0]);
+		assert!(state.query_radius(Vector3(0.0, 50.0, 0.0), 10.0).unwrap().is_empty());
+
+		let hit = state.query_radius(Vector3::ZERO, 0.0).unwrap();
+		assert_eq!(hit.len(), 1);
+		assert_eq!(hit[0].handle, handles[0]);
+	}
+
+	#[test]
+	fn copies_follow_particle_changes() {
+		let (mut state, handles) = line();
+		assert_eq!(state.query_radius(Vector3::ZERO, 1.0).unwrap().len(), 1);
+
+		state.particles.remove(handles[0]);
+		state.flush();
+		assert!(state.query_radius(Vector3::ZERO, 1.0).unwrap().is_empty());
+	}
+
+	#[test]
+	fn boxes_can_be_rotated() {
+		let (mut state, _) = line();
+		let extents = Vector3(16.0, 1.0, 1.0);
+		assert_eq!(sorted_xs(&state.query_box(Vector3(45.0, 0.0, 0.0), Quat::IDENTITY, extents).unwrap()), vec![30.0, 40.0, 50.0, 60.0]);
+		assert_eq!(state.count_in_box(Vector3(45.0, 0.0, 0.0), Quat::IDENTITY, extents).unwrap(), 4);
+
+		// Turned a quarter around z, so it's thin along x
+		let half = std::f32::consts::FRAC_1_SQRT_2;
+		let turned = Quat(0.0, 0.0, half, half);
+		assert!(state.query_box(Vector3(45.0, 0.0, 0.0), turned, extents).unwrap().is_empty());
+		assert_eq!(sorted_xs(&state.query_box(Vector3(40.5, 0.0, 0.0), turned, extents).unwrap()), vec![40.0]);
+		assert_eq!(state.count_in_box(Vector3(40.5, 0.0, 0.0), turned, extents).unwrap(), 1);
+	}
+
+	#[test]
+	fn nearest_are_sorted_by_distance() {
+		let (mut state, _) = line();
+		assert_eq!(xs(&state.query_nearest(Vector3(33.0, 0.0, 0.0), 3, f32::INFINITY).unwrap()), vec![30.0, 40.0, 20.0]);
+		assert_eq!(xs(&state.query_nearest(Vector3(33.0, 0.0, 0.0), 3, 5.0).unwrap()), vec![30.0]);
+		assert!(state.query_nearest(Vector3(33.0, 0.0, 0.0), 0, f32::INFINITY).unwrap().is_empty());
+
+		// Asking for more than there are finds every active particle, even from far away
+		let all = xs(&state.query_nearest(Vector3(-1000.0, 0.0, 0.0), 100, f32::INFINITY).unwrap());
+		assert_eq!(all, (0..10).map(|i| i as f32 * 10.0).collect::<Vec<_>>());
+	}
+
+	#[test]
+	fn stats_average_what_was_found() {
+		let (mut state, _) = line();
+		let hits = state.query_radius(Vector3(10.0, 0.0, 0.0), 10.0).unwrap();
+		let stats = QueryStats::new(&hits, 2.0);
+		assert_eq!((stats.count, stats.centroid, stats.volume), (3, Vector3(10.0, 0.0, 0.0), 6.0));
+
+		let empty = QueryStats::new(&[], 2.0);
+		assert_eq!((empty.count, empty.centroid, empty.volume), (0, Vector3::ZERO, 0.0));
+	}
+
+	#[test]
+	fn invalid_queries_are_rejected() {
+		let (mut state, _) = line();
+		assert!(matches!(state.query_radius(Vector3(f32::NAN, 0.0, 0.0), 1.0), Err(QueryError::NotFinite("center"))));
+		assert!(matches!(state.query_radius(Vector3::ZERO, -1.0), Err(QueryError::Negative("radius", _))));
+		assert!(matches!(state.query_radius(Vector3::ZERO, f32::INFINITY), Err(QueryError::NotFinite("radius"))));
+		assert!(matches!(state.query_box(Vector3::ZERO, Quat::IDENTITY, Vector3(1.0, -1.0, 1.0)), Err(QueryError::Negative("extents", _))));
+		assert!(matches!(state.count_in_box(Vector3::ZERO, Quat(f32::NAN, 0.0, 0.0, 1.0), Vector3::ZERO), Err(QueryError::NotFinite("rotation"))));
+		assert!(matches!(state.query_nearest(Vector3::ZERO, 1, -1.0), Err(QueryError::Negative("max distance", _))));
+		assert!(matches!(state.query_nearest(Vector3::ZERO, 1, f32::NAN), Err(QueryError::NotFinite("max distance"))));
+	}
+
+	fn assert_near(a: f32, b: f32) {
+		assert!((a - b).abs() < 1e-3, "{} != {}", a, b);
+	}
+
+	#[test]
+	fn rays_stop_at_the_first_particle() {
+		let (mut state, handles) = line();
+		let r = state.get_particle_radius();
+
+		let hit = state.trace_ray(Vector3(-50.0, 0.0, 0.0), Vector3(2.0, 0.0, 0.0), f32::INFINITY).unwrap().unwrap();
+		assert_eq!(hit.particle.handle, handles[0]);
+		assert_near(hit.distance, 50.0 - r);
+		assert_near(hit.pos.0, -r);
+		assert!(hit.normal.0 < -0.9, "Normal {:?} doesn't face the ray", hit.normal);
+
+		// From the other end, starting far enough out to walk several chunks
+		let hit = state.trace_ray(Vector3(1000.0, 0.0, 0.0), Vector3(-1.0, 0.0, 0.0), f32::INFINITY).unwrap().unwrap();
+		assert_eq!(hit.particle.handle, handles[9]);
+		assert_near(hit.distance, 910.0 - r);
+	}
+
+	#[test]
+	fn rays_can_miss() {
+		let (mut state, _) = line();
+		let r = state.get_particle_radius();
+
+		assert!(state.trace_ray(Vector3(-50.0, 0.0, 0.0), Vector3(-1.0, 0.0, 0.0), f32::INFINITY).unwrap().is_none());
+		assert!(state.trace_ray(Vector3(-50.0, r * 2.0, 0.0), Vector3(1.0, 0.0, 0.0), f32::INFINITY).unwrap().is_none());
+		assert!(state.trace_ray(Vector3(-50.0, 0.0, 0.0), Vector3(1.0, 0.0, 0.0), 40.0).unwrap().is_none());
+	}
+
+	#[test]
+	fn rays_starting_inside_stop_right_away() {
+		let (mut state, handles) = line();
+		let hit = state.trace_ray(Vector3(41.0, 0.0, 0.0), Vector3(0.0, 0.0, 1.0), 100.0).unwrap().unwrap();
+		assert_eq!((hit.particle.handle, hit.distance), (handles[4], 0.0));
+	}
+
+	#[test]
+	fn sweeps_hit_sooner_than_rays() {
+		let (mut state, handles) = line();
+		let r = state.get_particle_radius();
+
+		let hit = state.sweep_sphere(Vector3(-50.0, 0.0, 0.0), Vector3(1.0, 0.0, 0.0), f32::INFINITY, 3.0).unwrap().unwrap();
+		assert_eq!(hit.particle.handle, handles[0]);
+		assert_near(hit.distance, 50.0 - r - 3.0);
+
+		// Passes beside the line close enough for the sphere, but not the ray
+		let beside = Vector3(-50.0, r + 2.0, 0.0);
+		assert!(state.trace_ray(beside, Vector3(1.0, 0.0, 0.0), f32::INFINITY).unwrap().is_none());
+		assert!(state.sweep_sphere(beside, Vector3(1.0, 0.0, 0.0), f32::INFINITY, 3.0).unwrap().is_some());
+	}
+
+	#[test]
+	fn invalid_traces_are_rejected() {
+		let (mut state, _) = line();
+		assert!(matches!(state.trace_ray(Vector3::ZERO, Vector3::ZERO, 1.0), Err(QueryError::Direction)));
+		assert!(matches!(state.trace_ray(Vector3(f32::INFINITY, 0.0, 0.0), Vector3(1.0, 0.0, 0.0), 1.0), Err(QueryError::NotFinite("start"))));
+		assert!(matches!(state.trace_ray(Vector3::ZERO, Vector3(1.0, 0.0, 0.0), -1.0), Err(QueryError::Negative("max distance", _))));
+		assert!(matches!(state.sweep_sphere(Vector3::ZERO, Vector3(1.0, 0.0, 0.0), 1.0, f32::NAN), Err(QueryError::Negative("radius", _))));
+		assert!(matches!(state.sweep_sphere(Vector3::ZERO, Vector3(1.0, 0.0, 0.0), 1.0, f32::INFINITY), Err(QueryError::NotFinite("radius"))));
+	}
+}
@@ -0,0 +1,36 @@
+// Random numbers for spreading out spawned particles.
+use crate::types::Vector3;
+
+/// Small xorshift generator, good enough to spread particles out.
+#[derive(Debug, Clone)]
+pub struct Rng(u32);
+
+impl Rng {
+	pub fn new(seed: u32) -> Self {
+		// Zero would get stuck
+		Self(seed.wrapping_mul(0x9E37_79B9) | 1)
+	}
+
+	/// Uniform in `[0, 1)`
+	pub fn next(&mut self) -> f32 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 17;
+		self.0 ^= self.0 << 5;
+		(self.0 >> 8) as f32 / (1 << 24) as f32
+	}
+
+	/// Uniform in `[-1, 1)`
+	pub fn signed(&mut self) -> f32 {
+		self.next() * 2.0 - 1.0
+	}
+
+	/// Uniform inside the unit sphere
+	pub fn in_sphere(&mut self) -> Vector3 {
+		loop {
+			let v = Vector3(self.signed(), self.signed(), self.signed());
+			if v.length_sqr() <= 1.0 {
+				return v;
+			}
+		}
+	}
+}
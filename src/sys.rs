@@ -0,0 +1,124 @@
+//! FleX types and constants used throughout gfluid.
+//! With the `nvflex-sys` feature these are re-exported from the bindings, otherwise they're
+//! in-crate copies so the CPU backend can be built without the FleX SDK.
+
+#[cfg(feature = "nvflex-sys")]
+pub use nvflex_sys::{
+	NvFlexBoxGeometry, NvFlexCapsuleGeometry, NvFlexCollisionGeometry, NvFlexCollisionShapeType, NvFlexErrorSeverity,
+	NvFlexParams, NvFlexSphereGeometry,
+	eNvFlexLogAll, eNvFlexLogDebug, eNvFlexLogError, eNvFlexLogInfo, eNvFlexLogWarning,
+	eNvFlexPhaseFlagsMask, eNvFlexPhaseFluid, eNvFlexPhaseGroupMask, eNvFlexPhaseSelfCollide,
+	eNvFlexPhaseSelfCollideFilter, eNvFlexPhaseShapeChannelMask,
+	eNvFlexRelaxationGlobal, eNvFlexRelaxationLocal,
+	eNvFlexShapeBox, eNvFlexShapeCapsule, eNvFlexShapeFlagDynamic, eNvFlexShapeFlagTypeMask, eNvFlexShapeSphere,
+};
+
+#[cfg(not(feature = "nvflex-sys"))]
+pub use fallback::*;
+
+#[cfg(not(feature = "nvflex-sys"))]
+#[allow(non_snake_case, non_camel_case_types, non_upper_case_globals)]
+mod fallback {
+	/// Mirrors NvFlexErrorSeverity
+	pub type NvFlexErrorSeverity = i32;
+	pub const eNvFlexLogError: NvFlexErrorSeverity = 0;
+	pub const eNvFlexLogInfo: NvFlexErrorSeverity = 1;
+	pub const eNvFlexLogWarning: NvFlexErrorSeverity = 2;
+	pub const eNvFlexLogDebug: NvFlexErrorSeverity = 4;
+	pub const eNvFlexLogAll: NvFlexErrorSeverity = -1;
+
+	pub const eNvFlexPhaseGroupMask: i32 = 0x000f_ffff;
+	pub const eNvFlexPhaseFlagsMask: i32 = 0x00f0_0000;
+	pub const eNvFlexPhaseShapeChannelMask: i32 = 0xff00_0000_u32 as i32;
+
+	pub const eNvFlexPhaseSelfCollide: i32 = 1 << 20;
+	pub const eNvFlexPhaseSelfCollideFilter: i32 = 1 << 21;
+	pub const eNvFlexPhaseFluid: i32 = 1 << 22;
+
+	/// Mirrors NvFlexCollisionShapeType
+	pub type NvFlexCollisionShapeType = i32;
+	pub const eNvFlexShapeSphere: NvFlexCollisionShapeType = 0;
+	pub const eNvFlexShapeCapsule: NvFlexCollisionShapeType = 1;
+	pub const eNvFlexShapeBox: NvFlexCollisionShapeType = 2;
+
+	pub const eNvFlexShapeFlagTypeMask: i32 = 0x7;
+	pub const eNvFlexShapeFlagDynamic: i32 = 0x8;
+
+	pub const eNvFlexRelaxationGlobal: i32 = 0;
+	pub const eNvFlexRelaxationLocal: i32 = 1;
+
+	#[repr(C)]
+	#[derive(Debug, Clone, Copy)]
+	pub struct NvFlexSphereGeometry {
+		pub radius: f32,
+	}
+
+	#[repr(C)]
+	#[derive(Debug, Clone, Copy)]
+	pub struct NvFlexCapsuleGeometry {
+		pub radius: f32,
+		pub halfHeight: f32,
+	}
+
+	#[repr(C)]
+	#[derive(Debug, Clone, Copy)]
+	pub struct NvFlexBoxGeometry {
+		pub halfExtents: [f32; 3],
+	}
+
+	/// Only the primitive shapes gfluid creates are mirrored here.
+	#[repr(C)]
+	#[derive(Clone, Copy)]
+	pub union NvFlexCollisionGeometry {
+		pub sphere: NvFlexSphereGeometry,
+		pub capsule: NvFlexCapsuleGeometry,
+		pub box_: NvFlexBoxGeometry,
+	}
+
+	#[repr(C)]
+	#[derive(Debug, Clone, Copy)]
+	pub struct NvFlexParams {
+		pub numIterations: i32,
+		pub gravity: [f32; 3],
+		pub radius: f32,
+		pub solidRestDistance: f32,
+		pub fluidRestDistance: f32,
+		pub dynamicFriction: f32,
+		pub staticFriction: f32,
+		pub particleFriction: f32,
+		pub restitution: f32,
+		pub adhesion: f32,
+		pub sleepThreshold: f32,
+		pub maxSpeed: f32,
+		pub maxAcceleration: f32,
+		pub shockPropagation: f32,
+		pub dissipation: f32,
+		pub damping: f32,
+		pub wind: [f32; 3],
+		pub drag: f32,
+		pub lift: f32,
+		pub cohesion: f32,
+		pub surfaceTension: f32,
+		pub viscosity: f32,
+		pub vorticityConfinement: f32,
+		pub anisotropyScale: f32,
+		pub anisotropyMin: f32,
+		pub anisotropyMax: f32,
+		pub smoothing: f32,
+		pub solidPressure: f32,
+		pub freeSurfaceDrag: f32,
+		pub buoyancy: f32,
+		pub diffuseThreshold: f32,
+		pub diffuseBuoyancy: f32,
+		pub diffuseDrag: f32,
+		pub diffuseBallistic: i32,
+		pub diffuseLifetime: f32,
+		pub collisionDistance: f32,
+		pub particleCollisionMargin: f32,
+		pub shapeCollisionMargin: f32,
+		pub planes: [[f32; 4]; 8],
+		pub numPlanes: i32,
+		pub relaxationMode: i32,
+		pub relaxationFactor: f32,
+	}
+}
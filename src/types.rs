@@ -1,12 +1,132 @@
+use std::ops::{Add, AddAssign, Mul, Neg, Sub, SubAssign};
+
 #[repr(C)]
-#[derive(Clone, Copy, Debug, Default)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
 pub struct Vector3(pub f32, pub f32, pub f32);
 
+impl Vector3 {
+	pub const ZERO: Self = Self(0.0, 0.0, 0.0);
+
+	#[inline]
+	pub fn dot(self, rhs: Self) -> f32 {
+		self.0 * rhs.0 + self.1 * rhs.1 + self.2 * rhs.2
+	}
+
+	#[inline]
+	pub fn cross(self, rhs: Self) -> Self {
+		Self(
+			self.1 * rhs.2 - self.2 * rhs.1,
+			self.2 * rhs.0 - self.0 * rhs.2,
+			self.0 * rhs.1 - self.1 * rhs.0,
+		)
+	}
+
+	#[inline]
+	pub fn length_sqr(self) -> f32 {
+		self.dot(self)
+	}
+
+	#[inline]
+	pub fn length(self) -> f32 {
+		self.length_sqr().sqrt()
+	}
+
+	/// Returns the vector scaled to a length of 1, or zero if it has no length.
+	#[inline]
+	pub fn normalize(self) -> Self {
+		let len = self.length();
+		if len > f32::EPSILON {
+			self * (1.0 / len)
+		} else {
+			Self::ZERO
+		}
+	}
+
+	/// Scales the vector down so it's at most `max` long.
+	#[inline]
+	pub fn clamp_length(self, max: f32) -> Self {
+		let len = self.length();
+		if len > max {
+			self * (max / len)
+		} else {
+			self
+		}
+	}
+
+	#[inline]
+	pub fn with_w(self, w: f32) -> Vector4 {
+		Vector4(self.0, self.1, self.2, w)
+	}
+}
+
+impl Add for Vector3 {
+	type Output = Self;
+
+	#[inline]
+	fn add(self, rhs: Self) -> Self {
+		Self(self.0 + rhs.0, self.1 + rhs.1, self.2 + rhs.2)
+	}
+}
+
+impl Sub for Vector3 {
+	type Output = Self;
+
+	#[inline]
+	fn sub(self, rhs: Self) -> Self {
+		Self(self.0 - rhs.0, self.1 - rhs.1, self.2 - rhs.2)
+	}
+}
+
+impl Mul<f32> for Vector3 {
+	type Output = Self;
+
+	#[inline]
+	fn mul(self, rhs: f32) -> Self {
+		Self(self.0 * rhs, self.1 * rhs, self.2 * rhs)
+	}
+}
+
+impl Neg for Vector3 {
+	type Output = Self;
+
+	#[inline]
+	fn neg(self) -> Self {
+		Self(-self.0, -self.1, -self.2)
+	}
+}
+
+impl AddAssign for Vector3 {
+	#[inline]
+	fn add_assign(&mut self, rhs: Self) {
+		*self = *self + rhs;
+	}
+}
+
+impl SubAssign for Vector3 {
+	#[inline]
+	fn sub_assign(&mut self, rhs: Self) {
+		*self = *self - rhs;
+	}
+}
+
+impl From<[f32; 3]> for Vector3 {
+	fn from(v: [f32; 3]) -> Self {
+		Self(v[0], v[1], v[2])
+	}
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 /// x, y, z, imass
 pub struct Vector4(pub f32, pub f32, pub f32, pub f32);
 
+impl Vector4 {
+	#[inline]
+	pub fn xyz(self) -> Vector3 {
+		Vector3(self.0, self.1, self.2)
+	}
+}
+
 impl From<Vector4> for rglua::userdata::Vector {
 	fn from(v: Vector4) -> Self {
 		rglua::userdata::Vector {
@@ -17,8 +137,33 @@ impl From<Vector4> for rglua::userdata::Vector {
 	}
 }
 
+/// Refers to a particle, going stale once it's removed even if its slot is reused by another.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ParticleHandle {
+	pub index: u32,
+	pub generation: u32,
+}
+
+impl ParticleHandle {
+	/// Generations wrap around past this many bits, so handles fit in a lua number without losing precision.
+	pub const GENERATION_BITS: u32 = 21;
+	pub const GENERATION_MASK: u32 = (1 << Self::GENERATION_BITS) - 1;
+
+	pub fn to_bits(self) -> u64 {
+		(self.generation as u64) << 32 | self.index as u64
+	}
+
+	/// Returns [None] for numbers no handle turns into.
+	pub fn from_bits(bits: u64) -> Option<Self> {
+		let generation = u32::try_from(bits >> 32).ok().filter(|g| *g <= Self::GENERATION_MASK)?;
+		Some(Self { index: bits as u32, generation })
+	}
+}
+
 #[derive(Debug)]
 pub struct Particle<'a> {
+	pub handle: ParticleHandle,
+	pub active: bool,
 	pub pdata: &'a Vector4,
 	pub velocity: &'a Vector3,
 	pub phase: &'a i32,
@@ -28,3 +173,33 @@ pub struct Particle<'a> {
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Default)]
 pub struct Quat(pub f32, pub f32, pub f32, pub f32);
+
+impl Quat {
+	pub const IDENTITY: Self = Self(0.0, 0.0, 0.0, 1.0);
+
+	/// Returns the quaternion scaled to unit length.
+	/// A zero quaternion (what you get from an empty table in lua) is treated as no rotation.
+	#[inline]
+	pub fn normalize(self) -> Self {
+		let len = (self.0 * self.0 + self.1 * self.1 + self.2 * self.2 + self.3 * self.3).sqrt();
+		if len > f32::EPSILON {
+			Self(self.0 / len, self.1 / len, self.2 / len, self.3 / len)
+		} else {
+			Self::IDENTITY
+		}
+	}
+
+	#[inline]
+	pub fn conjugate(self) -> Self {
+		Self(-self.0, -self.1, -self.2, self.3)
+	}
+
+	/// Rotates `v` by this quaternion
+	#[inline]
+	pub fn rotate(self, v: Vector3) -> Vector3 {
+		let q = self.normalize();
+		let u = Vector3(q.0, q.1, q.2);
+		let t = u.cross(v) * 2.0;
+		v + t * q.3 + u.cross(t)
+	}
+}